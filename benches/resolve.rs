@@ -0,0 +1,152 @@
+//! Resolves the dependencies of a package in a synthetic index of 500 packages, to keep an eye on
+//! how much time the resolver spends cloning, hashing and comparing package ids.
+//!
+//! Run it with `cargo bench --bench resolve`.
+
+use elba::{
+    package::{Name, PackageId, Summary},
+    remote::resolution::{DirectRes, IndexRes, Resolution},
+    resolve::Resolver,
+    retrieve::{
+        cache::{Cache, Layout},
+        Refresh, Retriever,
+    },
+    util::{graph::Graph, shell::Shell},
+};
+use indexmap::indexmap;
+use itertools::Either::Right;
+use semver::Version;
+use slog::{o, Logger};
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tempdir::TempDir;
+
+const PACKAGES: usize = 500;
+const VERSIONS: &[&str] = &["1.0.0", "1.1.0", "1.2.0", "2.0.0"];
+const ITERATIONS: u32 = 10;
+
+/// The packages which package `i` depends on: a few of the ones before it, so that the whole index
+/// ends up in the graph with plenty of shared dependencies.
+fn deps(i: usize) -> Vec<usize> {
+    let mut deps = vec![i - 1, i / 2, i / 3]
+        .into_iter()
+        .filter(|&d| d > 0 && d < i)
+        .collect::<Vec<_>>();
+    deps.dedup();
+    deps
+}
+
+fn entry(name: &str, version: &str, deps: &[usize]) -> String {
+    let deps = deps
+        .iter()
+        .map(|d| format!(r#"{{ "name": "bench/pkg{}", "req": "^1.0.0" }}"#, d))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        r#"{{ "name": "{}", "version": "{}", "dependencies": [{}], "yanked": false, "location": "dir+bench" }}"#,
+        name, version, deps
+    )
+}
+
+fn write_index(dir: &Path) {
+    fs::create_dir_all(dir.join("bench")).unwrap();
+    fs::write(
+        dir.join("index.toml"),
+        "[index]\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+
+    for i in 1..PACKAGES {
+        let entries = VERSIONS
+            .iter()
+            .map(|v| entry(&format!("bench/pkg{}", i), v, &deps(i)))
+            .collect::<Vec<_>>();
+        fs::write(
+            dir.join("bench").join(format!("pkg{}", i)),
+            entries.join("\n"),
+        )
+        .unwrap();
+    }
+
+    let all = (1..PACKAGES).rev().step_by(7).collect::<Vec<_>>();
+    fs::write(
+        dir.join("bench").join("root"),
+        entry("bench/root", "1.0.0", &all),
+    )
+    .unwrap();
+}
+
+fn main() {
+    let tmp = TempDir::new("elba-bench").unwrap();
+    let index = tmp.path().join("index");
+    write_index(&index);
+
+    let logger = Logger::root(slog::Discard, o!());
+    let root = tmp.path().join("cache");
+    let layout = Layout {
+        bin: root.join("bin"),
+        build: root.join("build"),
+        indices: root.join("indices"),
+        keys: root.join("keys.toml"),
+        src: root.join("src"),
+        store: root.join("store"),
+        tmp: root.join("tmp"),
+    };
+    let cache = Cache::from_disk(&logger, layout, Shell::default()).unwrap();
+
+    let index = DirectRes::Dir { path: index };
+    let ixmap = indexmap!("bench".to_string() => IndexRes { res: index.clone() });
+    let root = Summary::new(
+        PackageId::new(
+            Name::from_str("bench/root").unwrap(),
+            Resolution::Index(IndexRes { res: index.clone() }),
+        ),
+        Version::parse("1.0.0").unwrap(),
+    );
+
+    let mut total = Duration::from_secs(0);
+    let mut packages = 0;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+
+        let mut indices = cache.get_indices(&[index.clone()], Refresh::Never, false);
+        let root_deps = indices
+            .select(&root)
+            .unwrap()
+            .dependencies
+            .iter()
+            .cloned()
+            .map(|d| (PackageId::new(d.name, Resolution::Index(d.index)), d.req))
+            .collect::<Vec<_>>();
+        let mut retriever = Retriever::new(
+            &logger,
+            &cache,
+            root.clone(),
+            root_deps,
+            Right(indices),
+            Graph::default(),
+            &ixmap,
+            Shell::default(),
+            // Offline, only packages already in the cache would count. The index is on disk, so
+            // there's nothing to fetch anyway.
+            false,
+            Refresh::Never,
+        );
+        let graph = Resolver::new(&logger, &mut retriever).solve().unwrap();
+
+        total += start.elapsed();
+        packages = graph.inner.node_count();
+    }
+
+    println!(
+        "resolved {} packages in {:.1}ms on average over {} iterations",
+        packages,
+        total.as_secs_f64() * 1000.0 / f64::from(ITERATIONS),
+        ITERATIONS
+    );
+}
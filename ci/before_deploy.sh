@@ -17,6 +17,16 @@ main() {
 
     test -f Cargo.lock || cargo generate-lockfile
 
+    # Releases are signed with the ed25519 key in the RELEASE_SIGNING_KEY secret (PEM-encoded),
+    # and the public half of it is built into elba, which `elba self update` checks new releases
+    # against. Signing needs OpenSSL 3. The key is kept out of the log.
+    set +x
+    local key=$(mktemp)
+    printf '%s\n' "$RELEASE_SIGNING_KEY" > $key
+    export ELBA_RELEASE_KEY=$(openssl pkey -in $key -pubout -outform DER | tail -c 32 | od -An -tx1 | tr -d ' \n')
+    set -x
+    test -n "$ELBA_RELEASE_KEY"
+
     cross rustc --bin elba --target $TARGET --release -- -C lto
 
     cp target/$TARGET/release/elba $stage/
@@ -25,6 +35,19 @@ main() {
     tar czf $src/$CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz *
     cd $src
 
+    # `elba self update` checks downloads against this
+    case $TRAVIS_OS_NAME in
+        linux)
+            sha256sum $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz > $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz.sha256
+            ;;
+        osx)
+            shasum -a 256 $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz > $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz.sha256
+            ;;
+    esac
+    openssl pkeyutl -sign -rawin -inkey $key -in $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz \
+        | od -An -tx1 | tr -d ' \n' > $CRATE_NAME-$TRAVIS_TAG-$TARGET.tar.gz.sig
+
+    rm -f $key
     rm -rf $stage
 }
 
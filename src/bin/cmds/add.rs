@@ -1,12 +1,14 @@
-use super::get;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
-    cli::build,
-    package::Spec,
+    cli::build::{self, AddOpts, DepSource},
+    package::Name,
     util::{config::Config, error::Result},
 };
 use failure::{format_err, ResultExt};
-use std::{env::current_dir, str::FromStr};
+use semver_constraints::Constraint;
+use std::{path::PathBuf, str::FromStr};
+use url::Url;
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("add")
@@ -15,21 +17,90 @@ pub fn cli() -> App<'static, 'static> {
             Arg::with_name("spec")
                 .takes_value(true)
                 .required(true)
-                .help("The package spec to add"),
+                .help(
+                "The package to add, optionally with a version constraint (like `group/name@^1.2`)",
+            ),
         )
-        .arg(Arg::with_name("dev").help("Whether to add the package as a dev_dependency"))
+        .arg(
+            Arg::with_name("dev")
+                .long("dev")
+                .help("Add the package as a dev dependency"),
+        )
+        .arg(
+            Arg::with_name("git")
+                .long("git")
+                .takes_value(true)
+                .conflicts_with_all(&["path", "registry"])
+                .help("Add the package from a git repository"),
+        )
+        .arg(
+            Arg::with_name("tag")
+                .long("tag")
+                .takes_value(true)
+                .requires("git")
+                .help("The branch, tag or commit of the git repository to use"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .conflicts_with("registry")
+                .help("Add the package from a directory"),
+        )
+        .arg(
+            Arg::with_name("registry")
+                .long("registry")
+                .takes_value(true)
+                .help(
+                    "The index to add the package from (defaults to the first one in the config)",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-update")
+                .long("no-update")
+                .help("Don't resolve dependencies again and update the lockfile afterward"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
-    let spec = Spec::from_str(args.value_of("spec").unwrap())?;
+    let spec = args.value_of("spec").unwrap();
+    let (name, req) = match spec.find('@') {
+        Some(ix) => (&spec[..ix], Some(&spec[ix + 1..])),
+        None => (spec, None),
+    };
+    let name = Name::from_str(name)
+        .with_context(|e| format_err!("the package name `{}` is invalid:\n{}", name, e))?;
+    let req = match req {
+        Some(req) => Some(
+            Constraint::from_str(req)
+                .map_err(|e| format_err!("the version constraint `{}` is invalid: {}", req, e))?,
+        ),
+        None => None,
+    };
 
-    let bcx = get::build_ctx(c, args);
+    let source = if let Some(repo) = args.value_of("git") {
+        DepSource::Git {
+            repo: Url::parse(repo)
+                .with_context(|e| format_err!("the git url `{}` is invalid: {}", repo, e))?,
+            tag: args.value_of("tag").map(|x| x.to_owned()),
+        }
+    } else if let Some(path) = args.value_of("path") {
+        DepSource::Path(PathBuf::from(path))
+    } else {
+        DepSource::Index(args.value_of("registry").map(|x| x.to_owned()))
+    };
 
-    let res = build::add(&bcx, &project, &spec, args.is_present("dev"))?;
+    let opts = AddOpts {
+        source,
+        dev: args.is_present("dev"),
+        update: !args.is_present("no-update"),
+    };
+
+    let bcx = get::build_ctx(c, args);
 
-    Ok(res)
+    build::add(&bcx, &project, &name, req.as_ref(), &opts)
 }
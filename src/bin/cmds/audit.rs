@@ -0,0 +1,97 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
+use elba::{
+    cli::audit::{self, Finding},
+    remote::advisory::Informational,
+    util::{config::Config, error::Result, shell::Verbosity},
+};
+use failure::bail;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("audit")
+        .about("Checks the packages in the lockfile against an advisory database")
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .takes_value(true)
+                .possible_values(&["warnings"])
+                .help("Fail on warnings (like unmaintained packages) as well as vulnerabilities"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+fn describe(finding: &Finding) -> String {
+    let advisory = &finding.advisory;
+    let mut res = format!(
+        "{} {}: {} {}",
+        finding.name, finding.version, advisory.id, advisory.title
+    );
+    if let Some(severity) = advisory.severity {
+        res.push_str(&format!(" (severity: {:?})", severity).to_lowercase());
+    }
+    if !advisory.patched.is_empty() {
+        let patched = advisory
+            .patched
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        res.push_str(&format!("\n{:>12} fixed in {}", "", patched.join(", ")));
+    }
+    if let Some(url) = &advisory.url {
+        res.push_str(&format!("\n{:>12} {}", "", url));
+    }
+
+    res
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    let report = audit::audit(&ctx, &project, &c.advisory_db)?;
+
+    if c.shell().is_json() {
+        get::print_json(&report)?;
+    } else {
+        for finding in &report.vulnerabilities {
+            c.shell().println(
+                style("Vulnerable").red().bold(),
+                describe(finding),
+                Verbosity::Quiet,
+            );
+        }
+        for finding in &report.warnings {
+            let status = match finding.advisory.informational {
+                Some(Informational::Unmaintained) => "Unmaintained",
+                _ => "Notice",
+            };
+            c.shell().println(
+                style(status).yellow().bold(),
+                describe(finding),
+                Verbosity::Quiet,
+            );
+        }
+    }
+
+    let vulns = report.vulnerabilities.len();
+    let warnings = report.warnings.len();
+    if vulns > 0 {
+        bail!("found {} vulnerable packages", vulns)
+    }
+    if warnings > 0 && args.value_of("deny") == Some("warnings") {
+        bail!("found {} warnings, which are denied", warnings)
+    }
+
+    if c.shell().is_json() {
+        Ok("".to_string())
+    } else if warnings > 0 {
+        Ok(format!(
+            "no vulnerabilities found, but {} warnings",
+            warnings
+        ))
+    } else {
+        Ok("no vulnerabilities found".to_string())
+    }
+}
@@ -1,10 +1,10 @@
 use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
 use elba::{
-    cli::build,
-    util::{config::Config, error::Result},
+    cli::{build, export},
+    util::{config::Config, error::Result, shell::Verbosity},
 };
-use failure::{format_err, ResultExt};
 use std::env::current_dir;
 
 pub fn cli() -> App<'static, 'static> {
@@ -18,6 +18,26 @@ pub fn cli() -> App<'static, 'static> {
         )
         .arg(args::target_bin())
         .arg(args::target_test())
+        .arg(args::all())
+        .arg(
+            Arg::with_name("check-mods")
+                .long("check-mods")
+                .conflicts_with("all")
+                .help(
+                    "Compare the mods of the lib target with its source files instead of building",
+                ),
+        )
+        .arg(
+            Arg::with_name("export-script")
+                .long("export-script")
+                .takes_value(true)
+                .value_name("path")
+                .conflicts_with_all(&["all", "check-mods"])
+                .help(
+                    "Write a shell script which builds the targets without elba, vendoring the \
+                     sources of every package next to it",
+                ),
+        )
         .arg(args::build_threads())
         .arg(args::offline())
         .arg(args::debug_log())
@@ -26,12 +46,14 @@ pub fn cli() -> App<'static, 'static> {
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
     let ctx = get::build_ctx(c, args);
 
+    if args.is_present("check-mods") {
+        return build::check_mods(&ctx, &project);
+    }
+
     let ts = (
         args.is_present("lib"),
         args.is_present("lib-cg"),
@@ -42,5 +64,24 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     // This is where our default codegen backend is set
     let backend = get::backends(c, args);
 
+    if let Some(script) = args.value_of_os("export-script") {
+        let script = current_dir()?.join(script);
+        return export::export_script(&ctx, &project, &ts, &backend, &script);
+    }
+
+    if args.is_present("all") {
+        let members = build::workspace_members(&project)?;
+        for (name, path) in &members {
+            ctx.shell.println(
+                style("Building").cyan().bold(),
+                format!("{} ({})", name, path.display()),
+                Verbosity::Quiet,
+            );
+            build::build(&ctx, path, &ts, true, &backend)?;
+        }
+
+        return Ok(format!("built {} packages", members.len()));
+    }
+
     build::build(&ctx, &project, &ts, true, &backend)
 }
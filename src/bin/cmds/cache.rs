@@ -0,0 +1,78 @@
+use super::{args, get};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::cache,
+    retrieve::cache::Cache,
+    util::{config::Config, error::Result},
+};
+use failure::bail;
+use std::time::Duration;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("cache")
+        .about("Manages the global cache")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("clean-tmp")
+                .about("Removes temporary build directories which aren't in use")
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks cached sources and builds for corruption")
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Evicts corrupt entries so that they get fetched or built again"),
+                )
+                .arg(args::debug_log()),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("clean-tmp", Some(args)) => clean_tmp(c, args),
+        ("verify", Some(args)) => verify(c, args),
+        (cmd, _) => bail!("unknown cache subcommand `{}`", cmd),
+    }
+}
+
+fn clean_tmp(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let logger = get::logger(c, args);
+    let cache = Cache::from_disk(&logger, c.layout(), c.shell())?
+        .with_lock_timeout(c.lock_timeout.map(Duration::from_secs));
+
+    let res = cache::clean_tmp(&cache)?;
+
+    if res.skipped == 0 {
+        Ok(format!("removed {} temporary directories", res.removed))
+    } else {
+        Ok(format!(
+            "removed {} temporary directories ({} in use)",
+            res.removed, res.skipped
+        ))
+    }
+}
+
+fn verify(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let logger = get::logger(c, args);
+    let cache = Cache::from_disk(&logger, c.layout(), c.shell())?
+        .with_lock_timeout(c.lock_timeout.map(Duration::from_secs));
+
+    let res = cache::verify(&cache, args.is_present("fix"))?;
+
+    if res.bad == 0 {
+        Ok(format!("verified {} cache entries", res.checked))
+    } else if res.evicted == res.bad {
+        Ok(format!(
+            "evicted {} bad entries out of {} cache entries",
+            res.evicted, res.checked
+        ))
+    } else {
+        bail!(
+            "found {} bad entries out of {} cache entries (rerun with --fix to evict them)",
+            res.bad,
+            res.checked
+        )
+    }
+}
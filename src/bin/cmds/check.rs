@@ -4,8 +4,6 @@ use elba::{
     cli::build,
     util::{config::Config, error::Result},
 };
-use failure::{format_err, ResultExt};
-use std::env::current_dir;
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("check")
@@ -21,9 +19,7 @@ pub fn cli() -> App<'static, 'static> {
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
     let ctx = get::build_ctx(c, args);
 
@@ -1,15 +1,58 @@
-use clap::{App, ArgMatches, SubCommand};
-use elba::util::{clear_dir, config::Config, error::Result};
+use super::get;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::clean::{self, CleanOpts},
+    util::{clear_dir, config::Config, error::Result},
+};
 use failure::{format_err, ResultExt};
 
 pub fn cli() -> App<'static, 'static> {
-    SubCommand::with_name("clean").about("Cleans the global cache")
+    SubCommand::with_name("clean")
+        .about("Cleans the global cache, or the target directory of a project")
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .help("Clean the whole target directory of the project instead of the cache"),
+        )
+        .arg(
+            Arg::with_name("lib")
+                .long("lib")
+                .help("Clean the typechecked library in the target directory"),
+        )
+        .arg(
+            Arg::with_name("bins")
+                .long("bins")
+                .help("Clean the binaries in the target directory"),
+        )
+        .arg(
+            Arg::with_name("artifacts")
+                .long("artifacts")
+                .help("Clean the codegen outputs of the library in the target directory"),
+        )
+        .arg(
+            Arg::with_name("doc")
+                .long("doc")
+                .help("Clean the documentation in the target directory"),
+        )
 }
 
-pub fn exec(c: &mut Config, _args: &ArgMatches) -> Result<String> {
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let opts = CleanOpts {
+        lib: args.is_present("lib"),
+        bins: args.is_present("bins"),
+        artifacts: args.is_present("artifacts"),
+        doc: args.is_present("doc"),
+    };
+    if args.is_present("target") || opts.lib || opts.bins || opts.artifacts || opts.doc {
+        let project = get::project(args)?;
+        let ctx = get::build_ctx(c, args);
+        return clean::clean(&ctx, &project, &opts);
+    }
+
     let layout = c.layout();
 
     clear_dir(&layout.src).context(format_err!("couldn't clear {}", layout.src.display()))?;
+    clear_dir(&layout.store).context(format_err!("couldn't clear {}", layout.store.display()))?;
     clear_dir(&layout.build).context(format_err!("couldn't clear {}", layout.build.display()))?;
     clear_dir(&layout.indices)
         .context(format_err!("couldn't clear {}", layout.indices.display()))?;
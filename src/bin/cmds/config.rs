@@ -0,0 +1,70 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::config,
+    util::{config::Config, error::Result},
+};
+use failure::{bail, format_err, ResultExt};
+use std::env::current_dir;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("config")
+        .about("Reads and writes elba's configuration")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Prints the value of a configuration key, like `term.verbosity`")
+                .arg(Arg::with_name("key").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Sets a configuration key in the global configuration file")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("value").required(true))
+                .arg(
+                    Arg::with_name("project")
+                        .long("project")
+                        .help("Set the key in the current project's configuration file instead"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Prints every configuration key along with its value"),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("get", Some(args)) => get(c, args),
+        ("set", Some(args)) => set(args),
+        ("list", Some(_)) => {
+            println!("{}", config::list(c)?);
+            Ok("".to_string())
+        }
+        (cmd, _) => bail!("unknown config subcommand `{}`", cmd),
+    }
+}
+
+fn get(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match config::get(c, args.value_of("key").unwrap())? {
+        toml::Value::String(s) => println!("{}", s),
+        value => println!("{}", value),
+    }
+
+    Ok("".to_string())
+}
+
+fn set(args: &ArgMatches) -> Result<String> {
+    let key = args.value_of("key").unwrap();
+    let path = if args.is_present("project") {
+        let cwd = current_dir().context(format_err!(
+            "couldn't get current dir; doesn't exist or no permissions..."
+        ))?;
+        Config::project_path(&cwd)
+    } else {
+        Config::global_path()
+    };
+
+    config::set(&path, key, args.value_of("value").unwrap())?;
+
+    Ok(format!("set `{}` in {}", key, path.display()))
+}
@@ -0,0 +1,42 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
+use elba::util::{
+    config::Config,
+    error::{Error, Result},
+};
+use failure::bail;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("explain")
+        .about("Explains an error code in detail, or lists every error code")
+        .arg(
+            Arg::with_name("code")
+                .takes_value(true)
+                .help("The code of the error, like E0301"),
+        )
+}
+
+pub fn exec(_c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let code = match args.value_of("code") {
+        Some(code) => code,
+        None => {
+            for e in Error::ALL {
+                println!("{} {}", style(e.code()).bold(), e);
+            }
+            return Ok("".to_string());
+        }
+    };
+
+    let e = match Error::from_code(code) {
+        Some(e) => e,
+        None => bail!(
+            "{} isn't an error code elba knows about; run `elba explain` to list them",
+            code
+        ),
+    };
+
+    println!("{} {}\n", style(format!("{}:", e.code())).bold(), e);
+    println!("{}", e.explanation());
+
+    Ok("".to_string())
+}
@@ -0,0 +1,29 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::tool::{self, Tool},
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("fmt")
+        .about("Formats the source files of a package with the configured formatter")
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Fail if any file isn't formatted instead of formatting it"),
+        )
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+    let tool = if args.is_present("check") {
+        Tool::FmtCheck
+    } else {
+        Tool::Fmt
+    };
+
+    let ctx = get::build_ctx(c, args);
+    tool::run_tool(&ctx, &project, tool, &c.tools)
+}
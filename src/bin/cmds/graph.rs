@@ -0,0 +1,44 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::graph::{self, GraphFormat, GraphOpts},
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("graph")
+        .about("Prints the dependency graph in the lockfile of a project")
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["dot", "json", "graphml"])
+                .default_value("dot")
+                .help("The format to print the graph in"),
+        )
+        .arg(
+            Arg::with_name("versions")
+                .long("versions")
+                .help("Label the packages in a DOT graph with their versions"),
+        )
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    let format = match args.value_of("format").unwrap() {
+        "json" => GraphFormat::Json,
+        "graphml" => GraphFormat::GraphMl,
+        _ => GraphFormat::Dot,
+    };
+    let opts = GraphOpts {
+        format,
+        versions: args.is_present("versions"),
+    };
+
+    println!("{}", graph::graph(&ctx, &project, opts)?);
+
+    Ok("".to_string())
+}
@@ -0,0 +1,67 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::index::{self, GroupOpts},
+    util::{config::Config, error::Result},
+};
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("group")
+        .about("Manages groups of packages on a registry")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("claim")
+                .about("Claims a group, so that only its owners can publish packages in it")
+                .arg(
+                    Arg::with_name("group")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The group to claim"),
+                )
+                .arg(args::index())
+                .arg(args::token())
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("owners")
+                .about("Lists the owners of a group")
+                .arg(
+                    Arg::with_name("group")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The group to look up"),
+                )
+                .arg(args::index())
+                .arg(args::token())
+                .arg(args::debug_log()),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("claim", Some(args)) => {
+            let opts = GroupOpts {
+                index: args.value_of("index").map(|x| x.to_owned()),
+                token: get::token(c, args)?,
+            };
+            let ctx = get::build_ctx(c, args);
+
+            index::claim_group(&ctx, args.value_of("group").unwrap(), &opts)
+        }
+        ("owners", Some(args)) => {
+            let opts = GroupOpts {
+                index: args.value_of("index").map(|x| x.to_owned()),
+                token: get::token(c, args)?,
+            };
+            let ctx = get::build_ctx(c, args);
+
+            for owner in index::group_owners(&ctx, args.value_of("group").unwrap(), &opts)? {
+                println!("{}", owner);
+            }
+
+            Ok("".to_string())
+        }
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,137 @@
+use std::{env::current_dir, str::FromStr};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::index,
+    util::{config::Config, error::Result, keys::PublicKey, normalize_path},
+};
+use failure::{bail, format_err, ResultExt};
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("index")
+        .about("Manages package indices")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("add-local")
+                .about("Packages the current project and adds it to a local directory registry")
+                .arg(
+                    Arg::with_name("registry")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The directory of the registry (created if it doesn't exist)"),
+                )
+                .arg(args::no_verify())
+                .arg(args::offline())
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("refresh")
+                .about("Retrieves the latest copy of the indices")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Throw away the cached copies and retrieve them from scratch"),
+                )
+                .arg(args::index())
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("Lists the keys trusted to sign the entries of a secure index")
+                .arg(args::index())
+                .arg(args::offline())
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("trust")
+                .about("Trusts keys to sign the entries of a secure index")
+                .arg(
+                    Arg::with_name("keys")
+                        .multiple(true)
+                        .required(true)
+                        .help("The keys to trust"),
+                )
+                .arg(args::index())
+                .arg(args::offline())
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("untrust")
+                .about("Stops trusting a key to sign the entries of a secure index")
+                .arg(Arg::with_name("key").help(
+                    "The key to stop trusting (every key if not given, which lets the index stop \
+                     being secure)",
+                ))
+                .arg(args::index()),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("add-local", Some(args)) => add_local(c, args),
+        ("refresh", Some(args)) => refresh(c, args),
+        ("keys", Some(args)) => keys(c, args),
+        ("trust", Some(args)) => trust(c, args),
+        ("untrust", Some(args)) => untrust(c, args),
+        (cmd, _) => bail!("unknown index subcommand `{}`", cmd),
+    }
+}
+
+fn add_local(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let cwd = current_dir().context(format_err!(
+        "couldn't get current dir; doesn't exist or no permissions..."
+    ))?;
+    let registry = normalize_path(&cwd.join(args.value_of("registry").unwrap()));
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+
+    index::add_local(&ctx, &project, &registry, !args.is_present("no-verify"))
+}
+
+fn refresh(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = match args.value_of("index") {
+        Some(ix) => Some(index::select_index(&c.indices, Some(ix))?),
+        None => None,
+    };
+    let ctx = get::build_ctx(c, args);
+
+    index::refresh(&ctx, ix.as_ref(), args.is_present("force"))
+}
+
+fn keys(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = index::select_index(&c.indices, args.value_of("index"))?;
+    let ctx = get::build_ctx(c, args);
+
+    let (trusted, listed) = index::keys(&ctx, &ix)?;
+    for key in &trusted {
+        println!("{}", key);
+    }
+    for key in listed.iter().filter(|x| !trusted.contains(x)) {
+        println!("{} (listed by the index, but not trusted)", key);
+    }
+
+    Ok("".to_string())
+}
+
+fn trust(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = index::select_index(&c.indices, args.value_of("index"))?;
+    let keys = args
+        .values_of("keys")
+        .unwrap()
+        .map(PublicKey::from_str)
+        .collect::<Result<Vec<_>>>()?;
+    let ctx = get::build_ctx(c, args);
+
+    index::trust(&ctx, &ix, keys)
+}
+
+fn untrust(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = index::select_index(&c.indices, args.value_of("index"))?;
+    let key = args.value_of("key").map(PublicKey::from_str).transpose()?;
+    let ctx = get::build_ctx(c, args);
+
+    index::untrust(&ctx, &ix, key.as_ref())
+}
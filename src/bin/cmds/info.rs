@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::index::{self, InfoOpts},
+    package::Name,
+    util::{config::Config, error::Result},
+};
+use failure::{bail, format_err, ResultExt};
+use semver::Version;
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("info")
+        .about("Shows information about a package from the indices")
+        .arg(
+            Arg::with_name("package")
+                .takes_value(true)
+                .required(true)
+                .help("The package to look up, or a group ending in a slash (like `group/`) to list its packages"),
+        )
+        .arg(
+            Arg::with_name("version")
+                .takes_value(true)
+                .help("The version to look up (defaults to the latest one)"),
+        )
+        .arg(
+            Arg::with_name("registry")
+                .long("registry")
+                .help("Ask the registry for the package's metadata instead of retrieving it"),
+        )
+        .arg(args::index())
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let pkg = args.value_of("package").unwrap();
+    if pkg.ends_with('/') {
+        return group(c, args, pkg);
+    }
+
+    let pkg = Name::from_str(pkg)
+        .with_context(|e| format_err!("the package name `{}` is invalid:\n{}", pkg, e))?;
+    let version = match args.value_of("version") {
+        Some(v) => Some(
+            Version::parse(v)
+                .with_context(|e| format_err!("the version `{}` is invalid: {}", v, e))?,
+        ),
+        None => None,
+    };
+
+    let opts = InfoOpts {
+        index: args.value_of("index").map(|x| x.to_owned()),
+        version,
+        registry: args.is_present("registry"),
+    };
+
+    let ctx = get::build_ctx(c, args);
+    let info = index::info(&ctx, &pkg, &opts)?;
+
+    if c.shell().is_json() {
+        get::print_json(&info)?;
+    } else {
+        println!("{}", info);
+    }
+
+    Ok("".to_string())
+}
+
+fn group(c: &mut Config, args: &ArgMatches, group: &str) -> Result<String> {
+    if args.is_present("version") {
+        bail!("a version can't be given when listing the packages in a group")
+    }
+
+    let ctx = get::build_ctx(c, args);
+    let hits = index::group_packages(&ctx, group, args.value_of("index"))?;
+
+    if c.shell().is_json() {
+        get::print_json(&hits)?;
+    } else {
+        for row in index::search_table(&hits, &ctx.indices) {
+            println!("{}", row);
+        }
+    }
+
+    Ok("".to_string())
+}
@@ -1,49 +1,49 @@
-use super::args;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
     cli::new,
-    package::Name,
     util::{config::Config, error::Result},
 };
 use failure::{format_err, ResultExt};
-use std::env::current_dir;
+use std::{env::current_dir, path::Path};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("init")
         .about("Initializes an elba project in the current directory")
         .arg(args::vcs())
+        .arg(args::package_name())
+        .args(&args::new_package())
         .arg(
-            Arg::with_name("lib")
-                .long("lib")
-                .help("Create a library project")
-                .multiple(false),
+            Arg::with_name("from-ipkg")
+                .long("from-ipkg")
+                .takes_value(true)
+                .value_name("ipkg")
+                .conflicts_with_all(&[
+                    "lib",
+                    "bin",
+                    "description",
+                    "license",
+                    "interactive",
+                    "package-name",
+                ])
+                .help("Create the manifest out of an existing ipkg file"),
         )
+        .arg(args::offline())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let cdir = current_dir().context(format_err!(
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
-    let name = cdir
-        .file_name()
-        .ok_or_else(|| format_err!("can't make a project in a root directory"))?
-        .to_string_lossy()
-        .into_owned();
-    let name = Name::new(name.clone(), name)?;
-    let bin = !args.is_present("lib");
-    let author = if let Some(profile) = &c.profile {
-        Some((profile.name.clone(), profile.email.clone()))
-    } else {
-        None
-    };
 
-    let new_ctx = new::NewCtx {
-        path: cdir,
-        author,
-        name,
-        bin,
-        git: args.value_of_lossy("vcs").unwrap() == "git",
-    };
+    if let Some(ipkg) = args.value_of_os("from-ipkg") {
+        let bcx = get::build_ctx(c, args);
+        let git = args.value_of_lossy("vcs").unwrap() == "git";
+        return new::init_from_ipkg(&bcx, &cdir, Path::new(ipkg), git);
+    }
+
+    let name = get::package_name(c, args, &cdir)?;
+    let new_ctx = get::new_ctx(c, args, cdir, name)?;
 
     new::init(new_ctx)
 }
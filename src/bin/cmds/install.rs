@@ -1,7 +1,7 @@
 use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
-    cli::build,
+    cli::build::{self, InstallOpts},
     package::Spec,
     util::{config::Config, error::Result},
 };
@@ -21,11 +21,27 @@ pub fn cli() -> App<'static, 'static> {
         .arg(
             Arg::with_name("force")
                 .long("force")
-                .help("Overwrite existing installed binaries"),
+                .help("Rebuild and overwrite existing installed binaries"),
+        )
+        .arg(
+            Arg::with_name("locked")
+                .long("locked")
+                .help("Install a local package with exactly the dependencies in its lockfile"),
+        )
+        .arg(
+            Arg::with_name("outdated")
+                .long("outdated")
+                .conflicts_with_all(&["spec", "force", "locked"])
+                .help("List the installed packages which have newer versions instead"),
         )
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    if args.is_present("outdated") {
+        let ctx = get::build_ctx(c, args);
+        return build::outdated_installs(&ctx);
+    }
+
     let current = current_dir();
 
     let proj = if let Some(spec) = args.value_of_lossy("spec") {
@@ -41,12 +57,14 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
 
     let ctx = get::build_ctx(c, args);
 
-    let targets = args
-        .values_of("bin")
-        .map(|x| x.collect())
-        .unwrap_or_else(|| vec![]);
+    let targets = args.values_of("bin").map(|x| x.collect::<Vec<_>>());
 
     let backend = get::backends(c, args);
 
-    build::install(&ctx, proj, &targets, &backend, args.is_present("force"))
+    let opts = InstallOpts {
+        force: args.is_present("force"),
+        locked: args.is_present("locked"),
+    };
+
+    build::install(&ctx, proj, &targets, &backend, opts)
 }
@@ -0,0 +1,59 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
+use elba::{
+    cli::license,
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("license")
+        .alias("licenses")
+        .about("Reports the licenses of the packages a project depends on")
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("license")
+                .help("Fail if a dependency can only be used under this license"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+    let deny = args.values_of_lossy("deny").unwrap_or_else(|| vec![]);
+
+    let ctx = get::build_ctx(c, args);
+    let report = license::licenses(&ctx, &project)?;
+
+    if c.shell().is_json() {
+        get::print_json(&report)?;
+        license::check_denied(&report, &deny)?;
+        return Ok("".to_string());
+    }
+
+    let mut total = 0;
+    for group in &report.groups {
+        let license = match &group.license {
+            Some(license) => style(license.as_str()).bold(),
+            None => style("no license given").yellow().bold(),
+        };
+        println!("{} ({})", license, group.packages.len());
+        for pkg in &group.packages {
+            println!("    {} {}", pkg.name, pkg.version);
+        }
+        total += group.packages.len();
+    }
+
+    license::check_denied(&report, &deny)?;
+
+    Ok(format!(
+        "found {} licenses across {} packages",
+        report.groups.len(),
+        total
+    ))
+}
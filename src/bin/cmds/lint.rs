@@ -0,0 +1,19 @@
+use super::{args, get};
+use clap::{App, ArgMatches, SubCommand};
+use elba::{
+    cli::tool::{self, Tool},
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("lint")
+        .about("Lints the source files of a package with the configured linter")
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    tool::run_tool(&ctx, &project, Tool::Lint, &c.tools)
+}
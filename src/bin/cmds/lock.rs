@@ -0,0 +1,54 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+use std::path::PathBuf;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("lock")
+        .alias("generate-lockfile")
+        .about("Resolves dependencies and writes elba.lock without building anything")
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Fail if elba.lock is out of date instead of writing it"),
+        )
+        .arg(
+            Arg::with_name("prune")
+                .long("prune")
+                .conflicts_with("check")
+                .help("Drop the packages nothing depends on anymore without resolving again"),
+        )
+        .arg(
+            Arg::with_name("merge")
+                .long("merge")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["ours", "theirs"])
+                .conflicts_with_all(&["check", "prune"])
+                .help(
+                    "Resolve a conflict between two lockfiles, writing the result to both \
+                     elba.lock and <ours>",
+                ),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+
+    if let Some(mut sides) = args.values_of("merge") {
+        let ours = PathBuf::from(sides.next().unwrap());
+        let theirs = PathBuf::from(sides.next().unwrap());
+        build::merge_lock(&ctx, &project, &ours, &theirs)
+    } else if args.is_present("prune") {
+        build::prune_lock(&ctx, &project)
+    } else {
+        build::lock(&ctx, &project, args.is_present("check"))
+    }
+}
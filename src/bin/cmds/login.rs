@@ -0,0 +1,51 @@
+use std::io::{self, BufRead};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
+use elba::{
+    cli::index,
+    util::{config::Config, credentials::Credentials, error::Result, shell::Verbosity},
+};
+use failure::bail;
+
+use super::args;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("login")
+        .about("Saves a token for authenticating with the registry of an index")
+        .arg(
+            Arg::with_name("token")
+                .takes_value(true)
+                .help("The token to save (read from stdin if not given)"),
+        )
+        .arg(args::index())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = index::select_index(&c.indices, args.value_of("index"))?;
+
+    let token = match args.value_of("token") {
+        Some(token) => token.to_owned(),
+        None => {
+            c.shell().println(
+                style("Login").cyan(),
+                format!("paste your token for {} below", ix),
+                Verbosity::Quiet,
+            );
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line
+        }
+    };
+    let token = token.trim();
+
+    if token.is_empty() {
+        bail!("no token given")
+    }
+
+    let mut creds = Credentials::load(&c.credentials_path())?;
+    creds.set(&ix, token.to_owned());
+    creds.save()?;
+
+    Ok(format!("saved token for {}", ix))
+}
@@ -0,0 +1,26 @@
+use clap::{App, ArgMatches, SubCommand};
+use elba::{
+    cli::index,
+    util::{config::Config, credentials::Credentials, error::Result},
+};
+use failure::bail;
+
+use super::args;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("logout")
+        .about("Removes the saved token for the registry of an index")
+        .arg(args::index())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let ix = index::select_index(&c.indices, args.value_of("index"))?;
+
+    let mut creds = Credentials::load(&c.credentials_path())?;
+    if creds.remove(&ix).is_none() {
+        bail!("not logged into {}", ix)
+    }
+    creds.save()?;
+
+    Ok(format!("removed token for {}", ix))
+}
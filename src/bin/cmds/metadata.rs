@@ -0,0 +1,37 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("metadata")
+        .about(
+            "Prints the manifest, workspace, resolved dependencies, targets and paths of a project",
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json"])
+                .default_value("json")
+                .help("The format to print the metadata in"),
+        )
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    let metadata = build::metadata(&ctx, &project)?;
+
+    if c.shell().is_json() {
+        get::print_json(&metadata)?;
+    } else {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+    }
+
+    Ok("".to_string())
+}
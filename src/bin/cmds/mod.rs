@@ -1,116 +1,238 @@
 mod add;
+mod audit;
 mod build;
+mod cache;
 mod check;
 mod clean;
+mod config;
 mod doc;
+mod explain;
+mod fmt;
+mod graph;
+mod group;
+mod index;
+mod info;
 mod init;
 mod install;
+mod license;
+mod lint;
+mod lock;
+mod login;
+mod logout;
+mod metadata;
 mod new;
+mod nix_gen;
+mod owner;
 mod package;
 mod print_config;
+mod publish;
+mod remove;
 mod repl;
+mod run;
+mod sbom;
 mod script;
 mod search;
+mod self_cmd;
+mod status;
 mod test;
 mod uninstall;
 mod update;
+mod verify;
 
 use clap::{App, ArgMatches};
-use elba::util::{
-    config::{Backend, Config},
-    error::Result,
-    shell::Verbosity,
+use elba::{
+    cli::build::find_manifest_root,
+    util::{
+        config::{Backend, Config},
+        error::Result,
+        shell::Verbosity,
+    },
 };
-use failure::{format_err, ResultExt};
+use failure::{bail, format_err, ResultExt};
 use itertools::Itertools;
-use slog::{o, Discard, Logger};
+use slog::{o, Discard, Duplicate, Level, Logger};
 use slog_async;
+use slog_json;
 use slog_term;
-use std::{env, process::Command};
+use std::{
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 pub type Exec = fn(&mut Config, &ArgMatches) -> Result<String>;
 
 pub fn subcommands() -> Vec<App<'static, 'static>> {
     vec![
         add::cli(),
+        audit::cli(),
         build::cli(),
+        cache::cli(),
         check::cli(),
         clean::cli(),
+        config::cli(),
         doc::cli(),
+        explain::cli(),
+        fmt::cli(),
+        graph::cli(),
+        group::cli(),
+        index::cli(),
+        info::cli(),
         init::cli(),
         install::cli(),
+        license::cli(),
+        lint::cli(),
+        lock::cli(),
+        login::cli(),
+        logout::cli(),
+        metadata::cli(),
         new::cli(),
+        nix_gen::cli(),
+        owner::cli(),
         package::cli(),
         print_config::cli(),
+        publish::cli(),
+        remove::cli(),
         repl::cli(),
+        run::cli(),
+        sbom::cli(),
         script::cli(),
         search::cli(),
+        self_cmd::cli(),
+        status::cli(),
         test::cli(),
         uninstall::cli(),
         update::cli(),
+        verify::cli(),
     ]
 }
 
 pub fn execute_internal(cmd: &str) -> Option<Exec> {
     match cmd {
         "add" => Some(add::exec),
+        "audit" => Some(audit::exec),
         "build" => Some(build::exec),
+        "cache" => Some(cache::exec),
         "check" => Some(check::exec),
         "clean" => Some(clean::exec),
+        "config" => Some(config::exec),
         "doc" => Some(doc::exec),
+        "explain" => Some(explain::exec),
+        "fmt" => Some(fmt::exec),
+        "graph" => Some(graph::exec),
+        "group" => Some(group::exec),
+        "index" => Some(index::exec),
+        "info" => Some(info::exec),
         "init" => Some(init::exec),
         "install" => Some(install::exec),
+        "license" => Some(license::exec),
+        "lint" => Some(lint::exec),
+        "lock" => Some(lock::exec),
+        "login" => Some(login::exec),
+        "logout" => Some(logout::exec),
+        "metadata" => Some(metadata::exec),
         "new" => Some(new::exec),
+        "nix-gen" => Some(nix_gen::exec),
+        "owner" => Some(owner::exec),
         "package" => Some(package::exec),
         "print-config" => Some(print_config::exec),
+        "publish" => Some(publish::exec),
+        "remove" => Some(remove::exec),
         "repl" => Some(repl::exec),
+        "run" => Some(run::exec),
+        "sbom" => Some(sbom::exec),
         "script" => Some(script::exec),
         "search" => Some(search::exec),
+        "self" => Some(self_cmd::exec),
+        "status" => Some(status::exec),
         "test" => Some(test::exec),
         "uninstall" => Some(uninstall::exec),
         "update" => Some(update::exec),
+        "verify" => Some(verify::exec),
         _ => None,
     }
 }
 
-pub fn execute_external(cmd: &str, args: &ArgMatches) -> Result<String> {
-    let ext_args: Vec<&str> = args
-        .values_of("")
+/// Finds the executable for an external subcommand `elba-{cmd}`, looking in the directories of
+/// `PATH` and then in the directory elba installs binaries to.
+pub fn find_external(c: &Config, cmd: &str) -> Option<PathBuf> {
+    let name = format!("elba-{}{}", cmd, env::consts::EXE_SUFFIX);
+    let mut dirs = env::var_os("PATH")
+        .map(|x| env::split_paths(&x).collect::<Vec<_>>())
+        .unwrap_or_else(|| vec![]);
+    dirs.push(c.directories.bin.clone());
+
+    dirs.into_iter()
+        .map(|x| x.join(&name))
+        .find(|x| x.is_file())
+}
+
+/// Runs an external subcommand, passing along the rest of the arguments elba was given.
+///
+/// The subcommand is told about the project in `dir` (if there is one) and about elba itself
+/// through environment variables, so that it doesn't have to work them out again.
+pub fn execute_external(
+    c: &mut Config,
+    path: &Path,
+    cmd: &str,
+    dir: Option<&Path>,
+    args: &ArgMatches,
+) -> Result<String> {
+    let ext_args: Vec<&OsStr> = args
+        .values_of_os("")
         .map(|x| x.collect())
         .unwrap_or_else(|| vec![]);
+    let display = ext_args.iter().map(|x| x.to_string_lossy()).join(" ");
 
-    Command::new(cmd)
+    let mut process = Command::new(path);
+    process
         .args(&ext_args)
-        .spawn()
-        .with_context(|e| {
-            format_err!(
-                "failed to spawn external command `elba-{} {}`:\n{}",
-                cmd,
-                ext_args.iter().join(" "),
-                e
-            )
-        })?
-        .wait_with_output()
-        .with_context(|e| {
-            format_err!(
-                "failed to get output of external command `elba-{} {}`:\n{}",
-                cmd,
-                ext_args.iter().join(" "),
-                e
-            )
-        })?;
+        .env("ELBA_CACHE_DIR", &c.directories.cache)
+        .env("ELBA_CONFIG", Config::global_path());
+    if let Ok(exe) = env::current_exe() {
+        process.env("ELBA", exe);
+    }
+    if c.shell().is_json() {
+        process.env("ELBA_TERM__MESSAGE_FORMAT", "json");
+    }
+    if let Some(root) = dir.and_then(find_manifest_root) {
+        process
+            .env("ELBA_PROJECT_ROOT", root)
+            .env("ELBA_MANIFEST_PATH", root.join("elba.toml"));
+    }
+
+    let status = process
+        .status()
+        .with_context(|e| format_err!("failed to run `elba-{} {}`:\n{}", cmd, display, e))?;
 
-    Ok(format!(
-        "finished executing `elba-{} {}`",
-        cmd,
-        ext_args.iter().join(" ")
-    ))
+    if !status.success() {
+        bail!("`elba-{} {}` failed: {}", cmd, display, status)
+    }
+
+    Ok(format!("finished executing `elba-{} {}`", cmd, display))
 }
 
 mod get {
     use super::*;
-    use elba::cli::build::BuildCtx;
+    use console::style;
+    use elba::{
+        cli::{
+            build::{self, BuildCtx},
+            index,
+            new::{self, NewCtx},
+        },
+        package::Name,
+        retrieve::Refresh,
+        util::credentials::Credentials,
+    };
     use slog::Drain;
+    use std::{
+        fs::File,
+        io::{self, BufRead, Write},
+        str::FromStr,
+        time::Duration,
+    };
 
     pub fn build_ctx(c: &mut Config, args: &ArgMatches) -> BuildCtx {
         let logger = get::logger(c, args);
@@ -119,23 +241,71 @@ mod get {
             compiler: c.compiler.clone(),
             indices: c.indices.to_owned(),
             global_cache: c.layout(),
+            mirrors: c.mirrors(),
             logger,
             threads: get::threads(c, args),
             shell: c.shell(),
-            offline: args.is_present("offline"),
+            offline: get::offline(c, args),
+            refresh: if get::offline(c, args) {
+                Refresh::Never
+            } else {
+                Refresh::Stale(Duration::from_secs(c.index_ttl))
+            },
             opts: get::idris_opts(c, args),
+            timeout: Duration::from_secs(c.network.timeout),
+            index_cache: c.index_cache,
+            policy: c.policy.clone(),
+            lock_timeout: c.lock_timeout.map(Duration::from_secs),
         }
     }
 
+    /// The logger for the library: a terminal drain at the level `-v`s asked for, and a JSON drain
+    /// writing everything to the `--log-file`, if there is one.
     pub fn logger(c: &mut Config, args: &ArgMatches) -> Logger {
+        let mut level = c.term.log_level;
         if args.is_present("debug-log") {
             c.term.verbosity = Verbosity::None;
-            let decorator = slog_term::TermDecorator::new().build();
+            level = level.max(2);
+        }
+
+        let term = log_level(level).map(|level| {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
             let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-            let drain = slog_async::Async::new(drain).build().fuse();
-            Logger::root(drain, o!())
-        } else {
-            Logger::root(Discard, o!())
+            slog_async::Async::new(drain.filter_level(level).fuse()).build()
+        });
+
+        let file = c
+            .term
+            .log_file
+            .as_ref()
+            .and_then(|path| match File::create(path) {
+                Ok(file) => {
+                    let drain = slog_json::Json::new(file).add_default_keys().build().fuse();
+                    Some(slog_async::Async::new(drain).build())
+                }
+                Err(e) => {
+                    c.shell().println(
+                        style("[warn]").yellow().bold(),
+                        format!("couldn't open log file {}: {}", path.display(), e),
+                        Verbosity::Quiet,
+                    );
+                    None
+                }
+            });
+
+        match (term, file) {
+            (Some(term), Some(file)) => Logger::root(Duplicate::new(term, file).fuse(), o!()),
+            (Some(drain), None) | (None, Some(drain)) => Logger::root(drain.fuse(), o!()),
+            (None, None) => Logger::root(Discard, o!()),
+        }
+    }
+
+    fn log_level(level: u8) -> Option<Level> {
+        match level {
+            0 => None,
+            1 => Some(Level::Info),
+            2 => Some(Level::Debug),
+            _ => Some(Level::Trace),
         }
     }
 
@@ -169,10 +339,174 @@ mod get {
         backend
     }
 
-    pub fn threads(_c: &mut Config, args: &ArgMatches) -> u32 {
+    /// The directory of the project to act on: the one `--manifest-path` points to if it's
+    /// given, and otherwise the closest one at or above the current directory with a manifest in
+    /// it, so that commands do the same thing from anywhere inside of a project. If there's no
+    /// manifest anywhere, this is the current directory, which might have an ipkg file in it.
+    pub fn project(args: &ArgMatches) -> Result<PathBuf> {
+        let cwd = env::current_dir().context(format_err!(
+            "couldn't get current dir; doesn't exist or no permissions..."
+        ))?;
+
+        match args.value_of_os("manifest-path") {
+            Some(path) => build::manifest_dir(&cwd, Path::new(path)),
+            None => Ok(find_manifest_root(&cwd)
+                .map(|x| x.to_path_buf())
+                .unwrap_or(cwd)),
+        }
+    }
+
+    /// Asks the user a question on the terminal, giving back `default` if they don't answer.
+    fn prompt(question: &str, default: &str) -> Result<String> {
+        print!("{} [{}]: ", style(question).cyan(), default);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        let line = line.trim();
+
+        Ok(if line.is_empty() { default } else { line }.to_string())
+    }
+
+    /// Asks the user a yes-or-no question, which can only be answered yes when there's someone at
+    /// the terminal to answer it.
+    pub fn confirm(question: &str) -> Result<bool> {
+        if !console::user_attended() {
+            bail!(
+                "there's no one to ask `{}`; pass --yes to answer yes",
+                question
+            )
+        }
+
+        Ok(prompt(&format!("{} (y/n)", question), "n")?.eq_ignore_ascii_case("y"))
+    }
+
+    /// Works out what goes in a new package from the options `args::new_package` adds, asking
+    /// about the rest if `--interactive` is passed.
+    pub fn new_ctx(c: &Config, args: &ArgMatches, path: PathBuf, name: Name) -> Result<NewCtx> {
+        let mut description = args.value_of("description").map(|x| x.to_string());
+        let mut license = args.value_of("license").map(|x| x.to_string());
+        let mut lib = args.is_present("lib");
+        let mut bin = args.is_present("bin");
+        let mut git = args.value_of("vcs") == Some("git");
+
+        if args.is_present("interactive") {
+            if description.is_none() {
+                description = Some(prompt("Description", "")?).filter(|x| !x.is_empty());
+            }
+            if license.is_none() {
+                let question = format!("License ({}, or any other)", new::LICENSES.join(", "));
+                license = Some(prompt(&question, "none")?).filter(|x| x != "none");
+            }
+            if !lib && !bin {
+                match prompt("Targets (bin, lib or both)", "bin")?.as_str() {
+                    "bin" => bin = true,
+                    "lib" => lib = true,
+                    "both" => {
+                        lib = true;
+                        bin = true;
+                    }
+                    x => bail!("unknown kind of target `{}`", x),
+                }
+            }
+            if args.occurrences_of("vcs") == 0 {
+                git = match prompt("Version control (git or none)", "git")?.as_str() {
+                    "git" => true,
+                    "none" => false,
+                    x => bail!("unknown version control system `{}`", x),
+                };
+            }
+        }
+
+        let author = c
+            .profile
+            .as_ref()
+            .map(|profile| (profile.name.clone(), profile.email.clone()));
+
+        Ok(NewCtx {
+            path,
+            name,
+            author,
+            description,
+            license,
+            lib,
+            bin: bin || !lib,
+            git,
+        })
+    }
+
+    /// The name of a new package in `path`: the one `--name` gives, or otherwise the name of the
+    /// directory, in a group which the user is asked for if there's anyone at the terminal to ask.
+    /// The group defaults to the name of the directory too.
+    pub fn package_name(c: &Config, args: &ArgMatches, path: &Path) -> Result<Name> {
+        if let Some(name) = args.value_of("package-name") {
+            return Ok(Name::from_str(name)
+                .with_context(|e| format_err!("the name `{}` is invalid: {}", name, e))?);
+        }
+
+        let dir = path
+            .file_name()
+            .ok_or_else(|| {
+                format_err!(
+                    "{} has no name to give the package; pass `--name group/name` to name it",
+                    path.display()
+                )
+            })?
+            .to_string_lossy()
+            .into_owned();
+
+        let group = if console::user_attended() && !c.shell().is_json() {
+            prompt(
+                &format!("Group of the package {}", style(&dir).bold()),
+                &dir,
+            )?
+        } else {
+            dir.clone()
+        };
+
+        Ok(Name::new(group, dir.clone()).with_context(|e| {
+            format_err!(
+                "the package can't be named after the directory `{}`: {}\n\n\
+                 pass `--name group/name` to give it a different name",
+                dir,
+                e
+            )
+        })?)
+    }
+
+    /// Prints data as one line of JSON, so that it fits in with the events printed with
+    /// `--message-format json`.
+    pub fn print_json<T: serde::Serialize>(data: &T) -> Result<()> {
+        println!("{}", serde_json::to_string(data)?);
+        Ok(())
+    }
+
+    pub fn threads(c: &mut Config, args: &ArgMatches) -> u32 {
         args.value_of("threads")
             .and_then(|s| s.parse().ok())
-            .unwrap_or(2)
+            .unwrap_or(c.jobs)
+    }
+
+    /// Whether to run in offline mode, either because `--offline` was passed or because the
+    /// config says to.
+    pub fn offline(c: &Config, args: &ArgMatches) -> bool {
+        args.is_present("offline") || c.offline
+    }
+
+    /// The token for the registry of the index chosen with `--index`: the one passed with
+    /// `--token` or `ELBA_TOKEN` if there is one, and the one saved by `elba login` otherwise.
+    pub fn token(c: &mut Config, args: &ArgMatches) -> Result<Option<String>> {
+        if let Some(token) = args.value_of("token") {
+            return Ok(Some(token.to_owned()));
+        }
+        if let Ok(token) = env::var("ELBA_TOKEN") {
+            return Ok(Some(token));
+        }
+
+        let ix = index::select_index(&c.indices, args.value_of("index"))?;
+        let creds = Credentials::load(&c.credentials_path())?;
+
+        Ok(creds.get(&ix).map(|x| x.to_owned()))
     }
 
     pub fn idris_opts(_c: &mut Config, args: &ArgMatches) -> Vec<String> {
@@ -217,6 +551,12 @@ mod args {
             .help("The names of the tests to which the command should apply (or all if no argument is provided)")
     }
 
+    pub fn all() -> Arg {
+        Arg::with_name("all")
+            .long("all")
+            .help("Apply the command to every package in the workspace")
+    }
+
     pub fn build_threads() -> Arg {
         Arg::with_name("threads")
             .long("threads")
@@ -264,6 +604,52 @@ mod args {
             .help("The VCS template to use when initializing a new repo")
     }
 
+    /// The options of `elba new` and `elba init` for what goes in the new package.
+    pub fn new_package() -> Vec<Arg> {
+        vec![
+            Arg::with_name("lib")
+                .long("lib")
+                .help("Create a library target (along with a binary one if --bin is passed)"),
+            Arg::with_name("bin")
+                .long("bin")
+                .help("Create a binary target (the default if --lib isn't passed)"),
+            Arg::with_name("description")
+                .long("description")
+                .takes_value(true)
+                .help("The description of the package"),
+            Arg::with_name("license")
+                .long("license")
+                .takes_value(true)
+                .help("The SPDX identifier of the license of the package"),
+            Arg::with_name("interactive")
+                .long("interactive")
+                .short("i")
+                .help("Ask about anything the other options don't say"),
+        ]
+    }
+
+    pub fn package_name() -> Arg {
+        Arg::with_name("package-name")
+            .long("name")
+            .takes_value(true)
+            .value_name("group/name")
+            .help("The name of the package, if it shouldn't be named after its directory")
+    }
+
+    pub fn index() -> Arg {
+        Arg::with_name("index")
+            .long("index")
+            .takes_value(true)
+            .help("The index to use (the name of an index in the config, or its url)")
+    }
+
+    pub fn token() -> Arg {
+        Arg::with_name("token")
+            .long("token")
+            .takes_value(true)
+            .help("The token to authenticate with the registry (overrides `elba login`)")
+    }
+
     pub fn debug_log() -> Arg {
         Arg::with_name("debug-log")
             .long("debug-log")
@@ -1,9 +1,9 @@
-use super::args;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
     cli::new,
     package::Name,
-    util::{config::Config, error::Result},
+    util::{config::Config, error::Result, normalize_path},
 };
 use failure::{format_err, ResultExt};
 use std::{env::current_dir, str::FromStr};
@@ -11,38 +11,52 @@ use std::{env::current_dir, str::FromStr};
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("new")
         .about("Creates a new empty project")
-        .arg(Arg::with_name("name").required(true))
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .help("The name of the package (group/name), or the directory to create it in"),
+        )
         .arg(args::vcs())
+        .arg(args::package_name())
+        .args(&args::new_package())
         .arg(
-            Arg::with_name("lib")
-                .long("lib")
-                .help("Create a library project")
-                .multiple(false),
+            Arg::with_name("workspace")
+                .long("workspace")
+                .conflicts_with_all(&[
+                    "lib",
+                    "bin",
+                    "description",
+                    "license",
+                    "interactive",
+                    "package-name",
+                ])
+                .help("Create an empty workspace in a directory with the given name instead"),
         )
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let name = &*args.value_of_lossy("name").unwrap();
-    let name = Name::from_str(name)
-        .with_context(|e| format_err!("the name `{}` is invalid: {}", name, e))?;
-    let bin = !args.is_present("lib");
-    let author = if let Some(profile) = &c.profile {
-        Some((profile.name.clone(), profile.email.clone()))
-    } else {
-        None
-    };
+    let target = &*args.value_of_lossy("path").unwrap();
     let cdir = current_dir().context(format_err!(
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
-    let path = cdir.join(name.name().to_string());
+    let git = args.value_of_lossy("vcs").unwrap() == "git";
 
-    let new_ctx = new::NewCtx {
-        path,
-        author,
-        name,
-        bin,
-        git: args.value_of_lossy("vcs").unwrap() == "git",
+    if args.is_present("workspace") {
+        return new::new_workspace(&cdir.join(target), git);
+    }
+
+    // `elba new group/name` makes the package in a directory named after it. Anything else is the
+    // directory to make the package in, which the package is named after unless `--name` is given.
+    let (path, name) = match Name::from_str(target) {
+        Ok(name) if !args.is_present("package-name") => (cdir.join(name.name()), name),
+        _ => {
+            let path = normalize_path(&cdir.join(target));
+            let name = get::package_name(c, args, &path)?;
+            (path, name)
+        }
     };
 
+    let new_ctx = get::new_ctx(c, args, path, name)?;
+
     new::new(new_ctx)
 }
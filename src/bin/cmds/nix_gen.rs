@@ -0,0 +1,42 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::nix,
+    util::{config::Config, error::Result, fs::write_atomic},
+};
+use failure::{format_err, ResultExt};
+use std::path::Path;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("nix-gen")
+        .about("Generates a Nix expression which builds a project from its lockfile")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("path")
+                .help("Write the expression to this file instead of printing it"),
+        )
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    let expr = nix::nix_gen(&ctx, &project)?;
+
+    match args.value_of_os("output") {
+        Some(path) => {
+            let path = Path::new(path);
+            write_atomic(path, format!("{}\n", expr))
+                .with_context(|e| format_err!("couldn't write {}: {}", path.display(), e))?;
+            Ok(format!("wrote Nix expression to {}", path.display()))
+        }
+        None => {
+            println!("{}", expr);
+            Ok("".to_string())
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::{
+        build,
+        index::{self, OwnerOpts},
+    },
+    package::Name,
+    util::{config::Config, error::Result},
+};
+use failure::{bail, format_err, ResultExt};
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("owner")
+        .about("Manages the owners of a package on a registry")
+        .arg(
+            Arg::with_name("package")
+                .takes_value(true)
+                .help("The package to manage (defaults to the current project)"),
+        )
+        .arg(
+            Arg::with_name("add")
+                .long("add")
+                .short("a")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A user to add as an owner"),
+        )
+        .arg(
+            Arg::with_name("remove")
+                .long("remove")
+                .short("r")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A user to remove as an owner"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .short("l")
+                .help("List the owners of the package"),
+        )
+        .arg(args::index())
+        .arg(args::token())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let pkg = match args.value_of("package") {
+        Some(pkg) => Name::from_str(pkg)
+            .with_context(|e| format_err!("the package name `{}` is invalid:\n{}", pkg, e))?,
+        None => {
+            let project = get::project(args)?;
+            let (_, manifest) = build::find_manifest(&project, false, None)?;
+            manifest.name().clone()
+        }
+    };
+
+    let values = |name| {
+        args.values_of(name)
+            .map(|x| x.map(|x| x.to_owned()).collect())
+            .unwrap_or_else(Vec::new)
+    };
+
+    let opts = OwnerOpts {
+        index: args.value_of("index").map(|x| x.to_owned()),
+        token: get::token(c, args)?,
+        add: values("add"),
+        remove: values("remove"),
+        list: args.is_present("list"),
+    };
+
+    if opts.add.is_empty() && opts.remove.is_empty() && !opts.list {
+        bail!("nothing to do; pass --add, --remove or --list")
+    }
+
+    let ctx = get::build_ctx(c, args);
+
+    index::owner(&ctx, &pkg, &opts)
+}
@@ -1,42 +1,79 @@
-use std::env::current_dir;
-
-use clap::{App, ArgMatches, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
-    cli::{build, index},
-    util::{
-        config::{Backend, Config},
-        error::Result,
+    cli::{
+        build,
+        index::{self, PublishOpts},
     },
+    util::{config::Config, error::Result},
 };
-use failure::{format_err, ResultExt};
 
 use super::{args, get};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("package")
         .arg(args::no_verify())
-        .about("Compiles the package and packages it into a nice tarball")
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .short("l")
+                .help("Prints the files which go into the tarball without verifying it"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with_all(&["no-verify", "list"])
+                .help("Make every check publishing would, like `elba publish --dry-run`"),
+        )
+        .arg(
+            Arg::with_name("allow-dirty")
+                .long("allow-dirty")
+                .requires("verify")
+                .help("Don't check for uncommitted changes"),
+        )
+        .arg(args::index())
+        .about("Packages the package into a nice tarball and makes sure that it compiles")
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
     let ctx = get::build_ctx(c, args);
-    let (project, _) = build::find_manifest(&project, false, None).unwrap();
 
-    if !args.is_present("no-verify") {
-        build::build(
-            &ctx,
-            &project,
-            &(true, false, None, None),
-            true,
-            &Backend::default(),
-        )?;
+    if args.is_present("verify") {
+        let opts = PublishOpts {
+            index: args.value_of("index").map(|x| x.to_owned()),
+            token: None,
+            allow_dirty: args.is_present("allow-dirty"),
+            dry_run: true,
+            warn_size: Some(c.package_warn_size),
+        };
+        let checklist = index::prepublish_checks(&ctx, &project, &opts)?;
+        if c.shell().is_json() {
+            get::print_json(&checklist)?;
+        } else {
+            index::print_checklist(ctx.shell, &checklist);
+        }
+        return checklist.result();
+    }
+
+    let (project, manifest) = build::find_manifest(&project, false, None)?;
+
+    if args.is_present("list") {
+        for file in index::package_files(&project, &manifest)? {
+            println!("{}", file.display());
+        }
     }
 
-    let (gz_name, _) = index::package(&project)?;
+    let (gz_name, manifest) = index::package(&project)?;
+    index::report_size(ctx.shell, &gz_name, Some(c.package_warn_size))?;
+
+    if args.is_present("list") {
+        return Ok("".to_string());
+    }
+
+    if !args.is_present("no-verify") {
+        index::verify_tarball(&ctx, &gz_name, &manifest)?;
+    }
 
     Ok(format!(
         "created compressed tarball at `{}`",
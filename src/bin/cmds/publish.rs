@@ -0,0 +1,42 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::index::{self, PublishOpts},
+    util::{config::Config, error::Result},
+};
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("publish")
+        .about("Verifies, packages and uploads the package to a registry")
+        .arg(args::index())
+        .arg(args::token())
+        .arg(
+            Arg::with_name("allow-dirty")
+                .long("allow-dirty")
+                .help("Publish even if there are uncommitted changes"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Do everything except actually uploading the package"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+
+    let opts = PublishOpts {
+        index: args.value_of("index").map(|x| x.to_owned()),
+        token: get::token(c, args)?,
+        allow_dirty: args.is_present("allow-dirty"),
+        dry_run: args.is_present("dry-run"),
+        warn_size: Some(c.package_warn_size),
+    };
+
+    index::publish(&ctx, &project, &opts)
+}
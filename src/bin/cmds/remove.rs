@@ -0,0 +1,50 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    package::Name,
+    util::{config::Config, error::Result},
+};
+use failure::{format_err, ResultExt};
+use std::str::FromStr;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("remove")
+        .about("Remove a package dependency from the current project")
+        .arg(
+            Arg::with_name("package")
+                .takes_value(true)
+                .required(true)
+                .help("The package to remove"),
+        )
+        .arg(
+            Arg::with_name("dev")
+                .long("dev")
+                .help("Remove the package from the dev dependencies"),
+        )
+        .arg(
+            Arg::with_name("no-update")
+                .long("no-update")
+                .help("Don't resolve dependencies again and update the lockfile afterward"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let pkg = args.value_of("package").unwrap();
+    let name = Name::from_str(pkg)
+        .with_context(|e| format_err!("the package name `{}` is invalid:\n{}", pkg, e))?;
+
+    let bcx = get::build_ctx(c, args);
+
+    build::remove(
+        &bcx,
+        &project,
+        &name,
+        args.is_present("dev"),
+        !args.is_present("no-update"),
+    )
+}
@@ -4,8 +4,6 @@ use elba::{
     cli::build,
     util::{config::Config, error::Result, shell::Verbosity},
 };
-use failure::{format_err, ResultExt};
-use std::env::current_dir;
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("repl")
@@ -31,9 +29,7 @@ pub fn cli() -> App<'static, 'static> {
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
     let ts = (
         args.is_present("lib"),
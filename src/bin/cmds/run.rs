@@ -0,0 +1,46 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("run")
+        .about("Builds and runs a binary of the root package")
+        .arg(
+            Arg::with_name("bin")
+                .long("bin")
+                .takes_value(true)
+                .number_of_values(1)
+                .help(
+                    "The name of the binary to run (default_run or the only binary if unspecified)",
+                ),
+        )
+        .arg(args::build_threads())
+        .arg(args::offline())
+        .arg(args::debug_log())
+        .arg(args::idris_opts())
+        .args(&args::backends())
+        .arg(
+            Arg::with_name("args")
+                .multiple(true)
+                .last(true)
+                .help("The arguments to pass to the binary"),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+
+    let bin_args = args
+        .values_of("args")
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_else(|| vec![]);
+
+    let backend = get::backends(c, args);
+
+    build::run(&ctx, &project, args.value_of("bin"), &bin_args, &backend)
+}
@@ -0,0 +1,53 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::sbom::{self, SbomFormat},
+    util::{config::Config, error::Result, fs::write_atomic},
+};
+use failure::{format_err, ResultExt};
+use std::env;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("sbom")
+        .about("Writes a software bill of materials for a project from its lockfile")
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["cyclonedx", "spdx-json"])
+                .default_value("cyclonedx")
+                .help("The format to write the bill of materials in"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("path")
+                .help(
+                    "The file to write to (bom.json or sbom.spdx.json in the project by default)",
+                ),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+    let format = match args.value_of("format").unwrap() {
+        "spdx-json" => SbomFormat::SpdxJson,
+        _ => SbomFormat::CycloneDx,
+    };
+    let path = match args.value_of_os("output") {
+        Some(path) => env::current_dir()?.join(path),
+        None => project.join(format.default_file()),
+    };
+
+    let ctx = get::build_ctx(c, args);
+    let bom = sbom::sbom(&ctx, &project, format)?;
+
+    write_atomic(&path, format!("{}\n", bom))
+        .with_context(|e| format_err!("couldn't write {}: {}", path.display(), e))?;
+
+    Ok(format!("wrote bill of materials to {}", path.display()))
+}
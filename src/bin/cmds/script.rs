@@ -1,27 +1,66 @@
-use std::env::current_dir;
-
 use clap::{App, Arg, ArgMatches, SubCommand};
 use console::style;
 use elba::{
     build::run_script,
-    cli::build::find_manifest,
+    cli::{build::find_manifest, script},
     util::{config::Config, error::Result, fmt_multiple, shell::Verbosity},
 };
 use failure::{format_err, ResultExt};
+use std::{env, path::Path};
+
+use super::{args, get};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("script")
-        .about("Run a project script")
-        .arg(Arg::with_name("script-name").required(true))
+        .about("Run a project script, or build and run a standalone Idris file")
+        .arg(
+            Arg::with_name("script-name")
+                .required(true)
+                .help("The name of a script in the manifest, or the path to an .idr file"),
+        )
+        .arg(args::build_threads())
+        .arg(args::offline())
+        .arg(args::debug_log())
+        .arg(args::idris_opts())
+        .args(&args::backends())
+        .arg(
+            Arg::with_name("args")
+                .multiple(true)
+                .last(true)
+                .help("The arguments to pass to the standalone file"),
+        )
+}
+
+/// Whether a script name is the path to a standalone Idris file rather than a manifest script.
+fn is_file(name: &str) -> bool {
+    let path = Path::new(name);
+    let ext = path.extension().and_then(|x| x.to_str());
+    (ext == Some("idr") || ext == Some("lidr")) && path.is_file()
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let name = &*args.value_of_lossy("script-name").unwrap();
-    let cdir = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
 
-    let (_, manifest) = find_manifest(&cdir, true, None)?;
+    if is_file(name) {
+        let file = env::current_dir()
+            .context(format_err!(
+                "couldn't get current dir; doesn't exist or no permissions..."
+            ))?
+            .join(name);
+        let ctx = get::build_ctx(c, args);
+        let file_args = args
+            .values_of("args")
+            .map(|x| x.collect::<Vec<_>>())
+            .unwrap_or_else(|| vec![]);
+        let backend = get::backends(c, args);
+
+        return script::run_file(&ctx, &file, &file_args, &backend);
+    }
+
+    let project = get::project(args)?;
+
+    // Scripts run in the root of the project, wherever elba is run from inside of it.
+    let (root, manifest) = find_manifest(&project, true, None)?;
 
     if let Some(s) = manifest.scripts.get(name) {
         c.shell().println(
@@ -31,7 +70,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         );
         c.shell().println_plain(
             fmt_multiple(
-                &run_script(&cdir, s)
+                &run_script(&root, s)
                     .with_context(|e| format_err!("error running script:\n{}", e))?,
             ),
             Verbosity::Normal,
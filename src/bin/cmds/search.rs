@@ -1,9 +1,11 @@
-use super::get;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
 use elba::{
-    cli::index,
-    util::{config::Config, error::Result},
+    cli::index::{self, SearchOpts, SearchSort},
+    util::{config::Config, error::Result, shell::Verbosity},
 };
+use failure::{bail, format_err, ResultExt};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("search")
@@ -11,16 +13,103 @@ pub fn cli() -> App<'static, 'static> {
         .arg(
             Arg::with_name("query")
                 .takes_value(true)
-                .required(true)
+                .required_unless("category")
                 .help("The search query."),
         )
+        .arg(
+            Arg::with_name("category")
+                .long("category")
+                .takes_value(true)
+                .help("Only show packages in this category"),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .takes_value(true)
+                .help("The most results to show at once (defaults to 20)"),
+        )
+        .arg(
+            Arg::with_name("page")
+                .long("page")
+                .takes_value(true)
+                .help("Which page of results to show, counting from 1"),
+        )
+        .arg(
+            Arg::with_name("toml")
+                .long("toml")
+                .help("Print each result as a line for the [dependencies] of a manifest"),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .takes_value(true)
+                .possible_values(&["relevance", "downloads"])
+                .help("How to order the results (defaults to relevance)"),
+        )
+        .arg(args::index())
+        .arg(args::offline())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let query = args.value_of("query").unwrap();
+    let query = args.value_of("query").unwrap_or("");
+    let limit = number(args, "limit")?.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 {
+        bail!("the limit has to be at least 1")
+    }
+    let page = number(args, "page")?.unwrap_or(1);
+    if page == 0 {
+        bail!("pages are counted from 1")
+    }
+    let opts = SearchOpts {
+        index: args.value_of("index").map(|x| x.to_owned()),
+        limit: Some(limit),
+        offset: (page - 1) * limit,
+        category: args.value_of("category").map(|x| x.to_owned()),
+        sort: match args.value_of("sort") {
+            Some("downloads") => SearchSort::Downloads,
+            _ => SearchSort::Relevance,
+        },
+    };
+
     let bcx = get::build_ctx(c, args);
+    let results = index::search(&bcx, &query, &opts)?;
+
+    if c.shell().is_json() {
+        get::print_json(&results.hits)?;
+    } else if args.is_present("toml") {
+        for hit in &results.hits {
+            println!("{}", index::dependency_line(hit, &bcx.indices));
+        }
+    } else {
+        for row in index::search_table(&results.hits, &bcx.indices) {
+            println!("{}", row);
+        }
+    }
+
+    let shown = opts.offset + results.hits.len();
+    if shown < results.total {
+        c.shell().println(
+            style("[note]").dim(),
+            format!(
+                "{} more results; use `--page {}` to see them",
+                results.total - shown,
+                page + 1
+            ),
+            Verbosity::Normal,
+        );
+    }
+
+    Ok(format!("found {} packages", results.total))
+}
 
-    println!("{}", index::search(&bcx, &query)?);
+/// How many results are shown at once, unless `--limit` says otherwise.
+const DEFAULT_LIMIT: usize = 20;
 
-    Ok("search complete".to_string())
+fn number(args: &ArgMatches, arg: &str) -> Result<Option<usize>> {
+    match args.value_of(arg) {
+        Some(x) => Ok(Some(x.parse::<usize>().with_context(|e| {
+            format_err!("the {} `{}` is invalid: {}", arg, x, e)
+        })?)),
+        None => Ok(None),
+    }
 }
@@ -0,0 +1,86 @@
+use super::get;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::self_update::{self, UpdateOpts},
+    util::{config::Config, error::Result},
+};
+use failure::{bail, format_err, ResultExt};
+use semver::Version;
+use std::time::Duration;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("self")
+        .about("Manages the installation of elba itself")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Replaces elba with the latest release, or another release")
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .takes_value(true)
+                        .help("The version to install (can be older than this one)"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Install the release even if it's the version which is installed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("uninstall")
+                .about("Removes elba, along with its caches, data and installed binaries")
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("Don't ask for confirmation"),
+                ),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("update", Some(args)) => update(c, args),
+        ("uninstall", Some(args)) => uninstall(c, args),
+        (cmd, _) => bail!("unknown self subcommand `{}`", cmd),
+    }
+}
+
+fn update(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let version = match args.value_of("version") {
+        Some(v) => Some(
+            Version::parse(v.trim_start_matches('v'))
+                .with_context(|e| format_err!("the version `{}` is invalid: {}", v, e))?,
+        ),
+        None => None,
+    };
+    let opts = UpdateOpts {
+        version,
+        force: args.is_present("force"),
+    };
+
+    self_update::update(
+        c.shell(),
+        &c.releases,
+        Duration::from_secs(c.network.timeout),
+        &opts,
+    )
+}
+
+fn uninstall(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    if !args.is_present("yes") {
+        let question = format!(
+            "Remove elba, along with the binaries it installed in {}, its caches in {} and its \
+             data in {}?",
+            c.directories.bin.display(),
+            c.directories.cache.display(),
+            c.directories.data.display()
+        );
+        if !get::confirm(&question)? {
+            bail!("nothing was uninstalled")
+        }
+    }
+
+    self_update::uninstall(c.shell(), &c.layout(), &c.credentials_path())
+}
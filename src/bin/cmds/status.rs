@@ -0,0 +1,30 @@
+use super::{args, get};
+use clap::{App, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("status")
+        .about("Shows what building the root package would take, without building anything")
+        .arg(args::offline())
+        .arg(args::debug_log())
+        .args(&args::backends())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+    let backend = get::backends(c, args);
+    let status = build::status(&ctx, &project, &backend)?;
+
+    if c.shell().is_json() {
+        get::print_json(&status)?;
+    } else {
+        println!("{}", status);
+    }
+
+    Ok("".to_string())
+}
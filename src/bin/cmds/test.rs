@@ -1,16 +1,16 @@
 use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use console::style;
 use elba::{
     cli::build,
-    util::{config::Config, error::Result},
+    util::{config::Config, error::Result, shell::Verbosity},
 };
-use failure::{format_err, ResultExt};
-use std::env::current_dir;
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("test")
         .about("Runs the tests of the root package")
         .args(&args::backends())
+        .arg(args::all())
         .arg(args::build_threads())
         .arg(args::offline())
         .arg(args::debug_log())
@@ -26,13 +26,16 @@ pub fn cli() -> App<'static, 'static> {
                 .multiple(true)
                 .help("The names of the tests to run (all tests are run if unspecified)"),
         )
-        .arg(args::idris_opts())
+        .arg(
+            Arg::with_name("args")
+                .multiple(true)
+                .last(true)
+                .help("The arguments to pass to every test binary"),
+        )
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
     let ctx = get::build_ctx(c, args);
 
@@ -44,10 +47,29 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         .map(|x| x.collect())
         .unwrap_or_else(|| vec![]);
 
+    let test_args = args
+        .values_of("args")
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_else(|| vec![]);
+
     let test_threads = args
         .value_of("test-threads")
         .and_then(|x| x.parse::<u32>().ok())
         .unwrap_or(1);
 
-    build::test(&ctx, &project, &targets, &backend, test_threads)
+    if args.is_present("all") {
+        let members = build::workspace_members(&project)?;
+        for (name, path) in &members {
+            ctx.shell.println(
+                style("Testing").cyan().bold(),
+                format!("{} ({})", name, path.display()),
+                Verbosity::Quiet,
+            );
+            build::test(&ctx, path, &targets, &test_args, &backend, test_threads)?;
+        }
+
+        return Ok(format!("tested {} packages", members.len()));
+    }
+
+    build::test(&ctx, &project, &targets, &test_args, &backend, test_threads)
 }
@@ -6,7 +6,7 @@ use elba::{
     util::{config::Config, error::Result},
 };
 use failure::{format_err, ResultExt};
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("uninstall")
@@ -29,7 +29,8 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let logger = get::logger(c, args);
     let shell = c.shell();
 
-    let cache = Cache::from_disk(&logger, c.layout(), shell)?;
+    let cache = Cache::from_disk(&logger, c.layout(), shell)?
+        .with_lock_timeout(c.lock_timeout.map(Duration::from_secs));
 
     let rc = cache.remove_bins(&spec, &targets)?;
 
@@ -3,10 +3,11 @@ use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
     cli::build,
     package::Spec,
+    retrieve::Refresh,
     util::{config::Config, error::Result},
 };
 use failure::{format_err, ResultExt};
-use std::{env::current_dir, str::FromStr};
+use std::str::FromStr;
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("update")
@@ -21,11 +22,11 @@ pub fn cli() -> App<'static, 'static> {
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
-    let project = current_dir().context(format_err!(
-        "couldn't get current dir; doesn't exist or no permissions..."
-    ))?;
+    let project = get::project(args)?;
 
-    let ctx = get::build_ctx(c, args);
+    let mut ctx = get::build_ctx(c, args);
+    // Updating is the whole point, so it'd be silly to keep using an old copy of the indices.
+    ctx.refresh = Refresh::Always;
 
     let packages = args
         .values_of("update")
@@ -0,0 +1,20 @@
+use super::{args, get};
+use clap::{App, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("verify")
+        .about("Checks the manifest of the root package for problems")
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = get::project(args)?;
+
+    let ctx = get::build_ctx(c, args);
+
+    build::verify(&ctx, &project)
+}
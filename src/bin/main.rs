@@ -5,9 +5,17 @@ mod cmds;
 
 use clap::{App, AppSettings, Arg, ArgMatches};
 use console::style;
-use elba::util::{config::Config, shell::Verbosity};
-use failure::{Error, ResultExt};
-use std::{process::exit, time::Instant};
+use elba::{
+    cli::{build, self_update},
+    util::{
+        config::Config,
+        error::{error_code, error_package, InPackage},
+        profile::{self, Span},
+        shell::{to_ascii, Event, MessageFormat, Verbosity},
+    },
+};
+use failure::{bail, Error, ResultExt};
+use std::{borrow::Cow, env, path::Path, process::exit, time::Instant};
 
 // TODO: Tasks and scripts (i.e. hooks)
 // Tasks are binary dependencies which can be executed from within the project with `elba task`.
@@ -42,7 +50,8 @@ fn cli() -> App<'static, 'static> {
             Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
-                .help("Verbose output")
+                .multiple(true)
+                .help("Verbose output, with logs on stderr (info with -v, debug with -vv, trace with -vvv)")
                 .global(true)
                 .conflicts_with("quiet"),
         )
@@ -52,6 +61,30 @@ fn cli() -> App<'static, 'static> {
                 .help("Quiet output")
                 .global(true),
         )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("path")
+                .help("Write every log record to this file as line-delimited JSON")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile-elba")
+                .long("profile-elba")
+                .takes_value(true)
+                .value_name("path")
+                .help("Write a profile of where elba spends its time to this file")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile-format")
+                .long("profile-format")
+                .takes_value(true)
+                .possible_values(&["chrome", "folded"])
+                .help("Write the profile as a Chrome trace (the default) or as folded stacks")
+                .global(true),
+        )
         .arg(
             Arg::with_name("color")
                 .long("color")
@@ -65,6 +98,41 @@ fn cli() -> App<'static, 'static> {
                 .help("Disable color output")
                 .global(true),
         )
+        .arg(
+            Arg::with_name("ascii")
+                .long("ascii")
+                .help("Print nothing but unstyled ASCII, for terminals which can't show more")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-index-cache")
+                .long("no-index-cache")
+                .help("Parse index entries from scratch instead of reading them from the cache")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("manifest-path")
+                .long("manifest-path")
+                .takes_value(true)
+                .value_name("path")
+                .help("Act on the project with this manifest instead of the current directory's")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("message-format")
+                .long("message-format")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .help("Print status lines for people or line-delimited JSON events")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print line-delimited JSON events; the same as --message-format json")
+                .global(true)
+                .conflicts_with("message-format"),
+        )
         .subcommands(cmds::subcommands())
 }
 
@@ -106,24 +174,85 @@ fn expand_aliases(
     Ok(args)
 }
 
-fn go() -> Result<String, Error> {
+/// The `--manifest-path` passed either before or after the subcommand.
+fn manifest_path<'a>(args: &'a ArgMatches) -> Option<&'a Path> {
+    args.value_of_os("manifest-path")
+        .or_else(|| args.subcommand().1?.value_of_os("manifest-path"))
+        .map(Path::new)
+}
+
+/// The message format asked for, either before or after the subcommand.
+fn message_format(args: &ArgMatches) -> Option<MessageFormat> {
+    let sub = args.subcommand().1;
+    if args.is_present("json") || sub.map(|x| x.is_present("json")).unwrap_or(false) {
+        return Some(MessageFormat::Json);
+    }
+    match args
+        .value_of("message-format")
+        .or_else(|| sub?.value_of("message-format"))
+    {
+        Some("json") => Some(MessageFormat::Json),
+        Some("human") => Some(MessageFormat::Human),
+        _ => None,
+    }
+}
+
+fn go(format: &mut MessageFormat, ascii: &mut bool) -> Result<String, Error> {
     let args = cli().get_matches();
-    let mut config =
-        Config::new().with_context(|e| format!("could not load configuration:\n{}", e))?;
+    if let Some(f) = message_format(&args) {
+        *format = f;
+    }
+    // The configuration which applies is that of the project being acted on, wherever it is.
+    let cwd = env::current_dir().ok();
+    let dir = match (manifest_path(&args), cwd.as_ref()) {
+        (Some(path), Some(cwd)) => Some(build::manifest_dir(cwd, path)?),
+        _ => cwd,
+    };
+    let mut config = Config::in_dir(dir.as_ref().map(|x| x.as_path()))
+        .with_context(|e| format!("could not load configuration:\n{}", e))?;
+    if let Some(f) = message_format(&args) {
+        config.message_format(f);
+    }
+    *format = config.term.message_format;
     let args = expand_aliases(&mut config, args)?;
+    // An alias can ask for JSON too.
+    if let Some(f) = message_format(&args) {
+        config.message_format(f);
+        *format = f;
+    }
 
     if args.is_present("verbose") {
         config.verbosity(Verbosity::Verbose);
+        let level = args.occurrences_of("verbose").min(3) as u8;
+        if level > config.term.log_level {
+            config.log_level(level);
+        }
     } else if args.is_present("quiet") {
         config.verbosity(Verbosity::Quiet);
     }
 
+    if let Some(path) = args.value_of_os("log-file") {
+        config.log_file(env::current_dir()?.join(path));
+    }
+
     if args.is_present("color") {
         config.color(true);
     } else if args.is_present("no-color") {
         config.color(false);
     }
 
+    if args.is_present("ascii") {
+        config.ascii(true);
+    }
+
+    if args.is_present("no-index-cache") {
+        config.index_cache = false;
+    }
+    *ascii = config.shell().ascii;
+    if *ascii {
+        console::set_colors_enabled(false);
+    }
+
     let (cmd, subcommand_args) = match args.subcommand() {
         (cmd, Some(args)) => (cmd, args),
         _ => {
@@ -132,14 +261,105 @@ fn go() -> Result<String, Error> {
         }
     };
 
+    let profile = match args
+        .value_of_os("profile-elba")
+        .or_else(|| subcommand_args.value_of_os("profile-elba"))
+    {
+        Some(path) => {
+            let format = args
+                .value_of("profile-format")
+                .or_else(|| subcommand_args.value_of("profile-format"))
+                .unwrap_or("chrome")
+                .parse()?;
+            profile::enable();
+            Some((env::current_dir()?.join(path), format))
+        }
+        None => None,
+    };
+
+    let res = {
+        let _span = Span::root(format!("elba {}", cmd));
+        run(
+            &mut config,
+            cmd,
+            subcommand_args,
+            dir.as_ref().map(|x| x.as_path()),
+        )
+    };
+
+    // A profile of a command which failed is still worth having.
+    if let Some((path, format)) = profile {
+        profile::write(&path, format)?;
+    }
+
+    res
+}
+
+fn run(
+    config: &mut Config,
+    cmd: &str,
+    args: &ArgMatches,
+    dir: Option<&Path>,
+) -> Result<String, Error> {
     if let Some(exec) = cmds::execute_internal(cmd) {
-        return exec(&mut config, subcommand_args);
+        return exec(config, args);
+    }
+
+    match cmds::find_external(config, cmd) {
+        Some(path) => cmds::execute_external(config, &path, cmd, dir, args),
+        None => {
+            cli().print_help()?;
+            bail!("no such subcommand: `{}`", cmd)
+        }
     }
+}
 
-    let res = cmds::execute_external(cmd, subcommand_args);
+/// Renders an error along with its code, the causes which its message doesn't already mention, the
+/// package it happened in, and what to try next.
+fn render_error(e: &Error) -> String {
+    let code = error_code(e);
+    let mut res = match code {
+        Some(code) => format!(
+            "{} {}",
+            style(format!("error[{}]:", code.code())).red().bold(),
+            e
+        ),
+        None => format!("{} {}", style("error:").red().bold(), e),
+    };
+
+    // Most errors already have the messages of their causes in them, so those aren't repeated.
+    let mut seen = e.to_string();
+    for cause in e.iter_chain().skip(1) {
+        if cause.downcast_ref::<InPackage>().is_some()
+            || cause
+                .downcast_ref::<failure::Context<InPackage>>()
+                .is_some()
+        {
+            continue;
+        }
+        let cause = cause.to_string();
+        if !cause.trim().is_empty() && !seen.contains(cause.trim()) {
+            res.push_str(&format!(
+                "\n  {} {}",
+                style("caused by:").bold(),
+                cause.trim()
+            ));
+            seen.push_str(&cause);
+        }
+    }
 
-    if res.is_err() {
-        cli().print_help()?;
+    if let Some(package) = error_package(e) {
+        res.push_str(&format!("\n  {} {}", style("package:").bold(), package));
+    }
+    if let Some(code) = code {
+        if let Some(hint) = code.hint() {
+            res.push_str(&format!("\n  {} {}", style("help:").cyan().bold(), hint));
+        }
+        res.push_str(&format!(
+            "\n  {} run `elba explain {}` for more about this error",
+            style("note:").bold(),
+            code.code()
+        ));
     }
 
     res
@@ -147,23 +367,63 @@ fn go() -> Result<String, Error> {
 
 fn main() {
     let start = Instant::now();
-    let res = go();
+    // On Windows, the last `elba self update` couldn't remove the executable it replaced.
+    self_update::remove_old();
+    let mut format = MessageFormat::Human;
+    let mut ascii = false;
+    let res = go(&mut format, &mut ascii);
+
+    if format == MessageFormat::Json {
+        let elapsed = start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        let success = res.is_ok();
+        let message = match res {
+            Err(e) => {
+                let message = console::strip_ansi_codes(&render_error(&e)).into_owned();
+                Event::Error {
+                    message: Cow::Borrowed(&message),
+                    code: error_code(&e).map(|x| x.code()),
+                }
+                .print();
+                e.to_string()
+            }
+            Ok(st) => st,
+        };
+        Event::Finished {
+            success,
+            message: Cow::Owned(message),
+            elapsed,
+        }
+        .print();
+        exit(if success { 0 } else { 1 });
+    }
+
+    let render = |s: String| {
+        if ascii {
+            to_ascii(&s).into_owned()
+        } else {
+            s
+        }
+    };
 
     println!();
     match res {
         Err(e) => {
-            eprintln!("{} {}", style("error:").red().bold(), e);
+            eprintln!("{}", render(render_error(&e)));
             exit(1);
         }
         Ok(st) => {
             let elapsed = start.elapsed();
             if !st.is_empty() {
                 println!(
-                    "{} {} [{}.{}s]",
-                    style("done!").green().bold(),
-                    st,
-                    elapsed.as_secs(),
-                    elapsed.subsec_millis() / 10
+                    "{}",
+                    render(format!(
+                        "{} {} [{}.{}s]",
+                        style("done!").green().bold(),
+                        st,
+                        elapsed.as_secs(),
+                        elapsed.subsec_millis() / 10
+                    ))
                 );
             }
             exit(0);
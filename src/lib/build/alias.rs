@@ -0,0 +1,264 @@
+//! Module aliasing, which lets a package import a dependency's modules under the namespace of
+//! the alias it gave the dependency.
+//!
+//! Every package has a default root module derived from its name: `me/lightyear` is
+//! `Me.Lightyear`. When a package depends on `me/lightyear` under the alias `yeet/lightyeet`, we
+//! generate a shim for each of its modules under its root module which re-exports it under the
+//! alias' root module instead:
+//!
+//! ```idris
+//! module Yeet.Lightyeet
+//!
+//! import public Me.Lightyear
+//! ```
+//!
+//! The shims are built as a package of their own, with the aliased package as a dependency, and
+//! stored in the global cache like any other build. They're then added to the import path of the
+//! package which gave the alias, alongside the aliased package itself.
+
+use std::{ffi::OsStr, fs};
+
+use console::style;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use super::{context::BuildContext, invoke::invoke_compile};
+use crate::{
+    package::{manifest::DepReq, Name},
+    remote::resolution::DirectRes,
+    retrieve::cache::{Binary, BuildHash, Source},
+    util::{
+        clear_dir, copy_dir_iter,
+        error::Result,
+        mod_path, normalize_path,
+        shell::{Shell, Verbosity},
+        valid_file,
+    },
+};
+
+/// A dependency which a package imports under an alias.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alias {
+    pub alias: Name,
+    pub package: Name,
+    /// The modules of the aliased package's lib target.
+    pub mods: Vec<String>,
+}
+
+impl Alias {
+    /// The shims to generate for the alias, as pairs of the name of the shim module and the
+    /// module it re-exports.
+    pub fn shims(&self) -> Vec<(String, String)> {
+        let from = namespace(&self.package);
+        let to = namespace(&self.alias);
+        if from == to {
+            return vec![];
+        }
+
+        self.mods
+            .iter()
+            .filter_map(|m| {
+                let m = m.trim_matches('.');
+                let rest = m.get(from.len()..)?;
+                if m.starts_with(&from) && (rest.is_empty() || rest.starts_with('.')) {
+                    Some((format!("{}{}", to, rest), m.to_owned()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The default root module of a package: the group and name of the package in title case, with
+/// hyphens and underscores left out.
+pub fn namespace(name: &Name) -> String {
+    let title = |s: &str| {
+        s.split(|c| c == '-' || c == '_')
+            .map(|w| {
+                let mut cs = w.chars();
+                match cs.next() {
+                    Some(c) => c.to_uppercase().chain(cs).collect::<String>(),
+                    None => String::new(),
+                }
+            })
+            .collect::<String>()
+    };
+
+    format!("{}.{}", title(name.group()), title(name.name()))
+}
+
+/// The aliases which a package gives the dependencies it has in the graph, out of `deps`.
+pub fn find_aliases(parent: &Source, deps: &[&Source], dev_deps: bool) -> Result<Vec<Alias>> {
+    let reqs = parent
+        .meta()
+        .dependencies
+        .iter()
+        .chain(parent.meta().dev_deps().filter(|_| dev_deps));
+
+    let mut aliases = vec![];
+    for (alias, req) in reqs {
+        let package = match req.package() {
+            Some(package) if package != alias => package,
+            _ => continue,
+        };
+        let candidates = deps
+            .iter()
+            .copied()
+            .filter(|dep| dep.meta().name() == package)
+            .collect::<Vec<_>>();
+        // If the package is in the graph more than once, we have to go by where it came from.
+        let found = if candidates.len() == 1 {
+            candidates.into_iter().next()
+        } else {
+            candidates.into_iter().find(|dep| is_for(req, parent, dep))
+        };
+        let dep = match found {
+            Some(dep) => dep,
+            None => continue,
+        };
+        if dep.meta().targets.lib.is_none() {
+            continue;
+        }
+        let mods = dep.meta().lib_mods(dep.path())?;
+
+        aliases.push(Alias {
+            alias: alias.clone(),
+            package: package.clone(),
+            mods,
+        });
+    }
+
+    Ok(aliases)
+}
+
+/// Whether a dependency in the graph is the one a requirement asks for.
+fn is_for(req: &DepReq, parent: &Source, dep: &Source) -> bool {
+    match (req, dep.location()) {
+        (DepReq::Git { git, .. }, DirectRes::Git { repo, .. }) => git == repo,
+        (DepReq::Local { path, .. }, DirectRes::Dir { path: dir }) => {
+            &normalize_path(&parent.path().join(path)) == dir
+        }
+        (DepReq::Registry(_), _) | (DepReq::RegLong { .. }, _) => {
+            dep.resolution().direct().is_none()
+        }
+        _ => false,
+    }
+}
+
+/// Builds the shims for an alias, or gets them from the cache if they've already been built.
+/// `deps` has to include the aliased package. Returns nothing if there's nothing to shim.
+pub async fn compile_alias<'a>(
+    alias: &'a Alias,
+    deps: &'a [Binary],
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Option<Binary>> {
+    let shims = alias.shims();
+    if shims.is_empty() {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::default();
+    hasher.input(b"alias");
+    if let Ok(ver) = bcx.compiler.version() {
+        hasher.input(ver.as_bytes());
+    }
+    for opt in &bcx.opts {
+        hasher.input(opt.as_bytes());
+    }
+    let mut dep_paths = deps
+        .iter()
+        .map(|x| x.target.path().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    dep_paths.sort();
+    for path in &dep_paths {
+        hasher.input(path.as_bytes());
+    }
+    for (shim, orig) in &shims {
+        hasher.input(shim.as_bytes());
+        hasher.input(orig.as_bytes());
+    }
+    let hash = BuildHash(hex::encode(hasher.result()));
+
+    if let Some(binary) = bcx.cache.checkout_build(&hash)? {
+        return Ok(Some(binary));
+    }
+
+    let layout = bcx.cache.checkout_tmp(&hash)?;
+    clear_dir(&layout.build)?;
+
+    for (shim, orig) in &shims {
+        let target = mod_path(shim).with_extension("idr");
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(layout.build.join(parent))?;
+        }
+        fs::write(
+            layout.build.join(&target),
+            format!("module {}\n\nimport public {}\n", shim, orig),
+        )?;
+
+        shell.println(
+            style("Aliasing").cyan(),
+            format!("{} as {} [{}]", orig, shim, alias.alias),
+            Verbosity::Normal,
+        );
+
+        invoke_compile(deps, &target, layout.build.clone(), &bcx.opts, bcx, shell).await?;
+    }
+
+    let from = if bcx.compiler.flavor().is_idris2() {
+        layout.build.join("build")
+    } else {
+        layout.build.clone()
+    };
+    let build_walker = WalkDir::new(&from).into_iter().filter_map(|x| {
+        x.ok().filter(|x| {
+            valid_file(x)
+                && x.path().extension() != Some(OsStr::new("idr"))
+                && x.path().extension() != Some(OsStr::new("lidr"))
+        })
+    });
+
+    clear_dir(&layout.lib)?;
+    copy_dir_iter(build_walker, &from, &layout.lib)?;
+
+    Ok(Some(bcx.cache.store_build(&layout.lib, &hash)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn alias_namespace() {
+        let name = Name::from_str("light_year/lightyear-ex").unwrap();
+        assert_eq!(namespace(&name), "LightYear.LightyearEx");
+    }
+
+    #[test]
+    fn alias_shims() {
+        let alias = Alias {
+            alias: Name::from_str("yeet/lightyeet").unwrap(),
+            package: Name::from_str("me/lightyear").unwrap(),
+            mods: vec![
+                "Me.Lightyear".to_string(),
+                "Me.Lightyear.Combinators".to_string(),
+                "Me.LightyearExtra".to_string(),
+                "Other.Module".to_string(),
+            ],
+        };
+
+        assert_eq!(
+            alias.shims(),
+            vec![
+                ("Yeet.Lightyeet".to_string(), "Me.Lightyear".to_string()),
+                (
+                    "Yeet.Lightyeet.Combinators".to_string(),
+                    "Me.Lightyear.Combinators".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,8 +1,10 @@
+use super::native::NativeFlags;
 use crate::{
     retrieve::cache::Cache,
     util::{config::Backend, error::Result, fmt_output},
 };
 use failure::{format_err, ResultExt};
+use semver::Version;
 use std::{
     path::{Path, PathBuf},
     process::Command,
@@ -18,6 +20,8 @@ pub struct BuildContext {
     pub cache: Cache,
     pub threads: u32,
     pub opts: Vec<String>,
+    /// The flags for the native libraries the packages being built link against.
+    pub native: NativeFlags,
 }
 
 /// Information on the compiler executable
@@ -73,6 +77,16 @@ impl Compiler {
             ))
         }
     }
+
+    /// Get the version of the compiler as a semver version, if it gives it in a form we can make
+    /// sense of.
+    pub fn semver(&self) -> Option<Version> {
+        self.version()
+            .ok()?
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(|x| Version::parse(x.split('-').next().unwrap()).ok())
+            .next()
+    }
 }
 
 impl Default for Compiler {
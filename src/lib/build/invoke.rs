@@ -111,10 +111,9 @@ pub async fn invoke_codegen<'a>(
             &bcx.backend.name,
         ]);
 
-    if !bcx.backend.opts.is_empty() && flavor.is_idris1() {
-        process
-            .arg("--cg-opt")
-            .arg(bcx.backend.opts.iter().join(" "));
+    let cg_opts = bcx.backend.opts.iter().chain(bcx.native.args()).join(" ");
+    if !cg_opts.is_empty() && flavor.is_idris1() {
+        process.arg("--cg-opt").arg(cg_opts);
     }
 
     process.args(args);
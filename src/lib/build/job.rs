@@ -1,21 +1,37 @@
-use super::{compile_bin, compile_doc, compile_lib, context::BuildContext, Target, Targets};
+use super::{
+    alias::{compile_alias, find_aliases, Alias},
+    compile_bin, compile_doc, compile_lib,
+    context::BuildContext,
+    native::NativeFlags,
+    Target, Targets,
+};
 use crate::{
+    package::Summary,
     retrieve::cache::{Binary, BuildHash, OutputLayout, Source},
     util::{
         clear_dir,
-        error::Result,
+        error::{Error, InPackage, Result},
         fmt_multiple,
         graph::Graph,
-        lock::DirLock,
+        profile::{self, Span},
         shell::{Shell, Verbosity},
     },
 };
 use console::style;
-use failure::{bail, format_err, ResultExt};
-use futures::future;
+use failure::{format_err, ResultExt};
+use futures::{
+    channel::mpsc::UnboundedReceiver,
+    future::{self, Either},
+    stream::FuturesUnordered,
+    StreamExt,
+};
 use petgraph::graph::NodeIndex;
 use slog::{debug, o, Logger};
-use std::{collections::HashSet, future::Future, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::PathBuf,
+};
 use tokio::runtime::Runtime;
 
 /// Work refers to either a Source and its BuildHash which needs to be built,
@@ -54,11 +70,325 @@ impl Work {
     }
 }
 
+/// Leaves the test dependencies of the root package out of a graph, along with everything which
+/// is only in it because of them.
+pub fn without_test_deps(solve: Graph<Source>) -> Graph<Source> {
+    let root = NodeIndex::new(0);
+    let keep = {
+        let meta = solve[root].meta();
+        let mut keep = HashSet::new();
+        keep.insert(root);
+        for (child, _) in solve
+            .children(root)
+            .filter(|(_, dep)| !meta.is_test_only(dep.meta().name()))
+        {
+            keep.extend(solve.sub_tree(child).map(|(ix, _)| ix));
+        }
+        keep
+    };
+    if keep.len() == solve.inner.node_count() {
+        return solve;
+    }
+
+    Graph::new(solve.inner.filter_map(
+        |ix, source| {
+            if keep.contains(&ix) {
+                Some(source.clone())
+            } else {
+                None
+            }
+        },
+        |_, _| Some(()),
+    ))
+}
+
+/// Builds the dependencies of the root of a solve while it's still being retrieved, starting on
+/// each one as soon as it and everything it depends on has been.
+///
+/// The builds end up in the global cache under the same hashes `JobQueue::new` gives them, so
+/// the JobQueue for the whole solve, once it's been retrieved, finds them fresh. Builds are only
+/// locked while something is being built, so that nothing waits on a download with a build
+/// locked.
+pub fn prebuild(
+    solve: &Graph<Summary>,
+    sources: UnboundedReceiver<(NodeIndex, Source)>,
+    root: &Targets,
+    bcx: &BuildContext,
+    plog: &Logger,
+    shell: Shell,
+) -> Result<()> {
+    let logger = plog.new(o!("phase" => "prebuild", "threads" => bcx.threads));
+    let span = profile::span(&logger, "prebuild");
+    let prebuild = Prebuild {
+        solve,
+        sources: vec![None; solve.inner.node_count()],
+        state: vec![Prep::Waiting; solve.inner.node_count()],
+        held: HashMap::new(),
+        keep: None,
+        tests: root.has_tests(),
+        bcx,
+        logger,
+        shell,
+    };
+
+    let mut rt = Runtime::new().with_context(|_| format_err!("Couldn't start parallel runtime"))?;
+    rt.block_on(prebuild.exec(sources, &span))
+}
+
+/// How far along a package being prebuilt is.
+#[derive(Clone, Debug)]
+enum Prep {
+    /// Some of the package's sub-tree hasn't been retrieved yet.
+    Waiting,
+    /// The package needs building, once its dependencies have been built.
+    Dirty(BuildHash),
+    Building(BuildHash),
+    /// The package is in the global build cache.
+    Built(BuildHash),
+    /// The package couldn't be built here, and neither can anything which depends on it; the
+    /// JobQueue takes care of them instead.
+    Skipped,
+}
+
+struct Prebuild<'a> {
+    solve: &'a Graph<Summary>,
+    sources: Vec<Option<Source>>,
+    state: Vec<Prep>,
+    /// The builds we have locked for the builds going on right now.
+    held: HashMap<NodeIndex, Binary>,
+    /// The packages which need building, which we know once we have the root.
+    keep: Option<HashSet<NodeIndex>>,
+    tests: bool,
+    bcx: &'a BuildContext,
+    logger: Logger,
+    shell: Shell,
+}
+
+impl<'a> Prebuild<'a> {
+    async fn exec(
+        mut self,
+        mut incoming: UnboundedReceiver<(NodeIndex, Source)>,
+        span: &Span,
+    ) -> Result<()> {
+        let mut open = true;
+        let mut builds = FuturesUnordered::new();
+
+        loop {
+            self.prepare();
+            for node in self.buildable() {
+                match self.start(node, span)? {
+                    Some(build) => builds.push(build),
+                    None => self.state[node.index()] = Prep::Skipped,
+                }
+            }
+
+            if builds.is_empty() {
+                // Nothing is being built, so there's no reason to keep anything locked while we
+                // wait on the next download.
+                self.held.clear();
+                if !open {
+                    break;
+                }
+            }
+
+            let res = if !open {
+                builds.next().await
+            } else if builds.is_empty() {
+                open = self.receive(incoming.next().await);
+                None
+            } else {
+                match future::select(incoming.next(), builds.next()).await {
+                    Either::Left((next, _)) => {
+                        open = self.receive(next);
+                        None
+                    }
+                    Either::Right((res, _)) => res,
+                }
+            };
+
+            match res {
+                Some(Ok((node, binary, _))) => {
+                    self.state[node.index()] = match (&self.state[node.index()], binary) {
+                        (Prep::Building(hash), Some(binary)) => {
+                            self.held.insert(node, binary);
+                            Prep::Built(hash.clone())
+                        }
+                        _ => Prep::Skipped,
+                    };
+                }
+                Some(Err(err)) => return Err(err.context(Error::BuildFailed).into()),
+                None => {}
+            }
+        }
+
+        debug!(self.logger, "prebuild finished");
+        Ok(())
+    }
+
+    /// Takes in a package which has been retrieved, returning whether there are any more to come.
+    fn receive(&mut self, next: Option<(NodeIndex, Source)>) -> bool {
+        match next {
+            Some((ix, source)) => {
+                self.sources[ix.index()] = Some(source);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Works out the build hash of every package whose whole sub-tree has been retrieved, and
+    /// whether it needs building.
+    fn prepare(&mut self) {
+        let root = NodeIndex::new(0);
+        if self.keep.is_none() {
+            let meta = match &self.sources[root.index()] {
+                Some(source) => source.meta(),
+                None => return,
+            };
+            // Test dependencies only get built if the root's tests do, like in `JobQueue::new`.
+            let (solve, tests) = (self.solve, self.tests);
+            self.keep = Some(
+                solve
+                    .children(root)
+                    .filter(|(_, dep)| tests || !meta.is_test_only(dep.name()))
+                    .flat_map(|(child, _)| solve.sub_tree(child).map(|(ix, _)| ix))
+                    .filter(|&ix| ix != root)
+                    .collect(),
+            );
+        }
+        let keep = self.keep.as_ref().unwrap();
+
+        // Packages other than the root are only ever built as libraries, without codegen.
+        let targets = Targets::new(vec![Target::Lib(false)]);
+        for (node, _) in self.solve.topological() {
+            if !keep.contains(&node) {
+                continue;
+            }
+            match self.state[node.index()] {
+                Prep::Waiting => {}
+                _ => continue,
+            }
+
+            let mut tree = self.solve.sub_tree(node);
+            if !tree.all(|(ix, _)| self.sources[ix.index()].is_some()) {
+                continue;
+            }
+            let hashes = self
+                .solve
+                .sub_tree(node)
+                .map(|(ix, _)| self.sources[ix.index()].as_ref().unwrap().hash());
+            let hash = BuildHash::of(hashes, &targets, self.bcx, targets.is_codegen());
+
+            self.state[node.index()] = if self.bcx.cache.check_build(&hash).is_some() {
+                Prep::Built(hash)
+            } else {
+                Prep::Dirty(hash)
+            };
+        }
+    }
+
+    /// The packages which need building and whose dependencies all have been.
+    fn buildable(&self) -> Vec<NodeIndex> {
+        self.solve
+            .inner
+            .node_indices()
+            .filter(|&node| {
+                if let Prep::Dirty(_) = self.state[node.index()] {
+                    self.solve.children(node).all(|(child, _)| {
+                        if let Prep::Built(_) = self.state[child.index()] {
+                            true
+                        } else {
+                            false
+                        }
+                    })
+                } else {
+                    false
+                }
+            })
+            .collect()
+    }
+
+    /// Starts building a package, unless one of its dependencies has gone missing from the
+    /// global cache in the meantime.
+    fn start(
+        &mut self,
+        node: NodeIndex,
+        span: &Span,
+    ) -> Result<
+        Option<impl Future<Output = Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)>>>,
+    > {
+        let hash = match &self.state[node.index()] {
+            Prep::Dirty(hash) => hash.clone(),
+            _ => unreachable!(),
+        };
+
+        let mut deps = vec![];
+        for (child, _) in self.solve.children(node) {
+            if let Some(binary) = self.held.get(&child) {
+                deps.push(binary.clone());
+                continue;
+            }
+            let binary = match &self.state[child.index()] {
+                Prep::Built(hash) => self.bcx.cache.checkout_build(hash)?,
+                _ => unreachable!(),
+            };
+            match binary {
+                Some(binary) => {
+                    self.held.insert(child, binary.clone());
+                    deps.push(binary);
+                }
+                None => return Ok(None),
+            }
+        }
+
+        let source = self.sources[node.index()].clone().unwrap();
+        self.shell.println(
+            style("Building").cyan(),
+            format!("{} [{}..]", source.pretty_summary(), &hash.0[0..8]),
+            Verbosity::Normal,
+        );
+        let layout = self.bcx.cache.checkout_tmp(&hash)?;
+        let children = self
+            .solve
+            .children(node)
+            .map(|(ix, _)| self.sources[ix.index()].as_ref().unwrap())
+            .collect::<Vec<_>>();
+        let aliases = find_aliases(&source, &children, false)?;
+        self.state[node.index()] = Prep::Building(hash.clone());
+
+        let package = InPackage(source.pretty_summary());
+        let span = span.child(format!("build {}", source.pretty_summary()));
+        let res = JobQueue::compile_target(
+            node,
+            source,
+            hash,
+            Targets::new(vec![Target::Lib(false)]),
+            aliases,
+            deps,
+            layout,
+            false,
+            self.logger.clone(),
+            self.bcx.clone(),
+            self.shell,
+        );
+
+        Ok(Some(async move {
+            let res = res
+                .await
+                .map_err(|e| failure::Error::from(e.context(package)));
+            drop(span);
+            res
+        }))
+    }
+}
+
 /// A Job is an individual unit of work in the elba build graph.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Job {
     pub work: Work,
     pub targets: Targets,
+    /// The dependencies which the package imports under an alias.
+    pub aliases: Vec<Alias>,
 }
 
 impl Default for Job {
@@ -66,6 +396,7 @@ impl Default for Job {
         Job {
             work: Work::None,
             targets: Targets::new(vec![Target::Lib(false)]),
+            aliases: vec![],
         }
     }
 }
@@ -77,6 +408,9 @@ pub struct JobQueue {
     pub logger: Logger,
     pub shell: Shell,
     pub bcx: BuildContext,
+    /// Where the ibc files of each of the root's dependencies end up, for those whose builds we
+    /// looked at.
+    pub dep_ibcs: Vec<(Summary, PathBuf)>,
 }
 
 // The current implementation of the JobQueue combines target generation and dependency preparation
@@ -86,17 +420,29 @@ impl JobQueue {
         solve: Graph<Source>,
         root: &Targets,
         root_ol: Option<OutputLayout>,
-        bcx: BuildContext,
+        mut bcx: BuildContext,
         plog: &Logger,
         shell: Shell,
     ) -> Result<Self> {
+        // The test dependencies of the root package don't need to be built unless its tests do.
+        let solve = if root.has_tests() {
+            solve
+        } else {
+            without_test_deps(solve)
+        };
+
+        // Only the C backend links against native libraries. We look all of them up before
+        // building anything, so that a missing one stops the build before it gets anywhere.
+        if bcx.codegen && bcx.backend.name == "c" {
+            let natives = solve
+                .inner
+                .raw_nodes()
+                .iter()
+                .map(|x| &x.weight.meta().native);
+            bcx.native = NativeFlags::probe(natives)?;
+        }
         let mut graph = Graph::new(solve.inner.map(|_, _| Job::default(), |_, _| ()));
-
-        let mut curr_layer = HashSet::new();
-        let mut next_layer = HashSet::new();
-
-        // We start with the root node.
-        next_layer.insert(NodeIndex::new(0));
+        let mut dep_ibcs = vec![];
 
         let ver = bcx.compiler.version();
 
@@ -111,61 +457,75 @@ impl JobQueue {
             "compiler" => ver.clone().unwrap_or_else(|| "none".to_string())
         ));
 
-        while !next_layer.is_empty() {
-            debug_assert!(curr_layer.is_empty());
-
-            curr_layer.extend(next_layer.drain());
+        // We go through the packages from the root down, so that by the time we get to a package,
+        // we know whether anything which depends on it needs to be built. If nothing does, we
+        // don't need to look at it at all.
+        let order = solve.topological().map(|(ix, _)| ix).collect::<Vec<_>>();
+        for node in order.into_iter().rev() {
+            let is_root = node == NodeIndex::new(0);
+            if !is_root && !graph.parents(node).any(|(_, job)| job.work.is_dirty()) {
+                continue;
+            }
 
-            for node in curr_layer.drain() {
-                let source = &solve[node];
+            let source = &solve[node];
 
-                let targets = if node == NodeIndex::new(0) {
-                    root.clone()
-                } else {
-                    Targets::new(vec![Target::Lib(false)])
-                };
+            let targets = if is_root {
+                root.clone()
+            } else {
+                Targets::new(vec![Target::Lib(false)])
+            };
 
-                let build_hash = BuildHash::new(
-                    source,
-                    &solve,
-                    &targets,
-                    &bcx,
-                    (node != NodeIndex::new(0) || bcx.codegen) && targets.is_codegen(),
-                );
+            let build_hash = BuildHash::new(
+                source,
+                &solve,
+                &targets,
+                &bcx,
+                (!is_root || bcx.codegen) && targets.is_codegen(),
+            );
 
-                let root_ol = root_ol.as_ref();
-                let job = if node == NodeIndex::new(0)
-                    && root_ol.is_some()
-                    && root_ol.unwrap().is_built(&build_hash)
-                {
-                    Job {
-                        work: Work::None,
+            let root_ol = root_ol.as_ref();
+            let job = if is_root && root_ol.is_some() && root_ol.unwrap().is_built(&build_hash) {
+                Job {
+                    work: Work::None,
+                    targets,
+                    aliases: vec![],
+                }
+            } else {
+                match bcx.cache.checkout_build(&build_hash)? {
+                    Some(binary) => Job {
+                        work: Work::Fresh(binary),
                         targets,
-                    }
-                } else {
-                    match bcx.cache.checkout_build(&build_hash)? {
-                        Some(binary) => Job {
-                            work: Work::Fresh(binary),
+                        aliases: vec![],
+                    },
+                    None => {
+                        let deps = solve.children(node).map(|(_, dep)| dep).collect::<Vec<_>>();
+                        Job {
+                            work: Work::Dirty(source.clone(), build_hash),
                             targets,
-                        },
-                        None => {
-                            next_layer.extend(
-                                graph
-                                    .children(node)
-                                    // If the Job is none, that means that it hasn't been visited yet.
-                                    .filter(|(_, child)| child.work.is_none())
-                                    .map(|(index, _)| index),
-                            );
-
-                            Job {
-                                work: Work::Dirty(source.clone(), build_hash),
-                                targets,
-                            }
+                            aliases: find_aliases(source, &deps, is_root)?,
                         }
                     }
+                }
+            };
+
+            if solve.parents(node).any(|(ix, _)| ix == NodeIndex::new(0)) {
+                let ibc = match &job.work {
+                    Work::Fresh(binary) => Some(binary.target.path().to_path_buf()),
+                    // This is where the build is stored once it's done.
+                    Work::Dirty(_, hash) => Some(bcx.cache.layout.build.join(&hash.0)),
+                    Work::None => None,
                 };
-                graph[node] = job;
+                if let Some(ibc) = ibc {
+                    let sum = Summary::from_comps(
+                        source.meta().name().clone(),
+                        source.resolution().clone(),
+                        source.meta().version().clone(),
+                    );
+                    dep_ibcs.push((sum, ibc));
+                }
             }
+
+            graph[node] = job;
         }
 
         // We drop the all of the Sources, releasing our lock on them. We don't need them anymore.
@@ -177,16 +537,21 @@ impl JobQueue {
             bcx,
             logger,
             shell,
+            dep_ibcs,
         })
     }
 
     pub fn exec<'a>(self) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+        let span = profile::span(&self.logger, "build");
         let mut rt =
             Runtime::new().with_context(|_| format_err!("Couldn't start parallel runtime"))?;
-        rt.block_on(self.exec_async())
+        rt.block_on(self.exec_async(&span))
     }
 
-    async fn exec_async<'a>(mut self) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+    async fn exec_async<'a>(
+        mut self,
+        span: &Span,
+    ) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
         let root_ol = &self.root_ol;
         let root_hash = self.graph.root().and_then(|x| {
             if let Work::Dirty(_, h) = &x.work {
@@ -200,6 +565,16 @@ impl JobQueue {
         let mut parallal_jobs_future = Vec::new();
         let mut bins_vec = Vec::new();
 
+        // Plain output doesn't show which packages are being built at the same time, so it says
+        // how far along the build is every time a package finishes instead.
+        let total = self
+            .graph
+            .inner
+            .node_indices()
+            .filter(|&index| self.graph[index].work.is_dirty())
+            .count();
+        let mut built = 0;
+
         loop {
             // Bottom jobs are Dirty jobs whose dependencies are all satisfied.
             let bottom_jobs = self.graph.inner.node_indices().filter(|&index| {
@@ -213,7 +588,7 @@ impl JobQueue {
             // Spwan new jobs
             for job in bottom_jobs {
                 if !ongoing_jobs.contains(&job) {
-                    parallal_jobs_future.push(Box::pin(self.complete_job(job)?));
+                    parallal_jobs_future.push(Box::pin(self.complete_job(job, span)?));
                     ongoing_jobs.insert(job);
                 }
             }
@@ -232,6 +607,15 @@ impl JobQueue {
                 Ok((job_index, binary, mut bins)) => {
                     ongoing_jobs.remove(&job_index);
 
+                    built += 1;
+                    if self.shell.is_plain() {
+                        self.shell.println(
+                            style("Built").dim(),
+                            format!("{}/{} packages", built, total),
+                            Verbosity::Normal,
+                        );
+                    }
+
                     // prg += 1;
                     // pb.set_position(prg);
                     if let Some(b) = binary {
@@ -266,9 +650,7 @@ impl JobQueue {
                 }
                 Err(err) => {
                     // pb.finish_and_clear();
-                    self.shell
-                        .println(style("[error]").red().bold(), err, Verbosity::Quiet);
-                    bail!("one or more packages couldn't be built");
+                    return Err(err.context(Error::BuildFailed).into());
                 }
             }
         }
@@ -322,6 +704,7 @@ impl JobQueue {
     fn complete_job(
         &self,
         job_index: NodeIndex,
+        span: &Span,
     ) -> Result<impl Future<Output = Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)>>>
     {
         if let Work::Dirty(source, build_hash) = &self.graph[job_index].work {
@@ -351,12 +734,16 @@ impl JobQueue {
                 .collect::<Vec<_>>();
 
             let targets = self.graph[job_index].targets.clone();
+            let aliases = self.graph[job_index].aliases.clone();
 
+            let package = InPackage(source.pretty_summary());
+            let span = span.child(format!("build {}", source.pretty_summary()));
             let res = Self::compile_target(
                 job_index,
                 source.clone(),
                 build_hash.clone(),
                 targets,
+                aliases,
                 deps,
                 layout,
                 self.root_ol.is_some(),
@@ -365,7 +752,13 @@ impl JobQueue {
                 self.shell,
             );
 
-            Ok(res)
+            Ok(async move {
+                let res = res
+                    .await
+                    .map_err(|e| failure::Error::from(e.context(package)));
+                drop(span);
+                res
+            })
         } else {
             unreachable!()
         }
@@ -376,13 +769,30 @@ impl JobQueue {
         source: Source,
         build_hash: BuildHash,
         targets: Targets,
-        deps: Vec<Binary>,
+        aliases: Vec<Alias>,
+        mut deps: Vec<Binary>,
         layout: OutputLayout,
         is_root: bool,
         logger: Logger,
         bcx: BuildContext,
         shell: Shell,
     ) -> Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)> {
+        // The shims for aliased dependencies go alongside the dependencies themselves.
+        for alias in &aliases {
+            let shims = compile_alias(alias, &deps, &bcx, shell)
+                .await
+                .with_context(|e| {
+                    format!(
+                        "Couldn't alias {} as {} for {}\n{}",
+                        alias.package,
+                        alias.alias,
+                        source.pretty_summary(),
+                        e
+                    )
+                })?;
+            deps.extend(shims);
+        }
+
         let mut res: Option<Binary> = None;
         let mut bins: Vec<(PathBuf, String)> = Vec::new();
         let has_lib = targets.has_lib();
@@ -410,7 +820,7 @@ impl JobQueue {
                         let out = fmt_multiple(&out);
                         shell.println_plain(out, Verbosity::Normal);
 
-                        let target = DirLock::acquire(&layout.lib)?;
+                        let target = layout.lock_lib()?;
                         Some(Binary::new(target))
                     } else {
                         Some(bcx.cache.store_build(&layout.lib, &build_hash)?)
@@ -424,13 +834,8 @@ impl JobQueue {
                         "summary" => source.summary()
                     );
                     let mut deps = deps.clone();
-                    let root_lib;
                     if has_lib {
-                        root_lib = {
-                            let target = DirLock::acquire(&layout.lib)?;
-                            Binary::new(target)
-                        };
-                        deps.push(root_lib);
+                        deps.push(Self::lib_binary(&res, &layout)?);
                     }
                     let (out, path) =
                         compile_bin(&source, Target::Bin(ix), &deps, &layout, &bcx, shell)
@@ -461,13 +866,8 @@ impl JobQueue {
                         "summary" => source.summary()
                     );
                     let mut deps = deps.clone();
-                    let root_lib;
                     if has_lib {
-                        root_lib = {
-                            let target = DirLock::acquire(&layout.lib)?;
-                            Binary::new(target)
-                        };
-                        deps.push(root_lib);
+                        deps.push(Self::lib_binary(&res, &layout)?);
                     }
                     let (out, _) =
                         compile_bin(&source, Target::Test(ix), &deps, &layout, &bcx, shell)
@@ -511,4 +911,13 @@ impl JobQueue {
 
         Ok((job_index, res, bins))
     }
+
+    /// Returns the library we just built in this layout, reusing our existing lock on it if we
+    /// already have one (locking it a second time would block forever).
+    fn lib_binary(res: &Option<Binary>, layout: &OutputLayout) -> Result<Binary> {
+        match res {
+            Some(b) if b.target.path() == layout.lib => Ok(b.clone()),
+            _ => Ok(Binary::new(layout.lock_lib()?)),
+        }
+    }
 }
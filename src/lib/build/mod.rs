@@ -1,8 +1,10 @@
 //! Actually building Idris packages.
 
+pub mod alias;
 pub mod context;
 pub mod invoke;
 pub mod job;
+pub mod native;
 
 use std::{
     env,
@@ -28,7 +30,7 @@ use crate::{
     util::{
         clear_dir, copy_dir, copy_dir_iter,
         error::Result,
-        fmt_multiple, fmt_output, generate_ipkg,
+        fmt_multiple, fmt_output, generate_ipkg, mod_path,
         shell::{OutputGroup, Shell, Verbosity},
         valid_file,
     },
@@ -130,6 +132,12 @@ impl Targets {
             .unwrap_or(false)
     }
 
+    pub fn has_tests(&self) -> bool {
+        self.0
+            .iter()
+            .any(|x| if let Target::Test(_) = x { true } else { false })
+    }
+
     // This makes doc targets part of the
     pub fn is_codegen(&self) -> bool {
         self.0.iter().any(|x| x.is_codegen())
@@ -153,11 +161,12 @@ pub async fn compile_lib<'a>(
 
     // We know that lib_target.path will be relative to the package root
     let src_path = source.path().join(&lib_target.path.0);
-    let mut targets = lib_target
-        .mods
+    let mut targets = source
+        .meta()
+        .lib_mods(source.path())?
         .iter()
         .map(|mod_name| {
-            let path: PathBuf = mod_name.trim_matches('.').replace(".", "/").into();
+            let path = mod_path(&mod_name);
             if src_path.join(&path).with_extension("idr").exists() {
                 Ok(path.with_extension("idr"))
             } else if src_path.join(&path).with_extension("lidr").exists() {
@@ -386,12 +395,7 @@ pub async fn compile_bin<'a>(
 
     let target_bin = target_path.with_extension("ibc");
 
-    let name = if let Some(ex) = &bcx.backend.extension {
-        let p: PathBuf = bin_target.name.into();
-        p.with_extension(ex)
-    } else {
-        bin_target.name.into()
-    };
+    let name = bin_target.output_file(bcx.backend.extension.as_ref().map(|x| x.as_str()));
 
     if !bcx.codegen {
         return Ok((res, None));
@@ -425,7 +429,7 @@ pub async fn compile_bin<'a>(
 
     if out.exists() {
         Ok((res, Some(out)))
-    } else if out.with_extension("exe").exists() {
+    } else if bin_target.extension.is_none() && out.with_extension("exe").exists() {
         Ok((res, Some(out.with_extension("exe"))))
     } else {
         bail!("couldn't locate codegen output file: {}", out.display())
@@ -442,7 +446,7 @@ pub async fn compile_doc<'a>(
         bail!("The Idris 2 compiler currently can't build documentation")
     }
 
-    let lib_target = source.meta().targets.lib.clone().ok_or_else(|| {
+    source.meta().targets.lib.as_ref().ok_or_else(|| {
         format_err!(
             "package {} doesn't contain a lib target, which is needed to build docs",
             source.meta().name()
@@ -460,7 +464,7 @@ pub async fn compile_doc<'a>(
     let name = source.meta().name().name();
     let lib_path = "lib";
     let mut opts = String::new();
-    let mods = lib_target.mods.join(", ");
+    let mods = source.meta().lib_mods(source.path())?.join(", ");
 
     // Include dependencies
     for binary in deps {
@@ -507,7 +511,8 @@ pub async fn compile_doc<'a>(
     Ok(res.into())
 }
 
-pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
+/// A command which runs `cmd` through the shell of the platform in the directory `root`.
+pub fn shell_command(root: &Path, cmd: &str) -> Command {
     let mut process = if cfg!(target_os = "windows") {
         let mut p = Command::new("cmd");
         p.args(&["/C", cmd]);
@@ -525,6 +530,11 @@ pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
         process.env("PATH", v);
     }
 
+    process
+}
+
+pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
+    let mut process = shell_command(root, cmd);
     let res = process.output()?;
     if !res.status.success() {
         bail!("> {:#?}\n{}", process, fmt_output(&res))
@@ -0,0 +1,221 @@
+//! Native libraries which packages link against through the C backend.
+
+use std::{env, ffi::OsString, io, path::PathBuf, process::Command};
+
+use failure::{bail, format_err};
+use itertools::Itertools;
+
+use crate::{
+    package::manifest::NativeInfo,
+    util::{error::Result, fmt_output},
+};
+
+/// The flags the C compiler needs to find the native libraries of a build.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NativeFlags {
+    /// Flags for finding headers, like `-I/usr/include/ffi`.
+    pub cflags: Vec<String>,
+    /// Flags for linking, like `-L/usr/local/lib -lgmp`.
+    pub libs: Vec<String>,
+}
+
+impl NativeFlags {
+    /// Looks up every native library the packages of a build declare, failing with a hint on how
+    /// to install them if any of them are missing.
+    pub fn probe<'a>(natives: impl IntoIterator<Item = &'a NativeInfo>) -> Result<Self> {
+        let natives = natives.into_iter().collect::<Vec<_>>();
+        let mut res = NativeFlags::default();
+        let mut missing = vec![];
+
+        let pkg_config = natives.iter().flat_map(|x| &x.pkg_config).unique();
+        for name in pkg_config {
+            match pkg_config_flags(name)? {
+                Some((cflags, libs)) => {
+                    res.push_cflags(cflags);
+                    res.push_libs(libs);
+                }
+                None => missing.push(name.as_str()),
+            }
+        }
+
+        // Plain libraries could be in any of the directories pkg-config told us about, as well as
+        // the usual ones.
+        let mut dirs = res
+            .libs
+            .iter()
+            .filter(|x| x.starts_with("-L"))
+            .map(|x| PathBuf::from(&x[2..]))
+            .collect::<Vec<_>>();
+        dirs.extend(lib_dirs());
+        for name in natives.iter().flat_map(|x| &x.libs).unique() {
+            if find_lib(name, &dirs).is_some() {
+                res.push_libs(vec![format!("-l{}", name)]);
+            } else {
+                missing.push(name.as_str());
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!(
+                "couldn't find native libraries {}, which need to be installed to build:\n{}",
+                missing.iter().join(", "),
+                missing
+                    .iter()
+                    .map(|x| format!("  help: try `{}`", install_hint(x)))
+                    .join("\n")
+            )
+        }
+
+        Ok(res)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cflags.is_empty() && self.libs.is_empty()
+    }
+
+    /// All of the flags, in the order the C compiler should get them.
+    pub fn args(&self) -> impl Iterator<Item = &String> {
+        self.cflags.iter().chain(self.libs.iter())
+    }
+
+    fn push_cflags(&mut self, flags: Vec<String>) {
+        for flag in flags {
+            if !self.cflags.contains(&flag) {
+                self.cflags.push(flag);
+            }
+        }
+    }
+
+    fn push_libs(&mut self, flags: Vec<String>) {
+        for flag in flags {
+            if !self.libs.contains(&flag) {
+                self.libs.push(flag);
+            }
+        }
+    }
+}
+
+/// Asks pkg-config for the compile and link flags of a library, returning `None` if it doesn't
+/// know about it.
+fn pkg_config_flags(name: &str) -> Result<Option<(Vec<String>, Vec<String>)>> {
+    let exe = env::var_os("PKG_CONFIG").unwrap_or_else(|| OsString::from("pkg-config"));
+    let query = |flag: &str| -> Result<Option<Vec<String>>> {
+        let out = match Command::new(&exe).arg(flag).arg(name).output() {
+            Ok(out) => out,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => bail!(
+                "couldn't find pkg-config, which is needed to look up native library {}\n  \
+                 help: install pkg-config with your system's package manager",
+                name
+            ),
+            Err(e) => return Err(format_err!("couldn't run pkg-config: {}", e)),
+        };
+        if out.status.success() {
+            Ok(Some(split_flags(&String::from_utf8_lossy(&out.stdout))))
+        } else if out.status.code() == Some(1) {
+            Ok(None)
+        } else {
+            bail!("pkg-config failed on {}:\n{}", name, fmt_output(&out))
+        }
+    };
+
+    match (query("--cflags")?, query("--libs")?) {
+        (Some(cflags), Some(libs)) => Ok(Some((cflags, libs))),
+        _ => Ok(None),
+    }
+}
+
+fn split_flags(s: &str) -> Vec<String> {
+    s.split_whitespace().map(|x| x.to_string()).collect()
+}
+
+/// The directories a C compiler looks for libraries in if it isn't told otherwise.
+fn lib_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    for var in &["LIBRARY_PATH", "LD_LIBRARY_PATH"] {
+        if let Some(paths) = env::var_os(var) {
+            dirs.extend(env::split_paths(&paths));
+        }
+    }
+    dirs.extend(
+        [
+            "/usr/local/lib",
+            "/usr/lib",
+            "/lib",
+            "/usr/local/lib64",
+            "/usr/lib64",
+            "/opt/homebrew/lib",
+            "/opt/local/lib",
+        ]
+        .iter()
+        .map(PathBuf::from),
+    );
+    dirs.push(PathBuf::from(format!(
+        "/usr/lib/{}-linux-gnu",
+        env::consts::ARCH
+    )));
+    dirs
+}
+
+/// Finds a library a linker could use for `-l<name>` in one of `dirs`.
+fn find_lib(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let files = [
+        format!("lib{}.so", name),
+        format!("lib{}.a", name),
+        format!("lib{}.dylib", name),
+        format!("lib{}.dll.a", name),
+        format!("{}.lib", name),
+    ];
+    dirs.iter()
+        .flat_map(|dir| files.iter().map(move |file| dir.join(file)))
+        .find(|x| x.is_file())
+}
+
+/// How someone would usually install a library, for the platform we're on.
+fn install_hint(name: &str) -> String {
+    let base = name.trim_start_matches("lib");
+    if cfg!(target_os = "macos") {
+        format!("brew install {}", base)
+    } else if cfg!(windows) {
+        format!("pacman -S mingw-w64-x86_64-{}", base)
+    } else {
+        format!("apt install lib{0}-dev` or `dnf install {0}-devel", base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn native_find_lib() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::write(tmp.path().join("libgmp.a"), "").unwrap();
+        let dirs = vec![tmp.path().join("nothing"), tmp.path().to_path_buf()];
+        assert_eq!(find_lib("gmp", &dirs), Some(tmp.path().join("libgmp.a")));
+        assert_eq!(find_lib("ffi", &dirs), None);
+    }
+
+    #[test]
+    fn native_flags_dedup() {
+        let mut flags = NativeFlags::default();
+        flags.push_cflags(split_flags("-I/usr/include/ffi\n"));
+        flags.push_libs(split_flags("-L/usr/local/lib -lffi"));
+        flags.push_libs(split_flags("-L/usr/local/lib -lgmp"));
+        assert_eq!(
+            flags.args().collect::<Vec<_>>(),
+            vec!["-I/usr/include/ffi", "-L/usr/local/lib", "-lffi", "-lgmp"]
+        );
+    }
+
+    #[test]
+    fn native_missing_libs() {
+        let native = NativeInfo {
+            libs: vec!["elba-surely-not-installed".to_string()],
+            pkg_config: vec![],
+        };
+        let e = NativeFlags::probe(vec![&native]).unwrap_err().to_string();
+        assert!(e.contains("elba-surely-not-installed"), "{}", e);
+        assert!(e.contains("help: try"), "{}", e);
+    }
+}
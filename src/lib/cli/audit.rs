@@ -0,0 +1,66 @@
+//! Checking the packages in a lockfile against an advisory database.
+
+use std::path::Path;
+
+use petgraph::graph::NodeIndex;
+use semver::Version;
+use serde::Serialize;
+
+use super::build::{locked_graph, BuildCtx};
+use crate::{
+    package::Name,
+    remote::{advisory::Advisory, resolution::DirectRes},
+    util::error::Result,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditReport {
+    /// Advisories about vulnerabilities, most severe first.
+    pub vulnerabilities: Vec<Finding>,
+    /// Advisories which aren't about vulnerabilities, like packages which aren't maintained
+    /// anymore.
+    pub warnings: Vec<Finding>,
+}
+
+/// A locked package which an advisory applies to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub name: Name,
+    pub version: Version,
+    pub advisory: Advisory,
+}
+
+/// Looks up every package in the lockfile of a project in an advisory database.
+pub fn audit(ctx: &BuildCtx, project: &Path, db: &DirectRes) -> Result<AuditReport> {
+    let (graph, _) = locked_graph(ctx, project)?;
+    let db = ctx.cache()?.get_advisory_db(db, ctx.refresh, ctx.offline)?;
+
+    let mut vulnerabilities = vec![];
+    let mut warnings = vec![];
+    for (_, sum) in graph.sub_tree(NodeIndex::new(0)).skip(1) {
+        for advisory in db.affecting(sum.name(), &sum.version) {
+            let finding = Finding {
+                name: sum.name().clone(),
+                version: sum.version.clone(),
+                advisory: advisory.clone(),
+            };
+            if advisory.is_vulnerability() {
+                vulnerabilities.push(finding);
+            } else {
+                warnings.push(finding);
+            }
+        }
+    }
+    vulnerabilities.sort_by(|a, b| {
+        b.advisory
+            .severity
+            .cmp(&a.advisory.severity)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    warnings.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(AuditReport {
+        vulnerabilities,
+        warnings,
+    })
+}
@@ -1,48 +1,70 @@
 use std::{
     convert::TryInto,
-    env, fs,
-    io::{prelude::*, Seek, SeekFrom},
+    env, fmt, fs,
+    io::prelude::*,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    time::Duration,
 };
 
 use console::style;
 use crossbeam::queue::MsQueue;
 use failure::{bail, format_err, ResultExt};
 use indexmap::IndexMap;
-use itertools::Either::{self, Left, Right};
+use itertools::{
+    Either::{self, Left, Right},
+    Itertools,
+};
 use petgraph::{graph::NodeIndex, visit::Dfs};
 use scoped_threadpool::Pool;
+use semver::Version;
+use semver_constraints::Constraint;
+use serde::Serialize;
 use slog::Logger;
 use toml;
 use toml_edit;
+use url::Url;
 
+use super::{
+    index::select_index,
+    paths::{self, PATHS_FILE},
+    policy::check_policy,
+};
 use crate::{
     build::{
         context::{BuildContext, Compiler},
-        job::{Job, JobQueue},
+        job::{prebuild, without_test_deps, Job, JobQueue},
+        native::NativeFlags,
         Target, Targets,
     },
     package::{
         ipkg::Ipkg,
-        lockfile::LockfileToml,
-        manifest::{BinTarget, Manifest},
-        PackageId, Spec, Summary,
+        lint::{self, Severity},
+        lockfile::{LockedPkg, LockfileToml},
+        manifest::{inherit, Manifest},
+        workspace::{read_manifest, Workspace},
+        Name, PackageId, Spec, Summary,
+    },
+    remote::{
+        mirror::Mirrors,
+        resolution::{DirectRes, IndexRes, Resolution},
     },
-    remote::resolution::{DirectRes, IndexRes, Resolution},
     resolve::Resolver,
     retrieve::{
-        cache::{Cache, Layout, OutputLayout},
+        cache::{BuildHash, Cache, InstalledBin, Layout, OutputLayout, Refresh, Source},
         Retriever,
     },
     util::{
-        config::Backend,
-        error::Result,
+        config::{Backend, Config, Policy},
+        error::{Error, Result},
         fmt_output,
+        fs::write_atomic,
+        git,
         graph::Graph,
-        lock::DirLock,
-        shell::{Shell, Verbosity},
+        lock::{DirLock, LockKind},
+        mod_path, normalize_path,
+        shell::{Event, Shell, Verbosity},
     },
 };
 
@@ -50,17 +72,49 @@ pub struct BuildCtx {
     pub compiler: String,
     pub indices: IndexMap<String, IndexRes>,
     pub global_cache: Layout,
+    /// The mirrors to retrieve indices and packages from.
+    pub mirrors: Mirrors,
     pub logger: Logger,
     pub threads: u32,
     pub shell: Shell,
     pub offline: bool,
+    /// When the indices should be brought up to date before they're used.
+    pub refresh: Refresh,
     pub opts: Vec<String>,
+    /// How long to wait on a download before giving up on it.
+    pub timeout: Duration,
+    /// Whether the parsed entries of indices are cached between runs.
+    pub index_cache: bool,
+    /// The rules resolved dependencies have to follow.
+    pub policy: Policy,
+    /// How long to wait on a directory somebody else has locked before giving up on it. Without
+    /// one, we wait for as long as it takes.
+    pub lock_timeout: Option<Duration>,
+}
+
+impl BuildCtx {
+    /// Opens the global cache, set up to retrieve things the way the context says to.
+    pub fn cache(&self) -> Result<Cache> {
+        Cache::from_disk(&self.logger, self.global_cache.clone(), self.shell)?
+            .with_mirrors(self.mirrors.clone())
+            .with_index_cache(self.index_cache)
+            .with_lock_timeout(self.lock_timeout)
+            .with_timeout(self.timeout)
+    }
+
+    /// Locks a directory, saying so through the shell if it has to be waited on.
+    pub fn lock(&self, path: &Path, kind: LockKind) -> Result<DirLock> {
+        DirLock::acquire_with(path, kind, Some(self.shell), self.lock_timeout)
+    }
 }
 
+/// Builds the test targets of a project (or the ones named in `targets`) and runs each of them
+/// with `args`, in the environment their targets give them.
 pub fn test(
     ctx: &BuildCtx,
     project: &Path,
     targets: &[&str],
+    args: &[&str],
     backend: &Backend,
     test_threads: u32,
 ) -> Result<String> {
@@ -70,16 +124,25 @@ pub fn test(
         bail!("at least one test must be defined")
     }
 
-    solve_local(&ctx, &project, 3, None, |cache, mut retriever, solve| {
-        let sources = retriever
-            .retrieve_packages(&solve)
-            .context(format_err!("package retrieval failed"))?;
-
-        // We drop the Retriever because we want to release our lock on the Indices as soon as we
-        // can to avoid stopping other instances of elba from downloading and resolving (even
-        // though we don't even need the Retriever anymore).
-        drop(retriever);
+    let mut root = vec![];
+    if manifest.targets.lib.is_some() {
+        root.push(Target::Lib(false));
+    } else {
+        ctx.shell.println(
+            style("[warn]").yellow().bold(),
+            "No lib target for tests to import",
+            Verbosity::Normal,
+        );
+    }
+    let emp = targets.is_empty();
+    for (ix, bt) in manifest.targets.test.iter().enumerate() {
+        if emp || targets.contains(&bt.name().as_str()) {
+            root.push(Target::Test(ix));
+        }
+    }
+    let root = Targets::new(root);
 
+    solve_local(&ctx, &project, 3, None, |cache, retriever, solve| {
         let bctx = BuildContext {
             backend: backend.clone(),
             codegen: true,
@@ -87,6 +150,7 @@ pub fn test(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            native: NativeFlags::default(),
         };
 
         ctx.shell.println(
@@ -95,33 +159,16 @@ pub fn test(
             Verbosity::Quiet,
         );
 
+        let sources = retrieve_and_prebuild(ctx, retriever, &solve, &root, &bctx)?;
+
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = ctx.lock(&target_dir(&project)?, LockKind::Target)?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
 
         let bin_dir = layout.bin.clone();
 
-        let mut root = vec![];
-        if manifest.targets.lib.is_some() {
-            root.push(Target::Lib(false));
-        } else {
-            ctx.shell.println(
-                style("[warn]").yellow().bold(),
-                "No lib target for tests to import",
-                Verbosity::Normal,
-            );
-        }
-        let emp = targets.is_empty();
-        for (ix, bt) in manifest.targets.test.iter().enumerate() {
-            let bt: BinTarget = bt.clone().into();
-            if emp || targets.contains(&bt.name.as_str()) {
-                root.push(Target::Test(ix));
-            }
-        }
-
-        let root = Targets::new(root);
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
-        q.exec()?;
+        exec_queue(ctx, &project, q)?;
 
         ctx.shell.println(
             style("[3/3]").dim().bold(),
@@ -129,12 +176,14 @@ pub fn test(
             Verbosity::Quiet,
         );
 
-        let root: Vec<BinTarget> = root
+        let ext = backend.extension.as_ref().map(|x| x.as_str());
+        let root: Vec<(String, PathBuf, &IndexMap<String, String>)> = root
             .0
-            .into_iter()
+            .iter()
             .filter_map(|t| {
                 if let Target::Test(ix) = t {
-                    Some(manifest.targets.test[ix].clone().into())
+                    let test = &manifest.targets.test[*ix];
+                    Some((test.name(), test.output_file(ext), &test.env))
                 } else {
                     None
                 }
@@ -151,28 +200,31 @@ pub fn test(
         pool.scoped(|scope| {
             // let mut prg = 0;
             let shell = ctx.shell;
-            for test in &root {
+            for (test, file, env) in &root {
                 let bin_dir = &bin_dir;
                 let runner = &backend.runner;
                 // let pb = &pb;
                 scope.execute(move || {
-                    shell.println(style("Running").cyan(), &test.name, Verbosity::Normal);
-                    let out = if let Some(r) = runner {
-                        Command::new(r).arg(bin_dir.join(&test.name)).output()
+                    shell.println(style("Running").cyan(), test, Verbosity::Normal);
+                    let mut cmd = if let Some(r) = runner {
+                        let mut cmd = Command::new(r);
+                        cmd.arg(bin_dir.join(file));
+                        cmd
                     } else {
-                        Command::new(bin_dir.join(&test.name)).output()
+                        Command::new(bin_dir.join(file))
                     };
+                    let out = cmd.args(args).envs(*env).output();
                     if out.is_err() {
                         shell.println(
                             style("[error]").red().bold(),
                             format!(
                                 "Test binary {} could not be executed",
-                                bin_dir.join(&test.name).display()
+                                bin_dir.join(file).display()
                             ),
                             Verbosity::Quiet,
                         );
                     }
-                    results.push(out.map(|x| (&test.name, x)));
+                    results.push(out.map(|x| (test, x)));
                     // prg += 1;
                     // pb.set_position(prg);
                 });
@@ -185,17 +237,29 @@ pub fn test(
         while let Some(res) = results.try_pop() {
             match res {
                 Ok((test, out)) => {
-                    ctx.shell.println(
-                        if out.status.success() {
-                            style("Passed").green()
-                        } else {
-                            style("Failed").red()
-                        },
-                        &test,
-                        Verbosity::Quiet,
-                    );
-
-                    ctx.shell.println_plain(fmt_output(&out), Verbosity::Quiet);
+                    if ctx.shell.is_json() {
+                        ctx.shell.event(
+                            &Event::Test {
+                                name: test,
+                                success: out.status.success(),
+                                stdout: String::from_utf8_lossy(&out.stdout),
+                                stderr: String::from_utf8_lossy(&out.stderr),
+                            },
+                            Verbosity::Quiet,
+                        );
+                    } else {
+                        ctx.shell.println(
+                            if out.status.success() {
+                                style("Passed").green()
+                            } else {
+                                style("Failed").red()
+                            },
+                            &test,
+                            Verbosity::Quiet,
+                        );
+
+                        ctx.shell.println_plain(fmt_output(&out), Verbosity::Quiet);
+                    }
 
                     if !out.status.success() {
                         errs += 1;
@@ -210,23 +274,36 @@ pub fn test(
                 "{} test binaries executed with {} failures",
                 root.len(),
                 errs
-            ))
+            )
+            .context(Error::TestsFailed)
+            .into())
         } else {
             Ok(format!("{} test binaries executed", root.len()))
         }
     })
 }
 
+/// Options for installing the binaries of a package.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstallOpts {
+    /// Rebuild the package even if it's already installed, and replace any binaries with the same
+    /// names, even if they came from other packages.
+    pub force: bool,
+    /// Install a local package with exactly the dependencies in its lockfile, failing if the
+    /// lockfile is out of date.
+    pub locked: bool,
+}
+
 pub fn install(
     ctx: &BuildCtx,
     name: Either<Spec, PathBuf>,
-    targets: &[&str],
+    targets: &Option<Vec<&str>>,
     backend: &Backend,
-    force: bool,
+    opts: InstallOpts,
 ) -> Result<String> {
     let f = |cache: &Cache, mut retriever: Retriever, solve| -> Result<String> {
         let sources = retriever
-            .retrieve_packages(&solve)
+            .retrieve_packages(&solve, ctx.threads)
             .context(format_err!("package retrieval failed"))?;
 
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
@@ -234,21 +311,21 @@ pub fn install(
         // though we don't even need the Retriever anymore).
         drop(retriever);
 
-        let mut contents = String::new();
-        let mut manifest = fs::File::open(sources[NodeIndex::new(0)].path().join("elba.toml"))
-            .context(format_err!("failed to read manifest file (elba.toml)"))?;
-        manifest.read_to_string(&mut contents)?;
-        let manifest = Manifest::from_str(&contents)?;
+        let manifest = read_manifest(sources[NodeIndex::new(0)].path())?;
+        // By default, we build the bin target picked by default_run, or all of them if the
+        // package doesn't pick one. A bare --bin asks for all of them either way.
+        let mut picked = match targets {
+            Some(targets) if !targets.is_empty() => targets
+                .iter()
+                .map(|target| manifest.run_target(Some(target)))
+                .collect::<Result<Vec<_>>>()?,
+            None if manifest.package.default_run.is_some() => vec![manifest.run_target(None)?],
+            _ => (0..manifest.targets.bin.len()).collect(),
+        };
+        picked.sort();
+        picked.dedup();
 
-        // By default, we build all bin targets.
-        let mut root = vec![];
-        let emp = targets.is_empty();
-        for (ix, bt) in manifest.targets.bin.iter().enumerate() {
-            if emp || targets.contains(&bt.name.as_str()) {
-                root.push(Target::Bin(ix));
-            }
-        }
-        let root = Targets::new(root);
+        let root = Targets::new(picked.iter().map(|&ix| Target::Bin(ix)).collect());
 
         let bctx = BuildContext {
             backend: backend.clone(),
@@ -257,8 +334,29 @@ pub fn install(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            native: NativeFlags::default(),
         };
 
+        // If every binary is already installed from the same build, there's nothing to do.
+        let root_src = &sources[NodeIndex::new(0)];
+        let build = BuildHash::new(root_src, &sources, &root, &bctx, root.is_codegen());
+        let bin_names = picked
+            .iter()
+            .map(|&ix| {
+                manifest.targets.bin[ix]
+                    .output_file(backend.extension.as_ref().map(|x| x.as_str()))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        if !opts.force && cache.bins_up_to_date(&bin_names, &build)? {
+            return Ok(format!(
+                "{} is already installed and up to date; pass --force to reinstall it",
+                root_src.pretty_summary()
+            ));
+        }
+        let compiler = bctx.compiler.version().ok();
+
         ctx.shell.println(
             style("[2/3]").dim().bold(),
             "Building targets...",
@@ -279,7 +377,18 @@ pub fn install(
             "Installing binaries...",
             Verbosity::Quiet,
         );
-        cache.store_bins(&bins, force)?;
+        let bins = bins
+            .into_iter()
+            .map(|(path, package)| {
+                let info = InstalledBin {
+                    package,
+                    build: Some(build.0.clone()),
+                    compiler: compiler.clone(),
+                };
+                (path, info)
+            })
+            .collect::<Vec<_>>();
+        cache.store_bins(&bins, opts.force)?;
 
         Ok(format!(
             "{} binaries installed into {}",
@@ -289,11 +398,138 @@ pub fn install(
     };
 
     match name {
+        Left(_) if opts.locked => bail!(
+            "--locked only works when installing a local project; packages from an index are \
+             installed without a lockfile"
+        ),
         Left(name) => solve_remote(ctx, &name, 3, f),
+        Right(path) if opts.locked => {
+            solve_local_with(ctx, &path, 3, None, LockMode::Check, None, f)
+        }
         Right(path) => solve_local(ctx, &path, 3, None, f),
     }
 }
 
+/// Lists the installed packages which have newer versions in their indices.
+pub fn outdated_installs(ctx: &BuildCtx) -> Result<String> {
+    let cache = ctx.cache()?;
+
+    let mut installed: IndexMap<Summary, Vec<String>> = IndexMap::new();
+    for (bin, info) in cache.installed_bins()? {
+        if let Some(sum) = info.summary() {
+            installed.entry(sum).or_insert_with(Vec::new).push(bin);
+        }
+    }
+
+    let dreses = installed
+        .keys()
+        .filter_map(|sum| match sum.resolution() {
+            Resolution::Index(ir) => Some(ir.res.clone()),
+            _ => None,
+        })
+        .unique()
+        .collect::<Vec<_>>();
+    let mut indices = cache.get_indices(&dreses, ctx.refresh, ctx.offline);
+
+    let mut outdated = 0;
+    for (sum, bins) in &installed {
+        if let Resolution::Direct(_) = sum.resolution() {
+            continue;
+        }
+        let entries = match indices.entries(sum.id()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let newest = entries
+            .iter()
+            .filter(|(v, e)| !e.yanked && (sum.version().is_prerelease() || !v.is_prerelease()))
+            .map(|(v, _)| v)
+            .max();
+        if let Some(newest) = newest.filter(|v| *v > sum.version()) {
+            outdated += 1;
+            ctx.shell.println_plain(
+                format!(
+                    "{} {} -> {} ({})",
+                    sum.name(),
+                    sum.version(),
+                    newest,
+                    bins.join(", ")
+                ),
+                Verbosity::Quiet,
+            );
+        }
+    }
+
+    Ok(format!(
+        "{} installed packages have newer versions",
+        outdated
+    ))
+}
+
+/// Builds a bin target of a project and runs it with `args`. If no target is given, the one the
+/// manifest picks with `default_run` is used.
+pub fn run(
+    ctx: &BuildCtx,
+    project: &Path,
+    target: Option<&str>,
+    args: &[&str],
+    backend: &Backend,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, None)?;
+    let ix = manifest.run_target(target)?;
+    let name = manifest.targets.bin[ix].name();
+    let file = manifest.targets.bin[ix].output_file(backend.extension.as_ref().map(|x| x.as_str()));
+    let env = &manifest.targets.bin[ix].env;
+
+    let root = Targets::new(vec![Target::Bin(ix)]);
+
+    solve_local(&ctx, &project, 3, None, |cache, retriever, solve| {
+        let bctx = BuildContext {
+            backend: backend.clone(),
+            codegen: true,
+            compiler: Compiler::new(&ctx.compiler)?,
+            opts: ctx.opts.clone(),
+            cache: cache.clone(),
+            threads: ctx.threads,
+            native: NativeFlags::default(),
+        };
+
+        ctx.shell.println(
+            style("[2/3]").dim().bold(),
+            "Building targets...",
+            Verbosity::Quiet,
+        );
+
+        let sources = retrieve_and_prebuild(ctx, retriever, &solve, &root, &bctx)?;
+
+        let lock = ctx.lock(&target_dir(&project)?, LockKind::Target)?;
+        let layout = OutputLayout::new(lock).context("could not create local target directory")?;
+        let bin = layout.bin.join(&file);
+
+        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
+        exec_queue(ctx, &project, q)?;
+
+        ctx.shell.println(
+            style("[3/3]").dim().bold(),
+            format!("Running {}...", name),
+            Verbosity::Quiet,
+        );
+
+        let status = if let Some(r) = &backend.runner {
+            Command::new(r).arg(&bin).args(args).envs(env).status()
+        } else {
+            Command::new(&bin).args(args).envs(env).status()
+        }
+        .with_context(|e| format_err!("binary {} could not be executed: {}", bin.display(), e))?;
+
+        if status.success() {
+            Ok(format!("{} exited successfully", name))
+        } else {
+            bail!("{} exited with {}", name, status)
+        }
+    })
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Interactivity {
     Normal,
@@ -315,14 +551,14 @@ pub fn repl(
     let mut parents = vec![];
     let mut paths = vec![];
 
-    if let Some(lib) = manifest.targets.lib {
+    if let Some(lib) = &manifest.targets.lib {
         if targets.1.is_none() || targets.0 {
             let src_path = lib.path.0.clone();
-            let new_paths = lib
-                .mods
+            let new_paths = manifest
+                .lib_mods(&project)?
                 .iter()
                 .map(|mod_name| {
-                    let path: PathBuf = mod_name.trim_matches('.').replace(".", "/").into();
+                    let path = mod_path(&mod_name);
                     if src_path.join(&path).with_extension("idr").exists() {
                         Ok((parents.len(), path.with_extension("idr")))
                     } else if src_path.join(&path).with_extension("lidr").exists() {
@@ -342,7 +578,7 @@ pub fn repl(
 
     for bin in manifest.targets.bin {
         if let Some(v) = targets.1.as_ref() {
-            if v.contains(&bin.name.as_ref()) {
+            if v.contains(&bin.name().as_str()) {
                 let resolved = bin.resolve_bin(Path::new(".")).ok_or_else(|| {
                     format_err!(
                         "module {} isn't a subpath and doesn't exist under path {}",
@@ -368,7 +604,7 @@ pub fn repl(
 
     solve_local(ctx, &project, 3, None, |cache, mut retriever, solve| {
         let sources = retriever
-            .retrieve_packages(&solve)
+            .retrieve_packages(&solve, ctx.threads)
             .context(format_err!("package retrieval failed"))?;
 
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
@@ -388,6 +624,7 @@ pub fn repl(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            native: NativeFlags::default(),
         };
 
         ctx.shell.println(
@@ -403,7 +640,7 @@ pub fn repl(
         // The reason we do this is because the repl is often used for interactive development.
         q.graph.inner[NodeIndex::new(0)] = Job::default();
 
-        let deps = q.exec()?.0;
+        let deps = exec_queue(ctx, &project, q)?.0;
 
         // From here, we basically manually build a CompileInvocation, but tailor-made for the
         // repl command.
@@ -485,16 +722,7 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
     }
     let root = Targets::new(root);
 
-    solve_local(ctx, &project, 2, None, |cache, mut retriever, solve| {
-        let sources = retriever
-            .retrieve_packages(&solve)
-            .context(format_err!("package retrieval failed"))?;
-
-        // We drop the Retriever because we want to release our lock on the Indices as soon as we
-        // can to avoid stopping other instances of elba from downloading and resolving (even
-        // though we don't even need the Retriever anymore).
-        drop(retriever);
-
+    solve_local(ctx, &project, 2, None, |cache, retriever, solve| {
         let backend = Backend::default();
 
         let bctx = BuildContext {
@@ -505,6 +733,7 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            native: NativeFlags::default(),
         };
 
         ctx.shell.println(
@@ -513,28 +742,126 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
             Verbosity::Quiet,
         );
 
+        let sources = retrieve_and_prebuild(ctx, retriever, &solve, &root, &bctx)?;
+
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = ctx.lock(&target_dir(&project)?, LockKind::Target)?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
 
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
         // Because we're just building, we don't need to do anything after executing the build
         // process. Yay abstraction!
-        q.exec()?;
+        exec_queue(ctx, &project, q)?;
 
         Ok("docs output available at `./target/docs`".to_string())
     })
 }
 
-pub fn build(
-    ctx: &BuildCtx,
-    project: &Path,
-    targets: &(bool, bool, Option<Vec<&str>>, Option<Vec<&str>>),
-    codegen: bool,
-    backend: &Backend,
-) -> Result<String> {
-    let (project, manifest) = find_manifest(project, true, None)?;
+/// Compares the modules the lib target of a project lists with the source files under its path,
+/// reporting any module which is listed but doesn't have a source file, or has a source file but
+/// isn't listed.
+pub fn check_mods(ctx: &BuildCtx, project: &Path) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, None)?;
+    let lib = manifest
+        .targets
+        .lib
+        .as_ref()
+        .ok_or_else(|| format_err!("the package doesn't have a library target"))?;
+    let listed = match &lib.mods {
+        Some(mods) => mods
+            .iter()
+            .map(|m| m.trim_matches('.').to_owned())
+            .collect::<Vec<_>>(),
+        None => {
+            return Ok(
+                "the lib target doesn't list its mods; every module under its path is used"
+                    .to_string(),
+            )
+        }
+    };
+    let found = manifest.discover_mods(&project)?;
+
+    let mut drift = 0;
+    for m in listed.iter().filter(|m| !found.contains(m)) {
+        let src = project.join(&lib.path.0).join(mod_path(m));
+        if !src.with_extension("idr").exists() && !src.with_extension("lidr").exists() {
+            drift += 1;
+            ctx.shell.println(
+                style("[warn]").yellow().bold(),
+                format!("module {} is listed in mods, but it has no source file", m),
+                Verbosity::Quiet,
+            );
+        }
+    }
+    for m in found.iter().filter(|m| !listed.contains(m)) {
+        drift += 1;
+        ctx.shell.println(
+            style("[warn]").yellow().bold(),
+            format!(
+                "module {} has a source file, but it isn't listed in mods",
+                m
+            ),
+            Verbosity::Quiet,
+        );
+    }
+
+    if drift > 0 {
+        bail!(
+            "the mods of the lib target don't match its source files ({} difference{})",
+            drift,
+            if drift == 1 { "" } else { "s" }
+        )
+    }
+
+    Ok(format!(
+        "all {} mods of the lib target match its source files",
+        listed.len()
+    ))
+}
+
+/// Lints the manifest of a project, printing every problem found. Fails if any of them are errors.
+pub fn verify(ctx: &BuildCtx, project: &Path) -> Result<String> {
+    let root = find_manifest_root(project).ok_or_else(|| Error::ManifestNotFound)?;
+    let raw = fs::read_to_string(root.join("elba.toml"))
+        .context(format_err!("failed to read manifest file (elba.toml)"))?;
+
+    let diags = lint::lint(root, &raw)?;
+    let mut errors = 0;
+    for diag in &diags {
+        let status = match diag.severity {
+            Severity::Error => {
+                errors += 1;
+                style("[error]").red().bold()
+            }
+            Severity::Warning => style("[warn]").yellow().bold(),
+        };
+        ctx.shell.println(status, diag, Verbosity::Quiet);
+    }
+    let warnings = diags.len() - errors;
+
+    if errors > 0 {
+        bail!(
+            "the manifest has {} error{} and {} warning{}",
+            errors,
+            if errors == 1 { "" } else { "s" },
+            warnings,
+            if warnings == 1 { "" } else { "s" }
+        )
+    }
+
+    Ok(format!(
+        "the manifest is valid ({} warning{})",
+        warnings,
+        if warnings == 1 { "" } else { "s" }
+    ))
+}
 
+/// The targets of a package which `elba build` builds, given whether `--lib` and `--lib-cg` were
+/// passed and which binaries and tests were asked for with `--bin` and `--test`.
+pub fn build_targets(
+    manifest: &Manifest,
+    targets: &(bool, bool, Option<Vec<&str>>, Option<Vec<&str>>),
+) -> Result<Targets> {
     // By default, we build all lib and bin targets.
     let mut root = vec![];
     if (targets.2.is_none() || targets.0 || targets.1) && manifest.targets.lib.is_some() {
@@ -555,7 +882,7 @@ pub fn build(
         let target_specified = targets
             .2
             .as_ref()
-            .map(|v| v.is_empty() || v.contains(&bt.name.as_str()))
+            .map(|v| v.is_empty() || v.contains(&bt.name().as_str()))
             .unwrap_or(false);
         // Case 2: Neither --bin nor --lib are specified. We're fine with --lib-cg.
         let neither_specified = !targets.0 && targets.2.is_none();
@@ -567,25 +894,27 @@ pub fn build(
     // We only build test targets if the user asks for them.
     if let Some(ts) = &targets.3 {
         for (ix, bt) in manifest.targets.test.iter().enumerate() {
-            let bt: BinTarget = bt.clone().into();
-            let target_specified = ts.is_empty() || ts.contains(&bt.name.as_str());
+            let target_specified = ts.is_empty() || ts.contains(&bt.name().as_str());
             if target_specified {
                 root.push(Target::Test(ix));
             }
         }
     }
 
-    let root = Targets::new(root);
-    solve_local(ctx, &project, 2, None, |cache, mut retriever, solve| {
-        let sources = retriever
-            .retrieve_packages(&solve)
-            .context(format_err!("package retrieval failed"))?;
+    Ok(Targets::new(root))
+}
 
-        // We drop the Retriever because we want to release our lock on the Indices as soon as we
-        // can to avoid stopping other instances of elba from downloading and resolving (even
-        // though we don't even need the Retriever anymore).
-        drop(retriever);
+pub fn build(
+    ctx: &BuildCtx,
+    project: &Path,
+    targets: &(bool, bool, Option<Vec<&str>>, Option<Vec<&str>>),
+    codegen: bool,
+    backend: &Backend,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, true, None)?;
+    let root = build_targets(&manifest, targets)?;
 
+    solve_local(ctx, &project, 2, None, |cache, retriever, solve| {
         let bctx = BuildContext {
             backend: backend.clone(),
             codegen,
@@ -593,6 +922,7 @@ pub fn build(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            native: NativeFlags::default(),
         };
 
         ctx.shell.println(
@@ -601,32 +931,36 @@ pub fn build(
             Verbosity::Quiet,
         );
 
+        let sources = retrieve_and_prebuild(ctx, retriever, &solve, &root, &bctx)?;
+
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = ctx.lock(&target_dir(&project)?, LockKind::Target)?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
 
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
         // Because we're just building, we don't need to do anything after executing the build
         // process. Yay abstraction!
-        q.exec()?;
+        exec_queue(ctx, &project, q)?;
 
         Ok("build output available at `./target`".to_string())
     })
 }
 
 pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result<String> {
-    let (project, _) = find_manifest(project, true, None)?;
-
-    let op = || -> Result<Graph<Summary>> {
-        let mut f = fs::File::open(&project.join("elba.lock"))?;
-        let mut contents = String::new();
-        f.read_to_string(&mut contents)?;
-        let toml = LockfileToml::from_str(&contents)?;
+    let (project, manifest) = find_manifest(project, true, None)?;
 
-        Ok(toml.into())
+    let lock_root = match Workspace::find(&project)? {
+        Some(ws) => ws.root,
+        None => project.clone(),
     };
-
-    let prev = op().ok();
+    let root = PackageId::new(
+        manifest.name().clone(),
+        DirectRes::Dir {
+            path: normalize_path(&project),
+        }
+        .into(),
+    );
+    let prev = read_project_lock(&lock_root, &root, false).ok();
 
     solve_local(ctx, &project, 1, ignore, |_, _, solve| {
         if let Some(prev) = prev.as_ref() {
@@ -676,90 +1010,954 @@ pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result
     })
 }
 
-pub fn add(ctx: &BuildCtx, project: &Path, spec: &Spec, dev: bool) -> Result<String> {
-    let mut contents = String::new();
-    let (project, _) = find_manifest(project, true, None)?;
-    let mut mf = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(project.join("elba.toml"))
-        .context(format_err!("failed to read manifest file (elba.toml)"))?;
-    mf.read_to_string(&mut contents)?;
-    let mut manifest = contents
-        .parse::<toml_edit::Document>()
-        .with_context(|e| format!("invalid manifest toml format: {}", e))?;
+/// The packages in the workspace a project is in, for commands which act on all of them.
+pub fn workspace_members(project: &Path) -> Result<Vec<(Name, PathBuf)>> {
+    let ws = Workspace::find(project)?.ok_or_else(|| {
+        format_err!(
+            "{} isn't in a workspace; --all only works in one",
+            project.display()
+        )
+    })?;
 
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
-    let indices = ctx
-        .indices
-        .values()
-        .cloned()
-        .map(|x| x.res)
-        .collect::<Vec<_>>();
-    let indices = cache.get_indices(&indices, false, false);
-    let target = indices.select_by_spec(&spec)?;
-    let target_s = target.to_string();
+    Ok(ws.members.into_iter().map(|(n, m)| (n, m.path)).collect())
+}
 
-    let res = match target.id.resolution() {
-        Resolution::Index(IndexRes { res }) => res.clone(),
-        _ => unreachable!(),
-    };
+/// The version of the layout of `elba metadata`'s output. This only goes up when something is
+/// taken out of it or changes meaning; new fields can show up without it changing.
+pub const METADATA_VERSION: u32 = 1;
+
+/// Everything elba knows about a project, for tools which want to work with it without having to
+/// understand manifests and lockfiles themselves.
+#[derive(Debug, Clone, Serialize)]
+pub struct Metadata {
+    pub version: u32,
+    /// The directory the project's manifest is in.
+    pub root: PathBuf,
+    pub manifest: Manifest,
+    /// The workspace the project is in, if it's in one.
+    pub workspace: Option<WorkspaceMetadata>,
+    /// The dependency graph recorded in the lockfile, if the project has been locked.
+    pub resolve: Option<LockfileToml>,
+    pub targets: Vec<TargetMetadata>,
+    pub paths: PathsMetadata,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkspaceMetadata {
+    pub root: PathBuf,
+    pub members: Vec<MemberMetadata>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberMetadata {
+    pub name: Name,
+    pub version: Version,
+    pub path: PathBuf,
+}
 
-    if !dev {
-        manifest["dependencies"][target.id.name().to_string()]["version"] =
-            toml_edit::value(target.version.to_string());
-        manifest["dependencies"][target.id.name().to_string()]["index"] =
-            toml_edit::value(res.to_string());
-        manifest["dependencies"][target.id.name().to_string()]
-            .as_inline_table_mut()
-            .map(|t| t.fmt());
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetMetadata {
+    /// One of `lib`, `bin` or `test`.
+    pub kind: &'static str,
+    pub name: String,
+    /// The directory the target's modules are in.
+    pub path: PathBuf,
+    /// The main module of a binary or test.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub main: Option<String>,
+    /// The modules a library exports.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mods: Option<Vec<String>>,
+    /// The options the target passes to the compiler.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub idris_opts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PathsMetadata {
+    pub target: PathBuf,
+    pub lockfile: PathBuf,
+    /// The global cache directories: see `Layout` for what goes in each one.
+    pub cache: IndexMap<&'static str, PathBuf>,
+}
+
+/// Gathers up everything elba knows about a project.
+pub fn metadata(ctx: &BuildCtx, project: &Path) -> Result<Metadata> {
+    let (project, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let ws = Workspace::find(&project)?;
+    let lock_root = ws
+        .as_ref()
+        .map(|x| x.root.clone())
+        .unwrap_or_else(|| project.clone());
+
+    let resolve = if lock_root.join("elba.lock").exists() {
+        Some(read_lockfile_toml(&lock_root)?)
     } else {
-        manifest["dev_dependencies"][target.id.name().to_string()]["version"] =
-            toml_edit::value(target.version.to_string());
-        manifest["dev_dependencies"][target.id.name().to_string()]["index"] =
-            toml_edit::value(res.to_string());
-        manifest["dev_dependencies"][target.id.name().to_string()]
-            .as_inline_table_mut()
-            .map(|t| t.fmt());
-    }
+        None
+    };
 
-    mf.seek(SeekFrom::Start(0))?;
-    mf.write_all(manifest.to_string().as_bytes())?;
+    let workspace = ws.map(|ws| WorkspaceMetadata {
+        root: ws.root,
+        members: ws
+            .members
+            .into_iter()
+            .map(|(name, m)| MemberMetadata {
+                name,
+                version: m.version,
+                path: m.path,
+            })
+            .collect(),
+    });
+
+    let targets = target_metadata(&project, &manifest)?;
+
+    let layout = &ctx.global_cache;
+    let mut cache = IndexMap::new();
+    cache.insert("bin", layout.bin.clone());
+    cache.insert("src", layout.src.clone());
+    cache.insert("store", layout.store.clone());
+    cache.insert("build", layout.build.clone());
+    cache.insert("tmp", layout.tmp.clone());
+    cache.insert("indices", layout.indices.clone());
+    cache.insert("keys", layout.keys.clone());
+
+    let paths = PathsMetadata {
+        target: target_dir(&project)?,
+        lockfile: lock_root.join("elba.lock"),
+        cache,
+    };
 
-    Ok(format!("added package {} to manifest", target_s))
+    Ok(Metadata {
+        version: METADATA_VERSION,
+        root: project,
+        manifest,
+        workspace,
+        resolve,
+        targets,
+        paths,
+    })
 }
 
-pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
-    ctx: &BuildCtx,
-    project: &Path,
-    total: u8,
-    ignore: Option<&[Spec]>,
-    mut f: F,
-) -> Result<String> {
-    let (project, manifest) = find_manifest(project, true, Some(ctx.shell))?;
+/// The targets of the project in `project`.
+pub fn target_metadata(project: &Path, manifest: &Manifest) -> Result<Vec<TargetMetadata>> {
+    let mut targets = vec![];
+    if let Some(lib) = &manifest.targets.lib {
+        targets.push(TargetMetadata {
+            kind: "lib",
+            name: manifest.name().to_string(),
+            path: project.join(&lib.path.0),
+            main: None,
+            mods: Some(manifest.lib_mods(project)?),
+            idris_opts: lib.idris_opts.clone(),
+        });
+    }
+    for bin in &manifest.targets.bin {
+        targets.push(TargetMetadata {
+            kind: "bin",
+            name: bin.name(),
+            path: project.join(&bin.path.0),
+            main: Some(bin.main.clone()),
+            mods: None,
+            idris_opts: bin.idris_opts.clone(),
+        });
+    }
+    for test in &manifest.targets.test {
+        targets.push(TargetMetadata {
+            kind: "test",
+            name: test.name(),
+            path: project.join(&test.path.0),
+            main: Some(test.main.clone()),
+            mods: None,
+            idris_opts: test.idris_opts.clone(),
+        });
+    }
 
-    let op = || -> Result<Graph<Summary>> {
-        let mut f = fs::File::open(&project.join("elba.lock"))?;
-        let mut contents = String::new();
-        f.read_to_string(&mut contents)?;
-        let toml = LockfileToml::from_str(&contents)?;
+    Ok(targets)
+}
 
-        Ok(toml.into())
+/// The dependency graph recorded in the lockfile of a project, with the project at its root,
+/// along with the names of the packages it only depends on as dev-dependencies.
+pub fn locked_graph(ctx: &BuildCtx, project: &Path) -> Result<(Graph<Summary>, Vec<Name>)> {
+    let (project, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let lock_root = match Workspace::find(&project)? {
+        Some(ws) => ws.root,
+        None => project.clone(),
     };
+    if !lock_root.join("elba.lock").exists() {
+        bail!("there's no elba.lock to read; run `elba lock` to make one")
+    }
 
-    let lock = match ignore {
-        None => {
-            if let Ok(solve) = op() {
-                solve
-            } else {
-                Graph::default()
+    let root = PackageId::new(
+        manifest.name().clone(),
+        DirectRes::Dir {
+            path: normalize_path(&project),
+        }
+        .into(),
+    );
+    let graph = read_project_lock(&lock_root, &root, false)?;
+
+    let normal = manifest.deps(&ctx.indices, &root, false)?;
+    let dev = manifest
+        .deps(&ctx.indices, &root, true)?
+        .into_iter()
+        .map(|(pid, _)| pid.name().clone())
+        .filter(|name| !normal.keys().any(|pid| pid.name() == name))
+        .collect();
+
+    Ok((graph, dev))
+}
+
+/// The packages in the lockfile of a project which it depends on, with the project first, along
+/// with everything else the lockfile records about them (like their checksums and sources).
+pub fn locked_packages(ctx: &BuildCtx, project: &Path) -> Result<Vec<LockedPkg>> {
+    let (graph, _) = locked_graph(ctx, project)?;
+    let (project, _) = find_manifest(project, false, None)?;
+    let lock_root = match Workspace::find(&project)? {
+        Some(ws) => ws.root,
+        None => project,
+    };
+    let lf = read_lockfile_toml(&lock_root)?;
+
+    Ok(graph
+        .sub_tree(NodeIndex::new(0))
+        .filter_map(|(_, sum)| lf.packages.iter().find(|pkg| &pkg.sum == sum).cloned())
+        .collect())
+}
+
+/// Whether the lockfile of a project still says what resolving its dependencies would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockState {
+    Fresh,
+    /// The manifest (or an index) changed since the project was locked.
+    Outdated,
+    Missing,
+}
+
+/// How far along a dependency is on its way to being built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DepState {
+    /// A build of the package is in the global cache.
+    Built,
+    /// The package is in the cache, but it hasn't been built with these dependencies and options.
+    NeedsBuild,
+    /// The package has to be downloaded before it can be built.
+    NeedsDownload,
+    /// The package is in the cache, but whether it's been built can't be told until the
+    /// dependencies it has which need downloading are downloaded.
+    Cached,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DepStatus {
+    pub package: Summary,
+    pub state: DepState,
+}
+
+/// What building a project would take, worked out without building (or downloading) anything.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub package: Summary,
+    pub lockfile: LockState,
+    pub deps: Vec<DepStatus>,
+    /// The targets of the project which building it would build, which is none of them if the
+    /// target directory is up to date.
+    pub pending: Vec<String>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", self.package.name(), self.package.version())?;
+        writeln!(
+            f,
+            "lockfile: {}",
+            match self.lockfile {
+                LockState::Fresh => "up to date",
+                LockState::Outdated => "out of date (run `elba lock` to update it)",
+                LockState::Missing => "missing (run `elba lock` to make one)",
             }
+        )?;
+
+        let count = |state| self.deps.iter().filter(|x| x.state == state).count();
+        writeln!(
+            f,
+            "dependencies: {} ({} built, {} to build, {} to download)",
+            self.deps.len(),
+            count(DepState::Built),
+            count(DepState::NeedsBuild),
+            count(DepState::NeedsDownload),
+        )?;
+        for dep in &self.deps {
+            let state = match dep.state {
+                DepState::Built => "built",
+                DepState::NeedsBuild => "needs build",
+                DepState::NeedsDownload => "needs download",
+                DepState::Cached => "cached",
+            };
+            writeln!(f, "  {} {}", dep.package, state)?;
         }
-        Some(i) => {
-            if i.is_empty() {
-                Graph::default()
-            } else if let Ok(mut solve) = op() {
-                for spec in i {
+
+        if self.pending.is_empty() {
+            write!(f, "pending: none; the target directory is up to date")
+        } else {
+            write!(f, "pending: {}", self.pending.join(", "))
+        }
+    }
+}
+
+/// Resolves the dependencies of a project and works out which of them are built, which are
+/// cached, and which have to be downloaded, along with which of the project's own targets are
+/// out of date, as `elba build` would see it. Nothing is written to the lockfile or the target
+/// directory.
+pub fn status(ctx: &BuildCtx, project: &Path, backend: &Backend) -> Result<Status> {
+    let (project, manifest) = find_manifest(project, true, Some(ctx.shell))?;
+    let lock_root = match Workspace::find(&project)? {
+        Some(ws) => ws.root,
+        None => project.clone(),
+    };
+    let root_id = PackageId::new(
+        manifest.name().clone(),
+        DirectRes::Dir {
+            path: normalize_path(&project),
+        }
+        .into(),
+    );
+    let locked = if lock_root.join("elba.lock").exists() {
+        Some(read_project_lock(&lock_root, &root_id, false)?)
+    } else {
+        None
+    };
+
+    // These are the targets `elba build` builds by default.
+    let mut targets = vec![];
+    if manifest.targets.lib.is_some() {
+        targets.push(Target::Lib(false));
+    }
+    targets.extend((0..manifest.targets.bin.len()).map(Target::Bin));
+    let targets = Targets::new(targets);
+
+    let mut status = None;
+    solve_local_with(
+        ctx,
+        &project,
+        1,
+        None,
+        LockMode::DryRun,
+        None,
+        |cache, mut retriever, solve| {
+            let lockfile = match &locked {
+                None => LockState::Missing,
+                Some(locked) => {
+                    let same = locked.inner.node_count() == solve.inner.node_count()
+                        && solve
+                            .inner
+                            .node_indices()
+                            .all(|ix| locked.find_by(|sum| sum == &solve[ix]).is_some());
+                    if same {
+                        LockState::Fresh
+                    } else {
+                        LockState::Outdated
+                    }
+                }
+            };
+
+            let mut cached = vec![];
+            for ix in solve.inner.node_indices() {
+                cached.push(retriever.is_cached(&solve[ix])?);
+            }
+
+            let mut deps = solve
+                .inner
+                .node_indices()
+                .skip(1)
+                .map(|ix| DepStatus {
+                    package: solve[ix].clone(),
+                    state: if cached[ix.index()] {
+                        DepState::Cached
+                    } else {
+                        DepState::NeedsDownload
+                    },
+                })
+                .collect::<Vec<_>>();
+
+            // Everything's here, so we can work out what's been built without going online.
+            let mut pending = targets.0.iter().map(|t| describe(&manifest, t)).collect();
+            if cached.iter().all(|x| *x) {
+                let sources = retriever.retrieve_packages(&solve, ctx.threads)?;
+                drop(retriever);
+
+                let bctx = BuildContext {
+                    backend: backend.clone(),
+                    codegen: true,
+                    compiler: Compiler::new(&ctx.compiler)?,
+                    opts: ctx.opts.clone(),
+                    cache: cache.clone(),
+                    threads: ctx.threads,
+                    native: NativeFlags::default(),
+                };
+
+                for (dep, ix) in deps.iter_mut().zip(sources.inner.node_indices().skip(1)) {
+                    let lib = Targets::new(vec![Target::Lib(false)]);
+                    let hash = BuildHash::new(&sources[ix], &sources, &lib, &bctx, false);
+                    dep.state = if cache.check_build(&hash).is_some() {
+                        DepState::Built
+                    } else {
+                        DepState::NeedsBuild
+                    };
+                }
+
+                let sources = without_test_deps(sources);
+                let hash = BuildHash::new(
+                    &sources[NodeIndex::new(0)],
+                    &sources,
+                    &targets,
+                    &bctx,
+                    targets.is_codegen(),
+                );
+                let target = target_dir(&project)?;
+                let built = fs::read_to_string(target.join("hash"))
+                    .map(|x| x == hash.0)
+                    .unwrap_or(false);
+                if built || cache.check_build(&hash).is_some() {
+                    pending = vec![];
+                }
+            }
+
+            status = Some(Status {
+                package: solve[NodeIndex::new(0)].clone(),
+                lockfile,
+                deps,
+                pending,
+            });
+
+            Ok(String::new())
+        },
+    )?;
+
+    Ok(status.unwrap())
+}
+
+/// The name a target goes by in messages.
+fn describe(manifest: &Manifest, target: &Target) -> String {
+    match target {
+        Target::Lib(_) => format!("lib {}", manifest.name()),
+        Target::Bin(ix) => format!("bin {}", manifest.targets.bin[*ix].name()),
+        Target::Test(ix) => format!("test {}", manifest.targets.test[*ix].name()),
+        Target::Doc => format!("docs for {}", manifest.name()),
+    }
+}
+
+/// Where a dependency added with `elba add` comes from.
+#[derive(Debug, Clone)]
+pub enum DepSource {
+    /// An index: either the name of one in the config or an index resolution. Defaults to the
+    /// first index in the config.
+    Index(Option<String>),
+    /// A git repository, at a branch, tag or commit (defaults to master).
+    Git { repo: Url, tag: Option<String> },
+    /// A directory, relative to the project.
+    Path(PathBuf),
+}
+
+/// Options for adding a dependency to a manifest.
+#[derive(Debug, Clone)]
+pub struct AddOpts {
+    pub source: DepSource,
+    /// Whether to add the package as a dev dependency.
+    pub dev: bool,
+    /// Whether to resolve the project's dependencies again afterward and update the lockfile.
+    pub update: bool,
+}
+
+/// Adds a dependency to the manifest of a project, keeping the rest of it (comments and all) the
+/// way it was. Without a version constraint, dependencies from an index are added at their latest
+/// version which hasn't been yanked.
+pub fn add(
+    ctx: &BuildCtx,
+    project: &Path,
+    name: &Name,
+    req: Option<&Constraint>,
+    opts: &AddOpts,
+) -> Result<String> {
+    let (project, _) = find_manifest(project, false, None)?;
+
+    let dep = match &opts.source {
+        DepSource::Index(index) => {
+            let ir = select_index(&ctx.indices, index.as_ref().map(|x| x.as_str()))?;
+            let cache = ctx.cache()?;
+            let indices = cache.get_indices(&[ir.res.clone()], ctx.refresh, ctx.offline);
+            let entries = indices
+                .indices
+                .get(&ir)
+                .and_then(|ix| ix.entries(name).ok())
+                .ok_or_else(|| format_err!("package {} wasn't found in index {}", name, ir))?;
+            let latest = entries
+                .values()
+                .filter(|x| !x.yanked && req.map(|c| c.satisfies(&x.version)).unwrap_or(true))
+                .map(|x| &x.version)
+                .max()
+                .ok_or_else(|| match req {
+                    Some(c) => format_err!("no version of {} in index {} matches {}", name, ir, c),
+                    None => format_err!("every version of {} has been yanked", name),
+                })?;
+            let version = match req {
+                Some(c) => c.to_string(),
+                None => latest.to_string(),
+            };
+
+            ctx.shell.println(
+                style("Adding").green(),
+                format!("{} {} ({})", name, version, latest),
+                Verbosity::Normal,
+            );
+
+            // Dependencies without an index come from the first one in the config.
+            match index {
+                Some(index) if ctx.indices.get_index(0).map(|x| x.1) != Some(&ir) => {
+                    let mut t = toml_edit::InlineTable::default();
+                    t.get_or_insert("version", version);
+                    t.get_or_insert("index", index.as_str());
+                    toml_edit::Item::Value(toml_edit::Value::InlineTable(t))
+                }
+                _ => toml_edit::value(version),
+            }
+        }
+        DepSource::Git { repo, tag } => {
+            if req.is_some() {
+                bail!("git dependencies can't have a version constraint; give a --tag instead")
+            }
+            ctx.shell.println(
+                style("Adding").green(),
+                format!("{} ({})", name, repo),
+                Verbosity::Normal,
+            );
+
+            let mut t = toml_edit::InlineTable::default();
+            t.get_or_insert("git", repo.as_str());
+            if let Some(tag) = tag {
+                t.get_or_insert("tag", tag.as_str());
+            }
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(t))
+        }
+        DepSource::Path(path) => {
+            if req.is_some() {
+                bail!("path dependencies can't have a version constraint")
+            }
+            let full = normalize_path(&project.join(path));
+            if !full.join("elba.toml").exists() {
+                bail!("there's no manifest file (elba.toml) in {}", full.display())
+            }
+            let (_, dep) = find_manifest(&full, false, None)?;
+            if dep.name() != name {
+                bail!(
+                    "the package at {} is {}, not {}",
+                    path.display(),
+                    dep.name(),
+                    name
+                )
+            }
+            ctx.shell.println(
+                style("Adding").green(),
+                format!("{} ({})", name, path.display()),
+                Verbosity::Normal,
+            );
+
+            let mut t = toml_edit::InlineTable::default();
+            t.get_or_insert("path", path.to_string_lossy().replace("\\", "/"));
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(t))
+        }
+    };
+
+    let (mut doc, old) = read_manifest_doc(&project)?;
+    let table = if opts.dev {
+        "dev_dependencies"
+    } else {
+        "dependencies"
+    };
+    // A dependency which is already there is replaced in place, even if it's spelled differently.
+    let key = dep_key(&doc, table, name).unwrap_or_else(|| name.to_string());
+    doc[table][key.as_str()] = dep;
+
+    edit_manifest(ctx, &project, &doc.to_string(), &old, opts.update)?;
+
+    Ok(format!("added {} to the manifest", name))
+}
+
+/// Removes a dependency from the manifest of a project.
+pub fn remove(
+    ctx: &BuildCtx,
+    project: &Path,
+    name: &Name,
+    dev: bool,
+    update: bool,
+) -> Result<String> {
+    let (project, _) = find_manifest(project, false, None)?;
+    let (mut doc, old) = read_manifest_doc(&project)?;
+
+    let (table, other) = if dev {
+        ("dev_dependencies", "dependencies")
+    } else {
+        ("dependencies", "dev_dependencies")
+    };
+    let key = match dep_key(&doc, table, name) {
+        Some(key) => key,
+        None if dep_key(&doc, other, name).is_some() => bail!(
+            "{} isn't in [{}], but it is in [{}]{}",
+            name,
+            table,
+            other,
+            if dev {
+                "; leave out --dev"
+            } else {
+                "; pass --dev"
+            }
+        ),
+        None => bail!("{} isn't a dependency of this package", name),
+    };
+    if let Some(t) = doc[table].as_table_mut() {
+        t.remove(&key);
+    }
+
+    ctx.shell
+        .println(style("Removing").red(), name, Verbosity::Normal);
+
+    edit_manifest(ctx, &project, &doc.to_string(), &old, update)?;
+
+    Ok(format!("removed {} from the manifest", name))
+}
+
+/// Reads the manifest of a project as an editable document, along with its original contents.
+fn read_manifest_doc(project: &Path) -> Result<(toml_edit::Document, String)> {
+    let contents = fs::read_to_string(project.join("elba.toml"))
+        .context(format_err!("failed to read manifest file (elba.toml)"))?;
+    let doc = contents
+        .parse::<toml_edit::Document>()
+        .with_context(|e| format!("invalid manifest toml format: {}", e))?;
+
+    Ok((doc, contents))
+}
+
+/// The key a package has in one of the dependency tables of a manifest, if it's there.
+fn dep_key(doc: &toml_edit::Document, table: &str, name: &Name) -> Option<String> {
+    doc.as_table()
+        .get(table)?
+        .as_table()?
+        .iter()
+        .find(|(k, _)| Name::from_str(k).ok().as_ref() == Some(name))
+        .map(|(k, _)| k.to_owned())
+}
+
+/// Writes an edited manifest back out, making sure it's still valid. If the lockfile is to be
+/// updated and that fails, the manifest is put back the way it was.
+fn edit_manifest(
+    ctx: &BuildCtx,
+    project: &Path,
+    contents: &str,
+    old: &str,
+    update_lock: bool,
+) -> Result<()> {
+    let ws = Workspace::root_of(project)?;
+    inherit(
+        contents,
+        Some(project),
+        ws.as_ref().map(|(root, info)| (root.as_path(), info)),
+    )
+    .and_then(Manifest::from_value)
+    .context(format_err!("the edited manifest is invalid"))?;
+
+    let path = project.join("elba.toml");
+    fs::write(&path, contents).context(format_err!("failed to write manifest file (elba.toml)"))?;
+
+    if update_lock {
+        if let Err(e) = update(ctx, project, None) {
+            fs::write(&path, old)?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the dependencies of a project and writes its lockfile, without building anything.
+///
+/// If `check` is set, the lockfile is left alone instead, and it's an error if it isn't what
+/// resolving would write.
+pub fn lock(ctx: &BuildCtx, project: &Path, check: bool) -> Result<String> {
+    let mode = if check {
+        LockMode::Check
+    } else {
+        LockMode::Write
+    };
+
+    solve_local_with(ctx, project, 1, None, mode, None, |_, _, solve| {
+        if check {
+            Ok("elba.lock is up to date".to_string())
+        } else {
+            Ok(format!(
+                "locked {} dependencies",
+                solve.inner.node_count().saturating_sub(1)
+            ))
+        }
+    })
+}
+
+/// Drops every package from the lockfile of a project which nothing in the project (or the rest
+/// of its workspace) depends on anymore, without resolving its dependencies again.
+pub fn prune_lock(ctx: &BuildCtx, project: &Path) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let (lock_root, roots) = match Workspace::find(&project)? {
+        Some(ws) => {
+            let roots = ws
+                .members
+                .keys()
+                .map(|name| ws.member_id(name).unwrap())
+                .collect::<Vec<_>>();
+            (ws.root, roots)
+        }
+        None => {
+            let root = PackageId::new(
+                manifest.name().clone(),
+                DirectRes::Dir {
+                    path: normalize_path(&project),
+                }
+                .into(),
+            );
+            (project, vec![root])
+        }
+    };
+
+    let lf = read_lockfile_toml(&lock_root)
+        .with_context(|e| format_err!("couldn't read elba.lock: {}", e))?;
+    let (lf, dropped) = lf.prune(&roots);
+    if dropped.is_empty() {
+        return Ok("elba.lock has nothing to prune".to_string());
+    }
+
+    for sum in &dropped {
+        ctx.shell
+            .println(style("Removing").red(), sum, Verbosity::Normal);
+    }
+
+    let lf = lf.relative_to(&normalize_path(&lock_root));
+    write_atomic(&lock_root.join("elba.lock"), toml::to_string_pretty(&lf)?)
+        .context(format_err!("could not write to elba.lock"))?;
+
+    Ok(format!("pruned {} packages from elba.lock", dropped.len()))
+}
+
+/// Resolves a conflict between two versions of the lockfile of a project, such as when a merge
+/// changes it on both sides. The dependencies are resolved again against every version of the
+/// manifest involved, sticking to the versions which either side locked wherever they still fit,
+/// and the result is written to both the project's lockfile and `ours`.
+///
+/// This is meant to be used as a git merge driver, which hands over the two sides of the
+/// conflict in temporary files and expects the result to end up in the first one. Git doesn't
+/// tell a merge driver what the manifest looks like on either side, so we go looking for it in
+/// `HEAD` and whichever revision is being merged in.
+pub fn merge_lock(ctx: &BuildCtx, project: &Path, ours: &Path, theirs: &Path) -> Result<String> {
+    let (project, _) = find_manifest(project, false, Some(ctx.shell))?;
+    let ws = Workspace::root_of(&project)?;
+    let lock_root = match &ws {
+        Some((root, _)) => root.clone(),
+        None => project.clone(),
+    };
+    let lock_root = normalize_path(&lock_root);
+
+    let read = |path: &Path| -> Result<LockfileToml> {
+        let contents = fs::read_to_string(path)
+            .with_context(|e| format_err!("couldn't read {}: {}", path.display(), e))?;
+        Ok(LockfileToml::from_str(&contents)
+            .with_context(|e| format_err!("couldn't read {}: {}", path.display(), e))?
+            .resolve_from(&lock_root))
+    };
+    let lockfile = read(ours)?.union(read(theirs)?);
+
+    let manifest_at = |rev: &str| -> Result<Manifest> {
+        let contents = git::read_at(&project.join("elba.toml"), rev)?;
+        Manifest::from_value(inherit(
+            &contents,
+            Some(project),
+            ws.as_ref().map(|(root, info)| (root.as_path(), info)),
+        )?)
+    };
+    let manifests = MERGE_HEADS
+        .iter()
+        .filter_map(|rev| manifest_at(rev).ok())
+        .collect::<Vec<_>>();
+    if manifests.len() < 2 {
+        ctx.shell.println(
+            style("[warn]").yellow().bold(),
+            "Couldn't find the manifest on both sides of the merge; only elba.toml as it is now \
+             is resolved against",
+            Verbosity::Normal,
+        );
+    }
+
+    let merge = Merge {
+        lockfile,
+        manifests,
+    };
+    let msg = solve_local_with(
+        ctx,
+        &project,
+        1,
+        None,
+        LockMode::Write,
+        Some(&merge),
+        |_, _, _| Ok("merged elba.lock".to_string()),
+    )?;
+
+    let lock_path = lock_root.join("elba.lock");
+    if normalize_path(&env::current_dir()?.join(ours)) != lock_path {
+        fs::copy(&lock_path, ours)
+            .with_context(|e| format_err!("couldn't write {}: {}", ours.display(), e))?;
+    }
+
+    Ok(msg)
+}
+
+/// The revisions a merge driver might find the two sides of a conflict in: `HEAD`, and whichever
+/// of the others git is in the middle of merging, cherry-picking, reverting or rebasing.
+const MERGE_HEADS: &[&str] = &[
+    "HEAD",
+    "MERGE_HEAD",
+    "CHERRY_PICK_HEAD",
+    "REVERT_HEAD",
+    "REBASE_HEAD",
+];
+
+/// What a solve resolving a conflict between two lockfiles starts from, in place of the
+/// project's own lockfile.
+struct Merge {
+    /// Everything either side of the conflict locked.
+    lockfile: LockfileToml,
+    /// The manifests of the project on either side, whose dependencies all have to be satisfied
+    /// along with the ones in the project's manifest.
+    manifests: Vec<Manifest>,
+}
+
+/// Builds everything in a JobQueue, then records where the dependencies of the project were
+/// built in its import paths.
+fn exec_queue(
+    ctx: &BuildCtx,
+    project: &Path,
+    q: JobQueue,
+) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+    let dep_ibcs = q.dep_ibcs.clone();
+    let res = q.exec()?;
+    if let Err(e) = paths::record_ibcs(project, &dep_ibcs) {
+        warn_paths(ctx, e);
+    }
+
+    Ok(res)
+}
+
+fn warn_paths(ctx: &BuildCtx, e: failure::Error) {
+    ctx.shell.println(
+        style("[warn]").yellow().bold(),
+        format!("Couldn't update {}: {}", PATHS_FILE, e),
+        Verbosity::Normal,
+    );
+}
+
+/// What to do with the lockfile once the dependencies of a project have been resolved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LockMode {
+    Write,
+    /// Make sure the lockfile already says what we'd write, without touching it.
+    Check,
+    /// Leave the lockfile alone, whatever it says.
+    DryRun,
+}
+
+/// Retrieves the packages of a solve for building `root`.
+///
+/// When some of them have to be downloaded, the dependencies whose sources are all there get
+/// built in the meantime (see `job::prebuild`), rather than everything waiting on the slowest
+/// download. The root itself is left for the JobQueue, which finds the rest already built.
+fn retrieve_and_prebuild(
+    ctx: &BuildCtx,
+    mut retriever: Retriever,
+    solve: &Graph<Summary>,
+    root: &Targets,
+    bctx: &BuildContext,
+) -> Result<Graph<Source>> {
+    let pending = retriever
+        .pending(solve)
+        .context(format_err!("package retrieval failed"))?;
+
+    // We drop the Retriever because we want to release our lock on the Indices as soon as we
+    // can to avoid stopping other instances of elba from downloading and resolving (even
+    // though we don't even need the Retriever anymore).
+    drop(retriever);
+
+    let pipeline = pending.downloads() > 0;
+    let (sources, prebuilt) = pending
+        .retrieve(solve, ctx.threads, |incoming| {
+            if pipeline {
+                prebuild(solve, incoming, root, bctx, &ctx.logger, ctx.shell)
+            } else {
+                Ok(())
+            }
+        })
+        .context(format_err!("package retrieval failed"))?;
+    prebuilt?;
+
+    Ok(sources)
+}
+
+pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
+    ctx: &BuildCtx,
+    project: &Path,
+    total: u8,
+    ignore: Option<&[Spec]>,
+    f: F,
+) -> Result<String> {
+    solve_local_with(ctx, project, total, ignore, LockMode::Write, None, f)
+}
+
+fn solve_local_with<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
+    ctx: &BuildCtx,
+    project: &Path,
+    total: u8,
+    ignore: Option<&[Spec]>,
+    mode: LockMode,
+    merge: Option<&Merge>,
+    mut f: F,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, true, Some(ctx.shell))?;
+    let ws = Workspace::find(&project)?;
+    let lock_root = ws
+        .as_ref()
+        .map(|x| x.root.clone())
+        .unwrap_or_else(|| project.clone());
+
+    let root = {
+        let cur = normalize_path(&project);
+        let pid = PackageId::new(manifest.name().clone(), DirectRes::Dir { path: cur }.into());
+        Summary::new(pid, manifest.version().clone())
+    };
+
+    let read_lock = || match merge {
+        Some(merge) => Ok(merge.lockfile.clone()),
+        None => read_lockfile_toml(&lock_root),
+    };
+    let op = || project_lock(read_lock()?.into(), &root.id, true);
+
+    let lock = match ignore {
+        None => {
+            if let Ok(solve) = op() {
+                solve
+            } else {
+                Graph::default()
+            }
+        }
+        Some(i) => {
+            if i.is_empty() {
+                Graph::default()
+            } else if let Ok(mut solve) = op() {
+                for spec in i {
                     let mut chosen: Option<Summary> = None;
                     let mut dfs = Dfs::new(&solve.inner, NodeIndex::new(0));
                     while let Some(ix) = dfs.next(&solve.inner) {
@@ -787,16 +1985,27 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         }
     };
 
-    let root = {
-        let cur = project.clone();
-        let pid = PackageId::new(manifest.name().clone(), DirectRes::Dir { path: cur }.into());
-        Summary::new(pid, manifest.version().clone())
-    };
+    let mut deps = manifest.deps(&ctx.indices, &root.id, true)?;
+    for other in merge.iter().flat_map(|x| &x.manifests) {
+        for (pkg, con) in other.deps(&ctx.indices, &root.id, true)? {
+            let con = match deps.get(&pkg) {
+                Some(old) => old.intersection(&con),
+                None => con,
+            };
+            deps.insert(pkg, con);
+        }
+    }
+    let deps = deps.into_iter().collect::<Vec<_>>();
 
-    let deps = manifest
-        .deps(&ctx.indices, &root.id, true)?
-        .into_iter()
-        .collect::<Vec<_>>();
+    let cache = ctx.cache()?;
+    let (deps, picked) = pick_indices(ctx, &cache, &manifest, deps, &lock);
+    let deps = match &ws {
+        Some(ws) => ws.redirect(deps),
+        None => deps,
+    };
+    // If we had to look through the indices to pick between them, they're as fresh as they're
+    // going to get.
+    let refresh = if picked { Refresh::Never } else { ctx.refresh };
 
     let dreses = deps
         .iter()
@@ -809,56 +2018,307 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         })
         .collect::<Vec<_>>();
 
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
-
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
         "Resolving dependencies...",
         Verbosity::Quiet,
     );
 
+    let old_lock = read_lock().ok();
+    let old_checksums = old_lock
+        .as_ref()
+        .map(|lf| lf.checksums())
+        .unwrap_or_default();
+    let old_sources = old_lock.as_ref().map(|lf| lf.sources()).unwrap_or_default();
+
+    let root_id = root.id.clone();
     let mut retriever = Retriever::new(
         &cache.logger,
         &cache,
         root,
         deps,
         Left(dreses),
-        lock,
+        lock.clone(),
         &ctx.indices,
         ctx.shell,
         ctx.offline,
+        refresh,
     );
+    let compiler = Compiler::new(&ctx.compiler).ok();
+    retriever.compiler = compiler.as_ref().and_then(|x| x.semver());
+    retriever.workspace = ws.clone();
+    // Packages we're updating aren't held to their old checksums.
+    retriever.locked_checksums = old_checksums
+        .iter()
+        .filter(|(sum, _)| lock.find_id(sum).is_some())
+        .map(|(sum, checksum)| (sum.clone(), checksum.clone()))
+        .collect();
+    retriever.locked_sources = old_sources
+        .iter()
+        .filter(|(sum, _)| lock.find_id(sum).is_some())
+        .map(|(sum, source)| (sum.clone(), source.clone()))
+        .collect();
     let solver = Resolver::new(&retriever.logger.clone(), &mut retriever);
     let solve = solver.solve()?;
-    ctx.shell.println(
-        style("Writing").dim(),
-        "lockfile at elba.lock",
-        Verbosity::Verbose,
-    );
-
-    let mut lockfile = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(project.join("elba.lock"))
-        .context(format_err!("could not open elba.lock for writing"))?;
+    let checksums = retriever.checksums(&solve)?;
+    let sources = retriever.sources(&solve);
 
     let lf_contents: LockfileToml = solve.clone().into();
+    let lf_contents = match &ws {
+        Some(ws) => with_siblings(lf_contents, ws, &root_id, old_lock.clone()),
+        None => lf_contents,
+    };
+    // The packages of the rest of the workspace keep the checksums and sources they already had.
+    let checksums: IndexMap<_, _> = old_checksums
+        .into_iter()
+        .filter(|(sum, _)| solve.find_id(sum).is_none())
+        .chain(checksums)
+        .collect();
+    let sources: IndexMap<_, _> = old_sources
+        .into_iter()
+        .filter(|(sum, _)| solve.find_id(sum).is_none())
+        .chain(sources)
+        .collect();
+    let lf_contents = lf_contents
+        .with_checksums(&checksums)
+        .with_sources(&sources);
+    // The lockfile only ever has what the new solve does, so anything in the old one which
+    // nothing depends on anymore is dropped from it.
+    let dropped = old_lock
+        .map(|old| {
+            old.packages
+                .into_iter()
+                .map(|pkg| pkg.sum)
+                .filter(|old| {
+                    !lf_contents
+                        .packages
+                        .iter()
+                        .any(|new| new.sum.id.lowkey_eq(&old.id))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let lf_contents = lf_contents.relative_to(&normalize_path(&lock_root));
+    check_policy(ctx, &lock_root, &lf_contents)?;
     let lf_contents = toml::to_string_pretty(&lf_contents)?;
 
-    lockfile
-        .write_all(lf_contents.as_bytes())
-        .context(format_err!("could not write to elba.lock"))?;
+    match mode {
+        LockMode::Write => {
+            if !dropped.is_empty() {
+                ctx.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "Dropping packages which nothing depends on anymore from elba.lock: {}",
+                        dropped.iter().join(", ")
+                    ),
+                    Verbosity::Normal,
+                );
+            }
+
+            ctx.shell.println(
+                style("Writing").dim(),
+                "lockfile at elba.lock",
+                Verbosity::Verbose,
+            );
+
+            write_atomic(&lock_root.join("elba.lock"), &lf_contents)
+                .context(format_err!("could not write to elba.lock"))?;
+        }
+        LockMode::Check => {
+            let old = fs::read_to_string(lock_root.join("elba.lock")).map_err(|_| {
+                format_err!("there's no elba.lock to check; run `elba lock` to make one")
+            })?;
+            if old != lf_contents {
+                bail!(Error::LockfileOutdated)
+            }
+        }
+        LockMode::DryRun => {}
+    }
+
+    // Editors can set themselves up with the new dependencies right away, without waiting for a
+    // build.
+    if mode != LockMode::DryRun {
+        if let Err(e) = paths::write_paths(ctx, &project, &manifest, compiler.as_ref(), &solve) {
+            warn_paths(ctx, e);
+        }
+    }
 
     f(&cache, retriever, solve)
 }
 
+/// Picks the index of every dependency which doesn't name one, going through the indices in the
+/// order they're listed in the config and taking the first one with a version that fits. If more
+/// than one index fits, we say so, since the package the user gets might not be the one they
+/// had in mind. Dependencies which are in the lockfile stay with the index they were locked to.
+///
+/// Returns the dependencies along with whether the indices had to be loaded to pick them.
+fn pick_indices(
+    ctx: &BuildCtx,
+    cache: &Cache,
+    manifest: &Manifest,
+    deps: Vec<(PackageId, Constraint)>,
+    lock: &Graph<Summary>,
+) -> (Vec<(PackageId, Constraint)>, bool) {
+    let bare = |pkg: &PackageId| {
+        manifest
+            .dep_req(pkg.name(), true)
+            .map(|(_, dep)| dep.is_bare())
+            .unwrap_or(false)
+    };
+
+    if ctx.indices.len() < 2 || !deps.iter().any(|(p, _)| bare(p)) {
+        return (deps, false);
+    }
+
+    let reses = ctx
+        .indices
+        .values()
+        .map(|x| x.res.clone())
+        .collect::<Vec<_>>();
+    let indices = cache.get_indices(&reses, ctx.refresh, ctx.offline);
+
+    let deps = deps
+        .into_iter()
+        .map(|(pkg, con)| {
+            if !bare(&pkg) {
+                return (pkg, con);
+            }
+
+            let locked = lock.find_by(|sum| {
+                sum.name() == pkg.name()
+                    && match sum.resolution() {
+                        Resolution::Index(ir) => ctx.indices.values().any(|x| x == ir),
+                        _ => false,
+                    }
+            });
+            if let Some(locked) = locked {
+                return (locked.id().clone(), con);
+            }
+
+            let found = ctx
+                .indices
+                .iter()
+                .filter(|(_, ir)| {
+                    indices
+                        .indices
+                        .get(*ir)
+                        .and_then(|ix| ix.entries(pkg.name()).ok())
+                        .map(|es| es.values().any(|e| !e.yanked && con.satisfies(&e.version)))
+                        .unwrap_or(false)
+                })
+                .collect::<Vec<_>>();
+
+            if found.len() > 1 {
+                ctx.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "{} is in more than one index ({}); using {} since it comes first. \
+                         Set the index of the dependency to pick another one.",
+                        pkg.name(),
+                        found.iter().map(|x| x.0).join(", "),
+                        found[0].0
+                    ),
+                    Verbosity::Quiet,
+                );
+            }
+
+            match found.first() {
+                Some((_, ir)) => (
+                    PackageId::new(pkg.name().clone(), (*ir).clone().into()),
+                    con,
+                ),
+                // If it isn't anywhere, the resolver can complain about it in the default index.
+                None => (pkg, con),
+            }
+        })
+        .collect();
+
+    (deps, true)
+}
+
+/// Reads the lockfile of a project, resolving any relative paths in it against the project root.
+fn read_lockfile(project: &Path) -> Result<Graph<Summary>> {
+    Ok(read_lockfile_toml(project)?.into())
+}
+
+/// Like `read_lockfile`, but keeps everything else the lockfile records about its packages.
+fn read_lockfile_toml(project: &Path) -> Result<LockfileToml> {
+    let mut f = fs::File::open(&project.join("elba.lock"))?;
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+    let toml = LockfileToml::from_str(&contents)?;
+
+    Ok(toml.resolve_from(&normalize_path(project)))
+}
+
+/// Reads the lockfile of a project, which is shared with the rest of its workspace if it's in one.
+///
+/// Only the part of the lockfile which the project depends on is given back, with the project at
+/// its root; anything else in it is stale. If the project hasn't been locked yet, `or_all` gives
+/// back the whole lockfile instead, so that in a workspace the project picks the same versions as
+/// its siblings where it can.
+fn read_project_lock(lock_root: &Path, root: &PackageId, or_all: bool) -> Result<Graph<Summary>> {
+    project_lock(read_lockfile(lock_root)?, root, or_all)
+}
+
+/// Like `read_project_lock`, for a lockfile which has already been read.
+fn project_lock(lock: Graph<Summary>, root: &PackageId, or_all: bool) -> Result<Graph<Summary>> {
+    match lock
+        .inner
+        .node_indices()
+        .find(|&ix| &lock.inner[ix].id == root)
+    {
+        Some(ix) => Ok(LockfileToml::from_tree(&lock, ix).into()),
+        None if or_all => Ok(lock),
+        None => bail!("{} hasn't been locked yet", root),
+    }
+}
+
+/// Adds the parts of a workspace's lockfile which belong to the other packages in it to the new
+/// lockfile of one of its packages.
+fn with_siblings(
+    mut lf: LockfileToml,
+    ws: &Workspace,
+    root: &PackageId,
+    old: Option<LockfileToml>,
+) -> LockfileToml {
+    let old: Graph<Summary> = match old {
+        Some(old) => old.into(),
+        None => return lf,
+    };
+
+    for name in ws.members.keys() {
+        let id = ws.member_id(name).unwrap();
+        if &id == root {
+            continue;
+        }
+        if let Some(ix) = old.inner.node_indices().find(|&ix| old.inner[ix].id == id) {
+            lf.packages
+                .extend(LockfileToml::from_tree(&old, ix).packages);
+        }
+    }
+
+    lf
+}
+
+/// The directory a project's build outputs go in, which is shared with the rest of its workspace
+/// if it's in one.
+pub fn target_dir(project: &Path) -> Result<PathBuf> {
+    let root = match Workspace::find(project)? {
+        Some(ws) => ws.root,
+        None => project.to_path_buf(),
+    };
+
+    Ok(root.join("target"))
+}
+
 pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
     ctx: &BuildCtx,
     name: &Spec,
     total: u8,
     mut f: F,
 ) -> Result<String> {
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let cache = ctx.cache()?;
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
         "Resolving dependencies...",
@@ -871,7 +2331,7 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
         .cloned()
         .map(|x| x.res)
         .collect::<Vec<_>>();
-    let mut indices = cache.get_indices(&indices, true, ctx.offline);
+    let mut indices = cache.get_indices(&indices, ctx.refresh, ctx.offline);
     ctx.shell.println(
         style("Cached").dim(),
         format!("indices at {}", cache.layout.indices.display()),
@@ -900,28 +2360,57 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
         &ctx.indices,
         ctx.shell,
         ctx.offline,
+        ctx.refresh,
     );
+    retriever.compiler = Compiler::new(&ctx.compiler).ok().and_then(|x| x.semver());
     let solve = Resolver::new(&retriever.logger.clone(), &mut retriever).solve()?;
+    // Nothing's written for packages installed from an index, but the policy still sees what
+    // would have been locked. There's no project for its hook to run in, so it runs wherever the
+    // global configuration is.
+    let lf_contents: LockfileToml = solve.clone().into();
+    let lf_contents = lf_contents.with_sources(&retriever.sources(&solve));
+    let hook_dir = Config::global_path()
+        .parent()
+        .filter(|x| x.is_dir())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(env::temp_dir);
+    check_policy(ctx, &hook_dir, &lf_contents)?;
 
     f(&cache, retriever, solve)
 }
 
+/// The root of the project a directory is in: the closest of its ancestors (including itself)
+/// with a manifest in it.
+pub fn find_manifest_root(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|p| p.join("elba.toml").exists())
+}
+
+/// The directory of the project a `--manifest-path` points to. Relative paths are resolved against
+/// `cwd`.
+pub fn manifest_dir(cwd: &Path, manifest_path: &Path) -> Result<PathBuf> {
+    let path = normalize_path(&cwd.join(manifest_path));
+    if path.file_name().map(|x| x != "elba.toml").unwrap_or(true) {
+        bail!(
+            "the manifest path {} has to point to an elba.toml file",
+            manifest_path.display()
+        )
+    }
+    if !path.is_file() {
+        bail!("the manifest {} doesn't exist", path.display())
+    }
+
+    Ok(path.parent().unwrap().to_path_buf())
+}
+
 pub fn find_manifest(
     path: &Path,
     allow_ipkg: bool,
     shell: Option<Shell>,
 ) -> Result<(PathBuf, Manifest)> {
-    let root = path.ancestors().find(|p| p.join("elba.toml").exists());
+    let root = find_manifest_root(path);
     match root {
         Some(root) => {
-            let toml_path = root.join("elba.toml");
-            let mut file = fs::File::open(&toml_path).context(format_err!(
-                "failed to read manifest file ({})",
-                toml_path.display()
-            ))?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            let manifest = Manifest::from_str(&contents)?;
+            let manifest = read_manifest(root)?;
             Ok((root.to_path_buf(), manifest))
         }
         None if allow_ipkg => {
@@ -962,8 +2451,6 @@ pub fn find_manifest(
 
             Ok((path.to_path_buf(), manifest))
         }
-        None => Err(format_err!(
-            "no manifest file (elba.toml) exists in any parent directory"
-        )),
+        None => Err(Error::ManifestNotFound)?,
     }
 }
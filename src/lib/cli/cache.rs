@@ -0,0 +1,197 @@
+//! Maintenance of the global cache.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use console::style;
+
+use crate::{
+    retrieve::cache::{
+        content_hash, remove_tmp, Cache, BUILD_CHECKSUM, CONTENT_HASH_VERSION, SESSIONS,
+    },
+    util::{
+        error::Result,
+        fs::is_tmp_name,
+        lock::{DirLock, LockKind},
+        shell::{Shell, Verbosity},
+    },
+};
+
+/// The results of checking the cache for corruption.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Verification {
+    /// The number of entries whose contents were checked.
+    pub checked: u32,
+    /// The number of entries which were corrupt or dangling.
+    pub bad: u32,
+    /// The number of bad entries which were evicted from the cache.
+    pub evicted: u32,
+}
+
+/// The results of cleaning out the cache's temporary build directories.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TmpCleanup {
+    /// The number of directories which were removed.
+    pub removed: u32,
+    /// The number of directories which were left alone because they're in use.
+    pub skipped: u32,
+}
+
+/// The directories in `tmp` which aren't temporary build directories. These belong to running
+/// scripts and verifying packages, which lock things further down instead.
+const TMP_RESERVED: &[&str] = &[SESSIONS, "scripts", "verify"];
+
+/// Removes every temporary build directory which isn't in use, along with the session manifests
+/// of elba processes which are no longer running.
+pub fn clean_tmp(cache: &Cache) -> Result<TmpCleanup> {
+    let mut res = TmpCleanup::default();
+    let shell = cache.shell;
+
+    res.removed += cache.reap_sessions()?;
+
+    for (dir, name) in entries(&cache.layout.tmp)? {
+        if TMP_RESERVED.contains(&name.as_str()) {
+            continue;
+        }
+
+        if remove_tmp(&cache.layout.tmp, &name, shell)? {
+            res.removed += 1;
+        } else {
+            res.skipped += 1;
+            shell.println(
+                style("Skipping").dim(),
+                format!("{} (in use)", dir.display()),
+                Verbosity::Normal,
+            );
+        }
+    }
+
+    Ok(res)
+}
+
+/// Rehashes everything in the store and every stored build, comparing them against the hashes we
+/// recorded for them. If `fix` is set, bad entries are evicted so that they get retrieved or
+/// rebuilt the next time they're needed.
+pub fn verify(cache: &Cache, fix: bool) -> Result<Verification> {
+    let mut res = Verification::default();
+    let shell = cache.shell;
+
+    // Whatever was stored by older versions of elba was hashed differently, so there's nothing
+    // we can check it against.
+    for (dir, name) in entries(&cache.layout.store)? {
+        if name != CONTENT_HASH_VERSION && !is_tmp_name(&name) {
+            shell.println(
+                style("Skipping").dim(),
+                format!("{} (stored by an older version of elba)", dir.display()),
+                Verbosity::Verbose,
+            );
+        }
+    }
+
+    // The directory names in the store are their content hashes.
+    for (dir, name) in entries(&cache.layout.store.join(CONTENT_HASH_VERSION))? {
+        // Leftovers from an interrupted copy into the store are never referred to by anything.
+        if is_tmp_name(&name) {
+            if fix {
+                remove_dir_all::remove_dir_all(&dir)?;
+            }
+            continue;
+        }
+
+        let lock = cache.lock(&dir, LockKind::Source)?;
+        res.checked += 1;
+        shell.println(style("Verifying").dim(), dir.display(), Verbosity::Verbose);
+        if content_hash(lock.path())? != name {
+            report(shell, &mut res, lock, fix, "contents don't match their hash")?;
+        }
+    }
+
+    // Evicting something from the store leaves any links to it dangling, so we check those after.
+    for (dir, _) in entries(&cache.layout.src)? {
+        if cache.stored_path(&dir) == Some(None) {
+            let lock = cache.lock(&dir, LockKind::Source)?;
+            res.checked += 1;
+            report(shell, &mut res, lock, fix, "links to missing contents")?;
+        }
+    }
+
+    for (dir, name) in entries(&cache.layout.build)? {
+        // The same goes for builds.
+        if is_tmp_name(&name) {
+            if fix {
+                remove_dir_all::remove_dir_all(&dir)?;
+            }
+            continue;
+        }
+
+        let lock = cache.lock(&dir, LockKind::Build)?;
+        let sum = fs::read_to_string(lock.path().join(BUILD_CHECKSUM)).unwrap_or_default();
+        let mut sum = sum.trim().splitn(2, ':');
+        let sum = match (sum.next(), sum.next()) {
+            (Some(version), Some(sum)) if version == CONTENT_HASH_VERSION => sum.to_owned(),
+            _ => {
+                // Builds stored by older versions of elba either didn't record a checksum, or
+                // recorded one we can't check.
+                shell.println(
+                    style("Skipping").dim(),
+                    format!("{} (no usable checksum)", dir.display()),
+                    Verbosity::Verbose,
+                );
+                continue;
+            }
+        };
+        res.checked += 1;
+        shell.println(style("Verifying").dim(), dir.display(), Verbosity::Verbose);
+        if content_hash(lock.path())? != sum {
+            report(shell, &mut res, lock, fix, "build doesn't match its checksum")?;
+        }
+    }
+
+    Ok(res)
+}
+
+fn report(
+    shell: Shell,
+    res: &mut Verification,
+    lock: DirLock,
+    fix: bool,
+    reason: &str,
+) -> Result<()> {
+    res.bad += 1;
+    shell.println(
+        style("Corrupt").red(),
+        format!("{}: {}", lock.path().display(), reason),
+        Verbosity::Quiet,
+    );
+
+    if fix {
+        remove_dir_all::remove_dir_all(lock.path())?;
+        res.evicted += 1;
+        shell.println(
+            style("Evicted").yellow(),
+            lock.path().display(),
+            Verbosity::Normal,
+        );
+    }
+
+    Ok(())
+}
+
+fn entries(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut res = vec![];
+
+    if !dir.exists() {
+        return Ok(res);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            res.push((entry.path(), entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+
+    Ok(res)
+}
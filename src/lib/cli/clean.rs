@@ -0,0 +1,78 @@
+//! Cleaning the outputs of local builds out of the target directory of a project.
+//!
+//! The target directory is laid out like an `OutputLayout`: typechecked libraries go in `lib`,
+//! binaries in `bin`, the codegen outputs of libraries in `artifacts`, documentation in `docs`
+//! and scratch files in `build`. Parts of it can be cleaned on their own, leaving the rest alone.
+
+use std::{fs, path::Path};
+
+use console::style;
+
+use super::build::{find_manifest_root, target_dir, BuildCtx};
+use crate::{
+    retrieve::cache::OutputLayout,
+    util::{clear_dir, error::Result, lock::LockKind, shell::Verbosity},
+};
+
+/// Which parts of a target directory to clean. If none of them are picked, all of it is cleaned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanOpts {
+    /// Typechecked libraries (the `.ibc` files in `lib`).
+    pub lib: bool,
+    /// Binaries and test binaries (`bin`).
+    pub bins: bool,
+    /// Codegen outputs of libraries (`artifacts`).
+    pub artifacts: bool,
+    /// Documentation (`docs`).
+    pub doc: bool,
+}
+
+impl CleanOpts {
+    fn is_everything(&self) -> bool {
+        !(self.lib || self.bins || self.artifacts || self.doc)
+    }
+}
+
+/// Cleans the target directory of a project, or parts of it.
+pub fn clean(ctx: &BuildCtx, project: &Path, opts: &CleanOpts) -> Result<String> {
+    let project = find_manifest_root(project).unwrap_or(project);
+    let target = target_dir(project)?;
+    if !target.exists() {
+        return Ok("nothing to clean".to_string());
+    }
+
+    // Holding the lock keeps a build from writing to the directory while we're clearing it.
+    let layout = OutputLayout::new(ctx.lock(&target, LockKind::Target)?)?;
+
+    if opts.is_everything() {
+        clear_dir(&layout.root)?;
+        return Ok(format!("cleaned {}", target.display()));
+    }
+
+    let parts = [
+        (opts.lib, &layout.lib),
+        (opts.bins, &layout.bin),
+        (opts.artifacts, &layout.artifacts),
+        (opts.doc, &layout.docs),
+    ];
+    for (_, dir) in parts.iter().filter(|(picked, _)| *picked) {
+        clear_dir(dir)?;
+        ctx.shell
+            .println(style("Removed").dim(), dir.display(), Verbosity::Verbose);
+    }
+    clear_dir(&layout.build)?;
+
+    // The hash of the last build says that all of its outputs are in the target directory, which
+    // isn't true anymore, so the next build has to put back whatever was cleaned. Dependencies
+    // stay built in the global cache, so only the project itself gets built again.
+    let hash = layout.root.join("hash");
+    if hash.exists() {
+        fs::remove_file(&hash)?;
+    }
+
+    Ok(format!(
+        "cleaned {} directories in {}",
+        parts.iter().filter(|(picked, _)| *picked).count(),
+        target.display()
+    ))
+}
@@ -0,0 +1,86 @@
+//! Reading and writing elba's configuration from the command line.
+
+use std::{fs, path::Path};
+
+use failure::{bail, format_err, ResultExt};
+
+use crate::util::{config::Config, error::Result};
+
+/// Every key of the configuration, with the value it ends up with once all of the configuration
+/// files and environment variables have been merged.
+pub fn list(c: &Config) -> Result<toml::Value> {
+    Ok(toml::Value::try_from(c)?)
+}
+
+/// Looks up a dotted key, like `term.verbosity`, in the configuration.
+pub fn get(c: &Config, key: &str) -> Result<toml::Value> {
+    let mut value = list(c)?;
+    for part in key.split('.') {
+        let next = match value {
+            toml::Value::Table(mut t) => t.remove(part),
+            toml::Value::Array(mut a) => match part.parse::<usize>() {
+                Ok(i) if i < a.len() => Some(a.remove(i)),
+                _ => None,
+            },
+            _ => None,
+        };
+        value = next.ok_or_else(|| format_err!("the configuration has no key `{}`", key))?;
+    }
+
+    Ok(value)
+}
+
+/// Sets a dotted key in a configuration file, keeping the rest of the file (comments and all) the
+/// way it was. The value is read as TOML if it can be, so that `4`, `true` and `["a", "b"]` all
+/// work, and as a string otherwise.
+pub fn set(path: &Path, key: &str, value: &str) -> Result<()> {
+    let parts = key.split('.').collect::<Vec<_>>();
+    if parts.iter().any(|x| x.is_empty()) {
+        bail!("`{}` isn't a valid key", key)
+    }
+
+    let contents = if path.exists() {
+        fs::read_to_string(path)
+            .with_context(|e| format_err!("couldn't read {}: {}", path.display(), e))?
+    } else {
+        String::new()
+    };
+    let mut doc = contents
+        .parse::<toml_edit::Document>()
+        .with_context(|e| format_err!("invalid configuration file {}: {}", path.display(), e))?;
+
+    let parsed = match format!("v = {}", value).parse::<toml_edit::Document>() {
+        Ok(parsed) => parsed["v"].clone(),
+        Err(_) => toml_edit::value(value),
+    };
+
+    let (last, tables) = parts.split_last().unwrap();
+    let mut table = doc.as_table_mut();
+    for (i, part) in tables.iter().enumerate() {
+        let item = table.entry(part);
+        if item.is_none() {
+            *item = toml_edit::table();
+        }
+        table = item.as_table_mut().ok_or_else(|| {
+            format_err!(
+                "`{}` isn't a table in {}",
+                parts[..=i].join("."),
+                path.display()
+            )
+        })?;
+    }
+    *table.entry(last) = parsed;
+
+    // Make sure that elba can still make sense of the file before writing it.
+    let contents = doc.to_string();
+    toml::from_str::<Config>(&contents)
+        .with_context(|e| format_err!("`{}` can't be set to `{}`: {}", key, value, e))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)
+        .with_context(|e| format_err!("couldn't write {}: {}", path.display(), e))?;
+
+    Ok(())
+}
@@ -0,0 +1,576 @@
+//! Exporting the build of a project as a shell script, for building it without elba.
+//!
+//! The script runs the compiler on every package the same way elba's build would, against copies
+//! of their sources which are vendored next to it. Distribution packagers can ship the two
+//! together and build the project with nothing but a shell and the compiler: no network access,
+//! and no elba.
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use itertools::{
+    Either::{self, Left, Right},
+    Itertools,
+};
+use petgraph::graph::NodeIndex;
+use semver::Version;
+
+use super::{
+    build::{build_targets, find_manifest, solve_local, BuildCtx},
+    index::package_files,
+    self_update::VERSION,
+};
+use crate::{
+    build::{context::Compiler, job::without_test_deps, Target},
+    package::Name,
+    retrieve::cache::Source,
+    util::{
+        config::Backend, error::Result, fs::write_atomic, mod_path, normalize_path,
+        shell::Verbosity,
+    },
+};
+
+/// The library target of a package in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExportLib {
+    /// The directory its modules are in, relative to the package.
+    path: PathBuf,
+    /// The files of its modules, relative to `path`.
+    mods: Vec<PathBuf>,
+    opts: Vec<String>,
+}
+
+/// A bin target of the root package in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExportBin {
+    name: String,
+    /// The directory its modules are in, relative to the package.
+    path: PathBuf,
+    /// The file of its main module relative to `path`, or the module and function to make a
+    /// main module out of.
+    main: Either<PathBuf, (String, String)>,
+    /// Whether it's in the same directory as the lib target, whose build it's compiled next to.
+    with_lib: bool,
+    opts: Vec<String>,
+    output: String,
+}
+
+/// A package in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExportPkg {
+    title: String,
+    /// The name of the package's directory in `vendor`, which its build and output directories
+    /// are named after too.
+    dir: String,
+    lib: Option<ExportLib>,
+    prebuild: Option<String>,
+    /// The directories of the packages it depends on.
+    deps: Vec<String>,
+}
+
+/// How the script runs the codegen backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExportBackend {
+    name: String,
+    portable: bool,
+    opts: Vec<String>,
+    /// Native libraries to link against by name.
+    libs: Vec<String>,
+    /// Native libraries to ask pkg-config about when the script runs.
+    pkg_config: Vec<String>,
+}
+
+/// Quotes a string for a POSIX shell, if it needs to be. The script is run by `sh` wherever it
+/// was exported, so it's always quoted the Unix way.
+fn sh_quote(s: &str) -> String {
+    if s.is_empty() {
+        return "''".to_string();
+    }
+    shell_escape::unix::escape(Cow::from(s)).into_owned()
+}
+
+fn sh_path(path: &Path) -> String {
+    sh_quote(&path.to_string_lossy().replace('\\', "/"))
+}
+
+/// Escapes a string to go inside double quotes in a POSIX shell, which `shell_escape` can't do.
+fn sh_dquote(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\"$`\\".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+fn vendor_dir(name: &Name, version: &Version) -> String {
+    format!("{}_{}-{}", name.group(), name.name(), version)
+}
+
+/// Copies the files of a package which would be published into `to`, leaving out anything under
+/// `skip`.
+fn vendor_source(source: &Source, to: &Path, skip: &[&Path]) -> Result<()> {
+    if to.exists() {
+        fs::remove_dir_all(to)
+            .with_context(|e| format_err!("couldn't clear {}: {}", to.display(), e))?;
+    }
+    for file in package_files(source.path(), source.meta())? {
+        let from = source.path().join(&file);
+        if skip.iter().any(|x| from.starts_with(x)) {
+            continue;
+        }
+        let dest = to.join(&file);
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::copy(&from, &dest)
+            .with_context(|e| format_err!("couldn't vendor {}: {}", from.display(), e))?;
+    }
+
+    Ok(())
+}
+
+fn export_lib(source: &Source, opts: &[String]) -> Result<Option<ExportLib>> {
+    let lib = match &source.meta().targets.lib {
+        Some(lib) => lib,
+        None => return Ok(None),
+    };
+
+    let src_path = source.path().join(&lib.path.0);
+    let mods = source
+        .meta()
+        .lib_mods(source.path())?
+        .iter()
+        .map(|mod_name| {
+            let path = mod_path(mod_name);
+            if src_path.join(&path).with_extension("idr").exists() {
+                Ok(path.with_extension("idr"))
+            } else if src_path.join(&path).with_extension("lidr").exists() {
+                Ok(path.with_extension("lidr"))
+            } else {
+                Err(format_err!(
+                    "module {} isn't a subpath and doesn't exist under path {}",
+                    mod_name,
+                    src_path.display()
+                ))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(ExportLib {
+        path: lib.path.0.clone(),
+        mods,
+        opts: lib.idris_opts.iter().chain(opts).cloned().collect(),
+    }))
+}
+
+fn export_bin(source: &Source, ix: usize, opts: &[String], backend: &Backend) -> Result<ExportBin> {
+    let bin = &source.meta().targets.bin[ix];
+    let (src_path, target_path) = bin.resolve_bin(source.path()).ok_or_else(|| {
+        format_err!(
+            "module {} isn't a subpath and doesn't exist under path {}",
+            bin.main,
+            bin.path.0.display()
+        )
+    })?;
+    let path = src_path
+        .strip_prefix(source.path())
+        .map(|x| x.to_path_buf())
+        .map_err(|_| format_err!("bin target {} isn't inside its package", bin.name()))?;
+
+    // As in the build, a main module which isn't an Idris file is a module and a function in it.
+    let main = match target_path.extension() {
+        Some(ext) if ext != OsStr::new("idr") && ext != OsStr::new("lidr") => {
+            let module = target_path
+                .with_extension("")
+                .to_string_lossy()
+                .replace("/", ".")
+                .replace("\\", ".");
+            Right((module, ext.to_string_lossy().to_string()))
+        }
+        _ => Left(target_path),
+    };
+    let with_lib = source
+        .meta()
+        .targets
+        .lib
+        .as_ref()
+        .map(|lib| lib.path == bin.path)
+        .unwrap_or(false);
+
+    Ok(ExportBin {
+        name: bin.name(),
+        path,
+        main,
+        with_lib,
+        opts: bin.idris_opts.iter().chain(opts).cloned().collect(),
+        output: bin
+            .output_file(backend.extension.as_ref().map(|x| x.as_str()))
+            .to_string_lossy()
+            .to_string(),
+    })
+}
+
+fn render_compile(res: &mut String, deps: &[&str], opts: &[String], module: &Path) {
+    write!(res, "(cd \"$build\" && \"$IDRIS\" --check").unwrap();
+    for dep in deps {
+        write!(res, " -i \"$OUT\"/lib/{}", dep).unwrap();
+    }
+    for opt in opts {
+        write!(res, " {}", sh_quote(opt)).unwrap();
+    }
+    writeln!(res, " {})", sh_path(module)).unwrap();
+}
+
+fn render_prep(res: &mut String, build: &str, src: &str, prebuild: &Option<String>) {
+    writeln!(res, "build=\"$OUT\"/build/{}", build).unwrap();
+    res.push_str("rm -rf \"$build\"\nmkdir -p \"$build\"\n");
+    writeln!(res, "cp -R \"$VENDOR\"/{}/. \"$build\"/", src).unwrap();
+    if let Some(prebuild) = prebuild {
+        writeln!(res, "(cd \"$build\" && sh -c {})", sh_quote(prebuild)).unwrap();
+    }
+}
+
+fn render(
+    root: &ExportPkg,
+    pkgs: &[ExportPkg],
+    bins: &[ExportBin],
+    compiler: &str,
+    backend: &ExportBackend,
+) -> String {
+    let mut res = String::new();
+    res.push_str("#!/bin/sh\n");
+    writeln!(
+        res,
+        "# Builds {} without elba, running the compiler on every package the same way\n\
+         # `elba build` would. The sources of every package are vendored in vendor/.\n\
+         #\n\
+         # Generated by `elba build --export-script` (elba {}) from elba.lock; run it again\n\
+         # when the lockfile changes. IDRIS is the compiler to build with, and OUT is the\n\
+         # absolute path of the directory to build in.",
+        root.title, VERSION
+    )
+    .unwrap();
+    res.push_str("set -eu\n\ncd \"$(dirname \"$0\")\"\n");
+    writeln!(res, "IDRIS=${{IDRIS:-{}}}", sh_quote(compiler)).unwrap();
+    res.push_str(
+        "OUT=${OUT:-\"$(pwd)/target/export\"}\n\
+         VENDOR=\"$(pwd)/vendor\"\n\
+         mkdir -p \"$OUT\"/build \"$OUT\"/lib \"$OUT\"/bin\n\n\
+         # Puts what a library was built into where the packages depending on it look for it,\n\
+         # leaving out its sources.\n\
+         install_lib() {\n    \
+         rm -rf \"$2\"\n    \
+         mkdir -p \"$2\"\n    \
+         cp -R \"$1\"/. \"$2\"/\n    \
+         find \"$2\" \\( -name '*.idr' -o -name '*.lidr' \\) -exec rm -f {} +\n\
+         }\n",
+    );
+
+    for pkg in pkgs {
+        let lib = match &pkg.lib {
+            Some(lib) => lib,
+            None => continue,
+        };
+        writeln!(res, "\n# {}", pkg.title).unwrap();
+        let src = format!("{}/{}", pkg.dir, sh_path(&lib.path));
+        render_prep(&mut res, &pkg.dir, &src, &pkg.prebuild);
+        let deps = pkg.deps.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        for module in &lib.mods {
+            render_compile(&mut res, &deps, &lib.opts, module);
+        }
+        writeln!(res, "install_lib \"$build\" \"$OUT\"/lib/{}", pkg.dir).unwrap();
+    }
+
+    // The compiler hands its codegen options to the C compiler through a shell of its own, so each
+    // of them is quoted for that shell, and then escaped to go in the double quotes around all of
+    // them, where the output of pkg-config still gets substituted in.
+    let mut cg_opts = backend
+        .opts
+        .iter()
+        .map(|x| sh_dquote(&sh_quote(x)))
+        .collect::<Vec<_>>();
+    cg_opts.extend(backend.pkg_config.iter().map(|x| {
+        format!(
+            "$(${{PKG_CONFIG:-pkg-config}} --cflags --libs {})",
+            sh_quote(x)
+        )
+    }));
+    cg_opts.extend(
+        backend
+            .libs
+            .iter()
+            .map(|x| sh_dquote(&sh_quote(&format!("-l{}", x)))),
+    );
+
+    let mut deps = root.deps.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    if root.lib.is_some() {
+        deps.push(&root.dir);
+    }
+    for bin in bins {
+        writeln!(res, "\n# bin target {}", bin.name).unwrap();
+        let src = format!("{}/{}", root.dir, sh_path(&bin.path));
+        render_prep(
+            &mut res,
+            &format!("bin-{}", sh_quote(&bin.name)),
+            &src,
+            &root.prebuild,
+        );
+        if bin.with_lib && root.lib.is_some() {
+            writeln!(res, "cp -R \"$OUT\"/lib/{}/. \"$build\"/", root.dir).unwrap();
+        }
+        let main = match &bin.main {
+            Left(path) => path.clone(),
+            Right((module, fun)) => {
+                writeln!(
+                    res,
+                    "cat > \"$build\"/elba-main.idr <<'EOF'\n\
+                     module Main\n\nimport {}\n\nmain : IO ()\nmain = {}\nEOF",
+                    module, fun
+                )
+                .unwrap();
+                PathBuf::from("elba-main.idr")
+            }
+        };
+        render_compile(&mut res, &deps, &bin.opts, &main);
+
+        write!(
+            res,
+            "(cd \"$OUT\"/bin && \"$IDRIS\" -o {} {} {}",
+            sh_quote(&bin.output),
+            if backend.portable {
+                "--portable-codegen"
+            } else {
+                "--codegen"
+            },
+            sh_quote(&backend.name)
+        )
+        .unwrap();
+        if !cg_opts.is_empty() {
+            write!(res, " --cg-opt \"{}\"", cg_opts.join(" ")).unwrap();
+        }
+        for opt in &bin.opts {
+            write!(res, " {}", sh_quote(opt)).unwrap();
+        }
+        writeln!(
+            res,
+            " -i \"$build\" \"$build\"/{})",
+            sh_path(&main.with_extension("ibc"))
+        )
+        .unwrap();
+    }
+
+    res
+}
+
+/// Writes a shell script to `script` which builds the targets of a project `elba build` would
+/// (given the same `targets`) without elba, vendoring the sources of every package in the
+/// `vendor` directory next to it.
+pub fn export_script(
+    ctx: &BuildCtx,
+    project: &Path,
+    targets: &(bool, bool, Option<Vec<&str>>, Option<Vec<&str>>),
+    backend: &Backend,
+    script: &Path,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let root = build_targets(&manifest, targets)?;
+    for target in &root.0 {
+        match target {
+            Target::Lib(false) | Target::Bin(_) => {}
+            _ => {
+                bail!("build scripts can only build lib targets (without codegen) and bin targets")
+            }
+        }
+    }
+    let compiler = Compiler::new(&ctx.compiler).ok();
+    if compiler.map(|x| x.flavor().is_idris2()).unwrap_or(false) {
+        bail!("build scripts can only be exported for the Idris 1 compiler")
+    }
+
+    let script = normalize_path(script);
+    let vendor = script
+        .parent()
+        .ok_or_else(|| format_err!("{} isn't a file", script.display()))?
+        .join("vendor");
+
+    solve_local(ctx, &project, 2, None, |_, mut retriever, solve| {
+        let sources = retriever
+            .retrieve_packages(&solve, ctx.threads)
+            .context(format_err!("package retrieval failed"))?;
+        drop(retriever);
+        let sources = without_test_deps(sources);
+
+        ctx.shell.println(
+            style("[2/2]").dim().bold(),
+            "Vendoring sources...",
+            Verbosity::Quiet,
+        );
+
+        let mut pkgs = vec![];
+        let mut root_pkg = None;
+        for (ix, source) in sources.topological() {
+            let meta = source.meta();
+            if !meta.aliases(false).is_empty() {
+                bail!(
+                    "{} renames its dependencies, which build scripts can't do yet",
+                    meta.name()
+                )
+            }
+            let is_root = ix == NodeIndex::new(0);
+            let lib = export_lib(source, &ctx.opts)?;
+            if lib.is_none() && !is_root {
+                bail!("dependency {} doesn't have a lib target", meta.name())
+            }
+
+            let dir = vendor_dir(meta.name(), meta.version());
+            vendor_source(source, &vendor.join(&dir), &[&vendor, &script])?;
+            if is_root {
+                root_pkg = Some(pkgs.len());
+            }
+            pkgs.push(ExportPkg {
+                title: format!("{} {}", meta.name(), meta.version()),
+                dir,
+                lib: if is_root && !root.has_lib() {
+                    None
+                } else {
+                    lib
+                },
+                prebuild: meta.scripts.get("prebuild").cloned(),
+                deps: sources
+                    .children(ix)
+                    .map(|(_, dep)| vendor_dir(dep.meta().name(), dep.meta().version()))
+                    .collect(),
+            });
+        }
+
+        let root_src = &sources[NodeIndex::new(0)];
+        let bins = root
+            .0
+            .iter()
+            .filter_map(|t| match t {
+                Target::Bin(ix) => Some(export_bin(root_src, *ix, &ctx.opts, backend)),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Native libraries only matter to the C backend, as in the build.
+        let natives = sources
+            .inner
+            .raw_nodes()
+            .iter()
+            .map(|x| &x.weight.meta().native)
+            .filter(|_| backend.name == "c")
+            .collect::<Vec<_>>();
+        let export_backend = ExportBackend {
+            name: backend.name.clone(),
+            portable: backend.portable,
+            opts: backend.opts.clone(),
+            libs: natives
+                .iter()
+                .flat_map(|x| &x.libs)
+                .unique()
+                .cloned()
+                .collect(),
+            pkg_config: natives
+                .iter()
+                .flat_map(|x| &x.pkg_config)
+                .unique()
+                .cloned()
+                .collect(),
+        };
+
+        let root_pkg = &pkgs[root_pkg.unwrap()];
+        let contents = render(root_pkg, &pkgs, &bins, &ctx.compiler, &export_backend);
+        write_atomic(&script, &contents)
+            .with_context(|e| format_err!("couldn't write {}: {}", script.display(), e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        }
+
+        Ok(format!(
+            "wrote build script to {} ({} packages vendored in {})",
+            script.display(),
+            pkgs.len(),
+            vendor.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_sh_quote() {
+        assert_eq!(sh_quote("--warnreach"), "--warnreach");
+        assert_eq!(sh_quote("a b"), "'a b'");
+        assert_eq!(sh_quote("it's"), "'it'\\''s'");
+        assert_eq!(sh_quote(""), "''");
+        assert_eq!(sh_dquote("$HOME \"x\""), "\\$HOME \\\"x\\\"");
+    }
+
+    #[test]
+    fn export_render() {
+        let dep = ExportPkg {
+            title: "grp/dep 1.0.0".to_string(),
+            dir: "grp_dep-1.0.0".to_string(),
+            lib: Some(ExportLib {
+                path: PathBuf::from("src"),
+                mods: vec![PathBuf::from("Dep/A.idr")],
+                opts: vec![],
+            }),
+            prebuild: None,
+            deps: vec![],
+        };
+        let root = ExportPkg {
+            title: "grp/app 0.1.0".to_string(),
+            dir: "grp_app-0.1.0".to_string(),
+            lib: None,
+            prebuild: Some("make gen".to_string()),
+            deps: vec!["grp_dep-1.0.0".to_string()],
+        };
+        let bins = vec![ExportBin {
+            name: "app".to_string(),
+            path: PathBuf::from("src"),
+            main: Left(PathBuf::from("Main.idr")),
+            with_lib: false,
+            opts: vec!["--warnreach".to_string()],
+            output: "app".to_string(),
+        }];
+        let backend = ExportBackend {
+            name: "c".to_string(),
+            portable: false,
+            opts: vec!["-DMSG=\"$it's\"".to_string()],
+            libs: vec!["gmp".to_string()],
+            pkg_config: vec!["libffi".to_string()],
+        };
+
+        let script = render(&root, &[dep, root.clone()], &bins, "idris", &backend);
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("IDRIS=${IDRIS:-idris}\n"));
+        assert!(script.contains("cp -R \"$VENDOR\"/grp_dep-1.0.0/src/. \"$build\"/\n"));
+        assert!(script.contains("(cd \"$build\" && \"$IDRIS\" --check Dep/A.idr)\n"));
+        assert!(script.contains("install_lib \"$build\" \"$OUT\"/lib/grp_dep-1.0.0\n"));
+        assert!(script.contains("(cd \"$build\" && sh -c 'make gen')\n"));
+        assert!(script.contains(
+            "(cd \"$build\" && \"$IDRIS\" --check -i \"$OUT\"/lib/grp_dep-1.0.0 --warnreach \
+             Main.idr)\n"
+        ));
+        assert!(script.contains(
+            "(cd \"$OUT\"/bin && \"$IDRIS\" -o app --codegen c \
+             --cg-opt \"'-DMSG=\\\"\\$it'\\\\''s\\\"' \
+             $(${PKG_CONFIG:-pkg-config} --cflags --libs libffi) -lgmp\" \
+             --warnreach -i \"$build\" \"$build\"/Main.ibc)\n"
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+//! Exporting the dependency graph of a project for other tools to draw or pick apart.
+
+use std::{collections::HashSet, fmt::Write, path::Path};
+
+use petgraph::{graph::NodeIndex, visit::EdgeRef};
+use semver::Version;
+use serde::Serialize;
+
+use super::build::{locked_graph, BuildCtx};
+use crate::{
+    package::{Name, Summary},
+    util::{error::Result, graph::Graph},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz's DOT language.
+    Dot,
+    /// A list of packages, each with the indices of the packages it depends on.
+    Json,
+    GraphMl,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GraphOpts {
+    pub format: GraphFormat,
+    /// Whether the nodes of a DOT graph are labelled with versions as well as names.
+    pub versions: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonGraph<'a> {
+    /// The index of the project itself, which is always 0.
+    root: usize,
+    nodes: Vec<JsonNode<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonNode<'a> {
+    id: String,
+    name: &'a Name,
+    version: &'a Version,
+    /// Whether the package is only needed for the project's dev-dependencies.
+    dev: bool,
+    dependencies: Vec<usize>,
+    dev_dependencies: Vec<usize>,
+}
+
+/// The dependency graph of a project, with which of its edges and nodes only exist because of the
+/// project's dev-dependencies.
+struct DepGraph {
+    graph: Graph<Summary>,
+    dev_edges: HashSet<(NodeIndex, NodeIndex)>,
+    dev_nodes: HashSet<NodeIndex>,
+}
+
+impl DepGraph {
+    fn new(graph: Graph<Summary>, dev: &[Name]) -> Self {
+        let root = NodeIndex::new(0);
+        let dev_edges = graph
+            .children(root)
+            .filter(|(_, sum)| dev.contains(sum.name()))
+            .map(|(ix, _)| (root, ix))
+            .collect::<HashSet<_>>();
+
+        // Everything which can't be reached without going through a dev-dependency is only there
+        // for the dev-dependencies.
+        let mut normal = HashSet::new();
+        let mut stack = vec![root];
+        while let Some(ix) = stack.pop() {
+            if normal.insert(ix) {
+                stack.extend(
+                    graph
+                        .children(ix)
+                        .map(|(child, _)| child)
+                        .filter(|&child| !dev_edges.contains(&(ix, child))),
+                );
+            }
+        }
+        let dev_nodes = graph
+            .inner
+            .node_indices()
+            .filter(|ix| !normal.contains(ix))
+            .collect();
+
+        DepGraph {
+            graph,
+            dev_edges,
+            dev_nodes,
+        }
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (NodeIndex, NodeIndex, bool)> + '_ {
+        self.graph.inner.edge_references().map(move |e| {
+            let edge = (e.source(), e.target());
+            (edge.0, edge.1, self.dev_edges.contains(&edge))
+        })
+    }
+
+    fn dot(&self, versions: bool) -> String {
+        let mut res = "digraph dependencies {\n".to_string();
+        for ix in self.graph.inner.node_indices() {
+            let sum = &self.graph[ix];
+            let label = if versions {
+                format!("{} {}", sum.name(), sum.version)
+            } else {
+                sum.name().to_string()
+            };
+            let style = if self.dev_nodes.contains(&ix) {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            writeln!(
+                res,
+                "    {} [label=\"{}\"{}];",
+                ix.index(),
+                escape_dot(&label),
+                style
+            )
+            .unwrap();
+        }
+        for (from, to, dev) in self.edges() {
+            let style = if dev { " [style=dashed]" } else { "" };
+            writeln!(res, "    {} -> {}{};", from.index(), to.index(), style).unwrap();
+        }
+        res.push('}');
+
+        res
+    }
+
+    fn json(&self) -> Result<String> {
+        let nodes = self
+            .graph
+            .inner
+            .node_indices()
+            .map(|ix| {
+                let sum = &self.graph[ix];
+                let (dev, normal): (Vec<_>, Vec<_>) = self
+                    .graph
+                    .children(ix)
+                    .map(|(child, _)| child)
+                    .partition(|&child| self.dev_edges.contains(&(ix, child)));
+                let mut dependencies = normal.into_iter().map(|x| x.index()).collect::<Vec<_>>();
+                let mut dev_dependencies = dev.into_iter().map(|x| x.index()).collect::<Vec<_>>();
+                dependencies.sort_unstable();
+                dev_dependencies.sort_unstable();
+
+                JsonNode {
+                    id: sum.id.to_string(),
+                    name: sum.name(),
+                    version: &sum.version,
+                    dev: self.dev_nodes.contains(&ix),
+                    dependencies,
+                    dev_dependencies,
+                }
+            })
+            .collect();
+
+        Ok(serde_json::to_string_pretty(&JsonGraph { root: 0, nodes })?)
+    }
+
+    fn graphml(&self) -> String {
+        let mut res = String::new();
+        res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        res.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        res.push_str(
+            "  <key id=\"id\" for=\"node\" attr.name=\"id\" attr.type=\"string\"/>\n  \
+             <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n  \
+             <key id=\"version\" for=\"node\" attr.name=\"version\" attr.type=\"string\"/>\n  \
+             <key id=\"dev\" for=\"all\" attr.name=\"dev\" attr.type=\"boolean\"/>\n",
+        );
+        res.push_str("  <graph id=\"dependencies\" edgedefault=\"directed\">\n");
+        for ix in self.graph.inner.node_indices() {
+            let sum = &self.graph[ix];
+            writeln!(
+                res,
+                "    <node id=\"n{}\">\n      \
+                 <data key=\"id\">{}</data>\n      \
+                 <data key=\"name\">{}</data>\n      \
+                 <data key=\"version\">{}</data>\n      \
+                 <data key=\"dev\">{}</data>\n    \
+                 </node>",
+                ix.index(),
+                escape_xml(&sum.id.to_string()),
+                escape_xml(&sum.name().to_string()),
+                escape_xml(&sum.version.to_string()),
+                self.dev_nodes.contains(&ix)
+            )
+            .unwrap();
+        }
+        for (from, to, dev) in self.edges() {
+            writeln!(
+                res,
+                "    <edge source=\"n{}\" target=\"n{}\">\n      \
+                 <data key=\"dev\">{}</data>\n    \
+                 </edge>",
+                from.index(),
+                to.index(),
+                dev
+            )
+            .unwrap();
+        }
+        res.push_str("  </graph>\n</graphml>");
+
+        res
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the dependency graph in the lockfile of a project. Packages which are only there for
+/// the project's dev-dependencies (and the edges to them from the project) are marked as such.
+pub fn graph(ctx: &BuildCtx, project: &Path, opts: GraphOpts) -> Result<String> {
+    let (graph, dev) = locked_graph(ctx, project)?;
+    let graph = DepGraph::new(graph, &dev);
+
+    match opts.format {
+        GraphFormat::Dot => Ok(graph.dot(opts.versions)),
+        GraphFormat::Json => graph.json(),
+        GraphFormat::GraphMl => Ok(graph.graphml()),
+    }
+}
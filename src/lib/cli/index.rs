@@ -1,77 +1,1530 @@
 //! Registry-related commands: publishing, yanking, etc.
 
 use std::{
-    fs::{create_dir_all, File},
+    fmt,
+    fs::{self, create_dir_all, File, OpenOptions},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
-    str::{self},
+    str::{self, FromStr},
 };
 
-use flate2::{write::GzEncoder, Compression};
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use flate2::{read::GzDecoder, Compression, GzBuilder};
+use git2::{Repository, StatusOptions};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use semver::Version;
+use serde::Serialize;
 use tar;
 
 use super::build;
 use crate::{
     cli::build::find_manifest,
-    package::manifest::Manifest,
-    retrieve::Cache,
-    util::{error::Result, valid_file},
+    package::{
+        manifest::{DepReq, Manifest},
+        workspace::manifest_toml,
+        Checksum, Name, PackageId,
+    },
+    remote::{
+        registry::{Registry, RegistryError},
+        resolution::{DirectRes, IndexRes},
+        tarball_name, Dep, Index, IndexConfig, IndexEntry, Indices, RawEntry, ResolvedDep,
+        SearchHit, ENTRY_VERSION, PACKAGES_DIR,
+    },
+    retrieve::Refresh,
+    util::{
+        clear_dir,
+        config::Backend,
+        error::Result,
+        keys::{PublicKey, TrustedKeys},
+        lock::LockKind,
+        shell::{pad, width, Shell, Verbosity},
+        valid_file,
+    },
 };
 
 pub fn package(project: &Path) -> Result<(PathBuf, Manifest)> {
     let (project, manifest) = find_manifest(project, false, None)?;
 
     let gz_name = format!(
-        "target/{}_{}-{}.tar.gz",
-        manifest.name().group(),
-        manifest.name().name(),
-        manifest.version()
+        "target/{}",
+        tarball_name(manifest.name(), manifest.version())
     );
 
     create_dir_all(project.join("target"))?;
     let tar_gz = File::create(project.join(&gz_name))?;
-    let enc = GzEncoder::new(tar_gz, Compression::default());
+    // The gzip header gets a fixed time and OS too, so it only depends on what's in the tarball.
+    let enc = GzBuilder::new()
+        .mtime(0)
+        .operating_system(255)
+        .write(tar_gz, Compression::new(6));
     let mut tar = tar::Builder::new(enc);
 
+    // A manifest which inherits anything from its workspace goes into the tarball with all of it
+    // filled in, since the workspace won't be there to inherit from.
+    let resolved = manifest_toml(&project)?;
+    let raw: toml::Value = toml::from_str(&fs::read_to_string(project.join("elba.toml"))?)?;
+
+    // The files are sorted, and nothing about them but their contents and whether they can be
+    // run goes into their headers, so the same files always make the same tarball.
+    for file in package_files(&project, &manifest)? {
+        let contents = if file == Path::new("elba.toml") && resolved != raw {
+            toml::to_string(&resolved)?.into_bytes()
+        } else {
+            fs::read(project.join(&file))?
+        };
+        let executable = is_executable(&fs::metadata(project.join(&file))?);
+        let mut header = file_header(contents.len() as u64, executable);
+        tar.append_data(&mut header, &file, contents.as_slice())?;
+    }
+
+    tar.into_inner()?.finish()?;
+
+    Ok((project.join(&gz_name), manifest))
+}
+
+/// The modification time of every file in a packaged tarball. Some tools take a time of 0 to
+/// mean that there isn't one, so this is the start of 1980 instead.
+const PACKAGE_MTIME: u64 = 315_532_800;
+
+/// The header of a file in a packaged tarball, which doesn't say anything about who owned the
+/// file or when it was changed.
+fn file_header(size: u64, executable: bool) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(if executable { 0o755 } else { 0o644 });
+    header.set_mtime(PACKAGE_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// The files which go into the tarball of a package, relative to the root of the project.
+pub fn package_files(project: &Path, manifest: &Manifest) -> Result<Vec<PathBuf>> {
     let walker = manifest
-        .list_files(&project, &project, |x| {
+        .list_files(project, project, |x| {
             x.file_name() != ".git" && x.file_name() != "target"
         })?
         .filter(valid_file);
 
-    for item in walker {
-        let suffix = item.path().strip_prefix(&project).unwrap();
-        tar.append_path_with_name(item.path(), suffix)?;
+    let mut files = walker
+        .map(|x| x.path().strip_prefix(project).unwrap().to_path_buf())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    Ok(files)
+}
+
+/// Prints how many files are in a packaged tarball and how big it is, warning if it's bigger
+/// than `warn_size` KiB once compressed.
+pub fn report_size(shell: Shell, tarball: &Path, warn_size: Option<u64>) -> Result<()> {
+    let (files, size, compressed) = tarball_size(tarball)?;
+
+    shell.println(
+        style("Packaged").cyan(),
+        format!(
+            "{} ({} files, {}, {} compressed)",
+            tarball.display(),
+            files,
+            human_size(size),
+            human_size(compressed)
+        ),
+        Verbosity::Normal,
+    );
+
+    if let Some(warn_size) = warn_size {
+        if compressed > warn_size * 1024 {
+            shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "The package is {} compressed, which is more than the {} KiB set by \
+                     package_warn_size; check what's in it with `elba package --list`",
+                    human_size(compressed),
+                    warn_size
+                ),
+                Verbosity::Quiet,
+            );
+        }
     }
 
-    // Finish writing to the tarball
-    drop(tar);
+    Ok(())
+}
 
-    Ok((project.join(&gz_name), manifest))
+/// How many files are in a packaged tarball, how big they are altogether, and how big the
+/// tarball itself is.
+fn tarball_size(tarball: &Path) -> Result<(u64, u64, u64)> {
+    let compressed = fs::metadata(tarball)?.len();
+    let mut files = 0;
+    let mut size = 0;
+    for entry in tar::Archive::new(GzDecoder::new(File::open(tarball)?)).entries()? {
+        files += 1;
+        size += entry?.header().size()?;
+    }
+
+    Ok((files, size, compressed))
 }
 
-pub fn search(bcx: &build::BuildCtx, query: &str) -> Result<String> {
-    let cache = Cache::from_disk(&bcx.logger, bcx.global_cache.clone(), bcx.shell)?;
-    let ixs = bcx
-        .indices
-        .values()
-        .cloned()
-        .map(|x| x.res)
+fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// Builds a package from its tarball rather than from the project it was packaged from, so that
+/// files which the build needs but which were left out of the tarball are caught before anyone
+/// else finds out about them.
+pub fn verify_tarball(bcx: &build::BuildCtx, tarball: &Path, manifest: &Manifest) -> Result<()> {
+    bcx.shell.println(
+        style("Verifying").cyan(),
+        format!("{} {}", manifest.name(), manifest.version()),
+        Verbosity::Normal,
+    );
+
+    let dir = bcx.global_cache.tmp.join("verify").join(format!(
+        "{}_{}-{}",
+        manifest.name().group(),
+        manifest.name().name(),
+        manifest.version()
+    ));
+    clear_dir(&dir)?;
+    tar::Archive::new(GzDecoder::new(File::open(tarball)?))
+        .unpack(&dir)
+        .with_context(|e| format_err!("couldn't unpack {}: {}", tarball.display(), e))?;
+
+    build::build(
+        bcx,
+        &dir,
+        &(true, false, None, None),
+        true,
+        &Backend::default(),
+    )
+    .with_context(|e| {
+        format_err!(
+            "the packaged tarball (unpacked in {}) didn't build: {}",
+            dir.display(),
+            e
+        )
+    })?;
+
+    remove_dir_all::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+/// Options for searching the indices.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOpts {
+    /// The index to search, like in `PublishOpts`. Defaults to every index.
+    pub index: Option<String>,
+    /// The most results to give back.
+    pub limit: Option<usize>,
+    /// How many of the best results to skip over before giving any back. Paging through results
+    /// `limit` at a time means skipping a multiple of `limit` of them.
+    pub offset: usize,
+    /// The category to narrow the results down to.
+    pub category: Option<String>,
+    /// How to order the results.
+    pub sort: SearchSort,
+}
+
+/// The orders search results can come in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchSort {
+    /// Best matches first.
+    Relevance,
+    /// Most downloaded first. Packages whose registries don't keep track come last.
+    Downloads,
+}
+
+impl Default for SearchSort {
+    fn default() -> Self {
+        SearchSort::Relevance
+    }
+}
+
+/// The results of a search which fit in its limit, along with how many results there were in all.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    pub total: usize,
+}
+
+/// Searches the indices for packages, best matches first.
+pub fn search(bcx: &build::BuildCtx, query: &str, opts: &SearchOpts) -> Result<SearchResults> {
+    let cache = bcx.cache()?;
+    let within = match &opts.index {
+        Some(ix) => Some(select_index(&bcx.indices, Some(ix))?),
+        None => None,
+    };
+    let ixs = match &within {
+        Some(ix) => vec![ix.res.clone()],
+        None => bcx.indices.values().cloned().map(|x| x.res).collect(),
+    };
+    let indices = cache.get_indices(&ixs, bcx.refresh, bcx.offline);
+
+    let mut hits = indices.search(
+        query,
+        within.as_ref(),
+        opts.category.as_ref().map(|x| x.as_str()),
+    )?;
+    let total = hits.len();
+
+    // We only ask the registries about the results we're going to show, unless we need every
+    // result's download count to know which ones those are.
+    match opts.sort {
+        SearchSort::Relevance => {
+            page(&mut hits, opts);
+            if !bcx.offline {
+                for hit in &mut hits {
+                    hit.downloads = downloads(&indices, &hit.index, &hit.name);
+                }
+            }
+        }
+        SearchSort::Downloads => {
+            if bcx.offline {
+                bail!("can't sort by downloads while offline")
+            }
+            for hit in &mut hits {
+                hit.downloads = downloads(&indices, &hit.index, &hit.name);
+            }
+            // The sort is stable, so ties stay in order of relevance.
+            hits.sort_by(|a, b| b.downloads.cmp(&a.downloads));
+            page(&mut hits, opts);
+        }
+    }
+
+    Ok(SearchResults { hits, total })
+}
+
+/// Narrows search results down to the ones on the page the options ask for.
+fn page(hits: &mut Vec<SearchHit>, opts: &SearchOpts) {
+    hits.drain(..opts.offset.min(hits.len()));
+    if let Some(limit) = opts.limit {
+        hits.truncate(limit);
+    }
+}
+
+/// Asks the registry of an index how many times a package has been downloaded. Registries don't
+/// have to keep track, so any failure just means that we don't know.
+fn downloads(indices: &Indices, index: &IndexRes, name: &Name) -> Option<u64> {
+    Registry::of(indices, index).ok()?.downloads(name).ok()
+}
+
+/// Formats a search result as a line which can be pasted into the `[dependencies]` section of a
+/// manifest, with its description and download count as a comment.
+pub fn dependency_line(hit: &SearchHit, ixmap: &IndexMap<String, IndexRes>) -> String {
+    let name = toml::Value::String(hit.name.to_string());
+    let version = toml::Value::String(hit.version.to_string());
+
+    let mut line = match index_name(hit, ixmap) {
+        None => format!("{} = {}", name, version),
+        Some(index) => format!(
+            "{} = {{ version = {}, index = {} }}",
+            name,
+            version,
+            toml::Value::String(index)
+        ),
+    };
+
+    let desc = hit.description.as_ref().and_then(|x| x.lines().next());
+    let downloads = hit.downloads.map(|x| format!("{} downloads", x));
+    match (desc, downloads) {
+        (Some(desc), Some(downloads)) => line.push_str(&format!(" # {} ({})", desc, downloads)),
+        (Some(desc), None) => line.push_str(&format!(" # {}", desc)),
+        (None, Some(downloads)) => line.push_str(&format!(" # {}", downloads)),
+        (None, None) => {}
+    }
+
+    line
+}
+
+/// Formats search results as a table, with a row for each package giving its name, its latest
+/// version, how many times it's been downloaded (if anything about that is known) and the first
+/// line of its description. Packages which don't come from the default index have the name of
+/// their index after their name.
+pub fn search_table(hits: &[SearchHit], ixmap: &IndexMap<String, IndexRes>) -> Vec<String> {
+    let rows = hits
+        .iter()
+        .map(|hit| {
+            let name = match index_name(hit, ixmap) {
+                Some(index) => format!("{} ({})", hit.name, index),
+                None => hit.name.to_string(),
+            };
+            let downloads = hit.downloads.map(|x| x.to_string()).unwrap_or_default();
+            let desc = hit
+                .description
+                .as_ref()
+                .and_then(|x| x.lines().next())
+                .unwrap_or("");
+            (name, hit.version.to_string(), downloads, desc)
+        })
         .collect::<Vec<_>>();
-    let indices = cache.get_indices(&ixs, false, false);
 
-    let pkgs = indices.search(query)?;
-    let mut res = String::new();
+    let name_width = rows.iter().map(|x| width(&x.0)).max().unwrap_or(0);
+    let version_width = rows.iter().map(|x| width(&x.1)).max().unwrap_or(0);
+    let downloads_width = rows.iter().map(|x| width(&x.2)).max().unwrap_or(0);
+
+    rows.iter()
+        .map(|(name, version, downloads, desc)| {
+            let mut row = format!("{}  {}", pad(name, name_width), pad(version, version_width));
+            if downloads_width > 0 {
+                row.push_str(&format!("  {:>w$}", downloads, w = downloads_width));
+            }
+            if !desc.is_empty() {
+                row.push_str(&format!("  {}", desc));
+            }
+            row.trim_end().to_string()
+        })
+        .collect()
+}
+
+/// The name of the index a search result comes from, unless it's the default index (the first
+/// one in the config), which dependencies come from when they don't say.
+fn index_name(hit: &SearchHit, ixmap: &IndexMap<String, IndexRes>) -> Option<String> {
+    if ixmap.get_index(0).map(|x| x.1) == Some(&hit.index) {
+        return None;
+    }
+
+    Some(
+        ixmap
+            .iter()
+            .find(|x| x.1 == &hit.index)
+            .map(|x| x.0.clone())
+            .unwrap_or_else(|| hit.index.to_string()),
+    )
+}
+
+/// Options for looking up a package.
+#[derive(Debug, Clone, Default)]
+pub struct InfoOpts {
+    /// The index to look in, like in `PublishOpts`. Defaults to every index in the config.
+    pub index: Option<String>,
+    /// The version to describe. Defaults to the latest version which hasn't been yanked.
+    pub version: Option<Version>,
+    /// Whether to get the package's metadata from the index's registry instead of retrieving the
+    /// package itself.
+    pub registry: bool,
+}
+
+/// Everything we know about a package.
+#[derive(Debug, Clone, Serialize)]
+pub struct Info {
+    pub name: Name,
+    pub index: IndexRes,
+    /// The version being described.
+    pub version: Version,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    pub location: DirectRes,
+    /// How many times the package has been downloaded, if its registry keeps track.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<u64>,
+    pub versions: Vec<VersionInfo>,
+}
+
+/// What the index says about a version of a package.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: Version,
+    pub yanked: bool,
+    pub dependencies: Vec<ResolvedDep>,
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {} ({})", self.name, self.version, self.index)?;
+        if let Some(description) = &self.description {
+            writeln!(f, "{}", description)?;
+        }
+        writeln!(f)?;
+        if let Some(license) = &self.license {
+            writeln!(f, "license: {}", license)?;
+        }
+        writeln!(f, "location: {}", self.location)?;
+        if let Some(downloads) = self.downloads {
+            writeln!(f, "downloads: {}", downloads)?;
+        }
+
+        if let Some(this) = self.versions.iter().find(|x| x.version == self.version) {
+            writeln!(f, "dependencies:")?;
+            if this.dependencies.is_empty() {
+                writeln!(f, "  (none)")?;
+            }
+            for dep in &this.dependencies {
+                if dep.index == self.index {
+                    writeln!(f, "  {} {}", dep.name, dep.req)?;
+                } else {
+                    writeln!(f, "  {} {} ({})", dep.name, dep.req, dep.index)?;
+                }
+            }
+        }
+
+        write!(
+            f,
+            "versions: {}",
+            self.versions
+                .iter()
+                .map(|x| if x.yanked {
+                    format!("{} (yanked)", x.version)
+                } else {
+                    x.version.to_string()
+                })
+                .join(", ")
+        )
+    }
+}
+
+/// Looks a package up in the indices, along with the metadata in its manifest.
+pub fn info(bcx: &build::BuildCtx, name: &Name, opts: &InfoOpts) -> Result<Info> {
+    let ixs = match &opts.index {
+        Some(ix) => vec![select_index(&bcx.indices, Some(ix))?],
+        None => bcx.indices.values().cloned().collect(),
+    };
+    let cache = bcx.cache()?;
+    let indices = cache.get_indices(
+        &ixs.iter().map(|x| x.res.clone()).collect::<Vec<_>>(),
+        bcx.refresh,
+        bcx.offline,
+    );
+
+    // The indices which the configured ones depend on are loaded too, but we only look in the
+    // ones we were asked to.
+    let mut found = ixs
+        .into_iter()
+        .filter_map(|ir| {
+            let mut entries = indices.indices.get(&ir)?.entries(name).ok()?;
+            entries.sort_keys();
+            Some((ir, entries))
+        })
+        .collect::<Vec<_>>();
+    let (index, entries) = match found.len() {
+        0 => bail!("package {} wasn't found in any index", name),
+        1 => found.pop().unwrap(),
+        _ => bail!(
+            "package {} is in more than one index, so one has to be picked with --index: {}",
+            name,
+            found.iter().map(|x| &x.0).join(", ")
+        ),
+    };
+
+    let entry = match &opts.version {
+        Some(v) => entries
+            .get(v)
+            .ok_or_else(|| format_err!("package {} has no version {}", name, v))?,
+        None => entries
+            .values()
+            .filter(|x| !x.yanked)
+            .last()
+            .or_else(|| entries.values().last())
+            .ok_or_else(|| format_err!("package {} has no versions", name))?,
+    };
+
+    let (description, license) = if opts.registry {
+        let meta = Registry::of(&indices, &index)?.metadata(name, &entry.version)?;
+        (meta.description, meta.license)
+    } else {
+        let pkg = PackageId::new(name.clone(), index.clone().into());
+        let source = cache.checkout_source(&pkg, &entry.location, false, bcx.offline, || {
+            bcx.shell.println(
+                style("Retrieving").cyan(),
+                format!("{} {}", name, entry.version),
+                Verbosity::Normal,
+            );
+        });
+        match source {
+            Ok((_, source)) => (
+                source.meta().package.description.clone(),
+                source.meta().package.license.clone(),
+            ),
+            Err(e) => {
+                // The index has told us plenty already, so we make do without the manifest.
+                bcx.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!("Couldn't retrieve {} {}: {}", name, entry.version, e),
+                    Verbosity::Quiet,
+                );
+                (None, None)
+            }
+        }
+    };
+
+    let downloads = if bcx.offline {
+        None
+    } else {
+        downloads(&indices, &index, name)
+    };
+
+    Ok(Info {
+        name: name.clone(),
+        version: entry.version.clone(),
+        description,
+        license,
+        location: entry.location.clone(),
+        downloads,
+        versions: entries
+            .values()
+            .map(|x| VersionInfo {
+                version: x.version.clone(),
+                yanked: x.yanked,
+                dependencies: x.dependencies.clone(),
+            })
+            .collect(),
+        index,
+    })
+}
+
+/// Options for publishing a package.
+#[derive(Debug, Clone, Default)]
+pub struct PublishOpts {
+    /// The index to publish to; either the name of an index in the config or an index resolution.
+    /// Defaults to the first index in the config.
+    pub index: Option<String>,
+    /// The token to authenticate with the registry.
+    pub token: Option<String>,
+    /// Whether to publish even if the project has uncommitted changes.
+    pub allow_dirty: bool,
+    /// Whether to only make the checks publishing would (see `prepublish_checks`), without
+    /// uploading anything.
+    pub dry_run: bool,
+    /// How big, in KiB, the package can get before we warn about it.
+    pub warn_size: Option<u64>,
+}
+
+/// Verifies, packages and uploads a package to the registry of an index.
+pub fn publish(bcx: &build::BuildCtx, project: &Path, opts: &PublishOpts) -> Result<String> {
+    if opts.dry_run {
+        let checklist = prepublish_checks(bcx, project, opts)?;
+        print_checklist(bcx.shell, &checklist);
+        return checklist.result();
+    }
+
+    let (project, manifest) = find_manifest(project, false, None)?;
+
+    check_publishable(&manifest)?;
+    if !opts.allow_dirty {
+        check_clean(&project)?;
+    }
+
+    let index = select_index(&bcx.indices, opts.index.as_ref().map(|x| x.as_str()))?;
+    let cache = bcx.cache()?;
+    // We want the freshest copy of the index so that we know what's already been published.
+    let mut indices = cache.get_indices(&[index.res.clone()], Refresh::Always, bcx.offline);
+
+    let registry = Registry::of(&indices, &index)?;
+
+    let pkg = PackageId::new(manifest.name().clone(), index.clone().into());
+    if let Ok(entries) = indices.entries(&pkg) {
+        if entries.contains_key(manifest.version()) {
+            bail!(
+                "{} {} has already been published to index {}",
+                manifest.name(),
+                manifest.version(),
+                index
+            )
+        }
+    }
+    // Building locks the indices all over again.
+    drop(indices);
+
+    // There's no point in building the package if the registry is going to turn it away.
+    if let Some(token) = &opts.token {
+        check_group(&registry, manifest.name(), token)?;
+    }
+
+    let (tarball, _) = package(&project)?;
+    report_size(bcx.shell, &tarball, opts.warn_size)?;
+
+    verify_tarball(bcx, &tarball, &manifest).context(format_err!(
+        "package failed to build; refusing to publish it"
+    ))?;
+
+    let token = opts.token.as_ref().ok_or_else(|| {
+        format_err!(
+            "no token given to authenticate with the registry {} (log in with `elba login`)",
+            registry.url()
+        )
+    })?;
+
+    bcx.shell.println(
+        style("Uploading").cyan(),
+        format!(
+            "{} {} to {}",
+            manifest.name(),
+            manifest.version(),
+            registry.url()
+        ),
+        Verbosity::Normal,
+    );
+
+    let file = File::open(&tarball)?;
+    let len = file.metadata()?.len();
+    let tarball = Progress {
+        inner: file,
+        read: 0,
+        len,
+        shell: bcx.shell,
+    };
+    registry.publish(tarball, len, token)?;
+
+    Ok(format!(
+        "published {} {} to {}",
+        manifest.name(),
+        manifest.version(),
+        registry.url()
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passed,
+    /// The check found something worth knowing about, which doesn't stop the package from being
+    /// published.
+    Warning,
+    Failed,
+    /// The check couldn't be made, because it was turned off or because an earlier check failed.
+    Skipped,
+}
+
+/// One of the checks made before publishing a package.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Every check made before publishing a package, in the order they were made.
+#[derive(Debug, Clone, Serialize)]
+pub struct Checklist {
+    pub name: Name,
+    pub version: Version,
+    pub checks: Vec<Check>,
+}
+
+impl Checklist {
+    fn push(&mut self, name: &'static str, status: CheckStatus, message: Option<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            message,
+        });
+    }
+
+    /// Records how a check went: it passed if it didn't fail.
+    fn record(&mut self, name: &'static str, res: Result<Option<String>>) -> bool {
+        match res {
+            Ok(message) => {
+                self.push(name, CheckStatus::Passed, message);
+                true
+            }
+            Err(e) => {
+                self.push(name, CheckStatus::Failed, Some(e.to_string()));
+                false
+            }
+        }
+    }
+
+    pub fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|x| x.status == CheckStatus::Failed)
+            .count()
+    }
+
+    /// Fails if any of the checks failed.
+    pub fn result(&self) -> Result<String> {
+        let failed = self.failed();
+        if failed > 0 {
+            bail!(
+                "{} of {} checks failed; {} {} can't be published",
+                failed,
+                self.checks.len(),
+                self.name,
+                self.version
+            )
+        }
+
+        Ok(format!(
+            "dry run: every check passed, so {} {} can be published",
+            self.name, self.version
+        ))
+    }
+}
+
+/// Makes every check which publishing a package would, without uploading anything: that the
+/// manifest has everything a published package needs, that the project has no uncommitted
+/// changes, that its version hasn't been published already, that the group it's in is owned by
+/// whoever the token belongs to, that it can be packaged, how big it is, and that it builds from
+/// its tarball.
+///
+/// Unlike publishing, a failed check doesn't stop the ones after it from being made (unless they
+/// depend on it), so that every problem is found at once.
+pub fn prepublish_checks(
+    bcx: &build::BuildCtx,
+    project: &Path,
+    opts: &PublishOpts,
+) -> Result<Checklist> {
+    let (project, manifest) = find_manifest(project, false, None)?;
+    let mut checklist = Checklist {
+        name: manifest.name().clone(),
+        version: manifest.version().clone(),
+        checks: vec![],
+    };
+
+    checklist.record("manifest", check_publishable(&manifest).map(|_| None));
+
+    if opts.allow_dirty {
+        checklist.push(
+            "uncommitted changes",
+            CheckStatus::Skipped,
+            Some("--allow-dirty was passed".to_string()),
+        );
+    } else {
+        checklist.record("uncommitted changes", check_clean(&project).map(|_| None));
+    }
+
+    match select_index(&bcx.indices, opts.index.as_ref().map(|x| x.as_str())) {
+        Ok(index) => {
+            let cache = bcx.cache()?;
+            let mut indices = cache.get_indices(&[index.res.clone()], Refresh::Always, bcx.offline);
+            let pkg = PackageId::new(manifest.name().clone(), index.clone().into());
+            let published = indices
+                .entries(&pkg)
+                .map(|x| x.contains_key(manifest.version()))
+                .unwrap_or(false);
+            checklist.record(
+                "version",
+                if published {
+                    Err(format_err!(
+                        "{} {} has already been published to index {}",
+                        manifest.name(),
+                        manifest.version(),
+                        index
+                    ))
+                } else {
+                    Ok(Some(format!("not yet published to index {}", index)))
+                },
+            );
+
+            match &opts.token {
+                Some(token) => {
+                    let res = Registry::of(&indices, &index)
+                        .and_then(|registry| check_group(&registry, manifest.name(), token));
+                    checklist.record("group ownership", res.map(|_| None));
+                }
+                None => checklist.push(
+                    "group ownership",
+                    CheckStatus::Skipped,
+                    Some("no token given".to_string()),
+                ),
+            }
+        }
+        Err(e) => {
+            checklist.record("version", Err(e));
+            checklist.push("group ownership", CheckStatus::Skipped, None);
+        }
+    }
+
+    let tarball = match package(&project) {
+        Ok((tarball, _)) => {
+            checklist.push(
+                "packaging",
+                CheckStatus::Passed,
+                Some(tarball.display().to_string()),
+            );
+            tarball
+        }
+        Err(e) => {
+            checklist.record("packaging", Err(e));
+            checklist.push("size", CheckStatus::Skipped, None);
+            checklist.push("build from tarball", CheckStatus::Skipped, None);
+            return Ok(checklist);
+        }
+    };
+
+    match tarball_size(&tarball) {
+        Ok((files, size, compressed)) => {
+            let message = format!(
+                "{} files, {}, {} compressed",
+                files,
+                human_size(size),
+                human_size(compressed)
+            );
+            match opts.warn_size {
+                Some(warn_size) if compressed > warn_size * 1024 => checklist.push(
+                    "size",
+                    CheckStatus::Warning,
+                    Some(format!(
+                        "{}, which is more than the {} KiB set by package_warn_size",
+                        message, warn_size
+                    )),
+                ),
+                _ => checklist.push("size", CheckStatus::Passed, Some(message)),
+            }
+        }
+        Err(e) => {
+            checklist.record("size", Err(e));
+        }
+    }
+
+    checklist.record(
+        "build from tarball",
+        verify_tarball(bcx, &tarball, &manifest).map(|_| None),
+    );
+
+    Ok(checklist)
+}
+
+/// Prints how each of the checks before publishing went.
+pub fn print_checklist(shell: Shell, checklist: &Checklist) {
+    for check in &checklist.checks {
+        let status = match check.status {
+            CheckStatus::Passed => style("Passed").green(),
+            CheckStatus::Warning => style("Warning").yellow(),
+            CheckStatus::Failed => style("Failed").red(),
+            CheckStatus::Skipped => style("Skipped").dim(),
+        };
+        let message = match &check.message {
+            Some(message) => format!("{}: {}", check.name, message),
+            None => check.name.to_string(),
+        };
+        shell.println(status.bold(), message, Verbosity::Quiet);
+    }
+}
+
+/// Makes sure that the user a token belongs to owns the group a package is in.
+fn check_group(registry: &Registry, name: &Name, token: &str) -> Result<()> {
+    let group = match registry.group(name.normalized_group(), Some(token)) {
+        Ok(group) => group,
+        // Registries without groups let anyone publish to any of them.
+        Err(e) => match e.downcast_ref::<RegistryError>() {
+            Some(RegistryError::NotFound(..)) => return Ok(()),
+            _ => return Err(e),
+        },
+    };
+
+    if !group.claimed {
+        bail!(
+            "group `{}` hasn't been claimed on registry {}; claim it with `elba group claim {}` \
+             before publishing to it",
+            name.group(),
+            registry.url(),
+            name.group()
+        )
+    }
+    if !group.owned {
+        bail!(
+            "group `{}` on registry {} belongs to someone else (owned by {}); ask one of its \
+             owners to add you, or publish under a group of your own",
+            name.group(),
+            registry.url(),
+            if group.owners.is_empty() {
+                "unknown users".to_string()
+            } else {
+                group.owners.join(", ")
+            }
+        )
+    }
+
+    Ok(())
+}
+
+/// Options for managing the owners of a package.
+#[derive(Debug, Clone, Default)]
+pub struct OwnerOpts {
+    /// The index the package is in, like in `PublishOpts`.
+    pub index: Option<String>,
+    /// The token to authenticate with the registry.
+    pub token: Option<String>,
+    /// Users to add as owners.
+    pub add: Vec<String>,
+    /// Users to remove as owners.
+    pub remove: Vec<String>,
+    /// Whether to print the owners of the package.
+    pub list: bool,
+}
+
+/// Adds, removes, and lists the owners of a package on the registry of an index.
+pub fn owner(bcx: &build::BuildCtx, pkg: &Name, opts: &OwnerOpts) -> Result<String> {
+    let index = select_index(&bcx.indices, opts.index.as_ref().map(|x| x.as_str()))?;
+    let cache = bcx.cache()?;
+    let indices = cache.get_indices(&[index.res.clone()], bcx.refresh, bcx.offline);
+    let registry = Registry::of(&indices, &index)?;
+    drop(indices);
+
+    let token = opts.token.as_ref().ok_or_else(|| {
+        format_err!(
+            "no token given to authenticate with the registry {} (log in with `elba login`)",
+            registry.url()
+        )
+    })?;
+
+    for user in &opts.add {
+        registry.add_owner(pkg, user, token)?;
+        bcx.shell.println(
+            style("Added").green(),
+            format!("{} as an owner of {}", user, pkg),
+            Verbosity::Normal,
+        );
+    }
+    for user in &opts.remove {
+        registry.remove_owner(pkg, user, token)?;
+        bcx.shell.println(
+            style("Removed").red(),
+            format!("{} as an owner of {}", user, pkg),
+            Verbosity::Normal,
+        );
+    }
+
+    if opts.list {
+        for owner in registry.owners(pkg, token)? {
+            bcx.shell.println_plain(owner, Verbosity::Quiet);
+        }
+    }
+
+    Ok(format!("updated owners of {}", pkg))
+}
+
+/// Options for managing a group of packages.
+#[derive(Debug, Clone, Default)]
+pub struct GroupOpts {
+    /// The index whose registry the group is on, like in `PublishOpts`.
+    pub index: Option<String>,
+    /// The token to authenticate with the registry.
+    pub token: Option<String>,
+}
+
+/// Checks that a group is valid, and normalizes it like the group of a `Name`. A trailing slash,
+/// as in `elba info group/`, is allowed.
+pub fn normalize_group(group: &str) -> Result<String> {
+    let group = group.trim_end_matches('/');
+    if group.is_empty() {
+        bail!("group cannot be empty")
+    }
+    if !group
+        .chars()
+        .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+    {
+        bail!("group can only contain letters, numbers, _, and -")
+    }
+
+    Ok(group.to_ascii_lowercase().replace('_', "-"))
+}
+
+/// Claims a group on the registry of an index, so that only its owners can publish to it.
+pub fn claim_group(bcx: &build::BuildCtx, group: &str, opts: &GroupOpts) -> Result<String> {
+    let group = normalize_group(group)?;
+    let index = select_index(&bcx.indices, opts.index.as_ref().map(|x| x.as_str()))?;
+    let cache = bcx.cache()?;
+    let indices = cache.get_indices(&[index.res.clone()], bcx.refresh, bcx.offline);
+    let registry = Registry::of(&indices, &index)?;
+    drop(indices);
+
+    let token = opts.token.as_ref().ok_or_else(|| {
+        format_err!(
+            "no token given to authenticate with the registry {} (log in with `elba login`)",
+            registry.url()
+        )
+    })?;
+
+    let current = registry.group(&group, Some(token)).map_err(|e| {
+        match e.downcast_ref::<RegistryError>() {
+            Some(RegistryError::NotFound(..)) => format_err!(
+                "registry {} doesn't have groups; anyone can publish to any group",
+                registry.url()
+            ),
+            _ => e,
+        }
+    })?;
+    if current.owned {
+        return Ok(format!("group `{}` is already yours", group));
+    }
+    if current.claimed {
+        bail!(
+            "group `{}` has already been claimed on registry {} by {}",
+            group,
+            registry.url(),
+            current.owners.join(", ")
+        )
+    }
+
+    registry.claim_group(&group, token)?;
+
+    Ok(format!("claimed group `{}` on {}", group, registry.url()))
+}
+
+/// The owners of a group on the registry of an index.
+pub fn group_owners(bcx: &build::BuildCtx, group: &str, opts: &GroupOpts) -> Result<Vec<String>> {
+    let group = normalize_group(group)?;
+    let index = select_index(&bcx.indices, opts.index.as_ref().map(|x| x.as_str()))?;
+    let cache = bcx.cache()?;
+    let indices = cache.get_indices(&[index.res.clone()], bcx.refresh, bcx.offline);
+    let registry = Registry::of(&indices, &index)?;
+    drop(indices);
+
+    let current = registry.group(&group, opts.token.as_ref().map(|x| x.as_str()))?;
+    if !current.claimed {
+        bail!(
+            "group `{}` hasn't been claimed on registry {}",
+            group,
+            registry.url()
+        )
+    }
+
+    Ok(current.owners)
+}
+
+/// Lists the packages in a group, in the index `index` or in every index in the config.
+pub fn group_packages(
+    bcx: &build::BuildCtx,
+    group: &str,
+    index: Option<&str>,
+) -> Result<Vec<SearchHit>> {
+    let group = normalize_group(group)?;
+    let cache = bcx.cache()?;
+    let within = match index {
+        Some(ix) => Some(select_index(&bcx.indices, Some(ix))?),
+        None => None,
+    };
+    let ixs = match &within {
+        Some(ix) => vec![ix.res.clone()],
+        None => bcx.indices.values().cloned().map(|x| x.res).collect(),
+    };
+    let indices = cache.get_indices(&ixs, bcx.refresh, bcx.offline);
 
-    for (name, ver, ir) in &pkgs {
-        if ir.res == ixs[0] {
-            res.push_str(&format!("\"{}\" = \"{}\"", name, ver));
+    let hits = indices.group(&group, within.as_ref())?;
+    if hits.is_empty() {
+        bail!("no packages in group `{}` were found in any index", group)
+    }
+
+    Ok(hits)
+}
+
+/// Refreshes the configured indices, or only `index` if it's given. With `force`, their cached
+/// copies are thrown away first, so that they're retrieved again from scratch.
+pub fn refresh(bcx: &build::BuildCtx, index: Option<&IndexRes>, force: bool) -> Result<String> {
+    if bcx.offline {
+        bail!("can't refresh indices while offline")
+    }
+
+    let ixs = match index {
+        Some(ix) => vec![ix.clone()],
+        None => bcx.indices.values().cloned().collect::<Vec<_>>(),
+    };
+    let cache = bcx.cache()?;
+    if force {
+        for ix in &ixs {
+            cache
+                .clear_index(&ix.res)
+                .with_context(|e| format_err!("couldn't clear cached index {}: {}", ix, e))?;
+        }
+    }
+
+    let indices = cache.get_indices(
+        &ixs.iter().map(|x| x.res.clone()).collect::<Vec<_>>(),
+        Refresh::Always,
+        false,
+    );
+    let failed = ixs
+        .iter()
+        .filter(|x| !indices.indices.contains_key(*x))
+        .join(", ");
+    if !failed.is_empty() {
+        bail!("couldn't refresh {}", failed)
+    }
+
+    Ok(format!("refreshed {} indices", indices.indices.len()))
+}
+
+/// The keys a secure index lists for signing its entries, fetched fresh unless we're offline.
+fn listed_keys(bcx: &build::BuildCtx, index: &IndexRes) -> Result<Vec<PublicKey>> {
+    let cache = bcx.cache()?;
+    let refresh = if bcx.offline {
+        Refresh::Never
+    } else {
+        Refresh::Always
+    };
+    let indices = cache.get_indices(&[index.res.clone()], refresh, bcx.offline);
+    let ix = indices
+        .indices
+        .get(index)
+        .ok_or_else(|| format_err!("couldn't load index {}", index))?;
+
+    if !ix.config.index.secure {
+        bail!("index {} isn't secure, so its entries aren't signed", index)
+    }
+
+    Ok(ix.config.index.keys.clone())
+}
+
+/// The keys trusted to sign the entries of a secure index, and the keys it lists.
+pub fn keys(bcx: &build::BuildCtx, index: &IndexRes) -> Result<(Vec<PublicKey>, Vec<PublicKey>)> {
+    // Loading the index for the first time trusts its keys, so this has to come first.
+    let listed = listed_keys(bcx, index)?;
+    let trusted = TrustedKeys::load(&bcx.global_cache.keys)?;
+    let trusted = trusted.get(index).map(|x| x.to_vec()).unwrap_or_default();
+
+    Ok((trusted, listed))
+}
+
+/// Trusts keys to sign the entries of a secure index, which is how a rotation of its keys is
+/// accepted. The keys have to be named: trusting whatever the index lists right now would let
+/// whoever controls the index pick the keys.
+pub fn trust(bcx: &build::BuildCtx, index: &IndexRes, keys: Vec<PublicKey>) -> Result<String> {
+    if keys.is_empty() {
+        bail!(
+            "no keys to trust for {} were given; `elba index keys` lists the ones it has",
+            index
+        )
+    }
+
+    let mut trusted = TrustedKeys::load(&bcx.global_cache.keys)?;
+    let mut added = 0;
+    for key in keys {
+        if trusted.trust(index, key) {
+            bcx.shell.println(
+                style("Trusting").cyan(),
+                format!("key {} for index {}", key, index),
+                Verbosity::Normal,
+            );
+            added += 1;
+        }
+    }
+    trusted.save()?;
+
+    Ok(format!("trusted {} new key(s) for {}", added, index))
+}
+
+/// Stops trusting a key to sign the entries of a secure index, once it's been rotated out. With
+/// no key, the index isn't trusted at all anymore, which is what lets it stop being secure.
+pub fn untrust(bcx: &build::BuildCtx, index: &IndexRes, key: Option<&PublicKey>) -> Result<String> {
+    let mut trusted = TrustedKeys::load(&bcx.global_cache.keys)?;
+    let msg = match key {
+        Some(key) => {
+            trusted.untrust(index, key)?;
+            format!("no longer trusting key {} for {}", key, index)
+        }
+        None => {
+            if !trusted.forget(index) {
+                bail!("no keys are trusted for {}", index)
+            }
+            format!("no longer trusting any keys for {}", index)
+        }
+    };
+    trusted.save()?;
+
+    Ok(msg)
+}
+
+/// Finds the index being referred to, either by its name in the config or by its resolution.
+pub fn select_index(ixmap: &IndexMap<String, IndexRes>, index: Option<&str>) -> Result<IndexRes> {
+    match index {
+        Some(ix) => {
+            if let Some(res) = ixmap.get(ix) {
+                Ok(res.clone())
+            } else {
+                IndexRes::from_str(ix)
+                    .with_context(|e| format_err!("no index named {} in the config: {}", ix, e))
+                    .map_err(failure::Error::from)
+            }
+        }
+        None => ixmap
+            .get_index(0)
+            .map(|x| x.1.clone())
+            .ok_or_else(|| format_err!("no default index")),
+    }
+}
+
+/// Packages a project and adds it to the local directory registry at `registry`, which is set up
+/// from scratch if it doesn't exist yet.
+pub fn add_local(
+    bcx: &build::BuildCtx,
+    project: &Path,
+    registry: &Path,
+    verify: bool,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, None)?;
+    check_dependencies(&manifest)?;
+
+    let (tarball, _) = package(&project)?;
+    if verify {
+        verify_tarball(bcx, &tarball, &manifest)
+            .context(format_err!("package failed to build; refusing to add it"))?;
+    }
+
+    create_dir_all(registry.join(PACKAGES_DIR))?;
+    let config_path = registry.join("index.toml");
+    let config = if config_path.exists() {
+        IndexConfig::from_str(&fs::read_to_string(&config_path)?)?
+    } else {
+        IndexConfig::default()
+    };
+    if config.index.secure {
+        bail!(
+            "the registry at {} is secure, and elba can't sign the entries it adds",
+            registry.display()
+        )
+    }
+    let mut index = Index::new(
+        IndexRes {
+            res: DirectRes::Dir {
+                path: registry.to_path_buf(),
+            },
+        },
+        bcx.lock(registry, LockKind::Index)?,
+        config,
+    );
+
+    if let Ok(entries) = index.entries(manifest.name()) {
+        if entries.contains_key(manifest.version()) {
+            bail!(
+                "{} {} is already in the registry at {}",
+                manifest.name(),
+                manifest.version(),
+                registry.display()
+            )
+        }
+    }
+
+    let mut dependencies = vec![];
+    for (name, dep) in &manifest.dependencies {
+        let (res, req) = match dep {
+            DepReq::Registry(req) => (select_index(&bcx.indices, None)?, req.clone()),
+            DepReq::RegLong { version, index, .. } => (
+                select_index(&bcx.indices, index.as_ref().map(String::as_str))?,
+                version.clone(),
+            ),
+            // check_dependencies has already turned these away.
+            _ => unreachable!(),
+        };
+        let dep_index = if res == index.id {
+            None
         } else {
-            res.push_str(&format!(
-                "\"{}\" = \"{{ version = {}, index = {} }}\"",
-                name, ver, ir
-            ));
+            Some(dependency_name(&mut index.config, &bcx.indices, res))
+        };
+        dependencies.push(Dep {
+            name: dep.package().unwrap_or(name).clone(),
+            index: dep_index,
+            req,
+        });
+    }
+
+    let contents = fs::read(&tarball)?;
+    fs::write(
+        registry
+            .join(PACKAGES_DIR)
+            .join(tarball_name(manifest.name(), manifest.version())),
+        &contents,
+    )?;
+
+    let entry: RawEntry = IndexEntry {
+        v: ENTRY_VERSION,
+        name: manifest.name().clone(),
+        version: manifest.version().clone(),
+        dependencies,
+        yanked: false,
+        location: None,
+        cksum: Some(Checksum::of(index.config.index.checksum_fmt(), &contents)),
+        features: IndexMap::new(),
+        min_compiler: None,
+        description: manifest.package.description.clone(),
+        keywords: manifest.package.keywords.clone(),
+        categories: manifest.package.categories.clone(),
+        metadata: match serde_json::to_value(&manifest.package.metadata)? {
+            serde_json::Value::Object(metadata) => metadata,
+            _ => serde_json::Map::new(),
+        },
+    };
+
+    // Every entry goes on its own line, so we might have to end the last one first.
+    let entries_path = registry.join(manifest.name().as_normalized());
+    create_dir_all(entries_path.parent().unwrap())?;
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    if fs::read(&entries_path)
+        .map(|x| !x.is_empty() && !x.ends_with(b"\n"))
+        .unwrap_or(false)
+    {
+        line.insert(0, '\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&entries_path)?
+        .write_all(line.as_bytes())?;
+
+    fs::write(&config_path, toml::to_string_pretty(&index.config)?)?;
+
+    Ok(format!(
+        "added {} {} to {}",
+        manifest.name(),
+        manifest.version(),
+        registry.display()
+    ))
+}
+
+/// Finds the name an index goes by in the config of another index, adding it to the config if
+/// it isn't there yet.
+fn dependency_name(
+    config: &mut IndexConfig,
+    ixmap: &IndexMap<String, IndexRes>,
+    res: IndexRes,
+) -> String {
+    let deps = &mut config.index.dependencies;
+    if let Some((name, _)) = deps.iter().find(|(_, x)| **x == res) {
+        return name.clone();
+    }
+
+    // We name the index after whatever it's called in our own config, if we can.
+    let name = ixmap
+        .iter()
+        .find(|(_, x)| **x == res)
+        .map(|(name, _)| name.clone())
+        .filter(|name| !deps.contains_key(name))
+        .unwrap_or_else(|| res.to_string());
+    deps.insert(name.clone(), res);
+
+    name
+}
+
+/// Makes sure that a package has everything it needs to be put on a registry.
+fn check_publishable(manifest: &Manifest) -> Result<()> {
+    let mut missing = vec![];
+    if manifest.package.authors.is_empty() {
+        missing.push("authors");
+    }
+    if manifest.package.description.is_none() {
+        missing.push("description");
+    }
+    if manifest.package.license.is_none() {
+        missing.push("license");
+    }
+    if !missing.is_empty() {
+        bail!(
+            "the manifest is missing fields required for publishing: {}",
+            missing.iter().join(", ")
+        )
+    }
+
+    // Versions which only differ in their build metadata have the same precedence, so an index
+    // couldn't tell them apart.
+    if !manifest.version().build.is_empty() {
+        bail!(
+            "version `{}` has build metadata, which can't be published to an index",
+            manifest.version()
+        )
+    }
+
+    // The manifest checks the number of keywords and what's in them when it's parsed.
+    if let Some(keyword) = manifest.package.keywords.iter().find(|x| x.len() > 20) {
+        bail!("keyword `{}` is over 20 characters", keyword)
+    }
+    if manifest.package.categories.len() > 5 {
+        bail!("packages can't be in more than 5 categories")
+    }
+    for category in &manifest.package.categories {
+        if category.is_empty() || category.len() > 50 {
+            bail!("category `{}` has to be 1 to 50 characters long", category)
+        }
+        if !category
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            bail!(
+                "category `{}` can only contain lowercase letters, digits and hyphens",
+                category
+            )
         }
     }
 
-    Ok(res)
+    check_dependencies(manifest)
+}
+
+/// Makes sure that a package doesn't have any dependencies which only make sense on our machine.
+fn check_dependencies(manifest: &Manifest) -> Result<()> {
+    // Nobody downloading the package from the registry will have our local directories or be
+    // able to rely on a git repository staying the same.
+    for (name, dep) in &manifest.dependencies {
+        match dep {
+            DepReq::Local { .. } => bail!("dependency {} is a path dependency", name),
+            DepReq::Git { .. } => bail!("dependency {} is a git dependency", name),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure that there are no uncommitted changes in the project, if it's in a git repository.
+fn check_clean(project: &Path) -> Result<()> {
+    let repo = match Repository::discover(project) {
+        Ok(repo) => repo,
+        Err(_) => return Ok(()),
+    };
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut opts))?;
+
+    let dirty = statuses
+        .iter()
+        .filter(|x| x.status() != git2::Status::CURRENT)
+        .filter_map(|x| x.path().map(|x| x.to_owned()))
+        .collect::<Vec<_>>();
+
+    if !dirty.is_empty() {
+        bail!(
+            "the project has uncommitted changes (pass --allow-dirty to publish anyway):\n{}",
+            dirty.iter().map(|x| format!("  {}", x)).join("\n")
+        )
+    }
+
+    Ok(())
+}
+
+/// A reader which reports how much of the package has been uploaded so far.
+struct Progress<R> {
+    inner: R,
+    read: u64,
+    len: u64,
+    shell: Shell,
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+
+        self.shell.print_progress(
+            style("Uploaded").dim(),
+            format!("{}/{} KiB", self.read / 1024, self.len / 1024),
+            self.read,
+            self.len,
+            Verbosity::Normal,
+        );
+        if n > 0 && self.read == self.len {
+            self.shell.end_progress(Verbosity::Normal);
+        }
+
+        Ok(n)
+    }
 }
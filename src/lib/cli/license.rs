@@ -0,0 +1,183 @@
+//! Reporting the licenses of the packages a project depends on.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use semver::Version;
+use serde::Serialize;
+
+use super::build::{solve_local, BuildCtx};
+use crate::{
+    package::Name,
+    util::{error::Result, shell::Verbosity},
+};
+
+/// The packages a project depends on, grouped by their licenses.
+#[derive(Debug, Clone, Serialize)]
+pub struct LicenseReport {
+    pub groups: Vec<LicenseGroup>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LicenseGroup {
+    /// The license expression from the packages' manifests, if they have one.
+    pub license: Option<String>,
+    pub packages: Vec<LicensedPackage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LicensedPackage {
+    pub name: Name,
+    pub version: Version,
+    /// The files in the root of the package which look like they have license text in them.
+    pub license_files: Vec<PathBuf>,
+}
+
+impl LicenseReport {
+    /// Every package whose license is in the deny list: that is, every package which has a license
+    /// and can't be used under any of the alternatives its license gives without agreeing to one
+    /// of the denied licenses.
+    pub fn denied<'a>(&'a self, deny: &'a [String]) -> impl Iterator<Item = &'a LicensedPackage> {
+        self.groups
+            .iter()
+            .filter(move |group| {
+                group
+                    .license
+                    .as_ref()
+                    .map(|license| is_denied(license, deny))
+                    .unwrap_or(false)
+            })
+            .flat_map(|group| group.packages.iter())
+    }
+}
+
+/// Whether a license expression like `MIT OR (Apache-2.0 AND BSD-3-Clause)` is ruled out by a
+/// list of denied licenses. An expression is only ruled out if every alternative separated with
+/// `OR` has a denied license in it.
+fn is_denied(license: &str, deny: &[String]) -> bool {
+    license.split(" OR ").all(|alt| {
+        alt.split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '/')
+            .filter(|id| !id.is_empty() && *id != "AND" && *id != "WITH")
+            .any(|id| deny.iter().any(|denied| denied.eq_ignore_ascii_case(id)))
+    })
+}
+
+/// The files in the root of a package which look like licenses.
+fn license_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut res = vec![];
+    for entry in fs::read_dir(root)
+        .with_context(|e| format_err!("couldn't read {}:\n{}", root.display(), e))?
+    {
+        let path = entry?.path();
+        let is_license = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_uppercase())
+            .map(|x| {
+                ["LICENSE", "LICENCE", "COPYING", "UNLICENSE"]
+                    .iter()
+                    .any(|prefix| x.starts_with(prefix))
+            })
+            .unwrap_or(false);
+        if is_license && path.is_file() {
+            res.push(path);
+        }
+    }
+    res.sort();
+
+    Ok(res)
+}
+
+/// Retrieves every package a project depends on and reads its license, both from its manifest
+/// and from the license files which come with it.
+pub fn licenses(ctx: &BuildCtx, project: &Path) -> Result<LicenseReport> {
+    let mut report = None;
+
+    solve_local(ctx, project, 2, None, |_, mut retriever, solve| {
+        ctx.shell.println(
+            style("[2/2]").dim().bold(),
+            "Reading licenses...",
+            Verbosity::Quiet,
+        );
+
+        let sources = retriever
+            .retrieve_packages(&solve, ctx.threads)
+            .context(format_err!("package retrieval failed"))?;
+        drop(retriever);
+
+        let mut groups: IndexMap<Option<String>, Vec<LicensedPackage>> = IndexMap::new();
+        // The project itself is at the root, and it isn't one of its own dependencies.
+        for ix in sources.inner.node_indices().skip(1) {
+            let source = &sources[ix];
+            let license = source.meta().package.license.clone();
+            groups
+                .entry(license)
+                .or_insert_with(Vec::new)
+                .push(LicensedPackage {
+                    name: source.meta().name().clone(),
+                    version: source.meta().version().clone(),
+                    license_files: license_files(source.path())?,
+                });
+        }
+
+        // Packages without a license go last, since those are the ones which need looking into.
+        let groups = groups
+            .into_iter()
+            .sorted_by(|a, b| match (&a.0, &b.0) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            })
+            .map(|(license, mut packages)| {
+                packages.sort_by(|a, b| a.name.cmp(&b.name));
+                LicenseGroup { license, packages }
+            })
+            .collect();
+
+        report = Some(LicenseReport { groups });
+
+        Ok("".to_string())
+    })?;
+
+    Ok(report.unwrap())
+}
+
+/// Fails if any of the packages in a report has a denied license.
+pub fn check_denied(report: &LicenseReport, deny: &[String]) -> Result<()> {
+    let denied = report.denied(deny).collect::<Vec<_>>();
+    if !denied.is_empty() {
+        bail!(
+            "packages with denied licenses are depended on: {}",
+            denied
+                .iter()
+                .map(|pkg| format!("{} {}", pkg.name, pkg.version))
+                .join(", ")
+        )
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn license_deny_expressions() {
+        let deny = vec!["GPL-3.0".to_string(), "agpl-3.0".to_string()];
+
+        assert!(is_denied("GPL-3.0", &deny));
+        assert!(is_denied("AGPL-3.0", &deny));
+        assert!(is_denied("MIT AND GPL-3.0", &deny));
+        assert!(is_denied("GPL-3.0 OR AGPL-3.0", &deny));
+        assert!(is_denied("(MIT AND GPL-3.0) OR AGPL-3.0", &deny));
+        assert!(!is_denied("MIT", &deny));
+        assert!(!is_denied("MIT OR GPL-3.0", &deny));
+        assert!(!is_denied("GPL-3.0-only", &deny));
+    }
+}
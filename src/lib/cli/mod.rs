@@ -1,5 +1,19 @@
 //! Handlers for all of the command-line actions of the binary.
 
+pub mod audit;
 pub mod build;
+pub mod cache;
+pub mod clean;
+pub mod config;
+pub mod export;
+pub mod graph;
 pub mod index;
+pub mod license;
 pub mod new;
+pub mod nix;
+pub mod paths;
+pub mod policy;
+pub mod sbom;
+pub mod script;
+pub mod self_update;
+pub mod tool;
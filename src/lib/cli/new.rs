@@ -1,20 +1,35 @@
 use crate::{
-    package::Name,
-    util::{error::Result, git},
+    cli::{build::BuildCtx, index},
+    package::{ipkg::Ipkg, workspace::Workspace, Name},
+    util::{civil_from_days, error::Result, git, normalize_path},
 };
 use failure::{bail, format_err, ResultExt};
 use inflector::Inflector;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use toml_edit;
 
 pub struct NewCtx {
     pub path: PathBuf,
     pub name: Name,
     // Tuple of name and email.
     pub author: Option<(String, String)>,
+    pub description: Option<String>,
+    /// The SPDX identifier of the license of the package. If it's one of `LICENSES`, a LICENSE
+    /// file is written for it too.
+    pub license: Option<String>,
+    pub lib: bool,
     pub bin: bool,
     pub git: bool,
 }
 
+/// The licenses which `elba new` can write a LICENSE file for.
+pub const LICENSES: &[&str] = &["MIT", "ISC", "BSD-3-Clause", "Unlicense"];
+
 pub fn new(ctx: NewCtx) -> Result<String> {
     let path = &ctx.path;
     if fs::metadata(path).is_ok() {
@@ -30,27 +45,139 @@ pub fn new(ctx: NewCtx) -> Result<String> {
     init(ctx)
 }
 
+/// Creates a new workspace with no members, which packages created under it join.
+pub fn new_workspace(path: &Path, git: bool) -> Result<String> {
+    if fs::metadata(path).is_ok() {
+        bail!("destination `{}` already exists", path.display())
+    }
+
+    fs::create_dir_all(path).context(format_err!("could not create dir {}", path.display()))?;
+    fs::write(
+        path.join("elba.toml"),
+        r#"# Packages created in this directory with `elba new` are added here.
+[workspace]
+"#,
+    )?;
+
+    if git {
+        write_gitignore(path)?;
+        git::init(path)?;
+    }
+
+    Ok(format!("new workspace created at {}", path.display()))
+}
+
+/// Creates a manifest in `path` equivalent to the ipkg file at `ipkg_path`. The packages it
+/// depends on are looked up by name in every index in `bcx`.
+pub fn init_from_ipkg(bcx: &BuildCtx, path: &Path, ipkg_path: &Path, git: bool) -> Result<String> {
+    if path.join("elba.toml").exists() {
+        bail!("elba project already exists in this directory")
+    }
+
+    let contents = fs::read_to_string(ipkg_path).context(format_err!(
+        "failed to read ipkg file ({})",
+        ipkg_path.display()
+    ))?;
+    let ipkg = Ipkg::from_str(&contents).context(format_err!("while parsing ipkg file"))?;
+
+    let cache = bcx.cache()?;
+    let ixs = bcx
+        .indices
+        .values()
+        .cloned()
+        .map(|x| x.res)
+        .collect::<Vec<_>>();
+    let indices = cache.get_indices(&ixs, bcx.refresh, bcx.offline);
+
+    let manifest = ipkg.to_manifest(|pkg| {
+        let pkg = pkg.to_ascii_lowercase().replace('_', "-");
+        Ok(indices
+            .search(&pkg, None, None)?
+            .iter()
+            .filter(|hit| hit.name.normalized_name() == pkg)
+            .map(|hit| index::dependency_line(hit, &bcx.indices))
+            .collect())
+    })?;
+
+    fs::write(path.join("elba.toml"), manifest)?;
+
+    if !path.join(".git").exists() && git {
+        write_gitignore(path)?;
+        git::init(path)?;
+    }
+
+    Ok(format!(
+        "new package created at {} from {}",
+        path.display(),
+        ipkg_path.display()
+    ))
+}
+
+/// Adds a package to the `[workspace]` section of the workspace root at `root`.
+fn join_workspace(root: &Path, name: &Name, path: &Path) -> Result<()> {
+    let toml_path = root.join("elba.toml");
+    let contents = fs::read_to_string(&toml_path).context(format_err!(
+        "failed to read manifest file ({})",
+        toml_path.display()
+    ))?;
+    let mut doc = contents
+        .parse::<toml_edit::Document>()
+        .with_context(|e| format!("invalid manifest toml format: {}", e))?;
+    let path = normalize_path(path);
+    let sub = path
+        .strip_prefix(root)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .replace("\\", "/");
+    doc["workspace"][name.as_str()] = toml_edit::value(sub);
+
+    fs::write(&toml_path, doc.to_string()).context(format_err!(
+        "failed to write manifest file ({})",
+        toml_path.display()
+    ))?;
+
+    Ok(())
+}
+
+fn write_gitignore(path: &Path) -> Result<()> {
+    if !path.join(".gitignore").exists() {
+        fs::write(
+            path.join(".gitignore"),
+            r#"/target
+*.ibc
+*.o
+.dirlock
+/.elba/paths.json
+"#,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn init(ctx: NewCtx) -> Result<String> {
     let name = &ctx.name;
-    let author = if let Some((author, email)) = ctx.author {
-        format!("{} <{}>", author, email)
+    let author = if let Some((author, email)) = &ctx.author {
+        format!("{:?}", format!("{} <{}>", author, email))
     } else {
         "".to_string()
     };
     let path = &ctx.path;
 
-    let target = if ctx.bin {
-        format!(
-            r#"[[targets.bin]]
-path = "src"
-name = "{}"
-main = "Main"
+    let mut package = format!(
+        "name = \"{}\"\nversion = \"0.1.0\"\nauthors = [{}]\n",
+        name, author
+    );
+    if let Some(description) = &ctx.description {
+        package.push_str(&format!("description = {:?}\n", description));
+    }
+    if let Some(license) = &ctx.license {
+        package.push_str(&format!("license = {:?}\n", license));
+    }
 
-"#,
-            name.name()
-        )
-    } else {
-        format!(
+    let mut target = String::new();
+    if ctx.lib {
+        target.push_str(&format!(
             r#"[targets.lib]
 path = "src"
 mods = [
@@ -59,7 +186,34 @@ mods = [
 
 "#,
             name.name().to_pascal_case(),
-        )
+        ));
+    }
+    if ctx.bin {
+        target.push_str(&format!(
+            r#"[[targets.bin]]
+path = "src"
+name = "{}"
+main = "Main"
+
+"#,
+            name.name()
+        ));
+    }
+
+    // A package made inside of a workspace becomes one of its members.
+    let workspace = match Workspace::enclosing_root(path)? {
+        Some((root, info)) => {
+            if let Some(sub) = info.members.get(name) {
+                bail!(
+                    "the workspace at {} already has a member named {} (at {})",
+                    root.display(),
+                    name,
+                    sub.0.display()
+                )
+            }
+            Some(root)
+        }
+        None => None,
     };
 
     if !ctx.path.join("elba.toml").exists() {
@@ -67,14 +221,11 @@ mods = [
             &ctx.path.join("elba.toml"),
             format!(
                 r#"[package]
-name = "{}"
-version = "0.1.0"
-authors = [{}]
-
+{}
 [dependencies]
 
 {}"#,
-                name, author, target
+                package, target
             )
             .as_bytes(),
         )?;
@@ -87,7 +238,7 @@ authors = [{}]
 
     let lib_path = path.join(format!("src/{}.idr", name.name().to_pascal_case()));
 
-    if !ctx.bin && !lib_path.exists() {
+    if ctx.lib && !lib_path.exists() {
         fs::write(
             lib_path,
             format!(
@@ -101,7 +252,8 @@ hello = do
             )
             .as_bytes(),
         )?;
-    } else if !path.join("src/Main.idr").exists() {
+    }
+    if ctx.bin && !path.join("src/Main.idr").exists() {
         fs::write(
             path.join("src/Main.idr"),
             r#"module Main
@@ -113,24 +265,168 @@ main = do
         )?;
     }
 
-    if !path.join(".git").exists() && !path.join(".gitignore").exists() && ctx.git {
-        fs::write(
-            path.join(".gitignore"),
-            r#"/target
-*.ibc
-*.o
-.dirlock
-"#,
-        )?;
+    if let Some(license) = &ctx.license {
+        let holder = match &ctx.author {
+            Some((author, _)) => author.clone(),
+            None => format!("the authors of {}", name),
+        };
+        if let Some(text) = license_text(license, &holder) {
+            if !path.join("LICENSE").exists() {
+                fs::write(path.join("LICENSE"), text)?;
+            }
+        }
+    }
+
+    let kind = match (ctx.lib, ctx.bin) {
+        (true, true) => "library and binary targets",
+        (true, false) => "library target",
+        _ => "binary target",
+    };
+
+    // Members of a workspace are left to the version control of the workspace.
+    if let Some(root) = &workspace {
+        join_workspace(root, name, path)?;
+
+        return Ok(format!(
+            "new package with {} created at {} and added to the workspace at {}",
+            kind,
+            path.display(),
+            root.display()
+        ));
     }
 
     if !path.join(".git").exists() && ctx.git {
+        write_gitignore(path)?;
         git::init(&ctx.path)?;
     }
 
     Ok(format!(
-        "new package with {} target created at {}",
-        if ctx.bin { "binary" } else { "library" },
+        "new package with {} created at {}",
+        kind,
         path.display()
     ))
 }
+
+/// The year it is right now, for the copyright line of a license.
+fn current_year() -> i64 {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64 / 86400)
+        .unwrap_or(0);
+
+    civil_from_days(days).0
+}
+
+/// The text of the LICENSE file for one of `LICENSES`.
+fn license_text(license: &str, holder: &str) -> Option<String> {
+    let copyright = format!("Copyright (c) {} {}", current_year(), holder);
+
+    let text = match license {
+        "MIT" => format!(
+            r#"MIT License
+
+{}
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+"#,
+            copyright
+        ),
+        "ISC" => format!(
+            r#"ISC License
+
+{}
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+"#,
+            copyright
+        ),
+        "BSD-3-Clause" => format!(
+            r#"BSD 3-Clause License
+
+{}
+All rights reserved.
+
+Redistribution and use in source and binary forms, with or without
+modification, are permitted provided that the following conditions are met:
+
+1. Redistributions of source code must retain the above copyright notice, this
+   list of conditions and the following disclaimer.
+
+2. Redistributions in binary form must reproduce the above copyright notice,
+   this list of conditions and the following disclaimer in the documentation
+   and/or other materials provided with the distribution.
+
+3. Neither the name of the copyright holder nor the names of its
+   contributors may be used to endorse or promote products derived from
+   this software without specific prior written permission.
+
+THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+"#,
+            copyright
+        ),
+        "Unlicense" => r#"This is free and unencumbered software released into the public domain.
+
+Anyone is free to copy, modify, publish, use, compile, sell, or
+distribute this software, either in source code form or as a compiled
+binary, for any purpose, commercial or non-commercial, and by any
+means.
+
+In jurisdictions that recognize copyright laws, the author or authors
+of this software dedicate any and all copyright interest in the
+software to the public domain. We make this dedication for the benefit
+of the public at large and to the detriment of our heirs and
+successors. We intend this dedication to be an overt act of
+relinquishment in perpetuity of all present and future rights to this
+software under copyright law.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR ANY CLAIM, DAMAGES OR
+OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE,
+ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
+OTHER DEALINGS IN THE SOFTWARE.
+
+For more information, please refer to <https://unlicense.org>
+"#
+        .to_string(),
+        _ => return None,
+    };
+
+    Some(text)
+}
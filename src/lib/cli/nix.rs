@@ -0,0 +1,428 @@
+//! Generating Nix expressions which build a project from its lockfile.
+//!
+//! The expression has a fixed-output derivation for every package the project depends on (and
+//! every index they come from), pinned by the checksums and commits in the lockfile. Its builder
+//! lays them out the way elba's cache would have them and then runs elba in offline mode, so the
+//! build never goes near the network and only ever sees what the lockfile says it should.
+
+use std::{
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use failure::{bail, format_err, ResultExt};
+use git2::Repository;
+use indexmap::{IndexMap, IndexSet};
+use serde::Serialize;
+use url::Url;
+
+use super::build::{find_manifest, locked_packages, BuildCtx};
+use crate::{
+    package::{
+        lockfile::LockedPkg, manifest::Manifest, workspace::Workspace, Checksum, ChecksumFmt,
+    },
+    remote::resolution::{DirectRes, IndexRes, Resolution},
+    retrieve::cache::Cache,
+    util::{error::Result, normalize_path, relative_path},
+};
+
+/// Something the builder copies into elba's cache before building.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NixSource {
+    /// The name of its directory in the cache.
+    dir: String,
+    fetch: NixFetch,
+}
+
+/// How a source gets into the Nix store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NixFetch {
+    /// A tarball, which the builder unpacks.
+    Url { url: String, cksum: Checksum },
+    /// A git repository at a commit.
+    Git { url: String, rev: String },
+}
+
+impl NixFetch {
+    fn is_tarball(&self) -> bool {
+        match self {
+            NixFetch::Url { .. } => true,
+            NixFetch::Git { .. } => false,
+        }
+    }
+
+    fn expr(&self) -> String {
+        match self {
+            NixFetch::Url { url, cksum } => format!(
+                "pkgs.fetchurl {{ url = {}; {} = {}; }}",
+                nix_str(url),
+                cksum.fmt,
+                nix_str(&cksum.hash)
+            ),
+            NixFetch::Git { url, rev } => format!(
+                "builtins.fetchGit {{ url = {}; rev = {}; }}",
+                nix_str(url),
+                nix_str(rev)
+            ),
+        }
+    }
+}
+
+/// The config the builder runs elba with, so that it knows the project's indices by the same
+/// names.
+#[derive(Debug, Serialize)]
+struct NixConfig<'a> {
+    indices: &'a IndexMap<String, IndexRes>,
+}
+
+/// Quotes a string for Nix.
+fn nix_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => res.push_str("\\$"),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+
+    res
+}
+
+/// A path relative to the generated expression, which lives in the project's root, as a Nix path.
+fn nix_path(rel: &Path) -> String {
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    if rel == "." {
+        "./.".to_string()
+    } else {
+        format!("./{}", rel)
+    }
+}
+
+/// How a tarball is fetched: Nix can only check the checksums of the formats it knows.
+fn fetch_tar(url: &Url, cksum: Option<Checksum>, what: &str) -> Result<NixFetch> {
+    let cksum = match cksum {
+        Some(cksum) => cksum,
+        None => bail!(
+            "{} has no checksum, so it can't be fetched by Nix; run `elba update` to lock it with one",
+            what
+        ),
+    };
+    if cksum.fmt == ChecksumFmt::Blake3 {
+        bail!(
+            "{} has a blake3 checksum, which Nix can't check; only sha256 and sha512 are supported",
+            what
+        )
+    }
+
+    Ok(NixFetch::Url {
+        url: url.to_string(),
+        cksum,
+    })
+}
+
+/// Where a locked package goes in the cache and how to fetch it, or `None` if it's a directory
+/// which comes along with the project.
+fn package_source(pkg: &LockedPkg, lock_root: &Path) -> Result<Option<NixSource>> {
+    let what = format!("{} {}", pkg.sum.name(), pkg.sum.version());
+    let locked_cksum = pkg
+        .checksum
+        .as_ref()
+        .and_then(|x| x.parse::<Checksum>().ok());
+
+    // Packages from indices are found offline by where their index says they are, without the
+    // tag of a git repository.
+    let (loc, include_tag) = match pkg.sum.resolution() {
+        Resolution::Index(_) => match &pkg.source {
+            Some(source) => (source.clone(), false),
+            None => bail!(
+                "elba.lock doesn't say where {} came from; run `elba lock` to record it",
+                what
+            ),
+        },
+        Resolution::Direct(direct) => (direct.clone(), true),
+    };
+
+    let fetch = match &loc {
+        DirectRes::Dir { path } => {
+            let inside = relative_path(path, lock_root)
+                .map(|rel| rel.components().all(|c| c != Component::ParentDir))
+                .unwrap_or(false);
+            if !inside {
+                bail!(
+                    "{} is in {}, outside of the project, so Nix can't build with it; \
+                     move it into the project or depend on a published version",
+                    what,
+                    path.display()
+                )
+            }
+            return Ok(None);
+        }
+        DirectRes::Tar { url, cksum } => fetch_tar(url, cksum.clone().or(locked_cksum), &what)?,
+        DirectRes::Git { repo, tag } => {
+            // elba only builds direct git dependencies from a checkout with its history, which
+            // Nix can't pin by checksum.
+            if include_tag {
+                bail!(
+                    "{} is a git dependency, which Nix can't build offline yet; \
+                     depend on a version from an index instead",
+                    what
+                )
+            }
+            NixFetch::Git {
+                url: repo.to_string(),
+                rev: tag.clone(),
+            }
+        }
+    };
+
+    Ok(Some(NixSource {
+        dir: Cache::get_source_dir(&loc, include_tag),
+        fetch,
+    }))
+}
+
+/// Where an index goes in the cache and how to fetch it, pinned to the commit the cached copy of
+/// it is at, which is what the lockfile was resolved against.
+fn index_source(ctx: &BuildCtx, index: &DirectRes) -> Result<NixSource> {
+    let dir = Cache::get_source_dir(index, false);
+    let fetch = match index {
+        DirectRes::Git { repo, .. } => {
+            let path = ctx.global_cache.indices.join(&dir);
+            let rev = Repository::open(&path)
+                .and_then(|repo| Ok(repo.head()?.peel_to_commit()?.id().to_string()))
+                .with_context(|e| {
+                    format_err!(
+                        "couldn't find the commit of index {} in the cache; \
+                         run `elba update` to retrieve it:\n{}",
+                        index,
+                        e
+                    )
+                })?;
+            NixFetch::Git {
+                url: repo.to_string(),
+                rev,
+            }
+        }
+        DirectRes::Tar { url, cksum } => {
+            fetch_tar(url, cksum.clone(), &format!("index {}", index))?
+        }
+        DirectRes::Dir { path } => bail!(
+            "index {} is a local directory, so Nix can't build with it",
+            path.display()
+        ),
+    };
+
+    Ok(NixSource { dir, fetch })
+}
+
+/// Renders the list of sources for a part of the cache.
+fn render_sources(res: &mut String, name: &str, sources: &[NixSource]) {
+    writeln!(res, "  {} = [", name).unwrap();
+    for source in sources {
+        writeln!(
+            res,
+            "    {{ dir = {}; tarball = {}; src = {}; }}",
+            nix_str(&source.dir),
+            source.fetch.is_tarball(),
+            source.fetch.expr()
+        )
+        .unwrap();
+    }
+    res.push_str("  ];\n\n");
+}
+
+/// Renders the whole expression.
+///
+/// `src` is the root of the lockfile relative to the project and `member` is the project relative
+/// to the root of the lockfile; they're only different in workspaces.
+fn render(
+    manifest: &Manifest,
+    src: &Path,
+    member: &Path,
+    indices: &[NixSource],
+    sources: &[NixSource],
+    config: &str,
+) -> String {
+    let mut res = String::new();
+    res.push_str(
+        "# Generated by `elba nix-gen` from elba.lock; run it again when the lockfile changes.\n",
+    );
+    res.push_str("{ pkgs ? import <nixpkgs> {}, elba ? pkgs.elba, idris ? pkgs.idris }:\n\n");
+    res.push_str("let\n");
+    render_sources(&mut res, "indices", indices);
+    render_sources(&mut res, "sources", sources);
+    writeln!(
+        res,
+        "  config = pkgs.writeText \"elba-config.toml\" {};\n",
+        nix_str(config)
+    )
+    .unwrap();
+    res.push_str(
+        "  unpack = kind: { dir, tarball, src }:\n    \
+         let to = \"$ELBA_CACHE_DIR/${kind}/${dir}\"; in\n    \
+         if tarball\n    \
+         then \"mkdir -p ${to} && tar -xzf ${src} -C ${to}\\n\"\n    \
+         else \"cp -r ${src} ${to}\\n\";\n",
+    );
+    res.push_str("in\n");
+    res.push_str("pkgs.stdenv.mkDerivation {\n");
+    writeln!(
+        res,
+        "  pname = {};",
+        nix_str(&format!(
+            "{}-{}",
+            manifest.name().group(),
+            manifest.name().name()
+        ))
+    )
+    .unwrap();
+    writeln!(
+        res,
+        "  version = {};",
+        nix_str(&manifest.version().to_string())
+    )
+    .unwrap();
+    writeln!(res, "  src = {};", nix_path(src)).unwrap();
+    res.push_str("  nativeBuildInputs = [ elba idris ];\n\n");
+    res.push_str(
+        "  configurePhase = ''\n    \
+         export HOME=$TMPDIR\n    \
+         export ELBA_CACHE_DIR=$TMPDIR/elba-cache\n    \
+         mkdir -p $HOME/.elba $ELBA_CACHE_DIR/indices $ELBA_CACHE_DIR/src\n    \
+         cp ${config} $HOME/.elba/config.toml\n    \
+         ${pkgs.lib.concatMapStrings (unpack \"indices\") indices}\n    \
+         ${pkgs.lib.concatMapStrings (unpack \"src\") sources}\n    \
+         chmod -R u+w $ELBA_CACHE_DIR\n  \
+         '';\n\n",
+    );
+    let member = member.to_string_lossy().replace('\\', "/");
+    if member == "." {
+        res.push_str("  buildPhase = ''\n    elba build --offline\n  '';\n\n");
+    } else {
+        writeln!(
+            res,
+            "  buildPhase = ''\n    (cd {} && elba build --offline)\n  '';\n",
+            member
+        )
+        .unwrap();
+    }
+    res.push_str(
+        "  installPhase = ''\n    \
+         mkdir -p $out\n    \
+         for dir in bin lib; do\n      \
+         if [ -d target/$dir ]; then cp -r target/$dir $out/; fi\n    \
+         done\n  \
+         '';\n",
+    );
+    res.push('}');
+
+    res
+}
+
+/// Generates a Nix expression which builds a project with exactly the packages in its lockfile.
+/// The expression is meant to go in the root of the project, since that's what its paths are
+/// relative to.
+pub fn nix_gen(ctx: &BuildCtx, project: &Path) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let lock_root = match Workspace::find(&project)? {
+        Some(ws) => ws.root,
+        None => project.clone(),
+    };
+    let (project, lock_root) = (normalize_path(&project), normalize_path(&lock_root));
+
+    let packages = locked_packages(ctx, &project)?;
+
+    let mut index_reses = IndexSet::new();
+    let mut sources: Vec<NixSource> = vec![];
+    // The project itself comes first, and it's already in `src`.
+    for pkg in packages.iter().skip(1) {
+        if let Resolution::Index(ir) = pkg.sum.resolution() {
+            index_reses.insert(ir.res.clone());
+        }
+        if let Some(source) = package_source(pkg, &lock_root)? {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+
+    let indices = index_reses
+        .iter()
+        .map(|index| index_source(ctx, index))
+        .collect::<Result<Vec<_>>>()?;
+
+    let config = toml::to_string(&NixConfig {
+        indices: &ctx.indices,
+    })?;
+
+    let src = relative_path(&lock_root, &project).unwrap_or_else(|| PathBuf::from("."));
+    let member = relative_path(&project, &lock_root).unwrap_or_else(|| PathBuf::from("."));
+
+    Ok(render(
+        &manifest, &src, &member, &indices, &sources, &config,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn nix_str_escapes() {
+        assert_eq!(nix_str("plain"), "\"plain\"");
+        assert_eq!(nix_str("a \"b\" \\ c"), "\"a \\\"b\\\" \\\\ c\"");
+        assert_eq!(nix_str("${x} $y\n"), "\"\\${x} $y\\n\"");
+    }
+
+    #[test]
+    fn nix_tarball_source() {
+        let lockfile = r#"
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+source = "tar+https://elba.io/pkg/terminator/one/0.1.4.tar.gz#sha256=b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c"
+        "#;
+        let lf = crate::package::lockfile::LockfileToml::from_str(lockfile).unwrap();
+        let pkg = lf.packages.get_index(0).unwrap();
+
+        let source = package_source(pkg, Path::new("/here")).unwrap().unwrap();
+        assert_eq!(
+            source.dir,
+            Cache::get_source_dir(pkg.source.as_ref().unwrap(), false)
+        );
+        assert_eq!(
+            source.fetch.expr(),
+            "pkgs.fetchurl { url = \"https://elba.io/pkg/terminator/one/0.1.4.tar.gz\"; \
+             sha256 = \"b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c\"; }"
+        );
+    }
+
+    #[test]
+    fn nix_rejects_outside_dirs() {
+        let lockfile = r#"
+[[packages]]
+id = "good/inside@dir+/here/there"
+version = "1.0.0"
+
+[[packages]]
+id = "bad/outside@dir+/elsewhere"
+version = "1.0.0"
+        "#;
+        let lf = crate::package::lockfile::LockfileToml::from_str(lockfile).unwrap();
+        let root = Path::new("/here");
+
+        let inside = lf.packages.get_index(0).unwrap();
+        assert_eq!(package_source(inside, root).unwrap(), None);
+        let outside = lf.packages.get_index(1).unwrap();
+        assert!(package_source(outside, root).is_err());
+    }
+}
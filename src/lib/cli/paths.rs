@@ -0,0 +1,216 @@
+//! The import paths of a project, which elba keeps in `.elba/paths.json` for editors and language
+//! servers.
+//!
+//! The file is written every time the dependencies of a project are resolved, and the directories
+//! of the dependencies' ibc files are filled in once they've been built. Tools can read it to run
+//! the compiler on a project the way elba would, without having to understand manifests or the
+//! global cache themselves.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use petgraph::graph::NodeIndex;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::build::{target_metadata, BuildCtx, TargetMetadata};
+use crate::{
+    build::context::{Compiler, CompilerFlavor},
+    package::{manifest::Manifest, Name, Summary},
+    util::{error::Result, fs::write_atomic, graph::Graph},
+};
+
+/// Where the import paths of a project go, relative to its root.
+pub const PATHS_FILE: &str = ".elba/paths.json";
+
+/// The version of the layout of the import paths file, which goes up under the same rules as
+/// `METADATA_VERSION`.
+pub const PATHS_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportPaths {
+    pub version: u32,
+    /// The directory the project's manifest is in.
+    pub root: PathBuf,
+    pub compiler: CompilerInfo,
+    /// The options passed to the compiler for every target, on top of the target's own.
+    pub idris_opts: Vec<String>,
+    pub targets: Vec<TargetMetadata>,
+    /// The packages the project depends on directly, which are the ones its modules can import.
+    pub dependencies: Vec<DepPaths>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompilerInfo {
+    pub path: String,
+    /// Either `idris1` or `idris2`, if the compiler could be run to find out which it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepPaths {
+    pub name: Name,
+    pub version: Version,
+    /// Where the package came from.
+    pub resolution: String,
+    /// The directory of the package's ibc files, once it's been built. Idris 1 takes it with
+    /// `-i`, and Idris 2 in `BLODWEN_PATH`.
+    pub ibc: Option<PathBuf>,
+}
+
+impl DepPaths {
+    fn new(sum: &Summary, ibc: Option<PathBuf>) -> Self {
+        DepPaths {
+            name: sum.name().clone(),
+            version: sum.version().clone(),
+            resolution: sum.resolution().to_string(),
+            ibc,
+        }
+    }
+
+    fn same_package(&self, other: &DepPaths) -> bool {
+        self.name == other.name
+            && self.version == other.version
+            && self.resolution == other.resolution
+    }
+}
+
+/// Writes the import paths of a project whose dependencies have just been resolved into `solve`.
+/// Dependencies which haven't changed since the file was last written keep the ibc directories
+/// they had, as long as those are still there.
+pub fn write_paths(
+    ctx: &BuildCtx,
+    project: &Path,
+    manifest: &Manifest,
+    compiler: Option<&Compiler>,
+    solve: &Graph<Summary>,
+) -> Result<()> {
+    let old = read_deps(project);
+    let dependencies = solve
+        .children(NodeIndex::new(0))
+        .map(|(_, sum)| {
+            let mut dep = DepPaths::new(sum, None);
+            dep.ibc = old
+                .iter()
+                .find(|x| x.same_package(&dep))
+                .and_then(|x| x.ibc.clone())
+                .filter(|x| x.exists());
+            dep
+        })
+        .collect();
+
+    let paths = ImportPaths {
+        version: PATHS_VERSION,
+        root: project.to_path_buf(),
+        compiler: CompilerInfo {
+            path: ctx.compiler.clone(),
+            flavor: compiler.map(|x| match x.flavor() {
+                CompilerFlavor::Idris1 => "idris1",
+                CompilerFlavor::Idris2 => "idris2",
+            }),
+        },
+        idris_opts: ctx.opts.clone(),
+        targets: target_metadata(project, manifest)?,
+        dependencies,
+    };
+
+    let file = project.join(PATHS_FILE);
+    fs::create_dir_all(file.parent().unwrap())?;
+    write_atomic(&file, &serde_json::to_string_pretty(&paths)?)
+}
+
+/// Fills in where the ibc files of the dependencies of a project went once they've been built.
+pub fn record_ibcs(project: &Path, ibcs: &[(Summary, PathBuf)]) -> Result<()> {
+    let file = project.join(PATHS_FILE);
+    let mut paths: Value = match fs::read(&file) {
+        Ok(contents) => serde_json::from_slice(&contents)?,
+        // Nothing's been resolved which could use them.
+        Err(_) => return Ok(()),
+    };
+
+    let mut changed = false;
+    if let Some(deps) = paths["dependencies"].as_array_mut() {
+        for entry in deps {
+            let dep: DepPaths = match serde_json::from_value(entry.clone()) {
+                Ok(dep) => dep,
+                Err(_) => continue,
+            };
+            let built = ibcs
+                .iter()
+                .find(|(sum, _)| DepPaths::new(sum, None).same_package(&dep));
+            if let Some((_, ibc)) = built {
+                if dep.ibc.as_ref() != Some(ibc) {
+                    entry["ibc"] = serde_json::to_value(ibc)?;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    if changed {
+        write_atomic(&file, &serde_json::to_string_pretty(&paths)?)?;
+    }
+
+    Ok(())
+}
+
+/// The dependencies in the import paths file of a project, if it has one we can make sense of.
+fn read_deps(project: &Path) -> Vec<DepPaths> {
+    #[derive(Deserialize)]
+    struct Deps {
+        dependencies: Vec<DepPaths>,
+    }
+
+    fs::read(project.join(PATHS_FILE))
+        .ok()
+        .and_then(|x| serde_json::from_slice::<Deps>(&x).ok())
+        .map(|x| x.dependencies)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        package::PackageId,
+        remote::resolution::{DirectRes, Resolution},
+    };
+    use std::str::FromStr;
+
+    fn summary(name: &str, version: &str) -> Summary {
+        let res = Resolution::Direct(DirectRes::Dir {
+            path: PathBuf::from("/deps").join(name),
+        });
+        Summary::new(
+            PackageId::new(Name::from_str(name).unwrap(), res),
+            Version::parse(version).unwrap(),
+        )
+    }
+
+    #[test]
+    fn paths_record_ibcs() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let a = summary("test/a", "1.0.0");
+        let b = summary("test/b", "0.1.0");
+        let deps = vec![DepPaths::new(&a, None), DepPaths::new(&b, None)];
+        let paths = serde_json::json!({ "version": PATHS_VERSION, "dependencies": deps });
+        fs::create_dir_all(tmp.path().join(".elba")).unwrap();
+        fs::write(tmp.path().join(PATHS_FILE), paths.to_string()).unwrap();
+
+        // Other versions of the same package aren't the dependency that was built.
+        let ibcs = vec![
+            (a.clone(), tmp.path().join("build/a")),
+            (summary("test/b", "0.2.0"), tmp.path().join("build/b")),
+        ];
+        record_ibcs(tmp.path(), &ibcs).unwrap();
+
+        let deps = read_deps(tmp.path());
+        assert_eq!(deps[0].ibc, Some(tmp.path().join("build/a")));
+        assert_eq!(deps[1].ibc, None);
+        assert!(record_ibcs(&tmp.path().join("nowhere"), &ibcs).is_ok());
+    }
+}
@@ -0,0 +1,253 @@
+//! Enforcing the policy in the configuration on the dependencies of a project.
+//!
+//! Every time the dependencies of a project are resolved, the lockfile that would come of it is
+//! checked against the `[policy]` section of the configuration before anything is written or
+//! built: first against the packages and git repositories it forbids, and then by the hook
+//! command, which gets the lockfile as JSON and can reject it for any reason of its own.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    process::Stdio,
+    str::FromStr,
+};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use itertools::Itertools;
+use url::Url;
+
+use super::build::BuildCtx;
+use crate::{
+    build::shell_command,
+    package::{
+        lockfile::{LockedPkg, LockfileToml},
+        Spec,
+    },
+    remote::resolution::DirectRes,
+    util::{config::Policy, error::Result, fmt_output, shell::Verbosity},
+};
+
+/// The git repository a locked package was retrieved from, if it was retrieved from one: either
+/// because it was depended on directly, or because its index says its contents live there.
+fn git_repo(pkg: &LockedPkg) -> Option<&Url> {
+    pkg.sum
+        .resolution()
+        .direct()
+        .into_iter()
+        .chain(pkg.source.as_ref())
+        .filter_map(|res| match res {
+            DirectRes::Git { repo, .. } => Some(repo),
+            _ => None,
+        })
+        .next()
+}
+
+/// Whether `repo` is `allowed` or somewhere under it: on the same host, with the path segments of
+/// `allowed` as a prefix of its own. `https://github.com/test` allows
+/// `https://github.com/test/pkg`, but not `https://github.com/tester` or `https://github.com.evil`.
+fn allows(allowed: &Url, repo: &Url) -> bool {
+    fn segments(url: &Url) -> Vec<&str> {
+        url.path_segments()
+            .map(|x| x.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default()
+    }
+
+    allowed.scheme() == repo.scheme()
+        && allowed.host() == repo.host()
+        && allowed.port_or_known_default() == repo.port_or_known_default()
+        && segments(repo).starts_with(&segments(allowed))
+}
+
+/// The ways a lockfile breaks the rules of a policy, leaving aside its hook.
+fn violations(policy: &Policy, lockfile: &LockfileToml) -> Result<Vec<String>> {
+    let deny = policy
+        .deny
+        .iter()
+        .map(|x| {
+            Spec::from_str(x)
+                .with_context(|e| format_err!("invalid spec `{}` in policy.deny: {}", x, e))
+                .map_err(failure::Error::from)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let allow_git = match &policy.allow_git {
+        Some(allowed) => Some(
+            allowed
+                .iter()
+                .map(|x| {
+                    Url::parse(x)
+                        .with_context(|e| {
+                            format_err!("invalid url `{}` in policy.allow_git: {}", x, e)
+                        })
+                        .map_err(failure::Error::from)
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        None => None,
+    };
+
+    let mut res = vec![];
+    for pkg in &lockfile.packages {
+        if let Some(spec) = deny.iter().find(|x| x.matches(&pkg.sum)) {
+            res.push(format!("{} is denied by `{}`", pkg.sum, spec));
+        }
+        if let (Some(allowed), Some(repo)) = (&allow_git, git_repo(pkg)) {
+            if !allowed.iter().any(|x| allows(x, repo)) {
+                res.push(format!(
+                    "{} comes from git repository {}, which isn't allowed",
+                    pkg.sum, repo
+                ));
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Runs the hook of a policy on a lockfile in the directory `root`, failing with what it printed
+/// if it rejects the lockfile.
+fn run_hook(root: &Path, hook: &str, lockfile: &LockfileToml) -> Result<()> {
+    let json = serde_json::to_string(lockfile)?;
+
+    let mut child = shell_command(root, hook)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|e| format_err!("couldn't run the policy hook `{}`: {}", hook, e))?;
+
+    // A hook which decides without reading the lockfile can exit before it's all been written.
+    match child.stdin.take().unwrap().write_all(json.as_bytes()) {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        res => res?,
+    }
+
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        bail!(
+            "the policy hook `{}` rejected the dependencies ({}):\n{}",
+            hook,
+            out.status,
+            fmt_output(&out)
+        )
+    }
+
+    Ok(())
+}
+
+/// Checks the lockfile resolved for the project in `root` against the policy of the context,
+/// failing if the policy doesn't allow it.
+pub fn check_policy(ctx: &BuildCtx, root: &Path, lockfile: &LockfileToml) -> Result<()> {
+    let policy = &ctx.policy;
+    if policy.is_empty() {
+        return Ok(());
+    }
+
+    ctx.shell.println(
+        style("Checking").dim(),
+        "dependencies against the policy",
+        Verbosity::Verbose,
+    );
+
+    let violations = violations(policy, lockfile)?;
+    if !violations.is_empty() {
+        bail!(
+            "the dependencies aren't allowed by the policy in the configuration:\n{}",
+            violations.iter().map(|x| format!("  {}", x)).join("\n")
+        )
+    }
+
+    if let Some(hook) = &policy.hook {
+        ctx.shell
+            .println(style(">").dim(), hook, Verbosity::Verbose);
+        run_hook(root, hook, lockfile)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::{Name, PackageId, Summary};
+    use crate::remote::resolution::Resolution;
+    use semver::Version;
+
+    fn locked(name: &str, version: &str, res: &str) -> LockedPkg {
+        let sum = Summary::new(
+            PackageId::new(
+                Name::from_str(name).unwrap(),
+                Resolution::from_str(res).unwrap(),
+            ),
+            Version::parse(version).unwrap(),
+        );
+        LockedPkg {
+            sum,
+            dependencies: vec![],
+            checksum: None,
+            source: None,
+            features: vec![],
+            platforms: vec![],
+        }
+    }
+
+    #[test]
+    fn policy_violations() {
+        let lockfile = LockfileToml {
+            packages: vec![
+                locked("test/root", "0.1.0", "dir+/test/root"),
+                locked("evil/pkg", "1.0.0", "index+dir+/index"),
+                locked(
+                    "test/git",
+                    "0.2.0",
+                    "git+https://example.com/test/git#master",
+                ),
+                locked("test/ok", "1.2.0", "git+https://github.com/test/ok#v1.2.0"),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        let policy = Policy::default();
+        assert!(violations(&policy, &lockfile).unwrap().is_empty());
+
+        let policy = Policy {
+            deny: vec!["evil/pkg".to_string(), "test/ok|1.0.0".to_string()],
+            allow_git: Some(vec!["https://github.com/".to_string()]),
+            hook: None,
+        };
+        let found = violations(&policy, &lockfile).unwrap();
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0].contains("evil/pkg"), "{:?}", found);
+        assert!(
+            found[1].contains("https://example.com/test/git"),
+            "{:?}",
+            found
+        );
+
+        let policy = Policy {
+            deny: vec!["not a spec".to_string()],
+            ..Policy::default()
+        };
+        assert!(violations(&policy, &lockfile).is_err());
+    }
+
+    #[test]
+    fn policy_hook() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let lockfile = LockfileToml {
+            packages: vec![locked("evil/pkg", "1.0.0", "index+dir+/index")]
+                .into_iter()
+                .collect(),
+        };
+
+        let hook = "grep -q evil/pkg && echo 'no evil here' >&2 && exit 1; exit 0";
+        let e = run_hook(tmp.path(), hook, &lockfile)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("no evil here"), "{}", e);
+        assert!(run_hook(tmp.path(), "grep -q good/pkg && exit 1; exit 0", &lockfile).is_ok());
+        // Hooks don't have to read the lockfile at all.
+        assert!(run_hook(tmp.path(), "true", &lockfile).is_ok());
+    }
+}
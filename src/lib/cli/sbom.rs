@@ -0,0 +1,344 @@
+//! Software bills of materials, which list everything a project is made of for supply-chain
+//! tooling to check.
+
+use std::{
+    env,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use indexmap::IndexMap;
+use semver::Version;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::{
+    build::{find_manifest, locked_packages, BuildCtx},
+    license::licenses,
+    self_update::VERSION,
+};
+use crate::{
+    package::{lockfile::LockedPkg, Checksum, ChecksumFmt, Name},
+    remote::resolution::DirectRes,
+    util::{civil_from_days, error::Result},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// CycloneDX 1.4, as JSON.
+    CycloneDx,
+    /// SPDX 2.3, as JSON.
+    SpdxJson,
+}
+
+impl SbomFormat {
+    /// The name of the file a bill of materials goes in if no other one is given, following the
+    /// conventions of each format.
+    pub fn default_file(self) -> &'static str {
+        match self {
+            SbomFormat::CycloneDx => "bom.json",
+            SbomFormat::SpdxJson => "sbom.spdx.json",
+        }
+    }
+}
+
+/// A package in a bill of materials.
+#[derive(Debug, Clone)]
+struct Component {
+    name: Name,
+    version: Version,
+    /// The license expression from the package's manifest.
+    license: Option<String>,
+    /// Where the package can be retrieved from, unless it's a directory on disk.
+    source: Option<DirectRes>,
+    checksum: Option<Checksum>,
+    /// The indices of the components it depends on.
+    dependencies: Vec<usize>,
+}
+
+impl Component {
+    fn new(pkg: &LockedPkg, license: Option<String>, packages: &[LockedPkg]) -> Self {
+        let source = pkg
+            .source
+            .clone()
+            .or_else(|| pkg.sum.resolution().direct().cloned())
+            .filter(|x| !x.is_dir());
+        let checksum = match &source {
+            Some(DirectRes::Tar {
+                cksum: Some(cksum), ..
+            }) => Some(cksum.clone()),
+            // Only the checksums of tarballs are checksums of files; the rest are git commits
+            // and hashes of directories.
+            _ => pkg.checksum.as_ref().and_then(|x| x.parse().ok()),
+        };
+        let dependencies = pkg
+            .dependencies
+            .iter()
+            .filter_map(|dep| packages.iter().position(|x| &x.sum == dep))
+            .collect();
+
+        Component {
+            name: pkg.sum.name().clone(),
+            version: pkg.sum.version().clone(),
+            license,
+            source,
+            checksum,
+            dependencies,
+        }
+    }
+
+    fn bom_ref(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+}
+
+/// Where a package can be downloaded from, written the way SPDX likes it.
+fn download_location(res: &DirectRes) -> String {
+    match res {
+        DirectRes::Git { repo, tag } => format!("git+{}@{}", repo, tag),
+        DirectRes::Tar { url, .. } => url.to_string(),
+        DirectRes::Dir { path } => path.display().to_string(),
+    }
+}
+
+/// Older manifests separate alternative licenses with slashes, which SPDX expressions spell out.
+fn license_expression(license: &str) -> String {
+    license
+        .split('/')
+        .map(|x| x.trim())
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+/// The current time in RFC 3339 format, or the time `SOURCE_DATE_EPOCH` gives for reproducible
+/// builds.
+fn timestamp() -> String {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs() as i64)
+                .unwrap_or(0)
+        });
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn cyclonedx(components: &[Component], application: bool) -> Value {
+    let alg = |fmt: ChecksumFmt| match fmt {
+        ChecksumFmt::Sha256 => "SHA-256",
+        ChecksumFmt::Sha512 => "SHA-512",
+        ChecksumFmt::Blake3 => "BLAKE3",
+    };
+    let entry = |c: &Component, kind: &str| {
+        let mut res = json!({
+            "type": kind,
+            "bom-ref": c.bom_ref(),
+            "group": c.name.group(),
+            "name": c.name.name(),
+            "version": c.version.to_string(),
+        });
+        if let Some(license) = &c.license {
+            res["licenses"] = json!([{ "expression": license_expression(license) }]);
+        }
+        if let Some(cksum) = &c.checksum {
+            res["hashes"] = json!([{ "alg": alg(cksum.fmt), "content": cksum.hash }]);
+        }
+        if let Some(source) = &c.source {
+            let kind = if source.is_git() {
+                "vcs"
+            } else {
+                "distribution"
+            };
+            res["externalReferences"] = json!([{ "type": kind, "url": download_location(source) }]);
+        }
+        res
+    };
+
+    let root = &components[0];
+    let root_kind = if application {
+        "application"
+    } else {
+        "library"
+    };
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.4",
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp(),
+            "tools": [{ "vendor": "elba", "name": "elba", "version": VERSION }],
+            "component": entry(root, root_kind),
+        },
+        "components": components[1..]
+            .iter()
+            .map(|c| entry(c, "library"))
+            .collect::<Vec<_>>(),
+        "dependencies": components
+            .iter()
+            .map(|c| json!({
+                "ref": c.bom_ref(),
+                "dependsOn": c
+                    .dependencies
+                    .iter()
+                    .map(|&ix| components[ix].bom_ref())
+                    .collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn spdx(components: &[Component]) -> Value {
+    let alg = |fmt: ChecksumFmt| match fmt {
+        ChecksumFmt::Sha256 => "SHA256",
+        ChecksumFmt::Sha512 => "SHA512",
+        ChecksumFmt::Blake3 => "BLAKE3",
+    };
+    let id = |ix: usize| format!("SPDXRef-Package-{}", ix);
+
+    let packages = components
+        .iter()
+        .enumerate()
+        .map(|(ix, c)| {
+            let mut res = json!({
+                "SPDXID": id(ix),
+                "name": c.name.to_string(),
+                "versionInfo": c.version.to_string(),
+                "downloadLocation": c
+                    .source
+                    .as_ref()
+                    .map(download_location)
+                    .unwrap_or_else(|| "NOASSERTION".to_string()),
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": c
+                    .license
+                    .as_ref()
+                    .map(|x| license_expression(x))
+                    .unwrap_or_else(|| "NOASSERTION".to_string()),
+                "copyrightText": "NOASSERTION",
+            });
+            if let Some(cksum) = &c.checksum {
+                res["checksums"] =
+                    json!([{ "algorithm": alg(cksum.fmt), "checksumValue": cksum.hash }]);
+            }
+            res
+        })
+        .collect::<Vec<_>>();
+
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": id(0),
+    })];
+    for (ix, c) in components.iter().enumerate() {
+        for &dep in &c.dependencies {
+            relationships.push(json!({
+                "spdxElementId": id(ix),
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": id(dep),
+            }));
+        }
+    }
+
+    // The namespace has to be unique to this document, so it has a hash of what's in it.
+    let mut hasher = Sha256::default();
+    for c in components {
+        hasher.input(c.bom_ref().as_bytes());
+        if let Some(source) = &c.source {
+            hasher.input(source.to_string().as_bytes());
+        }
+    }
+    let root = &components[0];
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": root.bom_ref(),
+        "documentNamespace": format!(
+            "https://elba.io/spdx/{}/{}-{}",
+            root.name,
+            root.version,
+            hex::encode(hasher.result())
+        ),
+        "creationInfo": {
+            "created": timestamp(),
+            "creators": [format!("Tool: elba-{}", VERSION)],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+/// Makes a bill of materials for a project from its lockfile, with the licenses from the
+/// manifests of the packages it depends on, which are retrieved to read them.
+pub fn sbom(ctx: &BuildCtx, project: &Path, format: SbomFormat) -> Result<String> {
+    // This locks the project first if it has to be, so the lockfile is read afterwards.
+    let report = licenses(ctx, project)?;
+    let (_, manifest) = find_manifest(project, false, Some(ctx.shell))?;
+    let packages = locked_packages(ctx, project)?;
+
+    let mut by_pkg = IndexMap::new();
+    for group in &report.groups {
+        for pkg in &group.packages {
+            by_pkg.insert((&pkg.name, &pkg.version), group.license.clone());
+        }
+    }
+
+    let components = packages
+        .iter()
+        .enumerate()
+        .map(|(ix, pkg)| {
+            let license = if ix == 0 {
+                manifest.package.license.clone()
+            } else {
+                by_pkg
+                    .get(&(pkg.sum.name(), pkg.sum.version()))
+                    .cloned()
+                    .flatten()
+            };
+            Component::new(pkg, license, &packages)
+        })
+        .collect::<Vec<_>>();
+
+    let bom = match format {
+        SbomFormat::CycloneDx => cyclonedx(&components, !manifest.targets.bin.is_empty()),
+        SbomFormat::SpdxJson => spdx(&components),
+    };
+
+    Ok(serde_json::to_string_pretty(&bom)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sbom_timestamp() {
+        env::set_var("SOURCE_DATE_EPOCH", "951782400");
+        assert_eq!(timestamp(), "2000-02-29T00:00:00Z");
+        env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        assert_eq!(timestamp(), "2023-11-14T22:13:20Z");
+        env::remove_var("SOURCE_DATE_EPOCH");
+    }
+
+    #[test]
+    fn sbom_license_expressions() {
+        assert_eq!(license_expression("MIT"), "MIT");
+        assert_eq!(license_expression("MIT/Apache-2.0"), "MIT OR Apache-2.0");
+        assert_eq!(license_expression("MIT OR Apache-2.0"), "MIT OR Apache-2.0");
+    }
+}
@@ -0,0 +1,230 @@
+//! Running standalone Idris files, which declare their dependencies in a header comment instead
+//! of in a manifest:
+//!
+//! ```idris
+//! -- elba: deps = { "contrib/foo" = "1.0", "grp/local" = { path = "../local" } }
+//! -- elba: idris_opts = ["-p", "contrib"]
+//! module Main
+//!
+//! main : IO ()
+//! main = putStrLn "hi"
+//! ```
+//!
+//! elba writes a manifest for the file into a directory of its own in the cache, and builds and
+//! runs it like any other project.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use failure::{bail, format_err, ResultExt};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::build::{self, BuildCtx};
+use crate::{
+    package::{manifest::DepReq, Name},
+    util::{config::Backend, error::Result, normalize_path},
+};
+
+/// What the `-- elba:` lines at the top of a file say.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptHeader {
+    #[serde(default)]
+    deps: IndexMap<Name, DepReq>,
+    #[serde(default)]
+    idris_opts: Vec<String>,
+}
+
+impl ScriptHeader {
+    /// Reads the header out of the contents of a file. Only the comments before the first line of
+    /// code count; every one of them which starts with `elba:` is a line of TOML.
+    fn parse(contents: &str) -> Result<Self> {
+        let toml = contents
+            .lines()
+            .map(|line| line.trim())
+            .take_while(|line| line.is_empty() || line.starts_with("--"))
+            .filter_map(|line| {
+                let comment = line.trim_start_matches('-').trim_start();
+                if comment.starts_with("elba:") {
+                    Some(&comment["elba:".len()..])
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Ok(toml::from_str(&toml).context(format_err!("invalid `-- elba:` header"))?)
+    }
+}
+
+/// The module a file declares itself to be, which is `Main` if it doesn't say.
+fn module_name(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("module "))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("Main")
+        .to_string()
+}
+
+/// Makes a name part out of a file name, since file names can have anything in them.
+fn name_part(stem: &str) -> String {
+    let part = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if part.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        part
+    } else {
+        format!("script{}", part)
+    }
+}
+
+/// Writes a file to a path unless it already has exactly those contents, so that the builds of
+/// scripts which haven't changed stay fresh.
+fn write_if_changed(path: &Path, contents: &str) -> Result<()> {
+    if fs::read_to_string(path).ok().as_ref().map(|x| x.as_str()) != Some(contents) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)
+            .with_context(|e| format_err!("couldn't write {}:\n{}", path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Sets up the project for a standalone Idris file in the cache, giving back its directory.
+///
+/// Every file gets a project of its own, picked by its path, so that its lockfile and build
+/// outputs are kept from one run to the next.
+pub fn script_project(ctx: &BuildCtx, file: &Path) -> Result<PathBuf> {
+    let file = normalize_path(file);
+    let contents = fs::read_to_string(&file)
+        .with_context(|e| format_err!("couldn't read {}:\n{}", file.display(), e))?;
+    let header = ScriptHeader::parse(&contents)?;
+    let parent = file.parent().unwrap();
+
+    let stem = name_part(
+        &file
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    );
+    let name = Name::new("script".to_string(), stem.clone())?;
+
+    // Path dependencies are relative to the file, not to the project we make for it.
+    let deps = header
+        .deps
+        .into_iter()
+        .map(|(name, dep)| match dep {
+            DepReq::Local { path, package } => (
+                name,
+                DepReq::Local {
+                    path: normalize_path(&parent.join(path)),
+                    package,
+                },
+            ),
+            dep => (name, dep),
+        })
+        .collect::<IndexMap<_, _>>();
+
+    let module = module_name(&contents);
+    let extension = match file.extension().and_then(|x| x.to_str()) {
+        Some("lidr") => "lidr",
+        _ => "idr",
+    };
+
+    let mut bin = toml::value::Table::new();
+    bin.insert("name".to_string(), stem.into());
+    bin.insert("main".to_string(), format!("{}.idr", module).into());
+    bin.insert(
+        "idris_opts".to_string(),
+        toml::Value::try_from(&header.idris_opts)?,
+    );
+    let mut package = toml::value::Table::new();
+    package.insert("name".to_string(), name.to_string().into());
+    package.insert("version".to_string(), "0.1.0".into());
+    package.insert("authors".to_string(), toml::Value::Array(vec![]));
+    let mut targets = toml::value::Table::new();
+    targets.insert(
+        "bin".to_string(),
+        toml::Value::Array(vec![toml::Value::Table(bin)]),
+    );
+    let mut manifest = toml::value::Table::new();
+    manifest.insert("package".to_string(), toml::Value::Table(package));
+    manifest.insert("dependencies".to_string(), toml::Value::try_from(&deps)?);
+    manifest.insert("targets".to_string(), toml::Value::Table(targets));
+
+    let project = ctx
+        .global_cache
+        .tmp
+        .join("scripts")
+        .join(hex::encode(Sha256::digest(
+            file.to_string_lossy().as_bytes(),
+        )));
+    let src = project
+        .join("src")
+        .join(format!("{}.{}", module.replace('.', "/"), extension));
+
+    write_if_changed(
+        &project.join("elba.toml"),
+        &toml::to_string_pretty(&toml::Value::Table(manifest))?,
+    )?;
+    write_if_changed(&src, &contents)?;
+
+    Ok(project)
+}
+
+/// Builds and runs a standalone Idris file.
+pub fn run_file(ctx: &BuildCtx, file: &Path, args: &[&str], backend: &Backend) -> Result<String> {
+    if !file.is_file() {
+        bail!("{} isn't a file", file.display())
+    }
+
+    let project = script_project(ctx, file)?;
+    build::run(ctx, &project, None, args, backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_header() {
+        let contents = r#"
+-- A quick experiment
+-- elba: deps = { "contrib/foo" = "1.0" }
+-- elba: idris_opts = ["-p", "contrib"]
+module Main
+
+-- elba: deps = { "not/read" = "1.0" }
+main : IO ()
+main = putStrLn "hi"
+"#;
+
+        let header = ScriptHeader::parse(contents).unwrap();
+        assert_eq!(header.deps.len(), 1);
+        assert!(header
+            .deps
+            .contains_key(&"contrib/foo".parse::<Name>().unwrap()));
+        assert_eq!(header.idris_opts, vec!["-p", "contrib"]);
+        assert_eq!(module_name(contents), "Main");
+        assert_eq!(module_name("main : IO ()"), "Main");
+        assert_eq!(name_part("my script"), "my_script");
+        assert_eq!(name_part("_hidden"), "script_hidden");
+    }
+}
@@ -0,0 +1,404 @@
+//! Updating and uninstalling elba itself, for when it wasn't installed with a package manager.
+//!
+//! Every release of elba comes with an archive of the executable for each platform, named
+//! `elba-<tag>-<target>.tar.gz`, along with a file holding the SHA-256 hash of the archive
+//! (`<archive>.sha256`) and a hex-encoded ed25519 signature of the archive made with the release
+//! key (`<archive>.sig`). Both of them are checked before anything is replaced.
+
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use flate2::read::GzDecoder;
+use reqwest::blocking::Client;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    retrieve::cache::Layout,
+    util::{
+        config::Releases,
+        error::{download_error, Result},
+        shell::{Shell, Verbosity},
+    },
+};
+
+/// The version of elba which is running.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Options for updating elba.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOpts {
+    /// The version to install, which can be older than the running one. Defaults to the latest.
+    pub version: Option<Version>,
+    /// Install the release even if it's the version which is already running.
+    pub force: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|x| x.name == name)
+    }
+}
+
+/// The target which release builds of elba for this platform are made for, if there are any
+/// which can be installed automatically.
+pub fn host_target() -> Option<&'static str> {
+    if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86_64",
+        target_env = "gnu"
+    )) {
+        Some("x86_64-unknown-linux-gnu")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86_64",
+        target_env = "musl"
+    )) {
+        Some("x86_64-unknown-linux-musl")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86",
+        target_env = "gnu"
+    )) {
+        Some("i686-unknown-linux-gnu")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86",
+        target_env = "musl"
+    )) {
+        Some("i686-unknown-linux-musl")
+    } else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+        Some("x86_64-apple-darwin")
+    } else {
+        None
+    }
+}
+
+/// What installed elba at `exe`, if it looks like something which should be left to update it.
+fn managed_by(exe: &Path) -> Option<&'static str> {
+    let path = exe.to_string_lossy();
+    if path.contains("/.cargo/bin/") || path.contains("\\.cargo\\bin\\") {
+        Some("cargo")
+    } else if path.starts_with("/nix/store/") {
+        Some("nix")
+    } else if path.contains("/Cellar/") {
+        Some("Homebrew")
+    } else if path.starts_with("/usr/bin/") || path.starts_with("/bin/") {
+        Some("the system's package manager")
+    } else {
+        None
+    }
+}
+
+/// The path of the running executable, as long as nothing else manages it.
+fn current_exe() -> Result<PathBuf> {
+    let exe = env::current_exe()
+        .and_then(|x| x.canonicalize())
+        .context(format_err!("couldn't find the elba executable"))?;
+    if let Some(manager) = managed_by(&exe) {
+        bail!(
+            "elba was installed with {} (at {}), which should be used to manage it instead",
+            manager,
+            exe.display()
+        )
+    }
+
+    Ok(exe)
+}
+
+/// Replaces the running executable with the latest release of elba, or the one `opts` asks for.
+pub fn update(
+    shell: Shell,
+    releases: &Releases,
+    timeout: Duration,
+    opts: &UpdateOpts,
+) -> Result<String> {
+    let exe = current_exe()?;
+    let key = releases.key.as_ref().ok_or_else(|| {
+        format_err!(
+            "this build of elba doesn't know the key releases are signed with, so they can't be \
+             verified; set releases.key in the configuration"
+        )
+    })?;
+    let target = host_target().ok_or_else(|| {
+        format_err!(
+            "there are no release builds of elba which can be installed automatically on this \
+             platform; download one from https://github.com/elba/elba/releases instead"
+        )
+    })?;
+    let current = Version::parse(VERSION).unwrap();
+
+    let client = Client::builder()
+        .timeout(timeout)
+        .user_agent(concat!("elba/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    let release = match &opts.version {
+        // Releases are usually tagged with a `v` in front of their version, but not always.
+        Some(v) => fetch_release(&client, &format!("{}/tags/v{}", releases.url, v))
+            .or_else(|_| fetch_release(&client, &format!("{}/tags/{}", releases.url, v)))
+            .context(format_err!("couldn't find release {} of elba", v))?,
+        None => fetch_release(&client, &format!("{}/latest", releases.url))
+            .context(format_err!("couldn't find the latest release of elba"))?,
+    };
+    let version = Version::parse(release.tag_name.trim_start_matches('v')).with_context(|e| {
+        format_err!(
+            "the release {} doesn't have a version: {}",
+            release.tag_name,
+            e
+        )
+    })?;
+
+    if !opts.force && (version == current || opts.version.is_none() && version < current) {
+        return Ok(format!("elba {} is already up to date", current));
+    }
+
+    let name = format!("elba-{}-{}.tar.gz", release.tag_name, target);
+    let archive = release
+        .asset(&name)
+        .ok_or_else(|| format_err!("release {} has no build for {}", release.tag_name, target))?;
+    let checksum = release.asset(&format!("{}.sha256", name)).ok_or_else(|| {
+        format_err!(
+            "release {} has no checksum for {}, so it can't be verified",
+            release.tag_name,
+            name
+        )
+    })?;
+
+    shell.println(
+        style("Downloading").cyan(),
+        format!("elba {} ({})", version, target),
+        Verbosity::Normal,
+    );
+    let bytes = download(&client, &archive.browser_download_url)?;
+
+    let expected = String::from_utf8(download(&client, &checksum.browser_download_url)?)?;
+    let expected = expected.split_whitespace().next().unwrap_or("");
+    let actual = hex::encode(Sha256::digest(&bytes));
+    if !expected.eq_ignore_ascii_case(&actual) {
+        bail!(
+            "the checksum of {} is {}, but the release says it should be {}",
+            name,
+            actual,
+            expected
+        )
+    }
+
+    let sig = release.asset(&format!("{}.sig", name)).ok_or_else(|| {
+        format_err!(
+            "release {} isn't signed, so it can't be verified",
+            release.tag_name
+        )
+    })?;
+    let sig = String::from_utf8(download(&client, &sig.browser_download_url)?)?;
+    if !key.verify(&bytes, sig.trim()) {
+        bail!("the signature of {} wasn't made with the release key", name)
+    }
+    shell.println(style("Verified").dim(), &name, Verbosity::Verbose);
+
+    let contents = extract(&bytes, &name)?;
+    replace(&exe, &contents)?;
+
+    Ok(format!("updated elba from {} to {}", current, version))
+}
+
+fn fetch_release(client: &Client, url: &str) -> Result<Release> {
+    let res = client
+        .get(url)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| download_error(url, e.into()))?;
+
+    Ok(serde_json::from_reader(res)?)
+}
+
+fn download(client: &Client, url: &str) -> Result<Vec<u8>> {
+    let mut res = client
+        .get(url)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| download_error(url, e.into()))?;
+
+    let mut bytes = vec![];
+    res.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Takes the executable out of a release archive.
+fn extract(archive: &[u8], name: &str) -> Result<Vec<u8>> {
+    let mut tar = tar::Archive::new(GzDecoder::new(archive));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name().and_then(|x| x.to_str()) == Some("elba") {
+            let mut contents = vec![];
+            entry.read_to_end(&mut contents)?;
+            return Ok(contents);
+        }
+    }
+
+    bail!("{} doesn't have an elba executable in it", name)
+}
+
+/// Where the executable at `exe` is moved to while it's being replaced.
+fn old_exe(exe: &Path) -> PathBuf {
+    let mut name = exe.file_name().unwrap().to_os_string();
+    name.push(".old");
+    exe.with_file_name(name)
+}
+
+/// Swaps the executable at `exe` out for `contents`. The new executable is written next to the
+/// old one, so that nothing is ever left half-written. Windows won't let a running executable be
+/// replaced or removed, but it will let it be renamed, so the old one is moved out of the way to
+/// `<exe>.old` first; if it can't be removed once the new one is in place, the next run of elba
+/// removes it (see `remove_old`).
+fn replace(exe: &Path, contents: &[u8]) -> Result<()> {
+    let dir = exe.parent().unwrap();
+    let tmp = dir.join(format!(".elba-update-{}", process::id()));
+    let old = old_exe(exe);
+
+    let res = write_executable(&tmp, contents).and_then(|_| {
+        // Whatever's left over from the last update is in the way.
+        if old.exists() {
+            fs::remove_file(&old)
+                .with_context(|e| format_err!("couldn't remove {}: {}", old.display(), e))?;
+        }
+        fs::rename(exe, &old)
+            .with_context(|e| format_err!("couldn't move {} aside: {}", exe.display(), e))?;
+        if let Err(e) = fs::rename(&tmp, exe) {
+            let _ = fs::rename(&old, exe);
+            bail!("couldn't replace {}: {}", exe.display(), e)
+        }
+        Ok(())
+    });
+    match res {
+        Ok(()) => {
+            let _ = fs::remove_file(&old);
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    res
+}
+
+/// Removes the executable an update left behind because it couldn't be removed while it was
+/// running, if there is one.
+pub fn remove_old() {
+    if let Ok(exe) = env::current_exe().and_then(|x| x.canonicalize()) {
+        let _ = fs::remove_file(old_exe(&exe));
+    }
+}
+
+fn write_executable(path: &Path, contents: &[u8]) -> Result<()> {
+    fs::write(path, contents)
+        .with_context(|e| format_err!("couldn't write {}: {}", path.display(), e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Removes elba and everything it keeps outside of projects: the binaries it installed, its
+/// caches, and its data (the `credentials` it logs into registries with, and trusted keys).
+/// Configuration files are left alone.
+///
+/// The directories these live in can be pointed anywhere, including at directories which have
+/// other things in them, so only what elba put there is removed. The directories themselves are
+/// only removed if that leaves them empty.
+pub fn uninstall(shell: Shell, layout: &Layout, credentials: &Path) -> Result<String> {
+    let exe = current_exe()?;
+    let removed =
+        |path: &Path| shell.println(style("Removed").dim(), path.display(), Verbosity::Normal);
+
+    // The binaries elba installed are the ones it keeps track of in `.bins`.
+    let bins = layout.bin.join(".bins");
+    if bins.exists() {
+        let contents = fs::read_to_string(&bins)
+            .with_context(|e| format_err!("couldn't read {}: {}", bins.display(), e))?;
+        let installed: toml::value::Table = toml::from_str(&contents)
+            .with_context(|e| format_err!("couldn't read {}: {}", bins.display(), e))?;
+        for name in installed.keys() {
+            let bin = layout.bin.join(name);
+            // Anything which isn't just a file in the bin directory wasn't put there by elba.
+            if bin.parent() == Some(layout.bin.as_path())
+                && bin.file_name().is_some()
+                && bin.exists()
+            {
+                fs::remove_file(&bin)
+                    .with_context(|e| format_err!("couldn't remove {}: {}", bin.display(), e))?;
+                removed(&bin);
+            }
+        }
+        fs::remove_file(&bins)?;
+    }
+
+    for dir in &[
+        &layout.build,
+        &layout.indices,
+        &layout.src,
+        &layout.store,
+        &layout.tmp,
+    ] {
+        if dir.exists() {
+            remove_dir_all::remove_dir_all(dir)
+                .with_context(|e| format_err!("couldn't remove {}: {}", dir.display(), e))?;
+            removed(dir);
+        }
+    }
+
+    for file in &[&layout.keys, credentials] {
+        if file.exists() {
+            fs::remove_file(file)
+                .with_context(|e| format_err!("couldn't remove {}: {}", file.display(), e))?;
+            removed(file);
+        }
+    }
+
+    // The data and bin directories can be inside the cache directory.
+    let parents = [
+        Some(layout.bin.as_path()),
+        layout.keys.parent(),
+        layout.src.parent(),
+    ];
+    for dir in parents.iter().filter_map(|x| *x) {
+        if fs::remove_dir(dir).is_ok() {
+            removed(dir);
+        }
+    }
+
+    fs::remove_file(&exe).with_context(|e| {
+        format_err!(
+            "couldn't remove {} (it can be deleted by hand): {}",
+            exe.display(),
+            e
+        )
+    })?;
+    shell.println(style("Removed").dim(), exe.display(), Verbosity::Normal);
+
+    Ok("uninstalled elba".to_string())
+}
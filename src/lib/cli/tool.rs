@@ -0,0 +1,120 @@
+//! Running the external formatter and linter over the source files of a package.
+//!
+//! elba doesn't format or lint Idris code itself; instead, the commands to do so are taken from
+//! the `[tools]` section of the configuration. A package can use commands of its own by giving
+//! its manifest `fmt`, `fmt-check` or `lint` scripts, which take priority over the configuration.
+
+use std::{borrow::Cow, path::Path};
+
+use console::style;
+use failure::{bail, format_err};
+use itertools::Itertools;
+
+use super::build::{find_manifest, BuildCtx};
+use crate::{
+    build::shell_command,
+    util::{config::Tools, error::Result, shell::Verbosity},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    /// Formats files in place.
+    Fmt,
+    /// Checks that files are formatted without changing them.
+    FmtCheck,
+    /// Lints files.
+    Lint,
+}
+
+impl Tool {
+    /// The name of the manifest script which overrides the configured command.
+    fn script(self) -> &'static str {
+        match self {
+            Tool::Fmt => "fmt",
+            Tool::FmtCheck => "fmt-check",
+            Tool::Lint => "lint",
+        }
+    }
+
+    /// The key of the configured command.
+    fn key(self) -> &'static str {
+        match self {
+            Tool::Fmt => "tools.fmt",
+            Tool::FmtCheck => "tools.fmt_check",
+            Tool::Lint => "tools.lint",
+        }
+    }
+
+    fn configured(self, tools: &Tools) -> Option<&String> {
+        match self {
+            Tool::Fmt => tools.fmt.as_ref(),
+            Tool::FmtCheck => tools.fmt_check.as_ref(),
+            Tool::Lint => tools.lint.as_ref(),
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Tool::Fmt => "formatter",
+            Tool::FmtCheck => "format checker",
+            Tool::Lint => "linter",
+        }
+    }
+}
+
+/// Runs a tool over every source file of the targets of a project. Fails if the tool isn't
+/// configured, or if it exits unsuccessfully (which for the format checker means that some file
+/// isn't formatted).
+pub fn run_tool(ctx: &BuildCtx, project: &Path, tool: Tool, tools: &Tools) -> Result<String> {
+    let (project, manifest) = find_manifest(project, false, None)?;
+
+    let cmd = manifest
+        .scripts
+        .get(tool.script())
+        .or_else(|| tool.configured(tools))
+        .ok_or_else(|| {
+            format_err!(
+                "no {} is configured; set `{}` in the configuration or add a `{}` script to the \
+                 manifest",
+                tool.describe(),
+                tool.key(),
+                tool.script()
+            )
+        })?;
+
+    let files = manifest.source_files(&project)?;
+    if files.is_empty() {
+        return Ok("no source files to check".to_string());
+    }
+
+    let args = files
+        .iter()
+        .map(|file| {
+            let file = file.strip_prefix(&project).unwrap_or(file);
+            shell_escape::escape(Cow::from(file.to_string_lossy().into_owned())).into_owned()
+        })
+        .join(" ");
+    let cmd = format!("{} {}", cmd, args);
+
+    ctx.shell.println(
+        style("Running").cyan(),
+        format!("{} over {} files", tool.describe(), files.len()),
+        Verbosity::Normal,
+    );
+    ctx.shell
+        .println(style(">").dim(), &cmd, Verbosity::Verbose);
+
+    let status = shell_command(&project, &cmd).status()?;
+    if !status.success() {
+        match tool {
+            Tool::FmtCheck => bail!("some files aren't formatted; run `elba fmt` to fix them"),
+            _ => bail!("the {} failed ({})", tool.describe(), status),
+        }
+    }
+
+    Ok(match tool {
+        Tool::Fmt => format!("formatted {} files", files.len()),
+        Tool::FmtCheck => format!("all {} files are formatted", files.len()),
+        Tool::Lint => format!("linted {} files", files.len()),
+    })
+}
@@ -1,15 +1,25 @@
-use std::{convert::TryFrom, str::FromStr};
+use std::{convert::TryFrom, fmt::Write, marker::PhantomData, mem, str::FromStr};
 
-use failure::format_err;
+use failure::{format_err, ResultExt};
 
 use indexmap::IndexMap;
 
+use itertools::Itertools;
+
 use semver::Version;
 
 use serde::Deserialize;
 
 use super::*;
-use crate::package::manifest::{BinTarget, LibTarget, Manifest, PackageInfo, Targets, TestTarget};
+use crate::{
+    package::manifest::{
+        BinTarget, LibTarget, Manifest, NativeInfo, PackageInfo, Targets, TestTarget, WorkspaceInfo,
+    },
+    util::SubPath,
+};
+
+/// Packages which ship with Idris, and so are never depended on through an index.
+const BUILTIN_PKGS: &[&str] = &["prelude", "base", "contrib", "effects", "pruviloj"];
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Ipkg {
@@ -55,11 +65,171 @@ pub struct Ipkg {
     tests: Vec<String>,
 }
 
+impl Ipkg {
+    /// Writes out an `elba.toml` equivalent to this ipkg file, to move a package over to elba.
+    ///
+    /// Each package this one depends on which doesn't ship with Idris is passed to `lookup`,
+    /// which returns the lines of `[dependencies]` that could stand for it. A package only
+    /// becomes a dependency if exactly one line comes back; anything else, along with whatever
+    /// else can't be carried over, is left as a TODO comment.
+    pub fn to_manifest<F>(mut self, mut lookup: F) -> Result<String>
+    where
+        F: FnMut(&str) -> Result<Vec<String>>,
+    {
+        let name = Name::new(self.name.clone(), self.name.clone())?;
+        let mut todos = String::new();
+
+        let version = match self.version.take() {
+            Some(version) => match Version::parse(&version) {
+                Ok(version) => version,
+                Err(_) => {
+                    writeln!(
+                        todos,
+                        "# TODO: the ipkg's version `{}` isn't a semantic version",
+                        version
+                    )?;
+                    Version::new(0, 1, 0)
+                }
+            },
+            None => Version::new(0, 1, 0),
+        };
+
+        if !self.libs.is_empty() || !self.objs.is_empty() {
+            writeln!(
+                todos,
+                "# TODO: the ipkg links against {}; build them with a `prebuild` script and pass \
+                 them to the compiler in `idris_opts`",
+                self.libs.iter().chain(self.objs.iter()).join(", ")
+            )?;
+        }
+
+        let mut deps = String::new();
+        for pkg in mem::replace(&mut self.pkgs, vec![]) {
+            if BUILTIN_PKGS.contains(&pkg.as_str()) {
+                self.pkgs.push(pkg);
+                continue;
+            }
+            let mut lines = lookup(&pkg)?;
+            match lines.len() {
+                0 => writeln!(deps, "# TODO: `{}` wasn't found in any index", pkg)?,
+                1 => writeln!(deps, "{}", lines.pop().unwrap())?,
+                _ => {
+                    writeln!(deps, "# TODO: `{}` could be any of these:", pkg)?;
+                    for line in lines {
+                        writeln!(deps, "# {}", line)?;
+                    }
+                }
+            }
+        }
+
+        // ipkg files often give all of their options as one string.
+        self.opts = self
+            .opts
+            .iter()
+            .flat_map(|opt| opt.split_whitespace())
+            .map(|opt| opt.to_owned())
+            .collect();
+
+        let manifest = Manifest::try_from(self)?;
+        let package = &manifest.package;
+        let mut out = todos;
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        writeln!(out, "[package]")?;
+        writeln!(out, "name = {}", toml::Value::from(name.as_str()))?;
+        writeln!(out, "version = {}", toml::Value::from(version.to_string()))?;
+        writeln!(
+            out,
+            "authors = {}",
+            toml::Value::from(package.authors.clone())
+        )?;
+        for (key, val) in &[
+            ("description", &package.description),
+            ("homepage", &package.homepage),
+            ("repository", &package.repository),
+            ("license", &package.license),
+        ] {
+            if let Some(val) = val {
+                writeln!(out, "{} = {}", key, toml::Value::from(val.as_str()))?;
+            }
+        }
+        if let Some(readme) = &package.readme {
+            let readme = readme.0.to_string_lossy().replace("\\", "/");
+            writeln!(out, "readme = {}", toml::Value::from(readme))?;
+        }
+
+        write!(out, "\n[dependencies]\n{}", deps)?;
+
+        if let Some(lib) = &manifest.targets.lib {
+            writeln!(out, "\n[targets.lib]")?;
+            writeln!(out, "path = {}", subpath_value(&lib.path))?;
+            if let Some(mods) = &lib.mods {
+                writeln!(out, "mods = {}", toml::Value::from(mods.clone()))?;
+            }
+            if !lib.idris_opts.is_empty() {
+                writeln!(
+                    out,
+                    "idris_opts = {}",
+                    toml::Value::from(lib.idris_opts.clone())
+                )?;
+            }
+        }
+
+        for bin in &manifest.targets.bin {
+            writeln!(out, "\n[[targets.bin]]")?;
+            writeln!(out, "name = {}", toml::Value::from(bin.name()))?;
+            writeln!(out, "path = {}", subpath_value(&bin.path))?;
+            writeln!(out, "main = {}", toml::Value::from(bin.main.as_str()))?;
+            if !bin.idris_opts.is_empty() {
+                writeln!(
+                    out,
+                    "idris_opts = {}",
+                    toml::Value::from(bin.idris_opts.clone())
+                )?;
+            }
+        }
+
+        for test in &manifest.targets.test {
+            writeln!(out, "\n[[targets.test]]")?;
+            writeln!(out, "path = {}", subpath_value(&test.path))?;
+            writeln!(out, "main = {}", toml::Value::from(test.main.as_str()))?;
+            if !test.idris_opts.is_empty() {
+                writeln!(
+                    out,
+                    "idris_opts = {}",
+                    toml::Value::from(test.idris_opts.clone())
+                )?;
+            }
+        }
+
+        if !manifest.scripts.is_empty() {
+            writeln!(out, "\n[scripts]")?;
+            for (name, script) in &manifest.scripts {
+                writeln!(out, "{} = {}", name, toml::Value::from(script.as_str()))?;
+            }
+        }
+
+        // Whatever we couldn't carry over is only ever commented out, so this should always hold.
+        out.parse::<Manifest>().context(format_err!(
+            "the manifest made from the ipkg file is invalid"
+        ))?;
+
+        Ok(out)
+    }
+}
+
+fn subpath_value(path: &SubPath) -> toml::Value {
+    toml::Value::from(path.0.to_string_lossy().replace("\\", "/"))
+}
+
 impl TryFrom<Ipkg> for Manifest {
     type Error = failure::Error;
 
     fn try_from(ipkg: Ipkg) -> Result<Self> {
         let package = PackageInfo {
+            manifest_version: None,
             name: Name::new("legacy".to_string(), ipkg.name)?,
             version: Version::new(0, 0, 0),
             authors: [ipkg.author, ipkg.maintainer]
@@ -69,11 +239,15 @@ impl TryFrom<Ipkg> for Manifest {
                 .collect(),
             description: ipkg.brief,
             keywords: Vec::new(),
+            categories: Vec::new(),
             homepage: ipkg.homepage,
             repository: ipkg.sourceloc,
             readme: ipkg.readme.map(|readme| readme.parse()).transpose()?,
             license: ipkg.license,
             exclude: None,
+            include: None,
+            default_run: None,
+            metadata: toml::value::Table::new(),
         };
 
         let mut idris_opts = Vec::new();
@@ -88,19 +262,25 @@ impl TryFrom<Ipkg> for Manifest {
         } else {
             Some(LibTarget {
                 path: ipkg.sourcedir.parse()?,
-                mods: ipkg.modules,
+                mods: Some(ipkg.modules),
+                exclude: vec![],
                 idris_opts: idris_opts.clone(),
             })
         };
 
         let bin_target = if let Some(main) = ipkg.main {
             vec![BinTarget {
-                name: ipkg
-                    .executable
-                    .unwrap_or(main.rsplit(".").next().unwrap_or("output").to_owned()),
+                name: Some(
+                    ipkg.executable
+                        .unwrap_or(main.rsplit(".").next().unwrap_or("output").to_owned()),
+                ),
                 path: ipkg.sourcedir.parse()?,
                 main: main.parse()?,
                 idris_opts: idris_opts.clone(),
+                output: None,
+                extension: None,
+                env: IndexMap::new(),
+                kind: PhantomData,
             }]
         } else {
             vec![]
@@ -113,6 +293,10 @@ impl TryFrom<Ipkg> for Manifest {
                 path: ipkg.sourcedir.parse()?,
                 main: test.parse()?,
                 idris_opts: idris_opts.clone(),
+                output: None,
+                extension: None,
+                env: IndexMap::new(),
+                kind: PhantomData,
             })
         }
 
@@ -125,13 +309,16 @@ impl TryFrom<Ipkg> for Manifest {
             package,
             dependencies: IndexMap::new(),
             dev_dependencies: IndexMap::new(),
+            test_dependencies: IndexMap::new(),
             targets: Targets {
                 lib: lib_target,
                 bin: bin_target,
                 test: test_targets,
             },
-            workspace: IndexMap::new(),
+            workspace: WorkspaceInfo::default(),
             scripts,
+            native: NativeInfo::default(),
+            metadata: toml::value::Table::new(),
         })
     }
 }
@@ -194,9 +381,11 @@ impl TryFrom<Manifest> for Ipkg {
                 .to_str()
                 .unwrap()
                 .to_owned(),
-            modules: manifest.targets.lib.unwrap().mods,
+            modules: manifest.targets.lib.unwrap().mods.ok_or_else(|| {
+                format_err!("the lib target has to list its mods to be written as an ipkg")
+            })?,
             main: manifest.targets.bin.get(0).map(|bin| bin.main.to_owned()),
-            executable: manifest.targets.bin.get(0).map(|bin| bin.name.to_owned()),
+            executable: manifest.targets.bin.get(0).map(|bin| bin.name()),
             tests: manifest
                 .targets
                 .test
@@ -0,0 +1,550 @@
+//! Checks of a manifest which go beyond whether it can be parsed.
+//!
+//! Each problem found is a `Diagnostic` pointing at the line of the manifest it's about, where we
+//! can find one. The toml parser doesn't keep track of where keys were, so we find them again by
+//! looking through the manifest for the table and key in question.
+
+use std::{collections::HashSet, fmt, path::Path};
+
+use super::{
+    manifest::{check_version, inherit, Manifest},
+    workspace::Workspace,
+    Name,
+};
+use crate::util::{error::Result, mod_path};
+
+/// The keys a table of the manifest can have.
+const TOP_KEYS: &[&str] = &[
+    "package",
+    "dependencies",
+    "dev_dependencies",
+    "test_dependencies",
+    "targets",
+    "workspace",
+    "scripts",
+    "native",
+    "metadata",
+];
+const PACKAGE_KEYS: &[&str] = &[
+    "manifest_version",
+    "name",
+    "version",
+    "authors",
+    "description",
+    "keywords",
+    "categories",
+    "homepage",
+    "repository",
+    "readme",
+    "license",
+    "exclude",
+    "include",
+    "default_run",
+    "metadata",
+];
+const NATIVE_KEYS: &[&str] = &["libs", "pkg-config"];
+const TARGETS_KEYS: &[&str] = &["lib", "bin", "test"];
+const LIB_KEYS: &[&str] = &["path", "mods", "exclude", "idris_opts"];
+const BIN_KEYS: &[&str] = &["name", "path", "main", "idris_opts", "output", "extension"];
+const DEP_KEYS: &[&str] = &[
+    "version",
+    "index",
+    "package",
+    "path",
+    "git",
+    "github",
+    "gitlab",
+    "sourcehut",
+    "tag",
+    "workspace",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A place in the manifest, as a line and column starting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity,
+            message,
+            span,
+            help: None,
+        }
+    }
+
+    fn help(self, help: Option<String>) -> Self {
+        Diagnostic { help, ..self }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "elba.toml:{}:{}: {}", span.line, span.col, self.message)?,
+            None => write!(f, "elba.toml: {}", self.message)?,
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the manifest of the package in `root`, whose contents are `raw`.
+pub fn lint(root: &Path, raw: &str) -> Result<Vec<Diagnostic>> {
+    let value: toml::Value = match toml::from_str(raw) {
+        Ok(value) => value,
+        Err(e) => {
+            let span = e.line_col().map(|(line, col)| Span {
+                line: line + 1,
+                col: col + 1,
+            });
+            return Ok(vec![Diagnostic::new(
+                Severity::Error,
+                format!("invalid toml: {}", e),
+                span,
+            )]);
+        }
+    };
+
+    // A manifest in a newer format than we understand would only turn up nonsense.
+    if let Err(e) = check_version(&value) {
+        return Ok(vec![Diagnostic::new(
+            Severity::Error,
+            e.to_string(),
+            locate(raw, &["package"], 0, Some("manifest_version")),
+        )]);
+    }
+
+    let mut diags = unknown_keys(raw, &value);
+    if !diags.is_empty() {
+        // Anything else we'd find would most likely just be fallout from the unknown keys.
+        return Ok(diags);
+    }
+
+    let ws = Workspace::root_of(root)?;
+    let manifest = match inherit(raw, Some(root), ws.as_ref().map(|(r, i)| (r.as_path(), i)))
+        .and_then(Manifest::from_value)
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            diags.push(Diagnostic::new(Severity::Error, e.to_string(), None));
+            return Ok(diags);
+        }
+    };
+
+    check_targets(root, raw, &manifest, &mut diags);
+    check_deps(raw, &value, &manifest, &mut diags);
+    check_spellings(raw, &manifest, &mut diags);
+
+    if !manifest.version().build.is_empty() {
+        diags.push(
+            Diagnostic::new(
+                Severity::Warning,
+                format!(
+                    "version {} has build metadata, so the package can't be published",
+                    manifest.version()
+                ),
+                locate(raw, &["package"], 0, Some("version")),
+            )
+            .help(Some(
+                "versions which only differ in build metadata can't be told apart by an index"
+                    .to_string(),
+            )),
+        );
+    }
+
+    Ok(diags)
+}
+
+/// Finds every key in the manifest which elba doesn't know about.
+pub fn unknown_keys(raw: &str, value: &toml::Value) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    let top = match value.as_table() {
+        Some(top) => top,
+        None => return diags,
+    };
+
+    check_table(raw, top, &[], 0, TOP_KEYS, &mut diags);
+    if let Some(package) = top.get("package").and_then(|x| x.as_table()) {
+        check_table(raw, package, &["package"], 0, PACKAGE_KEYS, &mut diags);
+    }
+    for section in &["dependencies", "dev_dependencies", "test_dependencies"] {
+        let deps = match top.get(*section).and_then(|x| x.as_table()) {
+            Some(deps) => deps,
+            None => continue,
+        };
+        for (name, dep) in deps {
+            if let Some(dep) = dep.as_table() {
+                for key in dep.keys().filter(|k| !DEP_KEYS.contains(&k.as_str())) {
+                    diags.push(
+                        Diagnostic::new(
+                            Severity::Error,
+                            format!("unknown key `{}` in dependency {}", key, name),
+                            locate(raw, &[*section], 0, Some(name.as_str())),
+                        )
+                        .help(suggest(key, DEP_KEYS)),
+                    );
+                }
+            }
+        }
+    }
+    if let Some(native) = top.get("native").and_then(|x| x.as_table()) {
+        check_table(raw, native, &["native"], 0, NATIVE_KEYS, &mut diags);
+    }
+    if let Some(targets) = top.get("targets").and_then(|x| x.as_table()) {
+        check_table(raw, targets, &["targets"], 0, TARGETS_KEYS, &mut diags);
+        if let Some(lib) = targets.get("lib").and_then(|x| x.as_table()) {
+            check_table(raw, lib, &["targets", "lib"], 0, LIB_KEYS, &mut diags);
+        }
+        for kind in &["bin", "test"] {
+            let bins = targets.get(*kind).and_then(|x| x.as_array());
+            for (ix, bin) in bins.into_iter().flatten().enumerate() {
+                if let Some(bin) = bin.as_table() {
+                    check_table(raw, bin, &["targets", *kind], ix, BIN_KEYS, &mut diags);
+                }
+            }
+        }
+    }
+
+    diags
+}
+
+fn check_table(
+    raw: &str,
+    table: &toml::value::Table,
+    path: &[&str],
+    nth: usize,
+    known: &[&str],
+    diags: &mut Vec<Diagnostic>,
+) {
+    for key in table.keys().filter(|k| !known.contains(&k.as_str())) {
+        let message = if path.is_empty() {
+            format!("unknown section `{}`", key)
+        } else {
+            format!("unknown key `{}` in [{}]", key, path.join("."))
+        };
+        diags.push(
+            Diagnostic::new(
+                Severity::Error,
+                message,
+                locate(raw, path, nth, Some(key.as_str())),
+            )
+            .help(suggest(key, known)),
+        );
+    }
+}
+
+fn check_targets(root: &Path, raw: &str, manifest: &Manifest, diags: &mut Vec<Diagnostic>) {
+    if let Some(lib) = &manifest.targets.lib {
+        let src = root.join(&lib.path.0);
+        if !src.is_dir() {
+            diags.push(Diagnostic::new(
+                Severity::Error,
+                format!(
+                    "the lib target's path {} doesn't exist",
+                    lib.path.0.display()
+                ),
+                locate(raw, &["targets", "lib"], 0, Some("path")),
+            ));
+        } else if let Some(mods) = &lib.mods {
+            for m in mods.iter().filter(|m| !m.contains('*')) {
+                let file = src.join(mod_path(m));
+                if !file.with_extension("idr").exists() && !file.with_extension("lidr").exists() {
+                    diags.push(Diagnostic::new(
+                        Severity::Error,
+                        format!("module {} of the lib target has no source file", m),
+                        locate(raw, &["targets", "lib"], 0, Some("mods")),
+                    ));
+                }
+            }
+        }
+    }
+
+    let bins = manifest
+        .targets
+        .bin
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(ix, bin)| ("bin", ix, bin));
+    let tests = manifest
+        .targets
+        .test
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(ix, test)| ("test", ix, test.into()));
+    let mut names = HashSet::new();
+    for (kind, ix, bin) in bins.chain(tests) {
+        if bin.resolve_bin(root).is_none() {
+            diags.push(Diagnostic::new(
+                Severity::Error,
+                format!(
+                    "the main module {} of {} target {} doesn't exist under {}",
+                    bin.main,
+                    kind,
+                    bin.name(),
+                    bin.path.0.display()
+                ),
+                locate(raw, &["targets", kind], ix, Some("main")),
+            ));
+        }
+        if !names.insert(bin.name()) {
+            diags.push(Diagnostic::new(
+                Severity::Error,
+                format!("more than one target is named {}", bin.name()),
+                locate(raw, &["targets", kind], ix, None),
+            ));
+        }
+    }
+}
+
+fn check_deps(raw: &str, value: &toml::Value, manifest: &Manifest, diags: &mut Vec<Diagnostic>) {
+    for name in manifest.dev_dependencies.keys() {
+        if manifest.dependencies.contains_key(name) {
+            diags.push(
+                Diagnostic::new(
+                    Severity::Warning,
+                    format!("{} is in both [dependencies] and [dev_dependencies]", name),
+                    locate(raw, &["dev_dependencies"], 0, Some(name.as_str())),
+                )
+                .help(Some(
+                    "the requirement in [dev_dependencies] replaces the other one when \
+                     building tests; remove one of them"
+                        .to_string(),
+                )),
+            );
+        }
+    }
+
+    // Names which only differ in case or in hyphens and underscores are the same name, so only
+    // one of them would make it into the manifest.
+    for section in &["dependencies", "dev_dependencies", "test_dependencies"] {
+        let keys = value
+            .get(*section)
+            .and_then(|x| x.as_table())
+            .map(|deps| deps.keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut seen: Vec<(Name, &String)> = vec![];
+        for key in keys {
+            let name = match key.parse::<Name>() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if let Some((_, other)) = seen.iter().find(|(n, _)| n == &name) {
+                diags.push(Diagnostic::new(
+                    Severity::Error,
+                    format!(
+                        "{} and {} in [{}] are the same package",
+                        other, key, section
+                    ),
+                    locate(raw, &[*section], 0, Some(key.as_str())),
+                ));
+            }
+            seen.push((name, key));
+        }
+    }
+}
+
+/// Finds names which differ only in case or in hyphens and underscores from another name in the
+/// manifest. elba treats these as the same name, but other tools and case-sensitive file systems
+/// might not.
+fn check_spellings(raw: &str, manifest: &Manifest, diags: &mut Vec<Diagnostic>) {
+    let mut seen: Vec<&Name> = vec![manifest.name()];
+    for section in &["dependencies", "dev_dependencies", "test_dependencies"] {
+        let deps = match *section {
+            "dependencies" => &manifest.dependencies,
+            "dev_dependencies" => &manifest.dev_dependencies,
+            _ => &manifest.test_dependencies,
+        };
+        for name in deps.keys() {
+            match seen.iter().find(|n| *n == &name) {
+                Some(other) if other.as_str() != name.as_str() => diags.push(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        format!("{} is also spelled {} in the manifest", name, other),
+                        locate(raw, &[*section], 0, Some(name.as_str())),
+                    )
+                    .help(Some(format!(
+                        "names are compared ignoring case, `_` and `-`; spell it {} everywhere",
+                        other
+                    ))),
+                ),
+                Some(_) => {}
+                None => seen.push(name),
+            }
+        }
+    }
+}
+
+/// Finds the line a key is on. `path` is the table the key is in, and `nth` is which table to
+/// look in if the table is in an array of tables. If the key isn't given, this finds the table
+/// itself.
+pub fn locate(raw: &str, path: &[&str], nth: usize, key: Option<&str>) -> Option<Span> {
+    let lines = raw.lines().enumerate().collect::<Vec<_>>();
+    let header = path.join(".");
+    let span = |(line, text): (usize, &str)| Span {
+        line: line + 1,
+        col: text.len() - text.trim_start().len() + 1,
+    };
+    let is_header = |text: &str| text.trim_start().starts_with('[');
+
+    // The lines of the table, not counting its header.
+    let body: &[(usize, &str)] = if path.is_empty() {
+        let end = lines
+            .iter()
+            .position(|(_, l)| is_header(l))
+            .unwrap_or_else(|| lines.len());
+        &lines[..end]
+    } else {
+        let start = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, l))| {
+                let l = l.trim();
+                l == format!("[{}]", header) || l == format!("[[{}]]", header)
+            })
+            .nth(nth)
+            .map(|(ix, _)| ix)?;
+        if key.is_none() {
+            return Some(span(lines[start]));
+        }
+        let end = lines[start + 1..]
+            .iter()
+            .position(|(_, l)| is_header(l))
+            .map(|x| x + start + 1)
+            .unwrap_or_else(|| lines.len());
+        &lines[start + 1..end]
+    };
+    let key = key?;
+
+    let found = body.iter().find(|(_, l)| is_key(l, key)).cloned();
+    // Top-level keys can also be tables of their own, and tables can also be written as dotted
+    // keys of the table they're in.
+    found
+        .or_else(|| {
+            lines
+                .iter()
+                .find(|(_, l)| {
+                    let l = l.trim();
+                    let full = if header.is_empty() {
+                        key.to_owned()
+                    } else {
+                        format!("{}.{}", header, key)
+                    };
+                    l.starts_with(&format!("[{}]", full))
+                        || l.starts_with(&format!("[{}.", full))
+                        || l.starts_with(&format!("[[{}]]", full))
+                        || is_key(l, &full)
+                })
+                .cloned()
+        })
+        .map(span)
+}
+
+/// Whether a line of a manifest sets a key.
+fn is_key(line: &str, key: &str) -> bool {
+    let line = line.trim_start();
+    let rest = [key.to_owned(), format!("\"{}\"", key), format!("'{}'", key)]
+        .iter()
+        .find(|k| line.starts_with(k.as_str()))
+        .map(|k| line[k.len()..].trim_start());
+
+    rest.map(|rest| rest.starts_with('=') || rest.starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// The known key closest to an unknown one, if any are close enough to be what was meant.
+fn suggest(key: &str, known: &[&str]) -> Option<String> {
+    let normalized = key.to_ascii_lowercase().replace("-", "_");
+    known
+        .iter()
+        .map(|k| (distance(&normalized, k), k))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| format!("did you mean `{}`?", k))
+}
+
+/// The edit distance between two strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr.push(sub.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_unknown_keys() {
+        let raw = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+licence = 'MIT'
+
+[dev-dependencies]
+'ayy/x' = '2.0'
+"#;
+        let value = toml::from_str(raw).unwrap();
+        let diags = unknown_keys(raw, &value);
+
+        assert_eq!(diags.len(), 2);
+        assert_eq!(diags[0].span, Some(Span { line: 8, col: 1 }));
+        assert_eq!(
+            diags[0].help,
+            Some("did you mean `dev_dependencies`?".to_string())
+        );
+        assert_eq!(diags[1].span, Some(Span { line: 6, col: 1 }));
+        assert_eq!(diags[1].help, Some("did you mean `license`?".to_string()));
+    }
+
+    #[test]
+    fn lint_locate_nth() {
+        let raw = r#"
+[[targets.bin]]
+name = 'a'
+main = 'A'
+
+[[targets.bin]]
+name = 'b'
+  main = 'B'
+"#;
+
+        assert_eq!(
+            locate(raw, &["targets", "bin"], 1, Some("main")),
+            Some(Span { line: 8, col: 3 })
+        );
+    }
+}
@@ -2,17 +2,24 @@
 //!
 //! Lockfiles are created based on dependency constraints, and ensure that builds are repeatable
 
-use crate::util::graph::Graph;
+use crate::{
+    remote::resolution::{DirectRes, Resolution},
+    util::{graph::Graph, normalize_path, relative_path},
+};
 use failure::{Error, ResultExt};
 use indexmap::{IndexMap, IndexSet};
 use petgraph::{self, graph::NodeIndex};
-use serde::{Deserialize, Serialize};
-use std::iter::FromIterator;
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use std::{
+    cmp::Ordering,
+    iter::FromIterator,
+    path::{Path, PathBuf},
+};
 use toml;
 
 use super::*;
 
-#[derive(Clone, Deserialize, Debug, Serialize)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct LockfileToml {
     pub packages: IndexSet<LockedPkg>,
 }
@@ -23,6 +30,68 @@ pub struct LockedPkg {
     pub sum: Summary,
     #[serde(default = "Vec::new")]
     pub dependencies: Vec<Summary>,
+    /// What the contents of the package were when it was locked: the checksum of its tarball
+    /// (`sha256=...`), the commit of its git repository (`git=...`), or the hash of the files in
+    /// its directory (`tree=...`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Where the contents of the package were retrieved from: the tarball of a package from an
+    /// index, or the commit of a git repository. With this, retrieving the package again doesn't
+    /// depend on what the index says now.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<DirectRes>,
+    /// The features the package was resolved with, so that a locked build turns on exactly the
+    /// same ones. Nothing turns features on yet, so this is always empty for now.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// The platforms the package was resolved for, if it's only a dependency on some of them.
+    /// Empty means every platform.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+}
+
+// The order packages end up in depends on the order the solve was walked in, which can change
+// when nothing else has. We write everything out in a canonical order instead, so that the
+// lockfile only changes where the solve does.
+impl Serialize for LockfileToml {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut packages = self
+            .packages
+            .iter()
+            .map(|pkg| {
+                let mut dependencies = pkg.dependencies.clone();
+                dependencies.sort_by(cmp_sums);
+                dependencies.dedup();
+                let mut features = pkg.features.clone();
+                features.sort();
+                features.dedup();
+                let mut platforms = pkg.platforms.clone();
+                platforms.sort();
+                platforms.dedup();
+                LockedPkg {
+                    dependencies,
+                    features,
+                    platforms,
+                    ..pkg.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        packages.sort_by(|a, b| cmp_sums(&a.sum, &b.sum));
+
+        let mut s = serializer.serialize_struct("LockfileToml", 1)?;
+        s.serialize_field("packages", &packages)?;
+        s.end()
+    }
+}
+
+/// Orders summaries by their ids, and then by their versions.
+fn cmp_sums(a: &Summary, b: &Summary) -> Ordering {
+    a.id.to_string()
+        .cmp(&b.id.to_string())
+        .then_with(|| a.version.cmp(&b.version))
 }
 
 impl FromStr for LockfileToml {
@@ -35,26 +104,181 @@ impl FromStr for LockfileToml {
     }
 }
 
-impl Into<LockfileToml> for Graph<Summary> {
-    fn into(self) -> LockfileToml {
-        let root = &self[NodeIndex::new(0)];
-
-        let pkg_iter = self
-            .sub_tree(self.find_id(root).unwrap())
-            .map(|(_, pkg)| LockedPkg {
-                sum: pkg.clone(),
-                dependencies: self
-                    .children(self.find_id(pkg).unwrap())
-                    .map(|x| x.1)
-                    .cloned()
-                    .collect(),
-            });
+impl LockfileToml {
+    /// Rewrites the paths of all path dependencies to be relative to the project root, so that the
+    /// lockfile stays valid if the project gets moved or checked out somewhere else.
+    pub fn relative_to(self, root: &Path) -> Self {
+        self.map_dirs(|path| relative_path(path, root).unwrap_or_else(|| path.to_path_buf()))
+    }
+
+    /// Resolves the paths of all path dependencies against the project root. Absolute paths, which
+    /// older lockfiles contain, are left alone.
+    pub fn resolve_from(self, root: &Path) -> Self {
+        self.map_dirs(|path| normalize_path(&root.join(path)))
+    }
+
+    fn map_dirs(self, f: impl Fn(&Path) -> PathBuf) -> Self {
+        let map = |sum: Summary| {
+            if let Resolution::Direct(DirectRes::Dir { path }) = sum.id.resolution() {
+                let res = DirectRes::Dir { path: f(path) };
+                Summary::new(PackageId::new(sum.id.name().clone(), res.into()), sum.version)
+            } else {
+                sum
+            }
+        };
+
+        let packages = self
+            .packages
+            .into_iter()
+            .map(|pkg| LockedPkg {
+                sum: map(pkg.sum),
+                dependencies: pkg.dependencies.into_iter().map(map).collect(),
+                ..pkg
+            })
+            .collect();
+
+        LockfileToml { packages }
+    }
+
+    /// The checksums the packages in the lockfile were locked with.
+    pub fn checksums(&self) -> IndexMap<Summary, String> {
+        self.packages
+            .iter()
+            .filter_map(|pkg| Some((pkg.sum.clone(), pkg.checksum.clone()?)))
+            .collect()
+    }
+
+    /// Records the checksums of the packages in the lockfile.
+    pub fn with_checksums(self, checksums: &IndexMap<Summary, String>) -> Self {
+        let packages = self
+            .packages
+            .into_iter()
+            .map(|pkg| LockedPkg {
+                checksum: checksums.get(&pkg.sum).cloned().or(pkg.checksum),
+                ..pkg
+            })
+            .collect();
+
+        LockfileToml { packages }
+    }
+
+    /// Where the packages in the lockfile were retrieved from.
+    pub fn sources(&self) -> IndexMap<Summary, DirectRes> {
+        self.packages
+            .iter()
+            .filter_map(|pkg| Some((pkg.sum.clone(), pkg.source.clone()?)))
+            .collect()
+    }
+
+    /// Records where the packages in the lockfile were retrieved from.
+    pub fn with_sources(self, sources: &IndexMap<Summary, DirectRes>) -> Self {
+        let packages = self
+            .packages
+            .into_iter()
+            .map(|pkg| LockedPkg {
+                source: sources.get(&pkg.sum).cloned().or(pkg.source),
+                ..pkg
+            })
+            .collect();
+
+        LockfileToml { packages }
+    }
+
+    /// Combines two lockfiles, like the two sides of a merge. Where both of them lock the same
+    /// package, the newer version wins, and it keeps the dependencies it has on either side.
+    pub fn union(self, other: Self) -> Self {
+        let mut packages: Vec<LockedPkg> = vec![];
+        for pkg in self.packages.into_iter().chain(other.packages) {
+            match packages
+                .iter()
+                .position(|p| p.sum.id.lowkey_eq(&pkg.sum.id))
+            {
+                Some(i) => {
+                    let mut dependencies = packages[i].dependencies.clone();
+                    dependencies.extend(pkg.dependencies.iter().cloned());
+                    if packages[i].sum.version < pkg.sum.version {
+                        packages[i] = pkg;
+                    }
+                    packages[i].dependencies = dependencies;
+                }
+                None => packages.push(pkg),
+            }
+        }
+
+        // Dependencies on the version which lost out point to the one which won instead.
+        let sums = packages.iter().map(|p| p.sum.clone()).collect::<Vec<_>>();
+        let packages = packages
+            .into_iter()
+            .map(|pkg| {
+                let mut dependencies = pkg
+                    .dependencies
+                    .into_iter()
+                    .map(|dep| {
+                        sums.iter()
+                            .find(|sum| sum.id.lowkey_eq(&dep.id))
+                            .cloned()
+                            .unwrap_or(dep)
+                    })
+                    .collect::<Vec<_>>();
+                dependencies.sort_by(cmp_sums);
+                dependencies.dedup();
+                LockedPkg {
+                    dependencies,
+                    ..pkg
+                }
+            })
+            .collect();
+
+        LockfileToml { packages }
+    }
+
+    /// Drops every package which can't be reached from one of the given roots, which are the
+    /// packages a lockfile is for. Gives back the pruned lockfile along with what was dropped.
+    pub fn prune(self, roots: &[PackageId]) -> (Self, Vec<Summary>) {
+        let graph: Graph<Summary> = self.clone().into();
+        let reachable = graph
+            .inner
+            .node_indices()
+            .filter(|&ix| roots.contains(&graph.inner[ix].id))
+            .flat_map(|ix| graph.sub_tree(ix).map(|(_, sum)| sum.clone()))
+            .collect::<IndexSet<_>>();
+
+        let (packages, dropped): (Vec<_>, Vec<_>) = self
+            .packages
+            .into_iter()
+            .partition(|pkg| reachable.contains(&pkg.sum));
+
+        let packages = packages.into_iter().collect();
+        let dropped = dropped.into_iter().map(|pkg| pkg.sum).collect();
+
+        (LockfileToml { packages }, dropped)
+    }
+}
+
+impl LockfileToml {
+    /// The lockfile of the part of a solve which is reachable from one of its packages.
+    pub fn from_tree(solve: &Graph<Summary>, root: NodeIndex) -> Self {
+        let tree = solve.subgraph(root);
+        let pkg_iter = tree.inner.node_indices().map(|ix| LockedPkg {
+            sum: tree[ix].clone(),
+            dependencies: tree.children(ix).map(|x| x.1).cloned().collect(),
+            checksum: None,
+            source: None,
+            features: vec![],
+            platforms: vec![],
+        });
 
         let packages = IndexSet::from_iter(pkg_iter);
         LockfileToml { packages }
     }
 }
 
+impl Into<LockfileToml> for Graph<Summary> {
+    fn into(self) -> LockfileToml {
+        LockfileToml::from_tree(&self, NodeIndex::new(0))
+    }
+}
+
 // TODO: verify that this is a valid solve
 impl From<LockfileToml> for Graph<Summary> {
     fn from(f: LockfileToml) -> Self {
@@ -109,4 +333,226 @@ dependencies = [
 
         assert!(LockfileToml::from_str(lockfile).is_ok());
     }
+
+    #[test]
+    fn lockfile_canonical_order() {
+        let a = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "good/dep@dir+/here/else", version = "0.1.0" },
+    { id = "bad/dep@dir+/here/bad", version = "0.2.0" },
+]
+
+[[packages]]
+id = "good/dep@dir+/here/else"
+version = "0.1.0"
+
+[[packages]]
+id = "bad/dep@dir+/here/bad"
+version = "0.2.0"
+        "#;
+        let b = r#"
+[[packages]]
+id = "bad/dep@dir+/here/bad"
+version = "0.2.0"
+
+[[packages]]
+id = "good/dep@dir+/here/else"
+version = "0.1.0"
+
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "bad/dep@dir+/here/bad", version = "0.2.0" },
+    { id = "good/dep@dir+/here/else", version = "0.1.0" },
+]
+        "#;
+
+        let a = toml::to_string_pretty(&LockfileToml::from_str(a).unwrap()).unwrap();
+        let b = toml::to_string_pretty(&LockfileToml::from_str(b).unwrap()).unwrap();
+        assert_eq!(a, b);
+        assert!(a.find("bad/dep").unwrap() < a.find("good/dep").unwrap());
+    }
+
+    #[test]
+    fn lockfile_checksums() {
+        let lockfile = r#"
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+checksum = "sha256=b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c"
+
+[[packages]]
+id = "good/package@git+https://github.com/good/package#master"
+version = "1.0.5"
+        "#;
+
+        let lf = LockfileToml::from_str(lockfile).unwrap();
+        let checksums = lf.checksums();
+        assert_eq!(checksums.len(), 1);
+
+        let git = lf.packages.get_index(1).unwrap().sum.clone();
+        let mut new = IndexMap::new();
+        new.insert(git.clone(), "git=0123abcd".to_string());
+        let lf = lf.with_checksums(&new);
+        let checksums = lf.checksums();
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(checksums[&git], "git=0123abcd");
+    }
+
+    #[test]
+    fn lockfile_sources() {
+        let lockfile = r#"
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+source = "tar+https://elba.io/pkg/terminator/one/0.1.4.tar.gz"
+        "#;
+
+        let lf = LockfileToml::from_str(lockfile).unwrap();
+        let sources = lf.sources();
+        assert_eq!(
+            sources.values().next().unwrap().to_string(),
+            "tar+https://elba.io/pkg/terminator/one/0.1.4.tar.gz"
+        );
+
+        let lf = LockfileToml::from_str(&toml::to_string_pretty(&lf).unwrap()).unwrap();
+        assert_eq!(lf.sources(), sources);
+    }
+
+    #[test]
+    fn lockfile_relative_dirs() {
+        let lockfile = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "good/dep@dir+/here/else", version = "0.1.0" }
+]
+
+[[packages]]
+id = "good/dep@dir+/here/else"
+version = "0.1.0"
+        "#;
+
+        let root = Path::new("/here/there");
+        let lf = LockfileToml::from_str(lockfile).unwrap().relative_to(root);
+        let dep = &lf.packages.get_index(0).unwrap().dependencies[0];
+        assert_eq!(dep.id.to_string(), "good/dep@dir+../else");
+
+        let lf = lf.resolve_from(root);
+        let pkgs = lf.packages.iter().map(|x| x.sum.id.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            pkgs,
+            vec!["good/package@dir+/here/there", "good/dep@dir+/here/else"]
+        );
+    }
+
+    #[test]
+    fn lockfile_prune() {
+        let lockfile = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "terminator/one@index+tar+https://elba.io/pkg", version = "0.1.4" }
+]
+
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+
+[[packages]]
+id = "stale/two@index+tar+https://elba.io/pkg"
+version = "0.2.0"
+dependencies = [
+    { id = "terminator/one@index+tar+https://elba.io/pkg", version = "0.1.4" }
+]
+        "#;
+
+        let lf = LockfileToml::from_str(lockfile).unwrap();
+        let root = lf.packages.get_index(0).unwrap().sum.id.clone();
+        let (lf, dropped) = lf.prune(&[root]);
+
+        assert_eq!(lf.packages.len(), 2);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].name().to_string(), "stale/two");
+    }
+
+    #[test]
+    fn lockfile_resolution_data() {
+        let old = r#"
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+        "#;
+
+        let lf = LockfileToml::from_str(old).unwrap();
+        let pkg = lf.packages.get_index(0).unwrap();
+        assert!(pkg.features.is_empty() && pkg.platforms.is_empty());
+        assert!(!toml::to_string_pretty(&lf).unwrap().contains("features"));
+
+        let new = r#"
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+features = ["zeta", "alpha"]
+platforms = ["windows"]
+        "#;
+
+        let lf = LockfileToml::from_str(new).unwrap();
+        let lf = LockfileToml::from_str(&toml::to_string_pretty(&lf).unwrap()).unwrap();
+        let pkg = lf.packages.get_index(0).unwrap();
+        assert_eq!(pkg.features, vec!["alpha", "zeta"]);
+        assert_eq!(pkg.platforms, vec!["windows"]);
+    }
+
+    #[test]
+    fn lockfile_union() {
+        let ours = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "terminator/one@index+tar+https://elba.io/pkg", version = "0.1.4" }
+]
+
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+        "#;
+        let theirs = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "terminator/one@index+tar+https://elba.io/pkg", version = "0.2.0" },
+    { id = "other/two@index+tar+https://elba.io/pkg", version = "1.0.0" }
+]
+
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.2.0"
+
+[[packages]]
+id = "other/two@index+tar+https://elba.io/pkg"
+version = "1.0.0"
+        "#;
+
+        let lf = LockfileToml::from_str(ours)
+            .unwrap()
+            .union(LockfileToml::from_str(theirs).unwrap());
+        let root = lf.packages.get_index(0).unwrap();
+        let deps = root
+            .dependencies
+            .iter()
+            .map(|x| format!("{} {}", x.name(), x.version()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(lf.packages.len(), 3);
+        assert_eq!(deps, vec!["other/two 1.0.0", "terminator/one 0.2.0"]);
+    }
 }
@@ -1,16 +1,19 @@
 //! Package manifest files.
 
 use std::{
+    env,
+    ffi::OsStr,
+    marker::PhantomData,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-use failure::{format_err, Error, ResultExt};
+use failure::{bail, format_err, Error, ResultExt};
 use ignore::gitignore::GitignoreBuilder;
 use indexmap::IndexMap;
 use semver::Version;
 use semver_constraints::Constraint;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 use toml;
 use url::Url;
 use walkdir::{DirEntry, WalkDir};
@@ -18,27 +21,9 @@ use walkdir::{DirEntry, WalkDir};
 use super::*;
 use crate::{
     remote::resolution::{DirectRes, IndexRes},
-    util::{valid_file, SubPath},
+    util::{error, is_junk, lock::LOCK_FILE, mod_path, normalize_path, valid_file, SubPath},
 };
 
-// TODO: Package aliasing. Have dummy alias files in the root target folder.
-//
-// e.g. to alias `me/lightyear` with default root module `Me.Lightyear` as the module
-// `Yeet.Lightyeet`, in the target folder, we make the following file in the proper directory
-// (directory won't matter for Blodwen/Idris 2):
-//
-// ```idris
-// module Yeet.Lightyeet
-//
-// import public Me.Lightyear
-// ```
-//
-// Behind the scenes, we build this as its own package with the package it's aliasing as
-// its only dependency, throw it in the global cache, and add this to the import dir of the root
-// package instead of the original.
-//
-// With this in place, we can safely avoid module namespace conflicts.
-
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Manifest {
@@ -47,18 +32,27 @@ pub struct Manifest {
     pub dependencies: IndexMap<Name, DepReq>,
     #[serde(default = "IndexMap::new")]
     pub dev_dependencies: IndexMap<Name, DepReq>,
+    /// Dependencies which only the test targets can use. These are only built along with them.
+    #[serde(default = "IndexMap::new", skip_serializing_if = "IndexMap::is_empty")]
+    pub test_dependencies: IndexMap<Name, DepReq>,
     #[serde(default)]
     pub targets: Targets,
     #[serde(default)]
-    pub workspace: IndexMap<Name, SubPath>,
+    pub workspace: WorkspaceInfo,
     #[serde(default)]
     pub scripts: IndexMap<String, String>,
+    /// The native libraries the package's code links against.
+    #[serde(default, skip_serializing_if = "NativeInfo::is_empty")]
+    pub native: NativeInfo,
+    /// Arbitrary data for other tools to use, which elba doesn't look at.
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    pub metadata: toml::value::Table,
 }
 
 impl Manifest {
     // Returns only the workspace portion of a manifest.
-    pub fn workspace(s: &str) -> Option<IndexMap<Name, SubPath>> {
-        toml::value::Value::try_from(&s)
+    pub fn workspace(s: &str) -> Option<WorkspaceInfo> {
+        toml::from_str::<toml::Value>(s)
             .ok()?
             .get("workspace")?
             .clone()
@@ -66,6 +60,14 @@ impl Manifest {
             .ok()
     }
 
+    /// Parses a manifest which has already had everything it inherits from its workspace filled
+    /// in (see `inherit`).
+    pub fn from_value(value: toml::Value) -> Result<Self> {
+        let toml: Manifest = value.try_into().map_err(invalid_manifest)?;
+        toml.validate()?;
+        Ok(toml)
+    }
+
     pub fn version(&self) -> &Version {
         &self.package.version
     }
@@ -81,23 +83,71 @@ impl Manifest {
         dev_deps: bool,
     ) -> Result<IndexMap<PackageId, Constraint>> {
         let mut deps = IndexMap::new();
-        for (n, dep) in &self.dependencies {
+        let mut keys: IndexMap<PackageId, &Name> = IndexMap::new();
+        let reqs = self
+            .dependencies
+            .iter()
+            .chain(self.dev_deps().filter(|_| dev_deps));
+        for (n, dep) in reqs {
             let dep = dep.clone();
             let (pid, c) = dep.into_dep(ixmap, parent_pkg, n.clone())?;
-            deps.insert(pid, c);
-        }
-
-        if dev_deps {
-            for (n, dep) in &self.dev_dependencies {
-                let dep = dep.clone();
-                let (pid, c) = dep.into_dep(ixmap, parent_pkg, n.clone())?;
-                deps.insert(pid, c);
+            // Two aliases can't stand for the same package, since we'd only have one version of
+            // it to give them.
+            if let Some(other) = keys.insert(pid.clone(), n) {
+                if other != n {
+                    bail!(
+                        "{} and {} are both {} ({}); aliases have to be of different packages",
+                        other,
+                        n,
+                        pid.name(),
+                        pid.resolution()
+                    )
+                }
             }
+            deps.insert(pid, c);
         }
 
         Ok(deps)
     }
 
+    /// Every dependency which only the root package needs: its dev dependencies and test
+    /// dependencies.
+    pub fn dev_deps(&self) -> impl Iterator<Item = (&Name, &DepReq)> {
+        self.dev_dependencies
+            .iter()
+            .chain(self.test_dependencies.iter())
+    }
+
+    /// Whether a package is only a dependency of the test targets.
+    pub fn is_test_only(&self, name: &Name) -> bool {
+        let is = |(n, dep): (&Name, &DepReq)| dep.package().unwrap_or(n) == name;
+        self.test_dependencies.iter().any(is)
+            && !self
+                .dependencies
+                .iter()
+                .chain(self.dev_dependencies.iter())
+                .any(is)
+    }
+
+    /// The requirement on a package, whether it's under the package's own name or an alias.
+    pub fn dep_req(&self, name: &Name, dev_deps: bool) -> Option<(&Name, &DepReq)> {
+        self.dependencies
+            .iter()
+            .chain(self.dev_deps().filter(|_| dev_deps))
+            .find(|(n, dep)| dep.package().unwrap_or(n) == name)
+    }
+
+    /// Every dependency which is under an alias, mapped from the alias to the name of the
+    /// package.
+    pub fn aliases(&self, dev_deps: bool) -> IndexMap<Name, Name> {
+        self.dependencies
+            .iter()
+            .chain(self.dev_deps().filter(|_| dev_deps))
+            .filter_map(|(n, dep)| Some((n.clone(), dep.package()?.clone())))
+            .filter(|(alias, name)| alias != name)
+            .collect()
+    }
+
     pub fn list_files<P>(
         &self,
         pkg_root: &Path,
@@ -122,14 +172,36 @@ impl Manifest {
             .build()
             .with_context(|e| format_err!("invalid excludes: {}", e))?;
 
+        // Included files are kept even if they'd otherwise be excluded.
+        let mut includes = GitignoreBuilder::new(pkg_root);
+        if let Some(rs) = self.package.include.as_ref() {
+            for r in rs {
+                includes.add_line(None, r)?;
+            }
+        }
+        let includes = includes
+            .build()
+            .with_context(|e| format_err!("invalid includes: {}", e))?;
+
+        let patterns = self.package.include.clone().unwrap_or_default();
+        let root = pkg_root.to_path_buf();
+
         let walker = WalkDir::new(search_root)
             .follow_links(true)
             .into_iter()
             .filter_entry(move |x| {
-                !excludes
-                    .matched_path_or_any_parents(x.path(), x.file_type().is_dir())
+                let is_dir = x.file_type().is_dir();
+                let kept = !excludes
+                    .matched_path_or_any_parents(x.path(), is_dir)
                     .is_ignore()
-                    && p(&x)
+                    // We can't leave out an excluded directory if something in it might be
+                    // included.
+                    || (is_dir && may_include(&patterns, &root, x.path()))
+                    || includes
+                        .matched_path_or_any_parents(x.path(), is_dir)
+                        .is_ignore();
+
+                x.file_name() != LOCK_FILE && !is_junk(x.file_name()) && kept && p(&x)
             })
             .filter_map(|x| {
                 x.ok()
@@ -139,6 +211,127 @@ impl Manifest {
         Ok(walker)
     }
 
+    /// The modules of the lib target, found from the source files under its path if they aren't
+    /// listed. `root` is the directory of the package.
+    pub fn lib_mods(&self, root: &Path) -> Result<Vec<String>> {
+        match self.targets.lib.as_ref().and_then(|lib| lib.mods.as_ref()) {
+            Some(mods) => Ok(mods.clone()),
+            None => self.discover_mods(root),
+        }
+    }
+
+    /// Expands the patterns in the mods of the lib target into the modules they match. In a
+    /// pattern, `*` stands for any part of one component of a module name, and `**` for any
+    /// number of components.
+    pub fn expand_mods(&mut self, root: &Path) -> Result<()> {
+        let patterns = match self.targets.lib.as_ref().and_then(|lib| lib.mods.as_ref()) {
+            Some(mods) if mods.iter().any(|m| m.contains('*')) => mods.clone(),
+            _ => return Ok(()),
+        };
+        let found = self.discover_mods(root)?;
+
+        let mut mods: Vec<String> = vec![];
+        for pattern in patterns {
+            let pattern = pattern.trim_matches('.');
+            let matched = if pattern.contains('*') {
+                let matched = found
+                    .iter()
+                    .filter(|m| mod_matches(pattern, m))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if matched.is_empty() {
+                    bail!("the pattern {} in mods doesn't match any modules", pattern)
+                }
+                matched
+            } else {
+                vec![pattern.to_owned()]
+            };
+            for m in matched {
+                if !mods.contains(&m) {
+                    mods.push(m);
+                }
+            }
+        }
+
+        if let Some(lib) = self.targets.lib.as_mut() {
+            lib.mods = Some(mods);
+        }
+
+        Ok(())
+    }
+
+    /// Every module with a source file under the path of the lib target, except for the ones the
+    /// lib target excludes and the mains of bin and test targets.
+    pub fn discover_mods(&self, root: &Path) -> Result<Vec<String>> {
+        let lib = match &self.targets.lib {
+            Some(lib) => lib,
+            None => return Ok(vec![]),
+        };
+        let src_path = root.join(&lib.path.0);
+        let mains = self
+            .targets
+            .bin
+            .iter()
+            .cloned()
+            .chain(self.targets.test.iter().cloned().map(BinTarget::from))
+            .filter_map(|bin| bin.resolve_bin(root))
+            .map(|(src, target)| normalize_path(&src.join(target)))
+            .collect::<Vec<_>>();
+
+        let mut mods = self
+            .list_files(root, &src_path, |x| {
+                x.file_name() != ".git" && x.file_name() != "target"
+            })?
+            .filter(|x| {
+                let ext = x.path().extension();
+                ext == Some(OsStr::new("idr")) || ext == Some(OsStr::new("lidr"))
+            })
+            .filter(|x| !mains.contains(&normalize_path(x.path())))
+            .filter_map(|x| module_name(x.path().strip_prefix(&src_path).ok()?))
+            .filter(|m| {
+                !lib.exclude.iter().any(|ex| {
+                    let ex = ex.trim_matches('.');
+                    m == ex || m.starts_with(&format!("{}.", ex))
+                })
+            })
+            .collect::<Vec<_>>();
+        mods.sort();
+
+        Ok(mods)
+    }
+
+    /// Every Idris source file under the paths of the targets of the package, which is what
+    /// `elba fmt` and `elba lint` act on. `root` is the directory of the package.
+    pub fn source_files(&self, root: &Path) -> Result<Vec<PathBuf>> {
+        let paths = self
+            .targets
+            .lib
+            .iter()
+            .map(|lib| &lib.path)
+            .chain(self.targets.bin.iter().map(|bin| &bin.path))
+            .chain(self.targets.test.iter().map(|test| &test.path))
+            .map(|path| root.join(&path.0))
+            .collect::<Vec<_>>();
+
+        let mut files = vec![];
+        for path in paths.iter().filter(|path| path.exists()) {
+            files.extend(
+                self.list_files(root, path, |x| {
+                    x.file_name() != ".git" && x.file_name() != "target"
+                })?
+                .filter(|x| {
+                    let ext = x.path().extension();
+                    ext == Some(OsStr::new("idr")) || ext == Some(OsStr::new("lidr"))
+                })
+                .map(|x| normalize_path(x.path())),
+            );
+        }
+        files.sort();
+        files.dedup();
+
+        Ok(files)
+    }
+
     pub fn validate(&self) -> Result<()> {
         if self
             .package
@@ -177,53 +370,552 @@ impl Manifest {
         {
             bail!(format_err!("one of the keywords contains whitespace"));
         }
+        if self.targets.bin.iter().any(|bin| bin.name.is_none()) {
+            bail!("every {} target needs a name", Bin::NAME)
+        }
+        let outputs = self
+            .targets
+            .bin
+            .iter()
+            .map(|bin| (Bin::NAME, bin.name(), &bin.output))
+            .chain(
+                self.targets
+                    .test
+                    .iter()
+                    .map(|test| (Test::NAME, test.name(), &test.output)),
+            );
+        for (kind, name, output) in outputs {
+            if let Some(output) = output {
+                if output.is_empty() || output.contains(|c: char| c == '/' || c == '\\') {
+                    bail!(
+                        "the output of {} target {} has to be a file name, not `{}`",
+                        kind,
+                        name,
+                        output
+                    )
+                }
+            }
+        }
+        for lib in self.native.libs.iter().chain(self.native.pkg_config.iter()) {
+            if lib.trim().is_empty() || lib.contains(char::is_whitespace) {
+                bail!("native library `{}` isn't a valid library name", lib)
+            }
+        }
+        if let Some(default_run) = &self.package.default_run {
+            if !self
+                .targets
+                .bin
+                .iter()
+                .any(|bin| &bin.name() == default_run)
+            {
+                bail!(
+                    "default_run is {}, but there's no bin target by that name",
+                    default_run
+                )
+            }
+        }
         Ok(())
     }
+
+    /// Picks the bin target to run: the one named `name`, or else the one named by `default_run`,
+    /// or else the only one there is.
+    pub fn run_target(&self, name: Option<&str>) -> Result<usize> {
+        let bins = &self.targets.bin;
+        let available = || {
+            bins.iter()
+                .map(|bin| bin.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let name = match name.or_else(|| self.package.default_run.as_ref().map(|x| x.as_str())) {
+            Some(name) => name,
+            None => match bins.len() {
+                0 => {
+                    bail!("the package doesn't have any binary targets. add one before proceeding")
+                }
+                1 => return Ok(0),
+                _ => bail!(
+                    "the package has more than one bin target; pick one with --bin or set \
+                     default_run in [package] (available bins: {})",
+                    available()
+                ),
+            },
+        };
+
+        bins.iter()
+            .position(|bin| bin.name() == name)
+            .ok_or_else(|| {
+                format_err!(
+                    "the package doesn't have a bin target named {} (available bins: {})",
+                    name,
+                    available()
+                )
+            })
+    }
 }
 
 impl FromStr for Manifest {
     type Err = failure::Error;
 
     fn from_str(raw: &str) -> Result<Self> {
-        let toml: Manifest = toml::from_str(raw)
-            .with_context(|e| format_err!("invalid manifest file: {}", e))
-            .map_err(Error::from)?;
-        toml.validate()?;
-        Ok(toml)
+        Manifest::from_value(inherit(raw, None, None)?)
+    }
+}
+
+/// Marks an error as coming from an invalid manifest, keeping what was wrong with it in the
+/// message.
+fn invalid_manifest(e: impl Into<Error>) -> Error {
+    let e = e.into();
+    let message = format!("invalid manifest file: {}", e);
+    Error::from(e.context(error::Error::InvalidManifest))
+        .context(message)
+        .into()
+}
+
+/// The newest version of the manifest format that we understand.
+///
+/// Only version 1 exists so far. When the format changes in a way older versions of elba would
+/// misread, it gets a new version, and older manifests are brought up to date in `inherit`
+/// before they're parsed.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Checks that we understand the version of the manifest format a manifest is written in, before
+/// trying to make sense of anything else in it.
+pub fn check_version(value: &toml::Value) -> Result<()> {
+    let version = match value.get("package").and_then(|x| x.get("manifest_version")) {
+        None => return Ok(()),
+        Some(toml::Value::Integer(v)) if *v >= 1 => *v,
+        Some(_) => bail!("manifest_version has to be a whole number of at least 1"),
+    };
+    if version > i64::from(MANIFEST_VERSION) {
+        bail!(
+            "this manifest requires a newer version of elba: it's written in version {} of the \
+             manifest format, and this version of elba only understands up to version {}",
+            version,
+            MANIFEST_VERSION
+        )
+    }
+
+    Ok(())
+}
+
+/// The `[workspace]` section of a manifest.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct WorkspaceInfo {
+    /// The fields of `[package]` which members can inherit.
+    #[serde(default)]
+    pub package: InheritedInfo,
+    /// The dependencies which members can inherit. Path dependencies are relative to the root of
+    /// the workspace.
+    #[serde(default = "IndexMap::new")]
+    pub dependencies: IndexMap<Name, DepReq>,
+    /// The name and location of every member of the workspace.
+    #[serde(flatten)]
+    pub members: IndexMap<Name, SubPath>,
+}
+
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InheritedInfo {
+    pub version: Option<Version>,
+    pub authors: Option<Vec<String>>,
+    pub license: Option<String>,
+}
+
+/// Parses a manifest, filling in every field marked `{ workspace = true }` with what the
+/// `[workspace]` section of its workspace root gives it. `dir` is the directory of the package, if
+/// it's known, and `ws` is the directory of the workspace root along with its `[workspace]`
+/// section, if the package is in a workspace.
+///
+/// The fields which can be inherited are `version`, `authors` and `license` in `[package]`, and
+/// the entries of `[dependencies]`, `[dev_dependencies]` and `[test_dependencies]`.
+///
+/// The variables in the paths of path dependencies and in the `idris_opts` of targets are filled
+/// in here too (see `interpolate`).
+pub fn inherit(
+    raw: &str,
+    dir: Option<&Path>,
+    ws: Option<(&Path, &WorkspaceInfo)>,
+) -> Result<toml::Value> {
+    let mut value: toml::Value = toml::from_str(raw).map_err(invalid_manifest)?;
+    check_version(&value)?;
+    let unknown = lint::unknown_keys(raw, &value);
+    if !unknown.is_empty() {
+        let unknown = unknown
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(invalid_manifest(format_err!("{}", unknown)));
+    }
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return Ok(value),
+    };
+    let workspace = |field: &str| {
+        ws.ok_or_else(|| {
+            format_err!(
+                "{} is inherited from the workspace, but the package isn't in one",
+                field
+            )
+        })
+    };
+
+    if let Some(package) = table.get_mut("package").and_then(|x| x.as_table_mut()) {
+        for field in &["version", "authors", "license"] {
+            if !package.get(*field).map(is_inherited).unwrap_or(false) {
+                continue;
+            }
+            let (_, ws) = workspace(field)?;
+            let inherited = match *field {
+                "version" => ws
+                    .package
+                    .version
+                    .as_ref()
+                    .map(|x| toml::Value::String(x.to_string())),
+                "authors" => ws.package.authors.as_ref().map(|x| {
+                    toml::Value::Array(x.iter().cloned().map(toml::Value::String).collect())
+                }),
+                _ => ws.package.license.clone().map(toml::Value::String),
+            };
+            let inherited = inherited.ok_or_else(|| {
+                format_err!(
+                    "{} is inherited from the workspace, but [workspace.package] doesn't set it",
+                    field
+                )
+            })?;
+            package.insert(field.to_string(), inherited);
+        }
+    }
+
+    for section in &["dependencies", "dev_dependencies", "test_dependencies"] {
+        let deps = match table.get_mut(*section).and_then(|x| x.as_table_mut()) {
+            Some(deps) => deps,
+            None => continue,
+        };
+        for (key, dep) in deps.iter_mut() {
+            if !is_inherited(dep) {
+                continue;
+            }
+            let (root, ws) = workspace(key)?;
+            let name = Name::from_str(key)?;
+            let req = ws
+                .dependencies
+                .iter()
+                .find(|(n, _)| *n == &name)
+                .map(|(_, req)| req.clone())
+                .ok_or_else(|| {
+                    format_err!(
+                        "{} is inherited from the workspace, but it isn't in \
+                         [workspace.dependencies]",
+                        name
+                    )
+                })?;
+            let req = match req {
+                DepReq::Local { path, package } => DepReq::Local {
+                    path: normalize_path(&root.join(path)),
+                    package,
+                },
+                req => req,
+            };
+            *dep = toml::Value::try_from(req)?;
+        }
+    }
+
+    let root = ws.map(|(root, _)| root).or(dir);
+    for section in &["dependencies", "dev_dependencies", "test_dependencies"] {
+        let deps = match table.get_mut(*section).and_then(|x| x.as_table_mut()) {
+            Some(deps) => deps,
+            None => continue,
+        };
+        for (key, dep) in deps.iter_mut() {
+            let req = match dep {
+                toml::Value::String(s) => shorthand(s)
+                    .with_context(|e| format_err!("invalid dependency {} = `{}`: {}", key, s, e))?,
+                toml::Value::Table(t) => {
+                    host_shorthand(t)
+                        .with_context(|e| format_err!("invalid dependency {}: {}", key, e))?;
+                    None
+                }
+                _ => None,
+            };
+            if let Some(req) = req {
+                *dep = toml::Value::try_from(req)?;
+            }
+            if let Some(toml::Value::String(path)) = dep.get_mut("path") {
+                *path = interpolate(path, root)?;
+            }
+        }
+    }
+    if let Some(targets) = table.get_mut("targets").and_then(|x| x.as_table_mut()) {
+        for (kind, target) in targets.iter_mut() {
+            let targets = match (kind.as_str(), target) {
+                ("lib", target) => vec![target],
+                (_, toml::Value::Array(targets)) => targets.iter_mut().collect(),
+                _ => continue,
+            };
+            let opts = targets
+                .into_iter()
+                .filter_map(|t| t.get_mut("idris_opts").and_then(|x| x.as_array_mut()))
+                .flatten();
+            for opt in opts {
+                if let toml::Value::String(opt) = opt {
+                    *opt = interpolate(opt, root)?;
+                }
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+/// Expands the shorthands for a dependency on a git repository or a directory, which are
+/// `"git+<url>#<tag>"` and `"path+<path>"` (or `"dir+<path>"`, as in a resolution), into the
+/// requirements they stand for. Any other string is a version constraint, and is left alone.
+fn shorthand(s: &str) -> Result<Option<DepReq>> {
+    let res = if s.starts_with("path+") {
+        format!("dir+{}", &s["path+".len()..])
+    } else if s.starts_with("git+") || s.starts_with("dir+") {
+        s.to_owned()
+    } else {
+        return Ok(None);
+    };
+
+    match DirectRes::from_str(&res)? {
+        DirectRes::Git { repo, tag } => Ok(Some(DepReq::Git {
+            git: repo,
+            tag,
+            package: None,
+        })),
+        DirectRes::Dir { path } => Ok(Some(DepReq::Local {
+            path,
+            package: None,
+        })),
+        DirectRes::Tar { .. } => unreachable!(),
+    }
+}
+
+/// The git hosts a dependency can name a repository on by its owner and name alone, and the URL
+/// of the repository `owner/repo` on each.
+const GIT_HOSTS: &[(&str, &str)] = &[
+    ("github", "https://github.com/"),
+    ("gitlab", "https://gitlab.com/"),
+    ("sourcehut", "https://git.sr.ht/~"),
+];
+
+/// Expands a dependency like `{ github = "owner/repo", tag = "v1.0" }` into the git dependency
+/// on the repository's URL it stands for. GitLab repositories can be in subgroups, so they can
+/// have more than one owner.
+fn host_shorthand(dep: &mut toml::value::Table) -> Result<()> {
+    let hosts = GIT_HOSTS
+        .iter()
+        .filter(|(host, _)| dep.contains_key(*host))
+        .collect::<Vec<_>>();
+    let (host, base) = match hosts.as_slice() {
+        [] => return Ok(()),
+        [host] => host,
+        _ => bail!("a dependency can only be on one git host"),
+    };
+    if dep.contains_key("git") {
+        bail!("{} and git can't both be given", host)
     }
+
+    let repo = match dep.remove(*host) {
+        Some(toml::Value::String(repo)) => repo,
+        _ => bail!("{} has to be a string like \"owner/repo\"", host),
+    };
+    let repo = repo.trim_matches('/').trim_end_matches(".git");
+    let parts = repo.trim_start_matches('~').split('/').collect::<Vec<_>>();
+    let valid = parts.len() >= 2
+        && (parts.len() == 2 || *host == "gitlab")
+        && parts.iter().all(|x| {
+            !x.is_empty()
+                && *x != "."
+                && *x != ".."
+                && x.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        });
+    if !valid {
+        bail!(
+            "{} = \"{}\" isn't a repository; it should look like \"owner/repo\"",
+            host,
+            repo
+        )
+    }
+
+    let url = format!("{}{}", base, parts.join("/"));
+    dep.insert("git".to_owned(), toml::Value::String(url));
+    Ok(())
+}
+
+/// Fills in the variables in a string from the manifest. `${ELBA_ROOT}` is `root`: the root of the
+/// workspace the package is in, or the package's own directory if it isn't in one. `${env:NAME}`
+/// is the environment variable `NAME`. Anything else in `${}` is an error, as is a variable which
+/// isn't set.
+fn interpolate(s: &str, root: Option<&Path>) -> Result<String> {
+    let mut res = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        res.push_str(&rest[..start]);
+        let len = rest[start..]
+            .find('}')
+            .ok_or_else(|| format_err!("`${{` is never closed in `{}`", s))?;
+        let var = &rest[start + 2..start + len];
+        if var == "ELBA_ROOT" {
+            let root = root.ok_or_else(|| {
+                format_err!(
+                    "`{}` uses ${{ELBA_ROOT}}, but the directory of the package isn't known",
+                    s
+                )
+            })?;
+            res.push_str(&root.to_string_lossy());
+        } else if var.starts_with("env:") {
+            let name = &var[4..];
+            let val = env::var(name).with_context(|e| {
+                format_err!(
+                    "`{}` uses the environment variable {}, which can't be used: {}",
+                    s,
+                    name,
+                    e
+                )
+            })?;
+            res.push_str(&val);
+        } else {
+            bail!(
+                "unknown variable `${{{}}}` in `{}`; the variables are ${{ELBA_ROOT}} and \
+                 ${{env:NAME}}",
+                var,
+                s
+            )
+        }
+        rest = &rest[start + len + 1..];
+    }
+    res.push_str(rest);
+
+    Ok(res)
+}
+
+/// The name of the module at a path relative to a source directory, if the path can be one.
+fn module_name(path: &Path) -> Option<String> {
+    let path = path.with_extension("");
+    let parts = path
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    let valid = parts.iter().all(|p| {
+        p.chars().next().map(char::is_alphabetic).unwrap_or(false)
+            && p.chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '\'')
+    });
+
+    if valid {
+        Some(parts.join("."))
+    } else {
+        None
+    }
+}
+
+/// Whether a module name matches a pattern from the mods of a lib target.
+fn mod_matches(pattern: &str, module: &str) -> bool {
+    fn components(p: &[&str], m: &[&str]) -> bool {
+        match p.split_first() {
+            None => m.is_empty(),
+            Some((&"**", rest)) => components(rest, m) || (!m.is_empty() && components(p, &m[1..])),
+            Some((first, rest)) => {
+                !m.is_empty()
+                    && component(first.as_bytes(), m[0].as_bytes())
+                    && components(rest, &m[1..])
+            }
+        }
+    }
+
+    fn component(p: &[u8], m: &[u8]) -> bool {
+        match p.split_first() {
+            None => m.is_empty(),
+            Some((b'*', rest)) => component(rest, m) || (!m.is_empty() && component(p, &m[1..])),
+            Some((c, rest)) => m.first() == Some(c) && component(rest, &m[1..]),
+        }
+    }
+
+    let p = pattern.split('.').collect::<Vec<_>>();
+    let m = module.split('.').collect::<Vec<_>>();
+    components(&p, &m)
+}
+
+/// Whether a field of a manifest is `{ workspace = true }`.
+fn is_inherited(value: &toml::Value) -> bool {
+    value
+        .as_table()
+        .filter(|t| t.len() == 1)
+        .and_then(|t| t.get("workspace"))
+        .and_then(|x| x.as_bool())
+        == Some(true)
 }
 
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PackageInfo {
+    /// The version of the manifest format the manifest is written in. Manifests without one are
+    /// in version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_version: Option<u32>,
     pub name: Name,
+    #[serde(deserialize_with = "deserialize_version")]
     pub version: Version,
     pub authors: Vec<String>,
     pub description: Option<String>,
     #[serde(default = "Vec::new")]
     pub keywords: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub categories: Vec<String>,
     pub homepage: Option<String>,
     pub repository: Option<String>,
     pub readme: Option<SubPath>,
     pub license: Option<String>,
     pub exclude: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    /// The bin target `elba run` runs when it isn't told which one to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_run: Option<String>,
+    /// Arbitrary data for other tools to use, which is published along with the package.
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    pub metadata: toml::value::Table,
 }
 
+/// Parses the version of a package, naming the version in the error if it isn't one.
+fn deserialize_version<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Version, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Version::parse(&s).map_err(|e| de::Error::custom(format!("invalid version `{}`: {}", s, e)))
+}
+
+/// A dependency requirement. Any of the long forms can give the name of the package being
+/// depended on in `package`, in which case the key the requirement is under is just an alias for
+/// it.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged, deny_unknown_fields)]
 pub enum DepReq {
     Registry(Constraint),
     RegLong {
         version: Constraint,
-        index: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        package: Option<Name>,
     },
     Local {
         path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        package: Option<Name>,
     },
     Git {
         git: Url,
         #[serde(default = "default_tag")]
         tag: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        package: Option<Name>,
     },
 }
 
@@ -232,21 +924,51 @@ fn default_tag() -> String {
 }
 
 impl DepReq {
+    /// The name of the package being depended on, if the requirement is under an alias for it.
+    pub fn package(&self) -> Option<&Name> {
+        match self {
+            DepReq::Registry(_) => None,
+            DepReq::RegLong { package, .. }
+            | DepReq::Local { package, .. }
+            | DepReq::Git { package, .. } => package.as_ref(),
+        }
+    }
+
+    /// Whether the requirement is on a package from whichever index has it, rather than from a
+    /// particular one.
+    pub fn is_bare(&self) -> bool {
+        match self {
+            DepReq::Registry(_) | DepReq::RegLong { index: None, .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Turns the requirement into a dependency, where `n` is the key the requirement is under.
     pub fn into_dep(
         self,
         ixmap: &IndexMap<String, IndexRes>,
         parent_pkg: &PackageId,
         n: Name,
     ) -> Result<(PackageId, Constraint)> {
+        let n = self.package().cloned().unwrap_or(n);
         match self {
-            DepReq::Registry(c) => {
+            DepReq::Registry(version)
+            | DepReq::RegLong {
+                version,
+                index: None,
+                ..
+            } => {
                 let def_index = ixmap
                     .get_index(0)
                     .ok_or_else(|| format_err!("no default index"))?;
                 let pi = PackageId::new(n, def_index.1.clone().into());
-                Ok((pi, c))
+                Ok((pi, version))
             }
-            DepReq::RegLong { version, index } => {
+            DepReq::RegLong {
+                version,
+                index: Some(index),
+                ..
+            } => {
                 if let Some(mapped) = ixmap.get(&index) {
                     let pi = PackageId::new(n, mapped.clone().into());
                     Ok((pi, version))
@@ -256,12 +978,14 @@ impl DepReq {
                     Ok((pi, version))
                 }
             }
-            DepReq::Local { path } => {
+            DepReq::Local { path, .. } => {
                 if let &Resolution::Direct(DirectRes::Dir { path: parent_root }) =
                     &parent_pkg.resolution()
                 {
+                    // Path dependencies are relative to the manifest which declares them, not to
+                    // wherever elba happens to have been invoked from.
                     let res = DirectRes::Dir {
-                        path: parent_root.join(path),
+                        path: normalize_path(&parent_root.join(path)),
                     };
                     let pi = PackageId::new(n, res.into());
                     Ok((pi, Constraint::any()))
@@ -274,7 +998,7 @@ impl DepReq {
                     ))
                 }
             }
-            DepReq::Git { git, tag } => {
+            DepReq::Git { git, tag, .. } => {
                 let res = DirectRes::Git { repo: git, tag };
                 let pi = PackageId::new(n, res.into());
                 Ok((pi, Constraint::any()))
@@ -283,6 +1007,24 @@ impl DepReq {
     }
 }
 
+/// Native libraries which have to be installed on the system for a package to link.
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct NativeInfo {
+    /// Libraries to link against by name, like `gmp` for `-lgmp`.
+    #[serde(default = "Vec::new")]
+    pub libs: Vec<String>,
+    /// Libraries to look up with pkg-config, which knows the flags they need.
+    #[serde(default = "Vec::new", rename = "pkg-config")]
+    pub pkg_config: Vec<String>,
+}
+
+impl NativeInfo {
+    pub fn is_empty(&self) -> bool {
+        self.libs.is_empty() && self.pkg_config.is_empty()
+    }
+}
+
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Targets {
@@ -298,7 +1040,14 @@ pub struct Targets {
 pub struct LibTarget {
     #[serde(default = "default_lib_subpath")]
     pub path: SubPath,
-    pub mods: Vec<String>,
+    /// The modules of the library. If they aren't listed, every module with a source file under
+    /// `path` is part of the library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mods: Option<Vec<String>>,
+    /// Modules to leave out of the library when its modules aren't listed, along with every
+    /// module under them. These are also left alone by `elba build --check-mods`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
     #[serde(default)]
     pub idris_opts: Vec<String>,
 }
@@ -307,58 +1056,121 @@ fn default_lib_subpath() -> SubPath {
     SubPath::from_path(Path::new("src")).unwrap()
 }
 
-#[serde(deny_unknown_fields)]
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
-pub struct BinTarget {
-    pub name: String,
-    #[serde(default = "default_bin_subpath")]
-    pub path: SubPath,
-    pub main: String,
-    #[serde(default)]
-    pub idris_opts: Vec<String>,
+/// A kind of target which is built into an executable, which decides what the fields of its
+/// `TargetSpec` default to.
+pub trait TargetKind {
+    /// What the kind of target is called in the manifest.
+    const NAME: &'static str;
+
+    /// The directory `main` is looked for in when a target doesn't give a `path`.
+    fn default_path() -> SubPath;
+
+    /// The name of a target which doesn't give one, if this kind of target can go without.
+    fn default_name(main: &str) -> Option<String>;
 }
 
-fn default_bin_subpath() -> SubPath {
-    SubPath::from_path(Path::new("src")).unwrap()
+/// A `[[targets.bin]]`, which has to be given a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bin {}
+
+impl TargetKind for Bin {
+    const NAME: &'static str = "bin";
+
+    fn default_path() -> SubPath {
+        SubPath::from_path(Path::new("src")).unwrap()
+    }
+
+    fn default_name(_: &str) -> Option<String> {
+        None
+    }
 }
 
-/// A TestTarget is literally exactly the same as a BinTarget, with the only difference being
-/// the difference in default path.
-///
-/// I know, code duplication sucks and is stupid, but what can ya do :v
-#[serde(deny_unknown_fields)]
+/// A `[[targets.test]]`, which is named after its main module by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Test {}
+
+impl TargetKind for Test {
+    const NAME: &'static str = "test";
+
+    fn default_path() -> SubPath {
+        SubPath::from_path(Path::new("tests")).unwrap()
+    }
+
+    fn default_name(main: &str) -> Option<String> {
+        Some(
+            format!("test-{}", main)
+                .trim_end_matches(".idr")
+                .trim_end_matches(".lidr")
+                .replace(|c| c == '/' || c == '\\' || c == '.', "_"),
+        )
+    }
+}
+
+/// A target which is built into an executable. Everything about bin and test targets is the
+/// same except for the defaults their kinds give them, so fields they share go here once.
+#[serde(deny_unknown_fields, bound = "")]
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
-pub struct TestTarget {
+pub struct TargetSpec<K: TargetKind> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
-    #[serde(default = "default_test_subpath")]
+    #[serde(default = "K::default_path")]
     pub path: SubPath,
     pub main: String,
     #[serde(default)]
     pub idris_opts: Vec<String>,
+    /// The file name of the executable, if it shouldn't be named after the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// The extension of the executable, which replaces the one the codegen backend gives it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+    /// Environment variables to set when elba runs the executable, with `elba run` for binaries
+    /// and `elba test` for tests.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+    #[serde(skip)]
+    pub kind: PhantomData<K>,
 }
 
-fn default_test_subpath() -> SubPath {
-    SubPath::from_path(Path::new("tests")).unwrap()
-}
+pub type BinTarget = TargetSpec<Bin>;
+pub type TestTarget = TargetSpec<Test>;
 
 impl From<TestTarget> for BinTarget {
     fn from(t: TestTarget) -> Self {
-        let default_name = format!("test-{}", &t.main)
-            .trim_end_matches(".idr")
-            .trim_end_matches(".lidr")
-            .replace("/", "_")
-            .replace(".", "_");
-
-        BinTarget {
-            name: t.name.unwrap_or(default_name),
+        TargetSpec {
+            name: Some(t.name()),
             path: t.path,
             main: t.main,
             idris_opts: t.idris_opts,
+            output: t.output,
+            extension: t.extension,
+            env: t.env,
+            kind: PhantomData,
         }
     }
 }
 
-impl BinTarget {
+impl<K: TargetKind> TargetSpec<K> {
+    /// The name of the target, or the one its kind gives it if it doesn't have one.
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .or_else(|| K::default_name(&self.main))
+            .unwrap_or_default()
+    }
+
+    /// The file name of the executable the target is built into, given the extension the codegen
+    /// backend gives executables. An empty extension leaves the executable without one.
+    pub fn output_file(&self, backend_ext: Option<&str>) -> PathBuf {
+        let name = self.output.clone().unwrap_or_else(|| self.name());
+        match self.extension.as_ref().map(|x| x.as_str()).or(backend_ext) {
+            Some(ext) if !ext.is_empty() => {
+                format!("{}.{}", name, ext.trim_start_matches('.')).into()
+            }
+            _ => name.into(),
+        }
+    }
+
     // A note on extensions:
     // - If the extension of the target_path is idr or empty, it will be treated as a Main file.
     // - If the extension of the target_path is anything else, that extension will be the function
@@ -407,7 +1219,7 @@ impl BinTarget {
         };
 
         if let Some(before) = before {
-            let target_path: PathBuf = before.replace(".", "/").into();
+            let target_path = mod_path(before);
             // If there is at least one dot in the name:
             if src_path
                 .join(&target_path)
@@ -450,6 +1262,33 @@ impl BinTarget {
     }
 }
 
+/// Whether any of the `include` patterns of a package could match something under a directory
+/// in it, so that the directory has to be walked even if it's excluded.
+///
+/// This errs on the side of walking: only a pattern anchored to a path which the directory isn't
+/// on (like `assets/logo.png` for the directory `tests`) rules it out.
+fn may_include(patterns: &[String], root: &Path, dir: &Path) -> bool {
+    let dir = match dir.strip_prefix(root) {
+        Ok(dir) => format!("{}/", dir.to_string_lossy().replace("\\", "/")),
+        Err(_) => return true,
+    };
+
+    patterns.iter().any(|pat| {
+        let pat = pat.trim_start_matches('!').trim_end_matches('/');
+        let anchored = pat.trim_start_matches('/');
+        // A pattern with no slash before its end matches at any depth.
+        if (!pat.starts_with('/') && !anchored.contains('/')) || anchored.starts_with("**") {
+            return true;
+        }
+
+        let glob = anchored
+            .find(|c| c == '*' || c == '?' || c == '[')
+            .unwrap_or_else(|| anchored.len());
+        let literal = &anchored[..glob];
+        literal.starts_with(&dir) || dir.starts_with(literal)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -467,7 +1306,9 @@ homepage = "https://github.com/elba/elba"
 repository = "https://github.com/elba/elba"
 readme = "README.md"
 keywords = ["package-manager", "packaging"]
-exclude = ["*.blah"]
+categories = ["development-tools"]
+exclude = ["*.blah", "fixtures/"]
+include = ["fixtures/small.blah"]
 
 [dependencies]
 'awesome/a' = '>= 1.0.0 < 2.0.0'
@@ -542,4 +1383,499 @@ mods = [
 
         assert!(Manifest::from_str(manifest).is_err());
     }
+
+    #[test]
+    fn manifest_unknown_key() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dev-dependencies]
+'ayy/x' = '2.0'
+"#;
+        let err = Manifest::from_str(manifest).unwrap_err().to_string();
+
+        assert!(err.contains("elba.toml:7:1: unknown section `dev-dependencies`"));
+    }
+
+    #[test]
+    fn manifest_metadata() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[package.metadata.docs]
+theme = 'dark'
+
+[metadata.some-tool]
+anything = { goes = ['here'] }
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+
+        assert!(manifest.metadata["some-tool"].get("anything").is_some());
+        assert_eq!(
+            manifest.package.metadata["docs"].get("theme"),
+            Some(&toml::Value::String("dark".to_string()))
+        );
+    }
+
+    #[test]
+    fn manifest_run_target() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+default_run = 'b'
+
+[[targets.bin]]
+name = 'a'
+main = 'A.idr'
+
+[[targets.bin]]
+name = 'b'
+main = 'B.idr'
+"#;
+        let mut manifest = Manifest::from_str(manifest).unwrap();
+
+        assert_eq!(manifest.run_target(None).unwrap(), 1);
+        assert_eq!(manifest.run_target(Some("a")).unwrap(), 0);
+        assert!(manifest.run_target(Some("c")).is_err());
+
+        manifest.package.default_run = None;
+        assert!(manifest.run_target(None).is_err());
+    }
+
+    #[test]
+    fn manifest_test_deps() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+
+[test_dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'heavy/tester' = '1.0'
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+
+        assert!(manifest.is_test_only(&Name::from_str("heavy/tester").unwrap()));
+        assert!(!manifest.is_test_only(&Name::from_str("awesome/a").unwrap()));
+        assert!(manifest
+            .dep_req(&Name::from_str("heavy/tester").unwrap(), true)
+            .is_some());
+        assert!(manifest
+            .dep_req(&Name::from_str("heavy/tester").unwrap(), false)
+            .is_none());
+    }
+
+    #[test]
+    fn manifest_newer_version() {
+        let manifest = r#"
+[package]
+manifest_version = 1000
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[some_future_section]
+"#;
+        let err = Manifest::from_str(manifest).unwrap_err().to_string();
+
+        assert!(err.contains("requires a newer version of elba"));
+    }
+
+    #[test]
+    fn manifest_interpolate() {
+        env::set_var("ELBA_TEST_INTERPOLATE", "fast");
+        let root = Path::new("/ws");
+
+        assert_eq!(
+            interpolate("${ELBA_ROOT}/libs/a", Some(root)).unwrap(),
+            "/ws/libs/a"
+        );
+        assert_eq!(
+            interpolate("--${env:ELBA_TEST_INTERPOLATE}", None).unwrap(),
+            "--fast"
+        );
+        assert_eq!(interpolate("$HOME", None).unwrap(), "$HOME");
+        assert!(interpolate("${ELBA_ROOT}/libs/a", None).is_err());
+        assert!(interpolate("${env:ELBA_TEST_UNSET_VARIABLE}", None).is_err());
+        assert!(interpolate("${HOME}", Some(root)).is_err());
+        assert!(interpolate("${ELBA_ROOT", Some(root)).is_err());
+    }
+
+    #[test]
+    fn manifest_interpolate_outside_workspace() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+"ring_ding/utils" = { path = "${ELBA_ROOT}/libs/utils" }
+"#;
+        let value = inherit(manifest, Some(Path::new("/pkg")), None).unwrap();
+
+        assert_eq!(
+            value["dependencies"]["ring_ding/utils"]["path"].as_str(),
+            Some("/pkg/libs/utils")
+        );
+    }
+
+    #[test]
+    fn manifest_dep_shorthand() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = 'git+https://github.com/awesome/a#v1'
+'awesome/b' = 'path+../b'
+'awesome/c' = '1.0.0'
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let deps = manifest.dependencies.values().collect::<Vec<_>>();
+
+        match deps[0] {
+            DepReq::Git { git, tag, .. } => {
+                assert_eq!(git.as_str(), "https://github.com/awesome/a");
+                assert_eq!(tag, "v1");
+            }
+            req => panic!("expected a git dependency, got {:?}", req),
+        }
+        match deps[1] {
+            DepReq::Local { path, .. } => assert_eq!(path, Path::new("../b")),
+            req => panic!("expected a path dependency, got {:?}", req),
+        }
+        match deps[2] {
+            DepReq::Registry(_) => {}
+            req => panic!("expected a registry dependency, got {:?}", req),
+        }
+
+        assert!(shorthand("git+not a url").is_err());
+    }
+
+    #[test]
+    fn manifest_host_shorthand() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = { github = "awesome/a", tag = "v1.0" }
+'awesome/b' = { gitlab = "awesome/sub/b.git" }
+'awesome/c' = { sourcehut = "~awesome/c", package = "awesome/cc" }
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let deps = manifest
+            .dependencies
+            .values()
+            .map(|dep| match dep {
+                DepReq::Git { git, tag, .. } => (git.as_str(), tag.as_str()),
+                req => panic!("expected a git dependency, got {:?}", req),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deps,
+            vec![
+                ("https://github.com/awesome/a", "v1.0"),
+                ("https://gitlab.com/awesome/sub/b", "master"),
+                ("https://git.sr.ht/~awesome/c", "master"),
+            ]
+        );
+
+        let parse = |dep: &str| {
+            let mut table = toml::from_str::<toml::value::Table>(dep).unwrap();
+            host_shorthand(&mut table).map(|_| table)
+        };
+        assert!(parse(r#"github = "awesome""#).is_err());
+        assert!(parse(r#"github = "awesome/a/b""#).is_err());
+        assert!(parse(r#"github = "awesome/a b""#).is_err());
+        assert!(parse(r#"github = "awesome/..""#).is_err());
+        assert!(parse(r#"gitlab = "awesome/../../evil/a""#).is_err());
+        assert!(parse(r#"github = "./a""#).is_err());
+        assert!(parse("github = 'awesome/a'\ngitlab = 'awesome/a'").is_err());
+        assert!(parse("github = 'awesome/a'\ngit = 'https://github.com/awesome/a'").is_err());
+        assert!(parse(r#"version = "1.0.0""#).unwrap().get("git").is_none());
+    }
+
+    #[test]
+    fn manifest_target_defaults() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[[targets.bin]]
+name = 'cli'
+main = 'Cli.idr'
+
+[[targets.test]]
+main = 'Tests.Unit.idr'
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+
+        assert_eq!(manifest.targets.bin[0].path.0, Path::new("src"));
+        assert_eq!(manifest.targets.test[0].path.0, Path::new("tests"));
+        assert_eq!(manifest.targets.test[0].name(), "test-Tests_Unit");
+        assert_eq!(
+            BinTarget::from(manifest.targets.test[0].clone()).name,
+            Some("test-Tests_Unit".to_string())
+        );
+
+        let unnamed = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[[targets.bin]]
+main = 'Cli.idr'
+"#;
+        assert!(Manifest::from_str(unnamed).is_err());
+    }
+
+    #[test]
+    fn manifest_output_file() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[[targets.bin]]
+name = 'cli'
+main = 'Cli.idr'
+
+[[targets.bin]]
+name = 'server'
+main = 'Server.idr'
+output = 'serve'
+extension = ''
+
+[[targets.test]]
+main = 'Tests.idr'
+extension = 'js'
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+
+        assert_eq!(
+            manifest.targets.bin[0].output_file(Some("exe")),
+            Path::new("cli.exe")
+        );
+        assert_eq!(
+            manifest.targets.bin[1].output_file(Some("exe")),
+            Path::new("serve")
+        );
+        assert_eq!(
+            manifest.targets.test[0].output_file(None),
+            Path::new("test-Tests.js")
+        );
+    }
+
+    #[test]
+    fn manifest_inherit() {
+        let root = r#"
+[workspace]
+'ring_ding/test' = 'test'
+
+[workspace.package]
+version = '1.0.0'
+license = 'MIT'
+
+[workspace.dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'great/c' = { path = 'here/right/now' }
+"#;
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = { workspace = true }
+authors = ['me']
+license = { workspace = true }
+
+[dependencies]
+'awesome/a' = { workspace = true }
+'great/c' = { workspace = true }
+"#;
+        let ws = Manifest::workspace(root).unwrap();
+        let manifest =
+            Manifest::from_value(inherit(manifest, None, Some((Path::new("/ws"), &ws))).unwrap())
+                .unwrap();
+
+        assert_eq!(manifest.version(), &Version::parse("1.0.0").unwrap());
+        assert_eq!(manifest.package.license, Some("MIT".to_string()));
+        match manifest.dependencies.get_index(1).unwrap().1 {
+            DepReq::Local { path, .. } => assert_eq!(path, Path::new("/ws/here/right/now")),
+            _ => panic!("path dependency wasn't inherited as one"),
+        }
+    }
+
+    #[test]
+    fn manifest_inherit_outside_workspace() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = { workspace = true }
+authors = ['me']
+"#;
+
+        assert!(Manifest::from_str(manifest).is_err());
+    }
+
+    #[test]
+    fn manifest_aliases() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'old/a' = { package = 'awesome/a', git = 'https://github.com/awesome/a', tag = "v0.1.0" }
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let aliases = manifest.aliases(false);
+
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(
+            aliases[&Name::from_str("old/a").unwrap()],
+            Name::from_str("awesome/a").unwrap()
+        );
+        assert_eq!(
+            manifest
+                .dep_req(&Name::from_str("awesome/a").unwrap(), false)
+                .unwrap()
+                .0,
+            &Name::from_str("awesome/a").unwrap()
+        );
+    }
+
+    #[test]
+    fn manifest_aliases_same_package() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'other/a' = { package = 'awesome/a', version = '1.0.0' }
+"#;
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let ixmap = indexmap::indexmap!("default".to_string() => IndexRes::from_str("index+dir+/index").unwrap());
+        let root = PackageId::new(
+            Name::from_str("ring_ding/test").unwrap(),
+            DirectRes::Dir {
+                path: PathBuf::from("/root"),
+            }
+            .into(),
+        );
+
+        assert!(manifest.deps(&ixmap, &root, false).is_err());
+    }
+
+    #[test]
+    fn manifest_module_name() {
+        assert_eq!(
+            module_name(Path::new("Control/Monad/Wow.idr")),
+            Some("Control.Monad.Wow".to_string())
+        );
+        assert_eq!(module_name(Path::new("elba-main.idr")), None);
+        assert_eq!(module_name(Path::new("1st/Thing.lidr")), None);
+    }
+
+    #[test]
+    fn manifest_mod_patterns() {
+        assert!(mod_matches("Data.Vect.*", "Data.Vect.Quantifiers"));
+        assert!(!mod_matches("Data.Vect.*", "Data.Vect"));
+        assert!(!mod_matches("Data.Vect.*", "Data.Vect.Views.Extra"));
+        assert!(mod_matches("Control.**", "Control.Monad.State"));
+        assert!(mod_matches("Control.**", "Control"));
+        assert!(mod_matches("Data.*Map", "Data.SortedMap"));
+        assert!(!mod_matches("Data.*Map", "Data.SortedSet"));
+    }
+
+    #[test]
+    fn manifest_invalid_name() {
+        let manifest = |name: &str| {
+            format!(
+                r#"
+[package]
+name = '{}'
+version = '0.1.0'
+authors = []
+"#,
+                name
+            )
+        };
+
+        for (name, err) in &[
+            ("grp/Ötzi", "invalid name `grp/Ötzi`"),
+            ("grp/-dash", "has to start with a letter or a number"),
+            ("con/pkg", "`con` is reserved"),
+            ("grp/a/b", "invalid name `grp/a/b`"),
+        ] {
+            let e = Manifest::from_str(&manifest(name)).unwrap_err().to_string();
+            assert!(e.contains(err), "{}", e);
+        }
+        assert!(Manifest::from_str(&manifest(&format!("grp/{}", "a".repeat(65)))).is_err());
+        assert!(Manifest::from_str(&manifest(&format!("grp/{}", "a".repeat(64)))).is_ok());
+    }
+
+    #[test]
+    fn manifest_native() {
+        let manifest = |native: &str| {
+            format!(
+                r#"
+[package]
+name = 'native/test'
+version = '0.1.0'
+authors = []
+
+[native]
+{}
+"#,
+                native
+            )
+        };
+
+        let m = Manifest::from_str(&manifest("libs = ['gmp']\npkg-config = ['libffi']")).unwrap();
+        assert_eq!(m.native.libs, vec!["gmp"]);
+        assert_eq!(m.native.pkg_config, vec!["libffi"]);
+        assert!(Manifest::from_str(&manifest("libs = ['lib gmp']")).is_err());
+        assert!(Manifest::from_str(&manifest("headers = ['gmp.h']")).is_err());
+    }
+
+    #[test]
+    fn manifest_may_include() {
+        let root = Path::new("/pkg");
+        let patterns = vec!["assets/logo.png".to_string(), "/gen/*.idr".to_string()];
+        assert!(may_include(&patterns, root, &root.join("assets")));
+        assert!(may_include(&patterns, root, &root.join("gen")));
+        assert!(!may_include(&patterns, root, &root.join("tests")));
+        assert!(!may_include(&patterns, root, &root.join("assets/fonts")));
+
+        // Patterns without a slash can match anywhere.
+        let patterns = vec!["*.png".to_string()];
+        assert!(may_include(&patterns, root, &root.join("tests/fixtures")));
+        assert!(!may_include(&[], root, &root.join("tests")));
+    }
 }
@@ -1,25 +1,77 @@
 //! Data structures related to packages.
 
 pub mod ipkg;
+pub mod lint;
 pub mod lockfile;
 pub mod manifest;
+pub mod workspace;
 
 use crate::{
     remote::resolution::Resolution,
     util::error::{Error, Result},
 };
 use failure::{bail, format_err};
+use lazy_static::lazy_static;
 use semver::Version;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
 use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
     fmt,
     hash::{Hash, Hasher},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex, Weak},
 };
 
+lazy_static! {
+    /// The names which are in use, by their serialization.
+    static ref NAMES: Mutex<Interner<String, NameInner>> = Mutex::new(Interner::new());
+    /// The package ids which are in use, by the serialization of their name and their resolution.
+    static ref PACKAGE_IDS: Mutex<Interner<(String, Resolution), PackageIdInner>> =
+        Mutex::new(Interner::new());
+}
+
+/// A table of the values which are in use, by what they were made from.
+///
+/// It only holds weak references, so it doesn't keep anything alive by itself: once the last
+/// copy of a value is dropped, it's freed, and the next one made from the same key is a fresh
+/// allocation. The entries left behind by freed values are swept out whenever the table has
+/// doubled in size since it was last swept, so a long-running process which goes through a lot
+/// of different names doesn't hang on to all of them.
+struct Interner<K, V> {
+    values: HashMap<K, Weak<V>>,
+    sweep_at: usize,
+}
+
+impl<K: Hash + Eq, V> Interner<K, V> {
+    const MIN_SWEEP: usize = 64;
+
+    fn new() -> Self {
+        Interner {
+            values: HashMap::new(),
+            sweep_at: Self::MIN_SWEEP,
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.values.get(key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: K, value: &Arc<V>) {
+        if self.values.len() >= self.sweep_at {
+            self.values.retain(|_, v| v.upgrade().is_some());
+            self.sweep_at = (self.values.len() * 2).max(Self::MIN_SWEEP);
+        }
+        self.values.insert(key, Arc::downgrade(value));
+    }
+}
+
 /// Struct `Name` represents the name of a package. All packages in elba are namespaced, so all
 /// packages have to have a group (pre-slash) and a name (post-slash).
+///
+/// Names are interned: making a name which is still in use somewhere hands back the same
+/// allocation, so that the same few names parsed over and over from indices and lockfiles don't
+/// cost anything more than the first time, and comparing them is usually just comparing pointers.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Name {
     inner: Arc<NameInner>,
@@ -46,19 +98,14 @@ struct NameInner {
 
 impl Name {
     pub fn new(group: String, name: String) -> Result<Self> {
-        let group_valid = group
-            .chars()
-            .all(|x| x.is_alphanumeric() || x == '_' || x == '-');
-        if !group_valid {
-            bail!("group can only contain letters, numbers, _, and -")
-        }
-        let name_valid = name
-            .chars()
-            .all(|x| x.is_alphanumeric() || x == '_' || x == '-');
-        if !name_valid {
-            bail!("name can only contain letters, numbers, _, and -")
+        let key = format!("{}/{}", group, name);
+        if let Some(inner) = NAMES.lock().unwrap().get(&key) {
+            return Ok(Name { inner });
         }
 
+        check_name_part("group", &group, &name)?;
+        check_name_part("name", &name, &group)?;
+
         let mut s = String::with_capacity(group.len() + 2 + name.len());
         s.push_str(&group);
         s.push('/');
@@ -70,9 +117,6 @@ impl Name {
             .drain(..)
             .map(|c| if c == '_' { '-' } else { c })
             .collect::<String>();
-        if pre.is_empty() {
-            bail!("group cannot be empty")
-        }
         n.push_str(pre);
 
         n.push('/');
@@ -82,19 +126,17 @@ impl Name {
             .drain(..)
             .map(|c| if c == '_' { '-' } else { c })
             .collect::<String>();
-        if post.is_empty() {
-            bail!("name cannot be empty")
-        }
         n.push_str(post);
 
-        Ok(Name {
-            inner: Arc::new(NameInner {
-                serialization: s,
-                normalization: n,
-                group,
-                name,
-            }),
-        })
+        let inner = Arc::new(NameInner {
+            serialization: s,
+            normalization: n,
+            group,
+            name,
+        });
+        NAMES.lock().unwrap().insert(key, &inner);
+
+        Ok(Name { inner })
     }
 
     pub fn group(&self) -> &str {
@@ -122,6 +164,67 @@ impl Name {
     }
 }
 
+/// The longest a group or a name can be.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Names which can't be used as a group or a name, since packages end up in directories named
+/// after them and Windows doesn't allow directories with these names.
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Checks one half of a name. `kind` is which half it is, and `other` is the other half, which
+/// is only used to show the whole name in errors.
+fn check_name_part(kind: &str, part: &str, other: &str) -> Result<()> {
+    let full = if kind == "group" {
+        format!("{}/{}", part, other)
+    } else {
+        format!("{}/{}", other, part)
+    };
+
+    if part.is_empty() {
+        bail!("invalid name `{}`: the {} cannot be empty", full, kind)
+    }
+    if let Some(c) = part
+        .chars()
+        .find(|&c| !c.is_ascii_alphanumeric() && c != '_' && c != '-')
+    {
+        bail!(
+            "invalid name `{}`: the {} can only contain letters, numbers, _, and - (found `{}`)",
+            full,
+            kind,
+            c
+        )
+    }
+    if !part.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        bail!(
+            "invalid name `{}`: the {} has to start with a letter or a number",
+            full,
+            kind
+        )
+    }
+    if part.len() > MAX_NAME_LEN {
+        bail!(
+            "invalid name `{}`: the {} is {} characters long, but it can be at most {}",
+            full,
+            kind,
+            part.len(),
+            MAX_NAME_LEN
+        )
+    }
+    if RESERVED_NAMES.contains(&part.to_ascii_lowercase().as_str()) {
+        bail!(
+            "invalid name `{}`: `{}` is reserved and can't be used as a {}",
+            full,
+            part,
+            kind
+        )
+    }
+
+    Ok(())
+}
+
 impl PartialEq for NameInner {
     fn eq(&self, other: &NameInner) -> bool {
         self.normalization == other.normalization
@@ -144,7 +247,8 @@ impl FromStr for Name {
 
         if v.len() != 2 {
             return Err(format_err!(
-                "name must have group and name separated by '/'"
+                "invalid name `{}`: name must have group and name separated by '/'",
+                s
             ))?;
         }
 
@@ -183,22 +287,40 @@ impl AsRef<str> for Name {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+/// A package id is the name of a package along with where it comes from.
+///
+/// Package ids are cloned, hashed and compared all over the resolver and the build graph, so
+/// they're interned like names are, and their hash is worked out once when they're made.
+#[derive(Clone)]
 pub struct PackageId {
     inner: Arc<PackageIdInner>,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
 struct PackageIdInner {
-    pub name: Name,
-    pub resolution: Resolution,
+    name: Name,
+    resolution: Resolution,
+    hash: u64,
 }
 
 impl PackageId {
     pub fn new(name: Name, resolution: Resolution) -> Self {
-        PackageId {
-            inner: Arc::new(PackageIdInner { name, resolution }),
+        let key = (name.as_str().to_string(), resolution);
+        let mut ids = PACKAGE_IDS.lock().unwrap();
+        if let Some(inner) = ids.get(&key) {
+            return PackageId { inner };
         }
+
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        key.1.hash(&mut hasher);
+        let inner = Arc::new(PackageIdInner {
+            name,
+            resolution: key.1.clone(),
+            hash: hasher.finish(),
+        });
+        ids.insert(key, &inner);
+
+        PackageId { inner }
     }
 
     pub fn name(&self) -> &Name {
@@ -216,6 +338,23 @@ impl PackageId {
     }
 }
 
+impl PartialEq for PackageId {
+    fn eq(&self, other: &PackageId) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+            || (self.inner.hash == other.inner.hash
+                && self.inner.name == other.inner.name
+                && self.inner.resolution == other.inner.resolution)
+    }
+}
+
+impl Eq for PackageId {}
+
+impl Hash for PackageId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.inner.hash);
+    }
+}
+
 impl FromStr for PackageId {
     type Err = failure::Error;
 
@@ -261,9 +400,19 @@ impl Serialize for PackageId {
     }
 }
 
+/// The hash functions checksums can be made with, from weakest to strongest.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ChecksumFmt {
     Sha256,
+    Blake3,
+    Sha512,
+}
+
+impl ChecksumFmt {
+    /// The strongest of a list of formats which elba knows how to check, if there are any.
+    pub fn strongest<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        names.into_iter().filter_map(|x| x.parse().ok()).max()
+    }
 }
 
 impl FromStr for ChecksumFmt {
@@ -272,6 +421,8 @@ impl FromStr for ChecksumFmt {
     fn from_str(s: &str) -> Result<Self> {
         match s {
             "sha256" => Ok(ChecksumFmt::Sha256),
+            "sha512" => Ok(ChecksumFmt::Sha512),
+            "blake3" => Ok(ChecksumFmt::Blake3),
             _ => Err(Error::InvalidSourceUrl)?,
         }
     }
@@ -281,21 +432,43 @@ impl fmt::Display for ChecksumFmt {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ChecksumFmt::Sha256 => write!(f, "sha256"),
+            ChecksumFmt::Sha512 => write!(f, "sha512"),
+            ChecksumFmt::Blake3 => write!(f, "blake3"),
         }
     }
 }
 
+/// A hash, along with the format it's in.
+///
+/// Checksums are written as `<format>=<hex-encoded hash>`, like `sha256=b5bb9d...`; the format can
+/// also be separated from the hash with a colon instead, as in `sha256:b5bb9d...`.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Checksum {
     pub fmt: ChecksumFmt,
     pub hash: String,
 }
 
+impl Checksum {
+    /// The checksum of some bytes in the given format.
+    pub fn of(fmt: ChecksumFmt, bytes: &[u8]) -> Self {
+        let mut hasher = ChecksumHasher::new(fmt);
+        hasher.input(bytes);
+        hasher.result()
+    }
+
+    /// Whether some bytes have this checksum.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        Checksum::of(self.fmt, bytes)
+            .hash
+            .eq_ignore_ascii_case(&self.hash)
+    }
+}
+
 impl FromStr for Checksum {
     type Err = failure::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let mut s = s.splitn(2, '=');
+        let mut s = s.splitn(2, |c| c == '=' || c == ':');
         let fmt = s.next().unwrap();
         let hash = s.next().ok_or_else(|| Error::InvalidSourceUrl)?.to_string();
         Ok(Checksum {
@@ -311,6 +484,45 @@ impl fmt::Display for Checksum {
     }
 }
 
+/// Works out a checksum from data which comes in bit by bit, like a download.
+pub enum ChecksumHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    pub fn new(fmt: ChecksumFmt) -> Self {
+        match fmt {
+            ChecksumFmt::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+            ChecksumFmt::Sha512 => ChecksumHasher::Sha512(Sha512::new()),
+            ChecksumFmt::Blake3 => ChecksumHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        match self {
+            ChecksumHasher::Sha256(h) => h.input(bytes),
+            ChecksumHasher::Sha512(h) => h.input(bytes),
+            ChecksumHasher::Blake3(h) => {
+                h.update(bytes);
+            }
+        }
+    }
+
+    pub fn result(self) -> Checksum {
+        let (fmt, hash) = match self {
+            ChecksumHasher::Sha256(h) => (ChecksumFmt::Sha256, hex::encode(h.result())),
+            ChecksumHasher::Sha512(h) => (ChecksumFmt::Sha512, hex::encode(h.result())),
+            ChecksumHasher::Blake3(h) => {
+                (ChecksumFmt::Blake3, hex::encode(h.finalize().as_bytes()))
+            }
+        };
+
+        Checksum { fmt, hash }
+    }
+}
+
 impl<'de> Deserialize<'de> for Checksum {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
         let s = String::deserialize(deserializer)?;
@@ -464,3 +676,101 @@ impl FromStr for Spec {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_separators() {
+        let a = Checksum::from_str("sha256=0123abcd").unwrap();
+        let b = Checksum::from_str("sha256:0123abcd").unwrap();
+
+        assert_eq!(a, b);
+        assert_eq!(b.to_string(), "sha256=0123abcd");
+        assert!(Checksum::from_str("md5=0123abcd").is_err());
+    }
+
+    #[test]
+    fn checksum_formats() {
+        assert_eq!(
+            Checksum::of(ChecksumFmt::Sha256, b"hello").hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            Checksum::of(ChecksumFmt::Sha512, b"").hash,
+            "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+             47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+        );
+        assert_eq!(
+            Checksum::of(ChecksumFmt::Blake3, b"").hash,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+
+        let cksum = Checksum::of(ChecksumFmt::Blake3, b"contents");
+        assert!(cksum.matches(b"contents"));
+        assert!(!cksum.matches(b"other contents"));
+    }
+
+    #[test]
+    fn checksum_strongest() {
+        assert_eq!(
+            ChecksumFmt::strongest(vec!["sha256", "blake3", "md5"]),
+            Some(ChecksumFmt::Blake3)
+        );
+        assert_eq!(
+            ChecksumFmt::strongest(vec!["sha512", "sha256", "blake3"]),
+            Some(ChecksumFmt::Sha512)
+        );
+        assert_eq!(ChecksumFmt::strongest(vec!["md5"]), None);
+    }
+
+    #[test]
+    fn interning() {
+        let a = PackageId::from_str("intern/pkg@dir+/pkg").unwrap();
+        let b = PackageId::from_str("intern/pkg@dir+/pkg").unwrap();
+        assert!(Arc::ptr_eq(&a.inner, &b.inner));
+        assert!(Arc::ptr_eq(&a.name().inner, &b.name().inner));
+
+        // Names which only differ in how they're written are equal, but are displayed the way
+        // they were written.
+        let c = PackageId::from_str("Intern/PKG@dir+/pkg").unwrap();
+        assert!(!Arc::ptr_eq(&a.inner, &c.inner));
+        assert_eq!(a, c);
+        assert_eq!(c.to_string(), "Intern/PKG@dir+/pkg");
+
+        let hash = |id: &PackageId| {
+            let mut hasher = DefaultHasher::new();
+            id.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&a), hash(&c));
+
+        assert_ne!(a, PackageId::from_str("intern/pkg@dir+/other").unwrap());
+    }
+
+    #[test]
+    fn interning_frees_unused() {
+        let id = PackageId::from_str("intern/freed@dir+/freed").unwrap();
+        let weak = Arc::downgrade(&id.inner);
+        let name = Arc::downgrade(&id.name().inner);
+        drop(id);
+
+        assert!(weak.upgrade().is_none());
+        assert!(name.upgrade().is_none());
+    }
+
+    #[test]
+    fn interner_sweeps() {
+        let mut interner: Interner<usize, usize> = Interner::new();
+        for i in 0..1000 {
+            interner.insert(i, &Arc::new(i));
+        }
+        let kept = Arc::new(1000);
+        interner.insert(1000, &kept);
+
+        assert!(interner.values.len() < 2 * Interner::<usize, usize>::MIN_SWEEP);
+        assert_eq!(interner.get(&1000), Some(kept));
+        assert_eq!(interner.get(&0), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Workspaces, which keep several packages in one directory tree.
+//!
+//! The root of a workspace is a directory whose manifest has a `[workspace]` section listing the
+//! name and sub-path of each of its members; the root manifest doesn't need a `[package]` of its
+//! own. Every package in a workspace shares the lockfile and `target` directory at its root, and
+//! dependencies of members on each other which go through an index are pointed at the members
+//! themselves instead.
+//!
+//! Members can also inherit some of their fields from `[workspace.package]` and
+//! `[workspace.dependencies]` in the root manifest by setting them to `{ workspace = true }`, so
+//! that anything reading the manifest of a package has to go through `read_manifest`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use failure::{bail, format_err, ResultExt};
+use indexmap::IndexMap;
+use semver::Version;
+use semver_constraints::Constraint;
+use toml;
+
+use super::{
+    manifest::{inherit, Manifest, WorkspaceInfo},
+    Name, PackageId,
+};
+use crate::{
+    cli::build::find_manifest_root,
+    remote::resolution::{DirectRes, Resolution},
+    util::{error::Result, normalize_path},
+};
+
+/// A package in a workspace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    /// The directory of the package.
+    pub path: PathBuf,
+    pub version: Version,
+}
+
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    /// The directory the workspace is rooted at.
+    pub root: PathBuf,
+    /// The name of the package at the root of the workspace, if there is one.
+    pub root_package: Option<Name>,
+    /// Every package in the workspace, starting with the root package if there is one.
+    pub members: IndexMap<Name, Member>,
+}
+
+impl Workspace {
+    /// Loads the workspace rooted at a directory, if its manifest has a `[workspace]` section,
+    /// making sure that every member is where the section says it is.
+    pub fn load(root: &Path) -> Result<Option<Self>> {
+        let root = normalize_path(root);
+        let toml_path = root.join("elba.toml");
+        let contents = fs::read_to_string(&toml_path).context(format_err!(
+            "failed to read manifest file ({})",
+            toml_path.display()
+        ))?;
+        let value: toml::Value = toml::from_str(&contents).with_context(|e| {
+            format_err!("invalid manifest file ({}): {}", toml_path.display(), e)
+        })?;
+        let info: WorkspaceInfo = match value.get("workspace") {
+            Some(ws) => ws.clone().try_into().with_context(|e| {
+                format_err!(
+                    "invalid [workspace] section in {}: {}",
+                    toml_path.display(),
+                    e
+                )
+            })?,
+            None => return Ok(None),
+        };
+
+        let mut members = IndexMap::new();
+        let root_package = if value.get("package").is_some() {
+            let manifest =
+                Manifest::from_value(inherit(&contents, Some(&root), Some((&root, &info)))?)?;
+            members.insert(
+                manifest.name().clone(),
+                Member {
+                    path: root.clone(),
+                    version: manifest.version().clone(),
+                },
+            );
+            Some(manifest.name().clone())
+        } else {
+            None
+        };
+
+        for (name, sub) in &info.members {
+            let name = name.clone();
+            let path = normalize_path(&root.join(&sub.0));
+            if path == root {
+                bail!(
+                    "workspace member {} can't be at the root of the workspace; put it in the \
+                     [package] section of {} instead",
+                    name,
+                    toml_path.display()
+                )
+            }
+            if let Some((other, _)) = members
+                .iter()
+                .filter(|(n, _)| Some(*n) != root_package.as_ref())
+                .find(|(_, m)| m.path.starts_with(&path) || path.starts_with(&m.path))
+            {
+                bail!(
+                    "workspace members {} ({}) and {} ({}) overlap; members can't be inside \
+                     each other",
+                    other,
+                    members[other].path.display(),
+                    name,
+                    path.display()
+                )
+            }
+
+            let member_toml = path.join("elba.toml");
+            let contents = fs::read_to_string(&member_toml).with_context(|_| {
+                format_err!(
+                    "workspace member {} doesn't have a manifest file ({})",
+                    name,
+                    member_toml.display()
+                )
+            })?;
+            let manifest = inherit(&contents, Some(&path), Some((&root, &info)))
+                .and_then(Manifest::from_value)
+                .with_context(|e| {
+                    format_err!("invalid manifest of workspace member {}: {}", name, e)
+                })?;
+            if manifest.name() != &name {
+                bail!(
+                    "the workspace lists {} at {}, but the package there is {}",
+                    name,
+                    path.display(),
+                    manifest.name()
+                )
+            }
+            if members.contains_key(&name) {
+                bail!("{} is in the workspace more than once", name)
+            }
+
+            members.insert(
+                name,
+                Member {
+                    path,
+                    version: manifest.version().clone(),
+                },
+            );
+        }
+
+        Ok(Some(Workspace {
+            root,
+            root_package,
+            members,
+        }))
+    }
+
+    /// Finds the workspace a project is in, if it's in one: the closest directory at or above
+    /// the project which is the root of a workspace including it. The project can be any
+    /// directory inside of it, not just its root.
+    pub fn find(project: &Path) -> Result<Option<Self>> {
+        let project = normalize_path(project);
+        let project = find_manifest_root(&project)
+            .unwrap_or(&project)
+            .to_path_buf();
+        for dir in project.ancestors() {
+            if !dir.join("elba.toml").exists() {
+                continue;
+            }
+            if let Some(ws) = Workspace::load(dir)? {
+                let member = ws
+                    .members
+                    .values()
+                    .any(|m| m.path != ws.root && project.starts_with(&m.path));
+                if dir == project || member {
+                    return Ok(Some(ws));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The directory of the workspace root which a package is a member of, along with the root's
+    /// `[workspace]` section. A workspace root with a package of its own is a member of itself.
+    pub fn root_of(dir: &Path) -> Result<Option<(PathBuf, WorkspaceInfo)>> {
+        let dir = normalize_path(dir);
+        for root in dir.ancestors() {
+            let toml_path = root.join("elba.toml");
+            if !toml_path.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(&toml_path).context(format_err!(
+                "failed to read manifest file ({})",
+                toml_path.display()
+            ))?;
+            let info = match Manifest::workspace(&contents) {
+                Some(info) => info,
+                None => continue,
+            };
+            if root == dir
+                || info
+                    .members
+                    .values()
+                    .any(|sub| normalize_path(&root.join(&sub.0)) == dir)
+            {
+                return Ok(Some((root.to_path_buf(), info)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The closest directory above `dir` which is the root of a workspace, whether or not `dir`
+    /// is a member of it yet.
+    pub fn enclosing_root(dir: &Path) -> Result<Option<(PathBuf, WorkspaceInfo)>> {
+        let dir = normalize_path(dir);
+        for root in dir.ancestors().skip(1) {
+            let toml_path = root.join("elba.toml");
+            if !toml_path.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(&toml_path).context(format_err!(
+                "failed to read manifest file ({})",
+                toml_path.display()
+            ))?;
+            if let Some(info) = Manifest::workspace(&contents) {
+                return Ok(Some((root.to_path_buf(), info)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn lockfile(&self) -> PathBuf {
+        self.root.join("elba.lock")
+    }
+
+    pub fn target(&self) -> PathBuf {
+        self.root.join("target")
+    }
+
+    /// The package id a member is known by.
+    pub fn member_id(&self, name: &Name) -> Option<PackageId> {
+        let member = self.members.get(name)?;
+        let res = DirectRes::Dir {
+            path: member.path.clone(),
+        };
+
+        Some(PackageId::new(name.clone(), res.into()))
+    }
+
+    /// Points dependencies on packages in the workspace which go through an index at the
+    /// packages themselves, as long as they satisfy the dependency's constraint.
+    pub fn redirect(
+        &self,
+        deps: impl IntoIterator<Item = (PackageId, Constraint)>,
+    ) -> Vec<(PackageId, Constraint)> {
+        deps.into_iter()
+            .map(
+                |(pkg, con)| match (pkg.resolution(), self.members.get(pkg.name())) {
+                    (Resolution::Index(_), Some(member)) if con.satisfies(&member.version) => {
+                        (self.member_id(pkg.name()).unwrap(), con)
+                    }
+                    _ => (pkg, con),
+                },
+            )
+            .collect()
+    }
+}
+
+/// The manifest of the package in a directory as a toml document, with everything it inherits
+/// from its workspace filled in.
+pub fn manifest_toml(dir: &Path) -> Result<toml::Value> {
+    let toml_path = dir.join("elba.toml");
+    let contents = fs::read_to_string(&toml_path).context(format_err!(
+        "failed to read manifest file ({})",
+        toml_path.display()
+    ))?;
+    let ws = Workspace::root_of(dir)?;
+
+    inherit(
+        &contents,
+        Some(dir),
+        ws.as_ref().map(|(root, info)| (root.as_path(), info)),
+    )
+}
+
+/// Reads the manifest of the package in a directory, expanding the patterns in the mods of its
+/// lib target.
+pub fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::from_value(manifest_toml(dir)?)?;
+    manifest.expand_mods(dir)?;
+
+    Ok(manifest)
+}
@@ -0,0 +1,180 @@
+//! Advisory databases, which record the security vulnerabilities of packages and the packages
+//! which aren't maintained anymore.
+//!
+//! An advisory database is a directory (usually a git repository) with an `advisories` folder in
+//! it. Every advisory is a TOML file somewhere under that folder; by convention, the advisories
+//! for `group/name` live in `advisories/group/name/`, with the id of the advisory as their file
+//! name:
+//!
+//! ```toml
+//! id = "ELBA-2020-0001"
+//! package = "group/name"
+//! title = "Parser overflows its stack on deeply nested input"
+//! date = "2020-03-01"
+//! url = "https://example.com/group/name/issues/12"
+//! severity = "high"
+//! # The versions which aren't affected
+//! patched = [">= 1.2.3"]
+//! unaffected = ["< 1.0.0"]
+//! ```
+//!
+//! Advisories which aren't about vulnerabilities (like a package which isn't maintained anymore)
+//! say so with `informational = "unmaintained"` or `informational = "notice"`.
+
+use std::{fs, path::Path, str::FromStr};
+
+use failure::{format_err, ResultExt};
+use indexmap::IndexMap;
+use semver::Version;
+use semver_constraints::Constraint;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{package::Name, util::error::Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Informational {
+    /// The package isn't maintained anymore.
+    Unmaintained,
+    /// Something users of the package should know about which isn't a vulnerability.
+    Notice,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Advisory {
+    pub id: String,
+    pub package: Name,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// Set if the advisory isn't about a vulnerability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub informational: Option<Informational>,
+    /// The versions which have the problem fixed.
+    #[serde(default)]
+    pub patched: Vec<Constraint>,
+    /// The versions which never had the problem in the first place.
+    #[serde(default)]
+    pub unaffected: Vec<Constraint>,
+}
+
+impl FromStr for Advisory {
+    type Err = failure::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        Ok(toml::from_str(raw).context(format_err!("invalid advisory"))?)
+    }
+}
+
+impl Advisory {
+    /// Whether a version of the package has the problem this advisory is about.
+    pub fn affects(&self, version: &Version) -> bool {
+        !self
+            .patched
+            .iter()
+            .chain(self.unaffected.iter())
+            .any(|c| c.satisfies(version))
+    }
+
+    /// Whether this advisory is about a vulnerability, rather than being a warning.
+    pub fn is_vulnerability(&self) -> bool {
+        self.informational.is_none()
+    }
+}
+
+/// Every advisory in an advisory database, by the package they're about.
+#[derive(Debug, Clone, Default)]
+pub struct AdvisoryDb {
+    pub advisories: IndexMap<Name, Vec<Advisory>>,
+}
+
+impl AdvisoryDb {
+    /// Reads all of the advisories in an advisory database on disk.
+    pub fn from_disk(path: &Path) -> Result<Self> {
+        let root = path.join("advisories");
+        if !root.is_dir() {
+            return Err(format_err!(
+                "{} isn't an advisory database: it has no advisories folder",
+                path.display()
+            ));
+        }
+
+        let mut advisories: IndexMap<Name, Vec<Advisory>> = IndexMap::new();
+        for entry in WalkDir::new(&root)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| e.path().extension().map(|x| x == "toml").unwrap_or(false))
+        {
+            let advisory = fs::read_to_string(entry.path())
+                .map_err(failure::Error::from)
+                .and_then(|x| Advisory::from_str(&x))
+                .with_context(|e| {
+                    format_err!("couldn't read advisory {}:\n{}", entry.path().display(), e)
+                })?;
+            advisories
+                .entry(advisory.package.clone())
+                .or_insert_with(Vec::new)
+                .push(advisory);
+        }
+
+        Ok(AdvisoryDb { advisories })
+    }
+
+    /// The advisories which affect a version of a package.
+    pub fn affecting<'a>(
+        &'a self,
+        name: &Name,
+        version: &'a Version,
+    ) -> impl Iterator<Item = &'a Advisory> + 'a {
+        self.advisories
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |advisory| advisory.affects(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advisory_affects() {
+        let advisory = Advisory::from_str(
+            r#"
+id = "ELBA-2020-0001"
+package = "grp/pkg"
+title = "Something bad"
+severity = "high"
+patched = [">= 1.2.3"]
+unaffected = ["< 1.0.0"]
+"#,
+        )
+        .unwrap();
+
+        assert!(advisory.is_vulnerability());
+        assert_eq!(advisory.severity, Some(Severity::High));
+        assert!(advisory.affects(&Version::parse("1.0.0").unwrap()));
+        assert!(advisory.affects(&Version::parse("1.2.2").unwrap()));
+        assert!(!advisory.affects(&Version::parse("1.2.3").unwrap()));
+        assert!(!advisory.affects(&Version::parse("0.9.0").unwrap()));
+    }
+}
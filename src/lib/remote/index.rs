@@ -27,22 +27,29 @@ use crate::{
     remote::resolution::{DirectRes, IndexRes, Resolution},
     util::{
         error::{Error, Result},
+        fs::write_atomic,
+        keys::PublicKey,
         lock::DirLock,
     },
 };
 use failure::{bail, format_err, ResultExt};
+use git2::Repository;
 use indexmap::IndexMap;
 use semver::Version;
 use semver_constraints::Constraint;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use sha2::{Digest, Sha256};
 use simsearch::{SearchOptions, SimSearch};
 use std::{
     fs,
     io::{self, prelude::*, BufReader},
+    path::{Path, PathBuf},
     str::FromStr,
+    time::UNIX_EPOCH,
 };
 use toml;
+use url::Url;
 use walkdir::WalkDir;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -60,7 +67,19 @@ impl FromStr for IndexConfig {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IndexConfInner {
+    /// Whether every entry of this index has to be signed by one of its keys.
     pub secure: bool,
+    /// The registry which packages can be published to for this index, if there is one.
+    #[serde(default)]
+    pub registry: Option<Url>,
+    /// The keys which the entries of this index are signed with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<PublicKey>,
+    /// The checksum formats which the entries of this index can use, which is just `sha256` if
+    /// none are listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<String>,
+    // This has to come last so that it gets serialized as a table after everything else.
     pub dependencies: IndexMap<String, IndexRes>,
 }
 
@@ -69,10 +88,22 @@ impl Default for IndexConfInner {
         IndexConfInner {
             secure: false,
             dependencies: IndexMap::new(),
+            registry: None,
+            keys: vec![],
+            checksums: vec![],
         }
     }
 }
 
+impl IndexConfInner {
+    /// The format the checksums of packages added to this index are in: the strongest one it
+    /// allows which elba knows about.
+    pub fn checksum_fmt(&self) -> ChecksumFmt {
+        ChecksumFmt::strongest(self.checksums.iter().map(|x| x.as_str()))
+            .unwrap_or(ChecksumFmt::Sha256)
+    }
+}
+
 /// A dependency.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Dep<T> {
@@ -177,40 +208,189 @@ impl Indices {
         }
     }
 
-    pub fn search(&self, query: &str) -> Result<Vec<(Name, Version, &IndexRes)>> {
-        let mut engine: SimSearch<(&IndexRes, &str)> =
+    /// Searches the indices for packages, or only the index `within` if it's given. If a
+    /// `category` is given, only packages whose latest version is in it are turned up.
+    ///
+    /// Exact matches of either the full name or the part after the slash come first, then names
+    /// which start with the query, then names which contain it, then fuzzy matches. Packages
+    /// whose every version has been yanked are left out.
+    pub fn search(
+        &self,
+        query: &str,
+        within: Option<&IndexRes>,
+        category: Option<&str>,
+    ) -> Result<Vec<SearchHit>> {
+        let query = query
+            .to_ascii_lowercase()
+            .replace('_', "-")
+            .trim()
+            .to_string();
+
+        let mut engine: SimSearch<usize> =
             SimSearch::new_with(SearchOptions::new().stop_words(&["/", "\\"]));
-        let x = self
+        let pkgs = self
             .indices
             .iter()
+            .filter(|x| within.is_none() || within == Some(x.0))
             .map(|x| x.1.packages().map(move |p| (x.0, p)))
             .flatten()
+            .filter_map(|(ir, p)| Some((ir, Name::from_str(&p).ok()?)))
             .collect::<Vec<_>>();
 
-        for (ir, pkg) in &x {
-            engine.insert((ir, pkg), pkg);
+        let mut ranked = vec![];
+        for (ix, (_, name)) in pkgs.iter().enumerate() {
+            let full = name.as_normalized();
+            let short = name.normalized_name();
+            let rank = if full == query || short == query {
+                0
+            } else if full.starts_with(&query) || short.starts_with(&query) {
+                1
+            } else if full.contains(&query) {
+                2
+            } else {
+                engine.insert(ix, full);
+                continue;
+            };
+            ranked.push((rank, ix));
+        }
+        ranked.sort_by_key(|&(rank, ix)| (rank, pkgs[ix].1.as_normalized()));
+        ranked.extend(engine.search(&query).into_iter().map(|ix| (3, ix)));
+
+        let mut res = vec![];
+        for (_, ix) in ranked {
+            let (ir, name) = &pkgs[ix];
+            if let Some(hit) = self.latest(ir, name)? {
+                if let Some(category) = category {
+                    if !hit
+                        .categories
+                        .iter()
+                        .any(|x| x.eq_ignore_ascii_case(category))
+                    {
+                        continue;
+                    }
+                }
+                res.push(hit);
+            }
         }
-        let pkgs = engine.search(query);
 
-        pkgs.iter()
-            .map(|(ir, pkg)| {
-                let name = Name::from_str(pkg).unwrap();
-                let ix = &self.indices[*ir];
-                let ver: Version = ix.entries(&name)?.into_iter().map(|x| x.0).last().unwrap();
+        Ok(res)
+    }
 
-                Ok((name, ver, *ir))
-            })
-            .collect::<Result<Vec<_>>>()
+    /// The packages in a group, or only those in the index `within` if it's given, in order of
+    /// name. Packages whose every version has been yanked are left out.
+    pub fn group(&self, group: &str, within: Option<&IndexRes>) -> Result<Vec<SearchHit>> {
+        let group = group.to_ascii_lowercase().replace('_', "-");
+
+        let mut pkgs = self
+            .indices
+            .iter()
+            .filter(|x| within.is_none() || within == Some(x.0))
+            .map(|x| x.1.packages().map(move |p| (x.0, p)))
+            .flatten()
+            .filter_map(|(ir, p)| Some((ir, Name::from_str(&p).ok()?)))
+            .filter(|(_, name)| name.normalized_group() == group)
+            .collect::<Vec<_>>();
+        pkgs.sort_by(|a, b| a.1.as_normalized().cmp(b.1.as_normalized()));
+
+        let mut res = vec![];
+        for (ir, name) in pkgs {
+            if let Some(hit) = self.latest(ir, &name)? {
+                res.push(hit);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// The latest version of a package in an index which hasn't been yanked, if there is one.
+    fn latest(&self, ir: &IndexRes, name: &Name) -> Result<Option<SearchHit>> {
+        let entries = self.indices[ir].entries(name)?;
+        let latest = entries
+            .into_iter()
+            .map(|x| x.1)
+            .filter(|x| !x.yanked)
+            .max_by(|a, b| a.version.cmp(&b.version));
+
+        Ok(latest.map(|latest| SearchHit {
+            name: latest.name,
+            version: latest.version,
+            index: ir.clone(),
+            description: latest.description,
+            keywords: latest.keywords,
+            categories: latest.categories,
+            downloads: None,
+        }))
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+/// A package which turned up in a search.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub name: Name,
+    /// The latest version of the package which hasn't been yanked.
+    pub version: Version,
+    pub index: IndexRes,
+    pub description: Option<String>,
+    pub keywords: Vec<String>,
+    pub categories: Vec<String>,
+    /// How many times the package has been downloaded, if its registry keeps track.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<u64>,
+}
+
+/// The directory in a local directory registry which holds the tarballs of its packages.
+pub const PACKAGES_DIR: &str = "packages";
+
+/// The name of the tarball a version of a package is packaged into.
+pub fn tarball_name(name: &Name, version: &Version) -> String {
+    format!("{}_{}-{}.tar.gz", name.group(), name.name(), version)
+}
+
+/// The newest version of the index entry format that we understand.
+///
+/// Version 1 entries have no `v` field; version 2 added `cksum`, `features`, `min_compiler`,
+/// `description`, `keywords` and `categories`.
+/// Since every field added in version 2 is optional, version 1 entries are read as version 2
+/// entries with nothing in them, and are written back out as version 2 entries. `metadata` was
+/// added to version 2 later on, since versions of elba which don't know about it just ignore it.
+pub const ENTRY_VERSION: u32 = 2;
+
+fn default_entry_version() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct IndexEntry<D, L> {
+    /// The version of the entry format this entry was written in.
+    #[serde(default = "default_entry_version")]
+    pub v: u32,
     pub name: Name,
     pub version: Version,
     pub dependencies: Vec<Dep<D>>,
     pub yanked: bool,
     pub location: L,
+    /// The checksum of the package's tarball. If the location is a tarball without a checksum of
+    /// its own, this one is used to verify it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cksum: Option<Checksum>,
+    /// The features the package declares, mapped to the other features they turn on.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub features: IndexMap<String, Vec<String>>,
+    /// The oldest version of the compiler which can build the package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_compiler: Option<Version>,
+    /// A short description of the package, for showing in search results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Words which the package should turn up for in searches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    /// The categories the package belongs to, which searches can be narrowed down to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    /// The `[package.metadata]` table of the package's manifest, for tools other than elba.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
 }
 
 pub type ResolvedEntry = IndexEntry<IndexRes, DirectRes>;
@@ -227,6 +407,18 @@ pub struct Index {
     pub path: DirLock,
     /// The configuration of this index.
     pub config: IndexConfig,
+    /// The keys which we trust to sign the entries of this index, if it's secure.
+    pub trusted: Vec<PublicKey>,
+    /// Where the entries of this index are cached once they've been parsed, if they are.
+    entry_cache: Option<EntryCache>,
+}
+
+/// The directory an index's parsed entries are cached in, and the key which says whether the
+/// files in it are still good.
+#[derive(Debug)]
+struct EntryCache {
+    dir: PathBuf,
+    key: String,
 }
 
 impl Index {
@@ -242,21 +434,107 @@ impl Index {
             .with_context(|e| format_err!("couldn't read index config {}: {}", pn.display(), e))?;
         let config = IndexConfig::from_str(&contents)?;
 
-        Ok(Index { id, path, config })
+        Ok(Index::new(id, path, config))
+    }
+
+    /// Creates an index with a config which has already been read, trusting none of its keys.
+    pub fn new(id: IndexRes, path: DirLock, config: IndexConfig) -> Self {
+        Index {
+            id,
+            path,
+            config,
+            trusted: vec![],
+            entry_cache: None,
+        }
+    }
+
+    /// Caches the entries of this index in `dir` once they've been parsed, so that later runs
+    /// can read them back without parsing and verifying them all over again.
+    ///
+    /// The cache is keyed on the commit the index is checked out at, its config, and the keys
+    /// trusted for it, so this should be called once those are all settled.
+    pub fn with_entry_cache(mut self, dir: PathBuf) -> Self {
+        let mut hasher = Sha256::default();
+        let commit = Repository::open(self.path.path())
+            .and_then(|repo| Ok(repo.head()?.peel_to_commit()?.id().to_string()))
+            .unwrap_or_default();
+        hasher.input(commit.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(toml::to_string(&self.config).unwrap_or_default().as_bytes());
+        for key in &self.trusted {
+            hasher.input(b"\0");
+            hasher.input(key.to_string().as_bytes());
+        }
+
+        self.entry_cache = Some(EntryCache {
+            dir,
+            key: hex::encode(hasher.result()),
+        });
+        self
     }
 
     pub fn entries(&self, name: &Name) -> Result<IndexMap<Version, ResolvedEntry>> {
-        let mut res = IndexMap::new();
         let path = self.path.path().join(name.as_normalized());
-        let file = fs::File::open(path).context(Error::PackageNotFound)?;
-        let r = io::BufReader::new(&file);
+        let file = fs::File::open(&path).context(Error::PackageNotFound)?;
+
+        let cache = match &self.entry_cache {
+            Some(cache) => cache,
+            None => return self.parse_entries(name, &file),
+        };
+        // Dir indices aren't checked out from anywhere, so it's the file itself which tells us
+        // whether it has changed.
+        let stamp = file.metadata().ok().and_then(|meta| {
+            let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+            Some((meta.len(), modified.as_nanos() as u64))
+        });
+        let cached = cache.dir.join(name.as_normalized());
+
+        if let Some(stamp) = stamp {
+            if let Some(entries) = read_cached_entries(&cached, &cache.key, stamp) {
+                return Ok(entries);
+            }
+        }
+
+        let entries = self.parse_entries(name, &file)?;
+        if let Some(stamp) = stamp {
+            // Failing to cache the entries only makes the next run slower.
+            let _ = write_cached_entries(&cached, &cache.key, stamp, &entries);
+        }
+
+        Ok(entries)
+    }
+
+    fn parse_entries(
+        &self,
+        name: &Name,
+        file: &fs::File,
+    ) -> Result<IndexMap<Version, ResolvedEntry>> {
+        let mut res = IndexMap::new();
+        let r = io::BufReader::new(file);
 
         for (lix, line) in r.lines().enumerate() {
-            let entry: RawEntry = serde_json::from_str(&line?).context(format_err!(
-                "index entry {} for package {} is invalid",
-                lix + 1,
-                name
-            ))?;
+            let invalid = || format_err!("index entry {} for package {} is invalid", lix + 1, name);
+            let mut entry: serde_json::Value = serde_json::from_str(&line?).context(invalid())?;
+
+            // Entries from the future might be shaped in ways we don't understand, so we leave
+            // them out rather than risk misreading them.
+            let v = entry
+                .get("v")
+                .and_then(|x| x.as_u64())
+                .unwrap_or_else(|| u64::from(default_entry_version()));
+            if v > u64::from(ENTRY_VERSION) {
+                continue;
+            }
+
+            let sig = entry.as_object_mut().and_then(|x| x.remove("sig"));
+            if self.is_secure() {
+                self.verify(&entry, sig.as_ref().and_then(|x| x.as_str()))
+                    .with_context(|e| {
+                        format_err!("index entry {} for package {}: {}", lix + 1, name, e)
+                    })?;
+            }
+
+            let entry: RawEntry = serde_json::from_value(entry).context(invalid())?;
 
             let dependencies = entry
                 .dependencies
@@ -275,20 +553,63 @@ impl Index {
                 })
                 .collect::<Vec<_>>();
 
-            let location = entry.location.ok_or_else(|| {
-                format_err!(
+            let location = match (entry.location, &self.id.res) {
+                (Some(location), _) => location,
+                // Entries in a local directory registry can leave their location out, in which
+                // case the package is the tarball for it in the `packages` directory. The
+                // directory is found through where the index is on disk, since the path it was
+                // given by can be relative.
+                (None, DirectRes::Dir { .. }) => {
+                    let tarball = self
+                        .path
+                        .path()
+                        .join(PACKAGES_DIR)
+                        .join(tarball_name(&entry.name, &entry.version));
+                    DirectRes::Tar {
+                        url: Url::from_file_path(&tarball).map_err(|_| {
+                            format_err!("invalid package tarball path {}", tarball.display())
+                        })?,
+                        cksum: None,
+                    }
+                }
+                (None, _) => bail!(
                     "no location for index entry {} of package {}",
                     lix + 1,
                     name
-                )
-            })?;
+                ),
+            };
+            let location = match location {
+                DirectRes::Tar { url, cksum: None } => DirectRes::Tar {
+                    url,
+                    cksum: entry.cksum.clone(),
+                },
+                location => location,
+            };
+            // The signature only vouches for the tarball through its checksum.
+            if let DirectRes::Tar { cksum: None, .. } = location {
+                if self.is_secure() {
+                    bail!(
+                        "index entry {} for package {} is a tarball without a checksum",
+                        lix + 1,
+                        name
+                    )
+                }
+            }
 
             let entry: ResolvedEntry = IndexEntry {
+                v: ENTRY_VERSION,
                 name: entry.name,
                 version: entry.version,
                 dependencies,
                 yanked: entry.yanked,
                 location,
+                cksum: entry.cksum,
+                features: entry.features,
+                min_compiler: entry.min_compiler,
+                description: entry.description,
+                keywords: entry.keywords,
+                categories: entry.categories,
+                metadata: entry.metadata,
             };
 
             res.insert(entry.version.clone(), entry);
@@ -297,14 +618,43 @@ impl Index {
         Ok(res)
     }
 
+    /// Whether the entries of this index have to be signed. An index we trust keys for is
+    /// secure whatever its config says.
+    pub fn is_secure(&self) -> bool {
+        self.config.index.secure || !self.trusted.is_empty()
+    }
+
+    /// Checks the signature of an entry of a secure index against the keys we trust for it.
+    ///
+    /// What's signed is the entry without its `sig` field, in the form `canonical_json` writes it
+    /// out in.
+    fn verify(&self, entry: &serde_json::Value, sig: Option<&str>) -> Result<()> {
+        let sig = sig.ok_or_else(|| format_err!("entry isn't signed"))?;
+        let msg = canonical_json(entry)?;
+
+        if self.trusted.iter().any(|k| k.verify(msg.as_bytes(), sig)) {
+            Ok(())
+        } else {
+            bail!(
+                "entry isn't signed by any key trusted for {} (if the index has rotated its \
+                 keys, trust the new ones with `elba index trust`)",
+                self.id
+            )
+        }
+    }
+
     pub fn packages(&self) -> impl Iterator<Item = String> {
         let root_path = self.path.path().to_path_buf();
         let git_path = root_path.join(".git");
+        let packages_path = root_path.join(PACKAGES_DIR);
         WalkDir::new(self.path.path())
             .min_depth(2)
             .max_depth(3)
             .into_iter()
-            .filter_entry(move |x| x.path().parent().unwrap() != git_path)
+            .filter_entry(move |x| {
+                x.path().parent().unwrap() != git_path
+                    && x.path().parent().unwrap() != packages_path
+            })
             .filter_map(|x| x.ok())
             .map(move |x| {
                 let stripped = x.path().strip_prefix(&root_path).unwrap();
@@ -316,3 +666,188 @@ impl Index {
         self.config.index.dependencies.iter().map(|x| x.1)
     }
 }
+
+/// The version of the format parsed entries are cached in. Caches written in any other version
+/// are parsed again from scratch.
+const ENTRY_CACHE_FORMAT: u32 = 1;
+
+/// The parsed entries of a package, as they're kept in the entry cache.
+///
+/// bincode can't read back fields which serde skips or values which describe their own shape, so
+/// these are kept apart from `IndexEntry`, with the metadata as a JSON string.
+#[derive(Deserialize, Serialize)]
+struct CachedEntries {
+    key: String,
+    len: u64,
+    modified: u64,
+    entries: Vec<CachedEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedEntry {
+    name: Name,
+    version: Version,
+    dependencies: Vec<ResolvedDep>,
+    yanked: bool,
+    location: DirectRes,
+    cksum: Option<Checksum>,
+    features: IndexMap<String, Vec<String>>,
+    min_compiler: Option<Version>,
+    description: Option<String>,
+    keywords: Vec<String>,
+    categories: Vec<String>,
+    metadata: String,
+}
+
+/// Writes out a JSON value the one way the entries of secure indices are signed in, so that the
+/// bytes which were signed don't depend on how anyone's JSON library happens to order or format
+/// things. Registries which sign entries have to write them out exactly the same way:
+///
+/// - no whitespace anywhere outside of strings;
+/// - the keys of every object in ascending order of their UTF-8 bytes;
+/// - in strings, `"` and `\` escaped with a backslash, backspace, form feed, newline, carriage
+///   return and tab as `\b`, `\f`, `\n`, `\r` and `\t`, any other character below U+0020 as
+///   `\u00xx` in lowercase hex, and everything else as itself;
+/// - numbers as integers in decimal, with a `-` if they're negative. Numbers with fractional
+///   parts or exponents can't be signed at all.
+pub fn canonical_json(value: &serde_json::Value) -> Result<String> {
+    let mut out = String::new();
+    write_canonical(value, &mut out)?;
+    Ok(out)
+}
+
+fn write_canonical(value: &serde_json::Value, out: &mut String) -> Result<()> {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => out.push_str(&n.to_string()),
+            (_, Some(n)) => out.push_str(&n.to_string()),
+            _ => bail!("{} isn't an integer, so it can't be signed", n),
+        },
+        Value::String(x) => write_canonical_str(x, out),
+        Value::Array(xs) => {
+            out.push('[');
+            for (ix, x) in xs.iter().enumerate() {
+                if ix > 0 {
+                    out.push(',');
+                }
+                write_canonical(x, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys = map.keys().collect::<Vec<_>>();
+            keys.sort();
+            out.push('{');
+            for (ix, key) in keys.into_iter().enumerate() {
+                if ix > 0 {
+                    out.push(',');
+                }
+                write_canonical_str(key, out);
+                out.push(':');
+                write_canonical(&map[key], out)?;
+            }
+            out.push('}');
+        }
+    }
+
+    Ok(())
+}
+
+fn write_canonical_str(x: &str, out: &mut String) {
+    out.push('"');
+    for c in x.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Reads the cached entries of a package, if they were cached with this key from a file which
+/// hasn't changed since.
+fn read_cached_entries(
+    path: &Path,
+    key: &str,
+    (len, modified): (u64, u64),
+) -> Option<IndexMap<Version, ResolvedEntry>> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.len() < 4 || bytes[..4] != ENTRY_CACHE_FORMAT.to_le_bytes() {
+        return None;
+    }
+    let cached: CachedEntries = bincode::deserialize(&bytes[4..]).ok()?;
+    if cached.key != key || cached.len != len || cached.modified != modified {
+        return None;
+    }
+
+    cached
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let entry = IndexEntry {
+                v: ENTRY_VERSION,
+                name: entry.name,
+                version: entry.version,
+                dependencies: entry.dependencies,
+                yanked: entry.yanked,
+                location: entry.location,
+                cksum: entry.cksum,
+                features: entry.features,
+                min_compiler: entry.min_compiler,
+                description: entry.description,
+                keywords: entry.keywords,
+                categories: entry.categories,
+                metadata: serde_json::from_str(&entry.metadata).ok()?,
+            };
+            Some((entry.version.clone(), entry))
+        })
+        .collect()
+}
+
+fn write_cached_entries(
+    path: &Path,
+    key: &str,
+    (len, modified): (u64, u64),
+    entries: &IndexMap<Version, ResolvedEntry>,
+) -> Result<()> {
+    let cached = CachedEntries {
+        key: key.to_owned(),
+        len,
+        modified,
+        entries: entries
+            .values()
+            .map(|entry| CachedEntry {
+                name: entry.name.clone(),
+                version: entry.version.clone(),
+                dependencies: entry.dependencies.clone(),
+                yanked: entry.yanked,
+                location: entry.location.clone(),
+                cksum: entry.cksum.clone(),
+                features: entry.features.clone(),
+                min_compiler: entry.min_compiler.clone(),
+                description: entry.description.clone(),
+                keywords: entry.keywords.clone(),
+                categories: entry.categories.clone(),
+                metadata: serde_json::to_string(&entry.metadata).unwrap_or_default(),
+            })
+            .collect(),
+    };
+
+    let mut bytes = ENTRY_CACHE_FORMAT.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut bytes, &cached)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, bytes)
+}
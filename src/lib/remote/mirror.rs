@@ -0,0 +1,158 @@
+//! Mirrors, which indices and packages can be retrieved from in place of their upstream sources.
+//!
+//! Resolutions always refer to the upstream location of an index or package: that's what goes
+//! into lockfiles, what the cache is keyed by, and what secure indices are trusted by. Only when
+//! something actually has to be retrieved is its resolution swapped for one pointing at a mirror,
+//! and whatever the retrieval hands back (like the commit a git repo was checked out at) is
+//! mapped back onto the upstream before anything else sees it. That way, lockfiles made on
+//! machines with different mirrors configured are interchangeable.
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::remote::resolution::{DirectRes, IndexRes};
+
+/// A mirror of everything under an upstream url.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Mirror {
+    /// The url being mirrored. Every git repo and tarball at or below it is retrieved from the
+    /// mirror instead.
+    pub upstream: Url,
+    /// The url of the mirror.
+    pub url: Url,
+}
+
+/// The mirrors in the config, which sit between resolutions and the places they're retrieved
+/// from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mirrors {
+    mirrors: Vec<Mirror>,
+}
+
+impl Mirrors {
+    pub fn new(mirrors: Vec<Mirror>) -> Self {
+        Mirrors { mirrors }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mirrors.is_empty()
+    }
+
+    /// The resolution to actually retrieve `res` from: the same resolution pointing at the mirror
+    /// of its upstream, if it has one. Checksums are kept, so a mirror can't hand us anything but
+    /// the tarball we asked for.
+    pub fn select(&self, res: &DirectRes) -> DirectRes {
+        self.map(res, |m| (&m.upstream, &m.url))
+    }
+
+    /// The upstream resolution of `res`, which might point at a mirror.
+    pub fn canonicalize(&self, res: &DirectRes) -> DirectRes {
+        self.map(res, |m| (&m.url, &m.upstream))
+    }
+
+    /// The upstream resolution of an index, which might point at a mirror.
+    pub fn canonicalize_index(&self, index: &IndexRes) -> IndexRes {
+        self.canonicalize(&index.res).into()
+    }
+
+    /// Moves the url of a resolution from under one url to under another. When more than one
+    /// mirror applies, the one with the longest matching url wins.
+    fn map(&self, res: &DirectRes, dir: impl Fn(&Mirror) -> (&Url, &Url)) -> DirectRes {
+        let url = match res {
+            DirectRes::Git { repo, .. } => repo,
+            DirectRes::Tar { url, .. } => url,
+            DirectRes::Dir { .. } => return res.clone(),
+        };
+
+        let new = self
+            .mirrors
+            .iter()
+            .map(&dir)
+            .filter_map(|(from, to)| Some((from.as_str().len(), rebase(url, from, to)?)))
+            .max_by_key(|x| x.0)
+            .map(|x| x.1);
+
+        match (res, new) {
+            (DirectRes::Git { tag, .. }, Some(repo)) => DirectRes::Git {
+                repo,
+                tag: tag.clone(),
+            },
+            (DirectRes::Tar { cksum, .. }, Some(url)) => DirectRes::Tar {
+                url,
+                cksum: cksum.clone(),
+            },
+            _ => res.clone(),
+        }
+    }
+}
+
+/// Swaps `from` for `to` at the start of `url`, if `url` is `from` or something below it.
+fn rebase(url: &Url, from: &Url, to: &Url) -> Option<Url> {
+    let from = from.as_str().trim_end_matches('/');
+    let rest = url.as_str().get(from.len()..)?;
+    if !url.as_str().starts_with(from) || !(rest.is_empty() || rest.starts_with('/')) {
+        return None;
+    }
+
+    Url::parse(&format!("{}{}", to.as_str().trim_end_matches('/'), rest)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn mirrors() -> Mirrors {
+        Mirrors::new(vec![
+            Mirror {
+                upstream: Url::parse("https://github.com/elba").unwrap(),
+                url: Url::parse("https://mirror.example.com/gh/").unwrap(),
+            },
+            Mirror {
+                upstream: Url::parse("https://github.com/elba/index").unwrap(),
+                url: Url::parse("https://index.example.com/elba").unwrap(),
+            },
+        ])
+    }
+
+    #[test]
+    fn mirror_select() {
+        let res = DirectRes::from_str("git+https://github.com/elba/elba#master").unwrap();
+        let mirrored = mirrors().select(&res);
+        assert_eq!(
+            mirrored.to_string(),
+            "git+https://mirror.example.com/gh/elba#master"
+        );
+        assert_eq!(mirrors().canonicalize(&mirrored), res);
+    }
+
+    #[test]
+    fn mirror_longest_match() {
+        let res = DirectRes::from_str("git+https://github.com/elba/index#master").unwrap();
+        let mirrored = mirrors().select(&res);
+        assert_eq!(
+            mirrored.to_string(),
+            "git+https://index.example.com/elba#master"
+        );
+        assert_eq!(mirrors().canonicalize_index(&mirrored.into()).res, res);
+    }
+
+    #[test]
+    fn mirror_keeps_cksum() {
+        let res =
+            DirectRes::from_str("tar+https://github.com/elba/a.tar.gz#sha256=0123456789abcdef")
+                .unwrap();
+        let mirrored = mirrors().select(&res);
+        assert_eq!(
+            mirrored.to_string(),
+            "tar+https://mirror.example.com/gh/a.tar.gz#sha256=0123456789abcdef"
+        );
+    }
+
+    #[test]
+    fn mirror_path_boundary() {
+        let res = DirectRes::from_str("git+https://github.com/elbaz/thing#master").unwrap();
+        assert_eq!(mirrors().select(&res), res);
+        assert_eq!(mirrors().canonicalize(&res), res);
+    }
+}
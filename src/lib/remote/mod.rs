@@ -1,4 +1,7 @@
+pub mod advisory;
 mod index;
+pub mod mirror;
+pub mod registry;
 pub mod resolution;
 
 pub use self::index::*;
@@ -0,0 +1,285 @@
+//! A client for the HTTP API of a registry.
+//!
+//! Everything elba asks of a registry goes through `Registry`, which knows the endpoints of
+//! version 1 of the registry API and turns what comes back into typed responses. Failures are
+//! reported as `RegistryError`s, so that callers (including tools which embed elba) can tell a
+//! rejected token apart from a registry which can't be reached.
+
+use std::io::Read;
+
+use failure::{format_err, Fail};
+use reqwest::{
+    blocking::{Body, Client, RequestBuilder},
+    StatusCode,
+};
+use semver::Version;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    package::Name,
+    remote::{resolution::IndexRes, Indices},
+    util::error::Result,
+};
+
+/// The ways in which a request to a registry can go wrong.
+#[derive(Debug, Fail)]
+pub enum RegistryError {
+    #[fail(display = "couldn't reach registry {}: {}", _0, _1)]
+    Unreachable(Url, String),
+    #[fail(
+        display = "registry {} didn't accept the token (log in again with `elba login`): {}",
+        _0, _1
+    )]
+    Unauthorized(Url, String),
+    #[fail(display = "registry {} couldn't find what was asked for: {}", _0, _1)]
+    NotFound(Url, String),
+    #[fail(display = "registry {} refused the request ({}): {}", _0, _1, _2)]
+    Refused(Url, StatusCode, String),
+    #[fail(display = "registry {} sent an invalid response: {}", _0, _1)]
+    InvalidResponse(Url, String),
+}
+
+/// The metadata of a version of a package.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub license: Option<String>,
+}
+
+/// A package which turned up in a search of the registry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub name: Name,
+    pub version: Version,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub downloads: Option<u64>,
+}
+
+/// Who owns a group of packages on the registry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Group {
+    /// Whether anyone has claimed the group yet. Nobody can publish to a group until it's been
+    /// claimed.
+    pub claimed: bool,
+    /// Whether the user the token belongs to is one of the group's owners.
+    #[serde(default)]
+    pub owned: bool,
+    #[serde(default)]
+    pub owners: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Downloads {
+    downloads: u64,
+}
+
+/// A registry, which packages can be published to and managed on.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    url: Url,
+    client: Client,
+}
+
+impl Registry {
+    pub fn new(url: Url) -> Self {
+        Registry {
+            url,
+            client: Client::new(),
+        }
+    }
+
+    /// The registry of an index, as given in its config.
+    pub fn of(indices: &Indices, index: &IndexRes) -> Result<Self> {
+        let url = indices
+            .indices
+            .get(index)
+            .ok_or_else(|| format_err!("couldn't retrieve index {}", index))?
+            .config
+            .index
+            .registry
+            .clone()
+            .ok_or_else(|| format_err!("index {} doesn't have a registry", index))?;
+
+        Ok(Registry::new(url))
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Uploads a packaged tarball, `len` bytes long.
+    pub fn publish<R: Read + Send + 'static>(
+        &self,
+        tarball: R,
+        len: u64,
+        token: &str,
+    ) -> Result<()> {
+        let url = self.endpoint("api/v1/packages", &[("token", token)])?;
+        self.send(self.client.put(url).body(Body::sized(tarball, len)))?;
+
+        Ok(())
+    }
+
+    /// Yanks a version of a package, or un-yanks it if `yanked` is false.
+    pub fn yank(&self, name: &Name, version: &Version, yanked: bool, token: &str) -> Result<()> {
+        let yanked = yanked.to_string();
+        let url = self.endpoint(
+            &format!("api/v1/{}/{}/yank", package_path(name), version),
+            &[("yanked", yanked.as_str()), ("token", token)],
+        )?;
+        self.send(self.client.patch(url))?;
+
+        Ok(())
+    }
+
+    /// The users who own a package.
+    pub fn owners(&self, name: &Name, token: &str) -> Result<Vec<String>> {
+        let url = self.endpoint(
+            &format!("api/v1/packages/{}/owners", package_path(name)),
+            &[("token", token)],
+        )?;
+
+        self.json(self.client.get(url))
+    }
+
+    pub fn add_owner(&self, name: &Name, user: &str, token: &str) -> Result<()> {
+        let url = self.endpoint(
+            &format!("api/v1/packages/{}/owners", package_path(name)),
+            &[("user", user), ("token", token)],
+        )?;
+        self.send(self.client.put(url))?;
+
+        Ok(())
+    }
+
+    pub fn remove_owner(&self, name: &Name, user: &str, token: &str) -> Result<()> {
+        let url = self.endpoint(
+            &format!("api/v1/packages/{}/owners", package_path(name)),
+            &[("user", user), ("token", token)],
+        )?;
+        self.send(self.client.delete(url))?;
+
+        Ok(())
+    }
+
+    /// Who owns a group. Registries which don't have groups let anyone publish to any group, and
+    /// answer with a `NotFound`.
+    pub fn group(&self, group: &str, token: Option<&str>) -> Result<Group> {
+        let url = self.endpoint(
+            &format!("api/v1/groups/{}", group),
+            &token.map(|x| vec![("token", x)]).unwrap_or_default(),
+        )?;
+
+        self.json(self.client.get(url))
+    }
+
+    /// Claims a group for the user the token belongs to.
+    pub fn claim_group(&self, group: &str, token: &str) -> Result<()> {
+        let url = self.endpoint(&format!("api/v1/groups/{}", group), &[("token", token)])?;
+        self.send(self.client.put(url))?;
+
+        Ok(())
+    }
+
+    /// The metadata of a version of a package. Registries don't have to support this.
+    pub fn metadata(&self, name: &Name, version: &Version) -> Result<Metadata> {
+        let url = self.endpoint(
+            &format!(
+                "api/v1/packages/{}/{}/metadata",
+                package_path(name),
+                version
+            ),
+            &[],
+        )?;
+
+        self.json(self.client.get(url))
+    }
+
+    /// How many times a package has been downloaded. Registries don't have to support this.
+    pub fn downloads(&self, name: &Name) -> Result<u64> {
+        let url = self.endpoint(
+            &format!("api/v1/packages/{}/downloads", package_path(name)),
+            &[],
+        )?;
+        let res: Downloads = self.json(self.client.get(url))?;
+
+        Ok(res.downloads)
+    }
+
+    /// Searches the registry for packages. Registries don't have to support this.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        let url = self.endpoint("api/v1/packages/search", &[("q", query)])?;
+
+        self.json(self.client.get(url))
+    }
+
+    /// Downloads the tarball of a version of a package.
+    pub fn download(&self, name: &Name, version: &Version) -> Result<Vec<u8>> {
+        let url = self.endpoint(
+            &format!(
+                "api/v1/packages/{}/{}/download",
+                package_path(name),
+                version
+            ),
+            &[],
+        )?;
+        let mut res = vec![];
+        self.send(self.client.get(url))?
+            .read_to_end(&mut res)
+            .map_err(|e| RegistryError::Unreachable(self.url.clone(), e.to_string()))?;
+
+        Ok(res)
+    }
+
+    fn endpoint(&self, path: &str, query: &[(&str, &str)]) -> Result<Url> {
+        let mut url = self.url.join(path)?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        Ok(url)
+    }
+
+    /// Sends a request, turning an unsuccessful response into an error.
+    fn send(&self, req: RequestBuilder) -> Result<reqwest::blocking::Response> {
+        let resp = req
+            .send()
+            .map_err(|e| RegistryError::Unreachable(self.url.clone(), e.to_string()))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+
+        let body = resp.text().unwrap_or_default();
+        let url = self.url.clone();
+        let err = match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                RegistryError::Unauthorized(url, body)
+            }
+            StatusCode::NOT_FOUND => RegistryError::NotFound(url, body),
+            _ => RegistryError::Refused(url, status, body),
+        };
+
+        Err(err.into())
+    }
+
+    fn json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T> {
+        let body = self
+            .send(req)?
+            .text()
+            .map_err(|e| RegistryError::Unreachable(self.url.clone(), e.to_string()))?;
+
+        Ok(serde_json::from_str(&body)
+            .map_err(|e| RegistryError::InvalidResponse(self.url.clone(), e.to_string()))?)
+    }
+}
+
+/// The part of a url which refers to a package.
+fn package_path(name: &Name) -> String {
+    format!("{}/{}", name.normalized_group(), name.normalized_name())
+}
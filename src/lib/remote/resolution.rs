@@ -1,23 +1,32 @@
-use std::io;
-
-use std::{fmt, fs, io::BufReader, path::PathBuf, str::FromStr};
+use std::{
+    fmt, fs,
+    io::{self, prelude::*, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use failure::{bail, format_err, ResultExt};
 use flate2::read::GzDecoder;
-use git2::{BranchType, Repository, Sort};
-use reqwest::blocking::Client;
+use git2::{BranchType, ObjectType, Repository, Sort};
+use reqwest::{
+    blocking::Client,
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use sha2::{Digest, Sha256};
 use tar::Archive;
 use url::Url;
 
 use crate::{
-    package::Checksum,
+    package::{Checksum, ChecksumFmt, ChecksumHasher},
     util::{
         clear_dir,
-        error::{Error, Result},
-        git::{clone, fetch, reset, update_submodules},
+        error::{download_error, Error, Result},
+        fs::{fill_dir_atomic, write_atomic},
+        git::{clone, fetch, is_dirty, reset, update_submodules, Progress},
+        is_junk,
         lock::DirLock,
+        long_path,
     },
 };
 
@@ -148,38 +157,157 @@ impl DirectRes {
     }
 }
 
+/// The name of the file in which we keep the HTTP validators of a downloaded tarball.
+pub const VALIDATORS: &str = ".validators";
+
+/// The validators a server handed us along with a tarball, which let us ask it later whether the
+/// tarball has changed without downloading it all over again.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn read(dir: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(dir.join(VALIDATORS)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        write_atomic(&dir.join(VALIDATORS), toml::to_string(self)?)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
 /// Retrieves a package in the form of a tarball.
+///
+/// If we've downloaded this tarball into the target before, we make a conditional request, and
+/// leave the target alone if the server tells us nothing has changed.
 fn retrieve_tar(
     url: Url,
     client: &Client,
     target: &DirLock,
     cksum: Option<&Checksum>,
+    progress: Progress,
 ) -> Result<()> {
-    let mut resp = client
-        .get(url.as_str())
-        .send()
-        .and_then(|resp| resp.error_for_status())?;
-
-    let mut buf: Vec<u8> = vec![];
-    resp.copy_to(&mut buf)?;
-
-    let hash = hex::encode(Sha256::digest(&buf[..]).as_slice());
-    if let Some(cksum) = cksum {
-        if cksum.hash != hash {
-            bail!(format_err!("tarball checksum doesn't match real checksum"));
+    let mut req = client.get(url.as_str());
+    if let Some(v) = Validators::read(target.path()) {
+        if let Some(etag) = v.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(lm) = v.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, lm);
         }
     }
 
-    let archive = GzDecoder::new(&buf[..]);
-    let mut archive = Archive::new(archive);
+    let resp = req.send().and_then(|resp| resp.error_for_status())?;
 
-    clear_dir(target.path())?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(());
+    }
 
-    archive.unpack(target.path())?;
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_owned())
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+
+    let len = resp.content_length().unwrap_or(0);
+    let reader = ProgressReader {
+        inner: resp,
+        read: 0,
+        len,
+        progress,
+    };
+    unpack_tar(reader, target, cksum)?;
+
+    if !validators.is_empty() {
+        validators.write(target.path())?;
+    }
 
     Ok(())
 }
 
+/// Decompresses and unpacks a tarball into the target, hashing it as it's read so that we only
+/// have to go through the archive once.
+///
+/// The tarball is unpacked next to the target and only moved into it once it's all there and its
+/// checksum matches, so the target is never left with half of a package in it.
+fn unpack_tar(archive: impl Read, target: &DirLock, cksum: Option<&Checksum>) -> Result<()> {
+    // Without a checksum to check against, the format doesn't matter.
+    let fmt = cksum.map(|x| x.fmt).unwrap_or(ChecksumFmt::Sha256);
+    let mut reader = HashingReader {
+        inner: archive,
+        hash: ChecksumHasher::new(fmt),
+    };
+
+    fill_dir_atomic(target.path(), move |tmp| {
+        let tmp = long_path(tmp);
+        let mut archive = Archive::new(GzDecoder::new(&mut reader));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.components().any(|x| is_junk(x.as_os_str())) {
+                continue;
+            }
+            entry.unpack_in(&tmp)?;
+        }
+        drop(archive);
+        // The archive doesn't have to read the stream all the way to the end, but the hash does.
+        io::copy(&mut reader, &mut io::sink())?;
+
+        let hash = reader.hash.result().hash;
+        if let Some(cksum) = cksum {
+            if !cksum.hash.eq_ignore_ascii_case(&hash) {
+                bail!(format_err!("tarball checksum doesn't match real checksum"));
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// A reader which hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hash: ChecksumHasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.input(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A reader which reports how much of a download has been read through it.
+struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    len: u64,
+    progress: Progress<'a>,
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if let Some(progress) = self.progress {
+            progress(self.read, self.len.max(self.read));
+        }
+        Ok(n)
+    }
+}
+
 impl DirectRes {
     pub fn retrieve(
         &self,
@@ -187,41 +315,37 @@ impl DirectRes {
         target: &DirLock,
         eager: bool,
         dl_f: impl Fn(bool) -> Result<()>,
+    ) -> Result<Option<DirectRes>> {
+        self.retrieve_with_progress(client, target, eager, dl_f, None)
+    }
+
+    /// Like `retrieve`, but reports how much of the resolution has been downloaded as it goes.
+    /// Progress is measured in bytes for tarballs and in objects for git repositories.
+    pub fn retrieve_with_progress(
+        &self,
+        client: &Client,
+        target: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+        progress: Progress,
     ) -> Result<Option<DirectRes>> {
         match self {
             DirectRes::Tar { url, cksum } => match url.scheme() {
                 "http" | "https" => {
                     dl_f(true)?;
-                    retrieve_tar(url.clone(), &client, &target, cksum.as_ref())
-                        .context(Error::CannotDownload)?;
+                    retrieve_tar(url.clone(), &client, &target, cksum.as_ref(), progress)
+                        .map_err(|e| download_error(url.as_str(), e))?;
 
                     Ok(None)
                 }
                 "file" => {
                     dl_f(false)?;
-                    let mut archive =
-                        fs::File::open(target.path()).context(Error::CannotDownload)?;
+                    let path = url
+                        .to_file_path()
+                        .map_err(|_| format_err!("invalid file url {}", url))?;
+                    let archive = fs::File::open(&path).context(Error::CannotDownload)?;
 
-                    let mut hash = Sha256::new();
-                    io::copy(&mut archive, &mut hash).context(Error::CannotDownload)?;
-                    let hash = hex::encode(hash.result());
-
-                    if let Some(cksum) = cksum {
-                        if cksum.hash != hash {
-                            return Err(format_err!(
-                                "tarball checksum doesn't match real checksum"
-                            ))?;
-                        }
-                    }
-
-                    let archive = BufReader::new(archive);
-                    let archive = GzDecoder::new(archive);
-                    let mut archive = Archive::new(archive);
-
-                    clear_dir(target.path())?;
-
-                    archive
-                        .unpack(target.path())
+                    unpack_tar(BufReader::new(archive), &target, cksum.as_ref())
                         .context(Error::CannotDownload)?;
 
                     Ok(None)
@@ -237,6 +361,15 @@ impl DirectRes {
                 let repo = match repo {
                     Ok(r) => {
                         let mut repo = r;
+                        // Cached checkouts aren't supposed to be touched, but if they have been,
+                        // we put things back the way they were so that what we build is what's
+                        // actually at HEAD.
+                        if is_dirty(&repo).unwrap_or(false) {
+                            let head = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
+                            reset(&repo, &head).with_context(|e| {
+                                format_err!("couldn't restore git repo {}: {}", url, e)
+                            })?;
+                        }
                         // This logic is for in case we are pointed to an existing git repository.
                         // We only want to NOT update an existing git repository if eager is false.
                         // We assume that the HEAD of the repo is at the current "locked" state.
@@ -309,17 +442,19 @@ impl DirectRes {
                         // Get everything!!
                         dl_f(true)?;
                         let refspec = "refs/heads/*:refs/heads/*";
-                        fetch(&mut repo, &url, refspec).with_context(|e| {
-                            format_err!("couldn't fetch git repo {}: {}", url, e)
-                        })?;
+                        fetch(&mut repo, &url, refspec, progress)
+                            .with_context(|e| format_err!("couldn't fetch git repo {}: {}", url, e))
+                            .map_err(|e| download_error(url.as_str(), e.into()))?;
                         repo
                     }
                     Err(_) => {
                         clear_dir(target.path())?;
                         dl_f(true)?;
-                        clone(url, target.path()).with_context(|e| {
-                            format_err!("couldn't fetch git repo {}:\n{}", url, e)
-                        })?
+                        clone(url, target.path(), progress)
+                            .with_context(|e| {
+                                format_err!("couldn't fetch git repo {}:\n{}", url, e)
+                            })
+                            .map_err(|e| download_error(url.as_str(), e.into()))?
                     }
                 };
 
@@ -462,6 +597,17 @@ impl FromStr for IndexRes {
         let url = parts.next().ok_or_else(|| Error::InvalidSourceUrl)?;
 
         match utype {
+            // A local directory registry can also be given as a file url, which is just another
+            // way of writing a dir resolution.
+            "index" if url.starts_with("file:") => {
+                let path = Url::parse(url)
+                    .ok()
+                    .and_then(|x| x.to_file_path().ok())
+                    .ok_or_else(|| Error::InvalidSourceUrl)?;
+                Ok(IndexRes {
+                    res: DirectRes::Dir { path },
+                })
+            }
             "index" => {
                 let res = DirectRes::from_str(url).context(Error::InvalidSourceUrl)?;
                 Ok(IndexRes { res })
@@ -34,6 +34,7 @@ use crate::{
     util::{
         error::{Error, Result},
         graph::Graph,
+        profile,
         shell::{Shell, Verbosity},
     },
 };
@@ -79,6 +80,7 @@ impl<'ret, 'cache: 'ret> Resolver<'ret, 'cache> {
 
     pub fn solve(self) -> Result<Graph<Summary>> {
         let mut s = self;
+        let _span = profile::span(&s.logger, "resolve");
 
         info!(s.logger, "beginning dependency resolution");
         let r = s.solve_loop();
@@ -49,42 +49,117 @@
 //! If we want to cache builds, we can just have a separate subfolder for ibcs.
 
 use std::{
-    collections::VecDeque,
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    ffi::OsStr,
     fs::{self, File},
     io::{self, prelude::*, BufReader},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use console::style;
 use failure::{bail, format_err, ResultExt};
+use git2::Repository;
 use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
 use reqwest::blocking::Client;
+use scoped_threadpool::Pool;
+use semver::Version;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use slog::{debug, o, Logger};
 use toml;
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 use crate::{
     build::{context::BuildContext, Targets},
     cli::build::find_manifest,
-    package::{manifest::Manifest, PackageId, Spec},
+    package::{manifest::Manifest, PackageId, Spec, Summary},
     remote::{
-        resolution::{DirectRes, Resolution},
-        Index, Indices,
+        advisory::AdvisoryDb,
+        mirror::Mirrors,
+        resolution::{DirectRes, Resolution, VALIDATORS},
+        Index, IndexConfig, Indices,
     },
     util::{
-        clear_dir, copy_dir,
+        clear_dir, copy_dir, copy_dir_iter,
         error::Result,
+        fs::{create_dir_atomic, is_complete, write_atomic},
+        git::Progress,
         graph::Graph,
-        lock::DirLock,
+        keys::TrustedKeys,
+        lock::{process_alive, DirLock, LockKind, LOCK_FILE},
+        profile,
         shell::{Shell, Verbosity},
         valid_file,
     },
 };
 
+/// The name of the file which marks a directory in `src` as a link into the content-addressed
+/// store. It contains the path of the package's contents relative to the store.
+pub const STORE_LINK: &str = ".store";
+
+/// The version of `content_hash`. Contents stored in the store go in a directory named after it,
+/// and the checksums of stored builds are prefixed with it, so that whatever was hashed some other
+/// way by an older version of elba is still found where it was, but never checked against a hash
+/// worked out the current way.
+pub const CONTENT_HASH_VERSION: &str = "v2";
+
+/// The name of the file in which we record the content hash of a stored build, so that we can
+/// check it for corruption later on.
+pub const BUILD_CHECKSUM: &str = ".checksum";
+
+/// The name of the file in the target directory of a local package where the hashes of its files
+/// are kept between builds.
+pub const FILE_HASHES: &str = ".file-hashes";
+
+/// The name of the file whose modification time records when an index was last retrieved.
+pub const INDEX_FETCHED: &str = ".fetched";
+
+/// The name of the directory in `indices` where the parsed entries of each index are cached.
+pub const INDEX_ENTRIES: &str = ".entries";
+
+/// The name of the directory in `tmp` where we keep a session manifest for each Cache which has
+/// checked out temporary build directories. A manifest is named after the process which wrote
+/// it, and lists the directories in `tmp` that it checked out.
+pub const SESSIONS: &str = ".sessions";
+
+static NEXT_SESSION: AtomicUsize = AtomicUsize::new(0);
+
+/// When the cached copy of an index should be brought up to date with its source.
+///
+/// Indices which haven't been retrieved at all are always retrieved, unless we're offline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    /// Use whatever copy of the index we already have.
+    Never,
+    /// Refresh the index if it was last retrieved longer ago than this.
+    Stale(Duration),
+    /// Refresh the index no matter what.
+    Always,
+}
+
+impl Refresh {
+    /// Whether an index which was last retrieved at `fetched` should be refreshed.
+    fn needed(self, fetched: Option<SystemTime>) -> bool {
+        match self {
+            Refresh::Never => false,
+            Refresh::Always => true,
+            Refresh::Stale(ttl) => fetched
+                .and_then(|x| x.elapsed().ok())
+                .map(|age| age >= ttl)
+                .unwrap_or(true),
+        }
+    }
+}
+
 /// The Cache encapsulates all of the global state required for `elba` to function.
 ///
 /// This global state includes stuff like temporary places to download and build packages, places
@@ -95,8 +170,13 @@ use crate::{
 pub struct Cache {
     pub layout: Layout,
     client: Client,
+    mirrors: Mirrors,
     pub logger: Logger,
     pub shell: Shell,
+    session: Arc<Mutex<Option<Session>>>,
+    index_cache: bool,
+    /// How long to wait on a directory somebody else has locked before giving up on it.
+    lock_timeout: Option<Duration>,
 }
 
 impl Cache {
@@ -106,12 +186,79 @@ impl Cache {
         let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
         let logger = plog.new(o!("phase" => "cache"));
 
-        Ok(Cache {
+        let cache = Cache {
             layout,
             client,
+            mirrors: Mirrors::default(),
             logger,
             shell,
-        })
+            session: Arc::new(Mutex::new(None)),
+            index_cache: true,
+            lock_timeout: None,
+        };
+
+        // Cleaning up after a crashed process is no reason for this one to fail.
+        if let Err(e) = cache.reap_sessions() {
+            debug!(cache.logger, "couldn't clean up after crashed sessions"; "err" => e.to_string());
+        }
+
+        Ok(cache)
+    }
+
+    /// Retrieves indices and packages from these mirrors instead of their upstream sources.
+    pub fn with_mirrors(mut self, mirrors: Mirrors) -> Self {
+        self.mirrors = mirrors;
+        self
+    }
+
+    /// Whether to cache the entries of indices once they've been parsed. On by default.
+    pub fn with_index_cache(mut self, index_cache: bool) -> Self {
+        self.index_cache = index_cache;
+        self
+    }
+
+    /// Gives up on directories which somebody else keeps locked for longer than this, rather than
+    /// waiting on them forever (the default).
+    pub fn with_lock_timeout(mut self, lock_timeout: Option<Duration>) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Locks a directory, saying so through our shell if it has to be waited on, and giving up
+    /// on it after the lock timeout.
+    pub fn lock(&self, path: &Path, kind: LockKind) -> Result<DirLock> {
+        DirLock::acquire_with(path, kind, Some(self.shell), self.lock_timeout)
+    }
+
+    /// Gives up on downloads which take longer than this.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.client = Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    /// Retrieves a resolution into a directory from its mirror, if it has one. Whatever the
+    /// retrieval hands back is mapped onto the upstream again, so that it's the upstream
+    /// resolution which ends up in lockfiles.
+    fn retrieve(
+        &self,
+        res: &DirectRes,
+        target: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+        progress: Progress,
+    ) -> Result<Option<DirectRes>> {
+        let mirrored = self.mirrors.select(res);
+        if &mirrored != res {
+            debug!(
+                self.logger, "retrieving from mirror";
+                "res" => res.to_string(),
+                "mirror" => mirrored.to_string()
+            );
+        }
+
+        let new = mirrored.retrieve_with_progress(&self.client, target, eager, dl_f, progress)?;
+
+        Ok(new.map(|x| self.mirrors.canonicalize(&x)))
     }
 
     /// Retrieve the metadata of a package, loading it into the cache if necessary.
@@ -154,7 +301,14 @@ impl Cache {
     ) -> Result<(Option<DirectRes>, DirLock)> {
         if let DirectRes::Dir { path } = loc {
             debug!(self.logger, "loaded source"; "cause" => "dir", "pkg" => pkg.to_string());
-            return Ok((None, DirLock::acquire(&path)?));
+            let lock = self.lock(&path, LockKind::Source)?;
+            // In offline mode, packages from indices are pointed at their directories in `src`.
+            let lock = if path.starts_with(&self.layout.src) {
+                self.open_stored(lock)?
+            } else {
+                lock
+            };
+            return Ok((None, lock));
         }
 
         let eager = if offline { false } else { eager };
@@ -162,15 +316,36 @@ impl Cache {
         let new_dir = self.layout.src.join(Self::get_source_dir(loc, true));
         // We record first if the directory existed before the retrieval process
 
+        // Tarballs without a checksum could change from under us, so when we're updating eagerly
+        // we ask the server whether they have.
+        let revalidate = eager
+            && match loc {
+                DirectRes::Tar { url, cksum: None } => url.scheme() != "file",
+                _ => false,
+            };
+
+        // If the link to the stored contents is dangling, there's nothing to revalidate.
+        if loc.is_tar() && self.stored_path(&new_dir) == Some(None) {
+            let _ = fs::remove_file(new_dir.join(VALIDATORS));
+        }
+
         // If it does exist, we can stop immediately
-        if loc.is_tar() && new_dir.exists() {
+        if loc.is_tar()
+            && !revalidate
+            && new_dir.exists()
+            && is_complete(&new_dir)
+            && self.stored_path(&new_dir) != Some(None)
+        {
             debug!(
                 self.logger, "loaded source";
                 "cause" => "exists",
                 "pkg" => pkg.to_string(),
                 "dir" => new_dir.display()
             );
-            return Ok((None, DirLock::acquire(&new_dir)?));
+            return Ok((
+                None,
+                self.open_stored(self.lock(&new_dir, LockKind::Source)?)?,
+            ));
         }
 
         let new_f = |dl_online| {
@@ -185,7 +360,10 @@ impl Cache {
         // which don't exist yet.
         // If we're in "offline" mode, we immediately return an error from here because we
         // won't be able to download anything anyways.
-        let dir = DirLock::acquire(&self.layout.src.join(Self::get_source_dir(loc, true)))?;
+        let dir = self.lock(
+            &self.layout.src.join(Self::get_source_dir(loc, true)),
+            LockKind::Source,
+        )?;
         let res = if let Resolution::Direct(g) = pkg.resolution() {
             // For a git repository, if the DirectRes and the PackageId don't match, we should try to
             // retrieve the locked variant (the DirectRes) and then update with the latest variant
@@ -196,32 +374,48 @@ impl Cache {
             // two fetch operations.
             if g.is_git() && g != loc {
                 debug_assert!(loc.is_git());
-                loc.retrieve(&self.client, &dir, eager, new_f)
-                    .and_then(|_| {
-                        g.retrieve(&self.client, &dir, false, |dl_online| {
+                self.retrieve(loc, &dir, eager, new_f, None).and_then(|_| {
+                    self.retrieve(
+                        g,
+                        &dir,
+                        false,
+                        |dl_online| {
                             if offline && dl_online {
                                 Err(format_err!("Can't download package in offline mode"))
                             } else {
                                 Ok(())
                             }
-                        })
-                    })
+                        },
+                        None,
+                    )
+                })
             } else {
-                loc.retrieve(&self.client, &dir, eager, new_f)
+                self.retrieve(loc, &dir, eager, new_f, None)
             }
         } else {
-            loc.retrieve(&self.client, &dir, eager, new_f)
+            self.retrieve(loc, &dir, eager, new_f, None)
         }?;
 
         let new_dir = self.layout.src.join(&Self::get_source_dir(
             if let Some(r) = res.as_ref() { r } else { &loc },
             true,
         ));
+        // Git repositories which we check out in place have to stay as they are, but everything
+        // else goes into the store.
         let dir = if new_dir != dir.path() {
             if !new_dir.exists() {
                 copy_dir(dir.path(), &new_dir, true)?;
+                self.intern(self.lock(&new_dir, LockKind::Source)?)?
+            } else {
+                self.open_stored(self.lock(&new_dir, LockKind::Source)?)?
+            }
+        } else if loc.is_tar() {
+            // If the server told us that nothing changed, the directory is still a link.
+            if self.stored_path(dir.path()).is_some() {
+                self.open_stored(dir)?
+            } else {
+                self.intern(dir)?
             }
-            DirLock::acquire(&new_dir)?
         } else {
             dir
         };
@@ -237,6 +431,101 @@ impl Cache {
         Ok((res, dir))
     }
 
+    /// Moves the contents of a freshly retrieved package into the content-addressed store,
+    /// leaving a link to them in their place, and returns a lock on the stored contents.
+    ///
+    /// Packages with identical contents (say, the tarball and the git tag of the same release)
+    /// end up sharing the same directory in the store.
+    fn intern(&self, dir: DirLock) -> Result<DirLock> {
+        let hash = content_hash(dir.path())?;
+        let link = format!("{}/{}", CONTENT_HASH_VERSION, hash);
+        let stored = self.layout.store.join(&link);
+
+        if !stored.exists() {
+            let from = dir.path();
+            create_dir_atomic(&stored, |tmp| {
+                let walker = WalkDir::new(from)
+                    .into_iter()
+                    .filter_entry(|x| !is_meta_entry(x))
+                    .filter_map(|x| x.ok())
+                    .filter(valid_file);
+                copy_dir_iter(walker, from, tmp)
+            })
+            .with_context(|e| {
+                format_err!("couldn't move {} into the store: {}", from.display(), e)
+            })?;
+        }
+
+        // We write the link before clearing anything out so that we never leave behind a
+        // directory which is neither a package nor a link to one.
+        write_atomic(&dir.path().join(STORE_LINK), &link).with_context(|e| {
+            format_err!("couldn't link {} to the store: {}", dir.path().display(), e)
+        })?;
+
+        for entry in fs::read_dir(dir.path())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if is_meta(&name) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                remove_dir_all::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        debug!(
+            self.logger, "interned source";
+            "dir" => dir.path().display(),
+            "hash" => &hash
+        );
+
+        drop(dir);
+        self.lock(&stored, LockKind::Source)
+    }
+
+    /// If a directory is a link into the store, trades our lock on it for a lock on the stored
+    /// contents. Otherwise, we just hand the lock back.
+    fn open_stored(&self, dir: DirLock) -> Result<DirLock> {
+        match self.stored_path(dir.path()) {
+            Some(Some(stored)) => {
+                drop(dir);
+                self.lock(&stored, LockKind::Source)
+            }
+            Some(None) => bail!(
+                "the contents of {} are missing from the store at {}",
+                dir.path().display(),
+                self.layout.store.display()
+            ),
+            None => Ok(dir),
+        }
+    }
+
+    /// Returns `None` if the directory isn't a link into the store, and `Some(None)` if it's a
+    /// link to something which isn't there anymore.
+    pub fn stored_path(&self, dir: &Path) -> Option<Option<PathBuf>> {
+        let hash = fs::read_to_string(dir.join(STORE_LINK)).ok()?;
+        let stored = self.layout.store.join(hash.trim());
+
+        if stored.exists() {
+            Some(Some(stored))
+        } else {
+            Some(None)
+        }
+    }
+
+    /// Whether the contents of a package are already in the cache (or on disk, for a directory),
+    /// so that checking it out doesn't need to go online.
+    pub fn has_source(&self, loc: &DirectRes) -> bool {
+        if let DirectRes::Dir { path } = loc {
+            return path.exists();
+        }
+
+        let dir = self.layout.src.join(Self::get_source_dir(loc, true));
+        dir.exists() && self.stored_path(&dir) != Some(None)
+    }
+
     /// Gets the corresponding directory of a package.
     pub fn get_source_dir(loc: &DirectRes, include_tag: bool) -> String {
         let mut hasher = Sha256::default();
@@ -255,7 +544,7 @@ impl Cache {
     /// Return the build directory exists, else None.
     pub fn checkout_build(&self, hash: &BuildHash) -> Result<Option<Binary>> {
         if let Some(path) = self.check_build(&hash) {
-            Ok(Some(Binary::new(DirLock::acquire(&path)?)))
+            Ok(Some(Binary::new(self.lock(&path, LockKind::Build)?)))
         } else {
             Ok(None)
         }
@@ -264,8 +553,16 @@ impl Cache {
     /// Returns a lock on a temporary build directory.
     /// Note that the format of this directory should be an OutputLayout.
     pub fn checkout_tmp(&self, hash: &BuildHash) -> Result<OutputLayout> {
+        // The directory goes in the session manifest before anything is put in it, so that it
+        // gets cleaned up even if we crash right away.
+        self.session
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Session::new(&self.layout.tmp, self.shell))
+            .record(&hash.0)?;
+
         let path = self.layout.tmp.join(&hash.0);
-        let lock = DirLock::acquire(&path)?;
+        let lock = self.lock(&path, LockKind::Build)?;
         if lock.path().exists() {
             clear_dir(&lock.path()).context(format_err!(
                 "couldn't remove existing output path: {}",
@@ -275,54 +572,112 @@ impl Cache {
         OutputLayout::new(lock)
     }
 
-    pub fn store_bins(&self, bins: &[(PathBuf, String)], force: bool) -> Result<()> {
-        // We use a file .bins in the bin directory to keep track of installed bins
-        let mut dot_f = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(self.layout.bin.join(".bins"))
-            .with_context(|e| format_err!("could not open .bins file:\n{}", e))?;
+    /// Removes the temporary build directories checked out by elba processes which are no longer
+    /// running, along with their session manifests. Directories which someone else has since
+    /// checked out are left to them. Returns the number of directories removed.
+    pub fn reap_sessions(&self) -> Result<u32> {
+        let dir = self.layout.tmp.join(SESSIONS);
+        if !dir.exists() {
+            return Ok(0);
+        }
 
-        let mut dot_c = String::new();
-        dot_f
-            .read_to_string(&mut dot_c)
-            .with_context(|e| format_err!("could not read .bins file:\n{}", e))?;
+        let mut removed = 0;
+        for entry in fs::read_dir(&dir)? {
+            let manifest = entry?.path();
+            let pid = manifest
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.split('-').next())
+                .and_then(|x| x.parse().ok());
+            match pid {
+                Some(pid) if !process_alive(pid) => {}
+                _ => continue,
+            }
 
-        let mut dot: IndexMap<String, String> = toml::from_str(&dot_c)
-            .with_context(|e| format_err!("could not deserialize .bins file:\n{}", e))?;
+            let contents = fs::read_to_string(&manifest).with_context(|e| {
+                format_err!(
+                    "couldn't read session manifest {}: {}",
+                    manifest.display(),
+                    e
+                )
+            })?;
+            for name in contents.lines().filter(|x| !x.trim().is_empty()) {
+                if remove_tmp(&self.layout.tmp, name.trim(), self.shell)? {
+                    removed += 1;
+                }
+            }
+            fs::remove_file(&manifest)?;
+        }
 
-        for (path, sum) in bins {
-            self.shell.println(
-                style("Installing").cyan(),
-                path.file_name().unwrap().to_string_lossy().as_ref(),
-                Verbosity::Normal,
-            );
-            self.store_bin(path, force)?;
-            dot.insert(
-                // We can unwrap the file name; we checked for an error in store_bin
-                path.file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_owned()
-                    .to_string(),
-                sum.to_string(),
-            );
+        Ok(removed)
+    }
+
+    /// The binaries installed in the bin directory, along with where each of them came from. We
+    /// keep track of these in a file `.bins` in the bin directory.
+    pub fn installed_bins(&self) -> Result<IndexMap<String, InstalledBin>> {
+        let path = self.layout.bin.join(".bins");
+        if !path.exists() {
+            return Ok(IndexMap::new());
         }
 
-        drop(dot_f);
-        fs::remove_file(self.layout.bin.join(".bins"))
-            .with_context(|e| format_err!("could not clear existing .bins file:\n{}", e))?;
+        let contents = fs::read_to_string(&path)
+            .with_context(|e| format_err!("could not read .bins file:\n{}", e))?;
+
+        Ok(toml::from_str(&contents)
+            .with_context(|e| format_err!("could not deserialize .bins file:\n{}", e))?)
+    }
 
+    fn write_installed_bins(&self, bins: &IndexMap<String, InstalledBin>) -> Result<()> {
         fs::write(
             self.layout.bin.join(".bins"),
-            toml::to_string(&dot).unwrap().as_bytes(),
+            toml::to_string(bins).unwrap().as_bytes(),
         )
         .with_context(|e| format_err!("could not write to .bins file:\n{}", e))?;
 
         Ok(())
     }
 
+    /// Checks whether every one of the given binaries is already installed from the exact same
+    /// build, in which case there's nothing to reinstall.
+    pub fn bins_up_to_date(&self, bins: &[String], build: &BuildHash) -> Result<bool> {
+        let installed = self.installed_bins()?;
+
+        Ok(!bins.is_empty()
+            && bins.iter().all(|bin| {
+                self.layout.bin.join(bin).exists()
+                    && installed.get(bin).and_then(|x| x.build.as_ref()) == Some(&build.0)
+            }))
+    }
+
+    /// Installs binaries into the bin directory. A binary which is already there is only replaced
+    /// if it was installed from (another version of) the same package, unless `force` is set.
+    pub fn store_bins(&self, bins: &[(PathBuf, InstalledBin)], force: bool) -> Result<()> {
+        let mut dot = self.installed_bins()?;
+
+        for (path, info) in bins {
+            // store_bin complains about paths without a file name for us
+            let bin = path
+                .file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let upgrade = dot.get(&bin).map(|x| x.id() == info.id()).unwrap_or(false);
+
+            self.shell.println(
+                if upgrade {
+                    style("Replacing").cyan()
+                } else {
+                    style("Installing").cyan()
+                },
+                &bin,
+                Verbosity::Normal,
+            );
+            self.store_bin(path, force || upgrade)?;
+            dot.insert(bin, info.clone());
+        }
+
+        self.write_installed_bins(&dot)
+    }
+
     fn store_bin(&self, from: &Path, force: bool) -> Result<()> {
         let bin_name = from
             .file_name()
@@ -373,24 +728,19 @@ impl Cache {
 
         let mut c = 0;
         if self.layout.bin.join(".bins").exists() {
-            let mut s = String::new();
-            let mut f = fs::OpenOptions::new()
-                .read(true)
-                .open(self.layout.bin.join(".bins"))
-                .with_context(|e| format_err!("could not open .bins file:\n{}", e))?;
-
-            f.read_to_string(&mut s)
-                .with_context(|e| format_err!("could not read from .bins file:\n{}", e))?;
-
-            let dot: IndexMap<String, String> = toml::from_str(&s)
-                .with_context(|e| format_err!("could not deserialize .bins file:\n{}", e))?;
+            let dot = self.installed_bins()?;
 
             let (discard, dot): (IndexMap<_, _>, IndexMap<_, _>) =
-                dot.into_iter().partition(|(bin, sum)| {
-                    (bins.is_empty() || bins.contains(&bin.as_str())) && contains(sum, query)
+                dot.into_iter().partition(|(bin, info)| {
+                    (bins.is_empty() || bins.contains(&bin.as_str()))
+                        && contains(&info.package, query)
                 });
 
-            let sums = discard.iter().dedup().collect::<Vec<_>>();
+            let sums = discard
+                .values()
+                .map(|x| &x.package)
+                .unique()
+                .collect::<Vec<_>>();
             if sums.len() > 1 {
                 return Err(format_err!(
                     "spec `{}` is ambiguous between {:?}",
@@ -405,15 +755,7 @@ impl Cache {
                 c += 1;
             }
 
-            drop(f);
-            fs::remove_file(self.layout.bin.join(".bins"))
-                .with_context(|e| format_err!("could not clear existing .bins file:\n{}", e))?;
-
-            fs::write(
-                self.layout.bin.join(".bins"),
-                toml::to_string(&dot).unwrap().as_bytes(),
-            )
-            .with_context(|e| format_err!("could not write to .bins file:\n{}", e))?;
+            self.write_installed_bins(&dot)?;
         }
 
         Ok(c)
@@ -422,19 +764,28 @@ impl Cache {
     pub fn store_build(&self, from: &Path, hash: &BuildHash) -> Result<Binary> {
         let dest = self.layout.build.join(&hash.0);
 
-        if !dest.exists() {
-            fs::create_dir_all(&dest)?;
-        }
-
-        let dest = DirLock::acquire(&dest)?;
+        // The build only shows up in the cache once all of it (and its checksum) is there, since
+        // a build which is there at all is taken to be finished.
+        create_dir_atomic(&dest, |tmp| {
+            copy_dir(from, tmp, false)?;
+
+            let sum = format!("{}:{}", CONTENT_HASH_VERSION, content_hash(tmp)?);
+            fs::write(tmp.join(BUILD_CHECKSUM), sum.as_bytes()).with_context(|e| {
+                format_err!(
+                    "couldn't record checksum of build {}: {}",
+                    dest.display(),
+                    e
+                )
+            })?;
 
-        clear_dir(dest.path())?;
-        copy_dir(from, dest.path(), false)?;
+            Ok(())
+        })?;
 
-        Ok(Binary::new(dest))
+        Ok(Binary::new(self.lock(&dest, LockKind::Build)?))
     }
 
-    fn check_build(&self, hash: &BuildHash) -> Option<PathBuf> {
+    /// The directory of a build in the cache, if it's there, without taking a lock on it.
+    pub fn check_build(&self, hash: &BuildHash) -> Option<PathBuf> {
         let path = self.layout.build.join(&hash.0);
 
         if path.exists() {
@@ -444,7 +795,45 @@ impl Cache {
         }
     }
 
-    pub fn get_indices(&self, index_reses: &[DirectRes], eager: bool, offline: bool) -> Indices {
+    /// Loads a set of indices and all of the indices they depend on, retrieving them first if
+    /// the refresh policy calls for it (and we aren't offline).
+    pub fn get_indices(
+        &self,
+        index_reses: &[DirectRes],
+        refresh: Refresh,
+        offline: bool,
+    ) -> Indices {
+        self.load_indices(index_reses, refresh, offline, vec![])
+    }
+
+    /// Loads a set of indices again, keeping the locks we already have on them.
+    ///
+    /// Whoever's reloading the indices might have locked some sources since they were first
+    /// loaded, so locking the indices from scratch could go against the lock order.
+    pub fn reload_indices(
+        &self,
+        old: Indices,
+        index_reses: &[DirectRes],
+        refresh: Refresh,
+        offline: bool,
+    ) -> Indices {
+        let held = old.indices.into_iter().map(|(_, ix)| ix.path).collect();
+        self.load_indices(index_reses, refresh, offline, held)
+    }
+
+    fn load_indices(
+        &self,
+        index_reses: &[DirectRes],
+        refresh: Refresh,
+        offline: bool,
+        mut held: Vec<DirLock>,
+    ) -> Indices {
+        let _span = profile::span(&self.logger, "indices");
+        let mut lock = |path: &Path| match held.iter().position(|x| x.path() == path) {
+            Some(ix) => Ok(held.swap_remove(ix)),
+            None => self.lock(path, LockKind::Index),
+        };
+
         let mut indices = vec![];
         let mut seen = vec![];
         let mut q: VecDeque<DirectRes> = index_reses.iter().cloned().collect();
@@ -456,7 +845,7 @@ impl Cache {
 
             // We special-case a local dir index because `dir` won't exist for it.
             if let DirectRes::Dir { path } = &index {
-                let lock = match DirLock::acquire(path) {
+                let lock = match lock(path) {
                     Ok(dir) => dir,
                     Err(e) => {
                         self.shell.println(
@@ -468,7 +857,10 @@ impl Cache {
                     }
                 };
 
-                let ix = Index::from_disk(index.clone(), lock);
+                let ix = Index::from_disk(index.clone(), lock).and_then(|mut ix| {
+                    self.trust_keys(&mut ix)?;
+                    Ok(self.cache_entries(ix))
+                });
                 if let Ok(ix) = ix {
                     for dependent in ix.depends().cloned().map(|i| i.res) {
                         q.push_back(dependent);
@@ -480,7 +872,7 @@ impl Cache {
             }
 
             let index_path = self.layout.indices.join(Self::get_index_dir(&index));
-            let dir = match DirLock::acquire(&index_path) {
+            let dir = match lock(&index_path) {
                 Ok(dir) => dir,
                 Err(e) => {
                     self.shell.println(
@@ -492,49 +884,266 @@ impl Cache {
                 }
             };
 
-            let res = index.retrieve(&self.client, &dir, eager, |dl_online| {
+            let ix = self
+                .load_cached_index(&index, dir, refresh, offline)
+                .and_then(|mut ix| {
+                    self.trust_keys(&mut ix)?;
+                    Ok(self.cache_entries(ix))
+                });
+            match ix {
+                Ok(ix) => {
+                    for dependent in ix.depends().cloned().map(|i| i.res) {
+                        q.push_back(dependent);
+                    }
+                    seen.push(index);
+                    indices.push(ix);
+                }
+                Err(e) => {
+                    self.shell.println(
+                        style("[warn]").yellow().bold(),
+                        format!("Couldn't retrieve index {}: {}", index, e),
+                        Verbosity::Quiet,
+                    );
+                }
+            }
+        }
+
+        Indices::new(indices)
+    }
+
+    /// Points an index at the directory its parsed entries get cached in, unless we've been told
+    /// not to cache them.
+    fn cache_entries(&self, ix: Index) -> Index {
+        if !self.index_cache {
+            return ix;
+        }
+
+        let dir = self
+            .layout
+            .indices
+            .join(INDEX_ENTRIES)
+            .join(Self::get_index_dir(&ix.id.res));
+        ix.with_entry_cache(dir)
+    }
+
+    /// Loads the cached copy of an index, retrieving or refreshing it first if it needs to be.
+    ///
+    /// A cached copy which is broken, whether from a fetch that was interrupted, a bad object, or
+    /// someone poking around in the cache, is thrown away and retrieved again from scratch.
+    fn load_cached_index(
+        &self,
+        index: &DirectRes,
+        dir: DirLock,
+        refresh: Refresh,
+        offline: bool,
+    ) -> Result<Index> {
+        let exists = dir.path().join("index.toml").exists();
+        let fetched = fs::metadata(dir.path().join(INDEX_FETCHED))
+            .and_then(|x| x.modified())
+            .ok();
+
+        if !exists || (!offline && refresh.needed(fetched)) {
+            if let Err(e) = self.fetch_index(index, &dir, exists, offline) {
+                // An index we couldn't update is still better than no index at all, as long as
+                // it's in one piece.
+                if exists && index_intact(dir.path()) {
+                    self.shell.println(
+                        style("[warn]").yellow().bold(),
+                        format!("Couldn't update index {}: {}", index, e),
+                        Verbosity::Quiet,
+                    );
+                } else if !exists || offline {
+                    return Err(e);
+                }
+            }
+        }
+
+        if !offline && !index_intact(dir.path()) {
+            self.shell.println(
+                style("[warn]").yellow().bold(),
+                format!("Index {} is corrupt; retrieving it again", index),
+                Verbosity::Quiet,
+            );
+            clear_dir(dir.path())?;
+            self.fetch_index(index, &dir, false, offline)?;
+        }
+
+        Index::from_disk(index.clone(), dir)
+    }
+
+    /// Retrieves an index into its directory in the cache, showing how far along it is.
+    fn fetch_index(
+        &self,
+        index: &DirectRes,
+        dir: &DirLock,
+        exists: bool,
+        offline: bool,
+    ) -> Result<()> {
+        let status = if exists { "Updating" } else { "Retrieving" };
+        let shown = Cell::new(false);
+        let progress: &dyn Fn(u64, u64) = &|done, total| {
+            shown.set(true);
+            self.shell.print_progress(
+                style("Received").dim(),
+                format!("index {}", index),
+                done,
+                total,
+                Verbosity::Normal,
+            );
+        };
+
+        let res = self.retrieve(
+            index,
+            dir,
+            true,
+            |dl_online| {
                 if offline && dl_online {
                     return Err(format_err!("Offline mode; can't update indices"));
                 }
                 self.shell.println(
-                    style("Retrieving").cyan(),
-                    format!("index {}", &index),
+                    style(status).cyan(),
+                    format!("index {}", index),
                     Verbosity::Normal,
                 );
                 Ok(())
-            });
+            },
+            Some(progress),
+        );
+        if shown.get() {
+            self.shell.end_progress(Verbosity::Normal);
+        }
+
+        res?;
+        File::create(dir.path().join(INDEX_FETCHED))?;
+
+        Ok(())
+    }
 
+    /// Loads an advisory database, retrieving it into the cache first if the refresh policy calls
+    /// for it. Like indices, an advisory database which couldn't be updated is still used as long
+    /// as an older copy of it is around.
+    pub fn get_advisory_db(
+        &self,
+        db: &DirectRes,
+        refresh: Refresh,
+        offline: bool,
+    ) -> Result<AdvisoryDb> {
+        if let DirectRes::Dir { path } = db {
+            return AdvisoryDb::from_disk(path);
+        }
+
+        let dir = self.lock(
+            &self
+                .layout
+                .indices
+                .join("advisories")
+                .join(Self::get_index_dir(db)),
+            LockKind::Index,
+        )?;
+        let exists = dir.path().join("advisories").exists();
+        let fetched = fs::metadata(dir.path().join(INDEX_FETCHED))
+            .and_then(|x| x.modified())
+            .ok();
+
+        if !exists || (!offline && refresh.needed(fetched)) {
+            let res = self.retrieve(
+                db,
+                &dir,
+                true,
+                |dl_online| {
+                    if offline && dl_online {
+                        return Err(format_err!("Offline mode; can't update advisory database"));
+                    }
+                    self.shell.println(
+                        style(if exists { "Updating" } else { "Retrieving" }).cyan(),
+                        format!("advisory database {}", db),
+                        Verbosity::Normal,
+                    );
+                    Ok(())
+                },
+                None,
+            );
             match res {
                 Ok(_) => {
-                    let ix = Index::from_disk(index.clone(), dir);
-                    match ix {
-                        Ok(ix) => {
-                            for dependent in ix.depends().cloned().map(|i| i.res) {
-                                q.push_back(dependent);
-                            }
-                            seen.push(index);
-                            indices.push(ix);
-                        }
-                        Err(e) => {
-                            self.shell.println(
-                                style("[warn]").yellow().bold(),
-                                format!("Invalid/corrupt index {}: {}", index, e),
-                                Verbosity::Quiet,
-                            );
-                        }
-                    }
+                    File::create(dir.path().join(INDEX_FETCHED))?;
                 }
-                Err(e) => {
+                Err(e) if exists => {
                     self.shell.println(
                         style("[warn]").yellow().bold(),
-                        format!("Couldn't retrieve cache {}: {}", index, e),
+                        format!("Couldn't update advisory database {}: {}", db, e),
                         Verbosity::Quiet,
                     );
                 }
+                Err(e) => return Err(e),
             }
         }
 
-        Indices::new(indices)
+        AdvisoryDb::from_disk(dir.path())
+    }
+
+    /// Throws away the cached copy of an index, so that it's retrieved from scratch the next time
+    /// it's needed. Local directory indices aren't cached, so there's nothing to throw away.
+    pub fn clear_index(&self, index: &DirectRes) -> Result<()> {
+        if let DirectRes::Dir { .. } = index {
+            return Ok(());
+        }
+
+        let dir = self.lock(
+            &self.layout.indices.join(Self::get_index_dir(index)),
+            LockKind::Index,
+        )?;
+        clear_dir(dir.path())
+    }
+
+    /// Works out which keys to trust for the entries of a secure index. If we've never seen the
+    /// index before, we trust the keys it lists; otherwise we stick with the keys we trusted
+    /// before, and warn about any new ones it lists.
+    ///
+    /// An index we've trusted keys for stays secure whatever its config says, since whoever
+    /// controls it could otherwise just turn signing off. It can only stop being secure once the
+    /// user stops trusting it.
+    fn trust_keys(&self, ix: &mut Index) -> Result<()> {
+        let listed = &ix.config.index.keys;
+        let mut trusted = TrustedKeys::load(&self.layout.keys)?;
+        if let Some(keys) = trusted.get(&ix.id) {
+            if !ix.config.index.secure {
+                bail!(
+                    "index {} used to be secure, but now says its entries aren't signed; if \
+                     that's expected, stop trusting it with `elba index untrust`",
+                    ix.id
+                )
+            }
+
+            let new = listed.iter().filter(|k| !keys.contains(k)).join(", ");
+            if !new.is_empty() {
+                self.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "Index {} lists keys which aren't trusted: {}; if it has rotated its keys, \
+                         trust them with `elba index trust`",
+                        ix.id, new
+                    ),
+                    Verbosity::Quiet,
+                );
+            }
+            ix.trusted = keys.to_vec();
+        } else if ix.config.index.secure {
+            if listed.is_empty() {
+                bail!("secure index {} doesn't list any keys", ix.id)
+            }
+            for key in listed {
+                trusted.trust(&ix.id, *key);
+            }
+            trusted.save()?;
+            self.shell.println(
+                style("Trusting").cyan(),
+                format!("keys {} for index {}", listed.iter().join(", "), ix.id),
+                Verbosity::Normal,
+            );
+            ix.trusted = listed.clone();
+        }
+
+        Ok(())
     }
 
     fn get_index_dir(loc: &DirectRes) -> String {
@@ -558,7 +1167,9 @@ impl Cache {
                 .to_string_lossy()
                 .to_string();
 
-            if dir.path().join("elba.toml").exists() {
+            if dir.path().join("elba.toml").exists()
+                || self.stored_path(dir.path()).map_or(false, |x| x.is_some())
+            {
                 res.insert(fname);
             }
         }
@@ -567,6 +1178,132 @@ impl Cache {
     }
 }
 
+/// Whether the cached copy of an index looks usable: its config has to make sense, and if it's a
+/// git repository, everything the commit it's checked out at needs has to be there.
+fn index_intact(path: &Path) -> bool {
+    if !is_complete(path) {
+        return false;
+    }
+
+    let config = fs::read_to_string(path.join("index.toml"))
+        .ok()
+        .and_then(|x| IndexConfig::from_str(&x).ok());
+    if config.is_none() {
+        return false;
+    }
+
+    if path.join(".git").exists() {
+        let repo = match Repository::open(path) {
+            Ok(repo) => repo,
+            Err(_) => return false,
+        };
+        let head = repo
+            .head()
+            .and_then(|x| x.peel_to_commit())
+            .and_then(|x| x.tree())
+            .and_then(|x| x.get_path(Path::new("index.toml")))
+            .and_then(|x| x.to_object(&repo));
+        if head.is_err() {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// The temporary build directories which one Cache has checked out. They're removed once every
+/// clone of the Cache is gone; if the process crashes before then, the manifest it leaves behind
+/// lets the next elba process remove them instead.
+#[derive(Debug)]
+struct Session {
+    manifest: PathBuf,
+    dirs: Vec<String>,
+    shell: Shell,
+}
+
+impl Session {
+    fn new(tmp: &Path, shell: Shell) -> Self {
+        let name = format!(
+            "{}-{}",
+            process::id(),
+            NEXT_SESSION.fetch_add(1, Ordering::SeqCst)
+        );
+
+        Session {
+            manifest: tmp.join(SESSIONS).join(name),
+            dirs: vec![],
+            shell,
+        }
+    }
+
+    fn record(&mut self, dir: &str) -> Result<()> {
+        if self.dirs.iter().any(|x| x == dir) {
+            return Ok(());
+        }
+        self.dirs.push(dir.to_string());
+
+        if let Some(parent) = self.manifest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(
+            &self.manifest,
+            self.dirs
+                .iter()
+                .map(|x| format!("{}\n", x))
+                .collect::<String>(),
+        )
+        .with_context(|e| {
+            format_err!(
+                "couldn't write session manifest {}: {}",
+                self.manifest.display(),
+                e
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(tmp) = self.manifest.parent().and_then(Path::parent) {
+            for dir in &self.dirs {
+                let _ = remove_tmp(tmp, dir, self.shell);
+            }
+        }
+        let _ = fs::remove_file(&self.manifest);
+    }
+}
+
+/// Removes a temporary build directory, unless someone is using it. Returns whether it was
+/// removed.
+pub fn remove_tmp(tmp: &Path, name: &str, shell: Shell) -> Result<bool> {
+    // Anything but a plain name would point outside of tmp, and nothing we put there has one.
+    if name.is_empty() || name.starts_with('.') || name.contains(|c| c == '/' || c == '\\') {
+        return Ok(false);
+    }
+
+    let path = tmp.join(name);
+    if !path.is_dir() {
+        return Ok(false);
+    }
+
+    match DirLock::try_acquire(&path, LockKind::Build, Some(shell))? {
+        Some(lock) => {
+            shell.println(
+                style("Removing").dim(),
+                lock.path().display(),
+                Verbosity::Verbose,
+            );
+            remove_dir_all::remove_dir_all(lock.path()).with_context(|e| {
+                format_err!("couldn't remove {}: {}", lock.path().display(), e)
+            })?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
 /// Layouts encapsulate the logic behind our directory structure.
 #[derive(Debug, Clone)]
 pub struct Layout {
@@ -574,12 +1311,16 @@ pub struct Layout {
     pub bin: PathBuf,
     /// Source download directory
     pub src: PathBuf,
+    /// Content-addressed store of package contents, which directories in `src` link to
+    pub store: PathBuf,
     /// Built library (ibc output) directory
     pub build: PathBuf,
     /// Temporary build directory
     pub tmp: PathBuf,
     /// Directory of all the indices
     pub indices: PathBuf,
+    /// File of the keys trusted to sign the entries of secure indices
+    pub keys: PathBuf,
 }
 
 impl Layout {
@@ -587,6 +1328,7 @@ impl Layout {
         // create_dir_all ignores pre-existing folders
         fs::create_dir_all(&self.bin)?;
         fs::create_dir_all(&self.src)?;
+        fs::create_dir_all(self.store.join(CONTENT_HASH_VERSION))?;
         fs::create_dir_all(&self.build)?;
         fs::create_dir_all(&self.indices)?;
         fs::create_dir_all(&self.tmp)?;
@@ -639,8 +1381,14 @@ impl OutputLayout {
         Ok(layout)
     }
 
+    /// Locks the `lib` directory of this layout. It's inside the layout's own directory, so it
+    /// gets the same kind of lock.
+    pub fn lock_lib(&self) -> Result<DirLock> {
+        Ok(DirLock::acquire(&self.lib, self.lock.kind())?)
+    }
+
     pub fn write_hash(&self, hash: &BuildHash) -> Result<()> {
-        fs::write(self.root.join("hash"), hash.0.as_bytes())
+        write_atomic(&self.root.join("hash"), &hash.0)
             .context(format_err!("couldn't write hash"))?;
 
         Ok(())
@@ -651,6 +1399,215 @@ impl OutputLayout {
     }
 }
 
+/// Hashes the contents of a directory: the relative path, length and contents of every file in
+/// it, in a stable order.
+///
+/// This is the hash by which package contents are addressed in the store, so changing how it's
+/// worked out means bumping `CONTENT_HASH_VERSION`.
+pub fn content_hash(dir: &Path) -> Result<String> {
+    let walker = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|x| !is_meta_entry(x))
+        .filter_map(|x| x.ok())
+        .filter(valid_file);
+
+    hash_files(dir, walker)
+}
+
+/// Whether a file is one of the files which elba keeps alongside the contents of a cache entry,
+/// rather than part of the contents themselves.
+fn is_meta(name: &OsStr) -> bool {
+    name == LOCK_FILE
+        || name == STORE_LINK
+        || name == BUILD_CHECKSUM
+        || name == VALIDATORS
+        || name == INDEX_FETCHED
+}
+
+/// Like `is_meta`, for an entry found by walking a cache entry. Only files right at the top of
+/// the entry are elba's; anything further down with the same name belongs to the package.
+fn is_meta_entry(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && is_meta(entry.file_name())
+}
+
+/// Hashes a set of files under a directory, in order of their paths relative to it.
+///
+/// Since the paths go into the hash too, renaming or moving a file changes it, and the order in
+/// which the file system hands us the files doesn't. Each file goes in as its path, its length
+/// and the hash of its contents, so that where one file ends and the next begins is never in
+/// question.
+fn hash_files(root: &Path, files: impl Iterator<Item = DirEntry>) -> Result<String> {
+    let mut files = files
+        .map(|f| {
+            let rel = f.path().strip_prefix(root).unwrap();
+            (rel.to_string_lossy().replace("\\", "/"), f)
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hash = Sha256::new();
+    for (rel, f) in files {
+        let mut contents = Sha256::new();
+        let mut file = File::open(f.path())?;
+        let len = io::copy(&mut file, &mut contents)?;
+
+        hash.input(rel.as_bytes());
+        hash.input(b"\0");
+        hash.input(len.to_string().as_bytes());
+        hash.input(b"\0");
+        hash.input(&contents.result());
+    }
+
+    Ok(hex::encode(hash.result()))
+}
+
+/// The hash of a file in a package, along with the size and modification time it had when it was
+/// hashed.
+#[derive(Deserialize, Serialize)]
+struct FileHash {
+    len: u64,
+    modified: u64,
+    hash: String,
+}
+
+/// The hashes of the files of a package, along with when they were hashed.
+#[derive(Default, Deserialize, Serialize)]
+struct FileHashes {
+    hashed: u64,
+    files: HashMap<String, FileHash>,
+}
+
+/// The modification time of a file, in nanoseconds since the epoch.
+fn modified_nanos(meta: &fs::Metadata) -> Option<u64> {
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(modified.as_nanos() as u64)
+}
+
+/// The current time according to the filesystem `dir` is on, which is the clock the modification
+/// times of the files in it come from. The system clock can be a little ahead of it.
+fn fs_now(dir: &Path) -> Option<u64> {
+    let stamp = dir.join(format!("{}.now", FILE_HASHES));
+    fs::write(&stamp, b"").ok()?;
+    let now = fs::metadata(&stamp)
+        .ok()
+        .and_then(|meta| modified_nanos(&meta));
+    let _ = fs::remove_file(&stamp);
+    now
+}
+
+/// Hashes the files of a package into the hash of its Source: the relative path and the hash of
+/// the contents of every file, in a stable order.
+///
+/// The files are hashed on as many threads as there are CPUs. Given the `cache` of file hashes
+/// from the last time around, files which have the same size and modification time they had then
+/// aren't read again; the cache is then updated with the hashes of the files as they are now.
+///
+/// Like git's index, the cache remembers when the hashing started. A file modified at that time
+/// or later could have been changed again within the same tick of the filesystem's clock without
+/// its size or modification time changing, so it's always hashed again.
+fn source_hash(
+    root: &Path,
+    files: impl Iterator<Item = DirEntry>,
+    cache: Option<&Path>,
+) -> Result<String> {
+    let started = cache.and_then(|cache| fs_now(cache.parent()?));
+    let mut files = files
+        .map(|f| {
+            let rel = f.path().strip_prefix(root).unwrap();
+            let stamp = fs::metadata(f.path())
+                .ok()
+                .and_then(|meta| Some((meta.len(), modified_nanos(&meta)?)));
+            (rel.to_string_lossy().replace("\\", "/"), f, stamp)
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let cached: FileHashes = cache
+        .and_then(|cache| fs::read(cache).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default();
+    let mut hashes = files
+        .iter()
+        .map(|(rel, _, stamp)| match (cached.files.get(rel), stamp) {
+            (Some(c), Some((len, modified)))
+                if c.len == *len && c.modified == *modified && c.modified < cached.hashed =>
+            {
+                Some(c.hash.clone())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let todo = (0..files.len())
+        .filter(|&ix| hashes[ix].is_none())
+        .collect::<Vec<_>>();
+    let hash_file = |ix: usize| -> Result<String> {
+        let mut hash = Sha256::new();
+        let mut file = File::open(files[ix].1.path())?;
+        io::copy(&mut file, &mut hash)?;
+        Ok(hex::encode(hash.result()))
+    };
+    let threads = num_cpus::get().min(todo.len() / 16).max(1);
+    if threads == 1 {
+        for &ix in &todo {
+            hashes[ix] = Some(hash_file(ix)?);
+        }
+    } else {
+        let done = Mutex::new(vec![]);
+        let chunk = (todo.len() + threads - 1) / threads;
+        Pool::new(threads as u32).scoped(|scope| {
+            for ixs in todo.chunks(chunk) {
+                let (done, hash_file) = (&done, &hash_file);
+                scope.execute(move || {
+                    let res = ixs
+                        .iter()
+                        .map(|&ix| (ix, hash_file(ix)))
+                        .collect::<Vec<_>>();
+                    done.lock().unwrap().extend(res);
+                });
+            }
+        });
+        for (ix, res) in done.into_inner().unwrap() {
+            hashes[ix] = Some(res?);
+        }
+    }
+
+    let mut hash = Sha256::new();
+    let mut fresh = HashMap::new();
+    for ((rel, _, stamp), file_hash) in files.into_iter().zip(hashes) {
+        let file_hash = file_hash.unwrap();
+        hash.input(rel.as_bytes());
+        hash.input(b"\0");
+        hash.input(file_hash.as_bytes());
+        hash.input(b"\n");
+        if let Some((len, modified)) = stamp {
+            fresh.insert(
+                rel,
+                FileHash {
+                    len,
+                    modified,
+                    hash: file_hash,
+                },
+            );
+        }
+    }
+
+    // Failing to save the hashes only makes the next build slower.
+    if let (Some(cache), Some(hashed)) = (cache, started) {
+        if !todo.is_empty() || fresh.len() != cached.files.len() {
+            let fresh = FileHashes {
+                hashed,
+                files: fresh,
+            };
+            if let Ok(bytes) = serde_json::to_vec(&fresh) {
+                let _ = write_atomic(cache, bytes);
+            }
+        }
+    }
+
+    Ok(hex::encode(hash.result()))
+}
+
 /// Information about the source of package that is available somewhere in the file system.
 /// Packages are stored as directories on disk (not archives because it would just be a bunch of
 /// pointless unpacking-repacking).
@@ -705,8 +1662,8 @@ impl Source {
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
             if let Some(x) = Manifest::workspace(&contents) {
-                if let Some(p) = x.get(pkg.name()) {
-                    let lock = DirLock::acquire(&path.path().join(&p.0))?;
+                if let Some(p) = x.members.get(pkg.name()) {
+                    let lock = DirLock::acquire(&path.path().join(&p.0), LockKind::Source)?;
                     // We immediately release our lock on the parent folder
                     drop(path);
                     return Source::from_folder(pkg, lock, location);
@@ -724,19 +1681,26 @@ impl Source {
             )
         }
 
-        // Creating the hash
+        // The hash is recomputed every time we check out a package, so that changes to a path
+        // dependency (or to a cached checkout) always make their way into the build hashes of
+        // everything depending on it.
         let walker = manifest
             .list_files(path.path(), path.path(), |entry| {
                 entry.file_name() != ".git" && entry.file_name() != "target"
             })?
             .filter(valid_file);
-
-        let mut hash = Sha256::new();
-        for f in walker {
-            let mut file = File::open(f.path())?;
-            io::copy(&mut file, &mut hash)?;
-        }
-        let hash = hex::encode(hash.result());
+        // Local packages are the ones which get checked out over and over while they're worked
+        // on, so they keep the hashes of their files in their target directory, if they have one.
+        let target = path.path().join("target");
+        let file_hashes = match location {
+            DirectRes::Dir { .. } if target.is_dir() => Some(target.join(FILE_HASHES)),
+            _ => None,
+        };
+        let hash = source_hash(
+            path.path(),
+            walker,
+            file_hashes.as_ref().map(|x| x.as_path()),
+        )?;
 
         Ok(Source {
             inner: Arc::new(SourceInner {
@@ -775,6 +1739,11 @@ impl Source {
         &self.inner.meta
     }
 
+    /// The resolution the package was asked for by.
+    pub fn resolution(&self) -> &Resolution {
+        &self.inner.res
+    }
+
     pub fn location(&self) -> &DirectRes {
         &self.inner.location
     }
@@ -797,6 +1766,72 @@ impl PartialEq for Source {
 impl Eq for Source {}
 
 /// Information about a built library that is available somewhere in the file system.
+/// What the `.bins` file records about an installed binary.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "BinRecord")]
+pub struct InstalledBin {
+    /// The summary (`name@resolution|version`) of the package the binary was built from.
+    pub package: String,
+    /// The hash of the build the binary came out of, which changes whenever anything that went
+    /// into it does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+    /// The version of the compiler the binary was built with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compiler: Option<String>,
+}
+
+impl InstalledBin {
+    /// The package the binary was built from, leaving out its version.
+    pub fn id(&self) -> &str {
+        self.package.rsplitn(2, '|').last().unwrap_or("")
+    }
+
+    /// The package and version the binary was built from.
+    pub fn summary(&self) -> Option<Summary> {
+        let mut parts = self.package.rsplitn(2, '|');
+        let version = Version::parse(parts.next()?).ok()?;
+        let id = PackageId::from_str(parts.next()?).ok()?;
+
+        Some(Summary::new(id, version))
+    }
+}
+
+// Older versions of elba only recorded the package each binary came from.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BinRecord {
+    Package(String),
+    Full {
+        package: String,
+        #[serde(default)]
+        build: Option<String>,
+        #[serde(default)]
+        compiler: Option<String>,
+    },
+}
+
+impl From<BinRecord> for InstalledBin {
+    fn from(record: BinRecord) -> Self {
+        match record {
+            BinRecord::Package(package) => InstalledBin {
+                package,
+                build: None,
+                compiler: None,
+            },
+            BinRecord::Full {
+                package,
+                build,
+                compiler,
+            } => InstalledBin {
+                package,
+                build,
+                compiler,
+            },
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Binary {
     pub target: Arc<DirLock>,
@@ -820,10 +1855,22 @@ impl BuildHash {
         targets: &Targets,
         ctx: &BuildContext,
         codegen: bool,
+    ) -> Self {
+        let tree = sources.sub_tree(sources.find_id(root).unwrap());
+        Self::of(tree.map(|(_, src)| src.hash()), targets, ctx, codegen)
+    }
+
+    /// The hash of a build out of sources with these hashes, going from the package itself down
+    /// through everything it depends on in the order `Graph::sub_tree` goes through them.
+    pub fn of<'a>(
+        hashes: impl Iterator<Item = &'a str>,
+        targets: &Targets,
+        ctx: &BuildContext,
+        codegen: bool,
     ) -> Self {
         let mut hasher = Sha256::default();
-        for (_, src) in sources.sub_tree(sources.find_id(root).unwrap()) {
-            hasher.input(&src.hash().as_bytes());
+        for hash in hashes {
+            hasher.input(hash.as_bytes());
         }
 
         // Take into account the build context
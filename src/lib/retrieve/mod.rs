@@ -6,19 +6,22 @@
 
 pub mod cache;
 
-use std::borrow::Cow;
+use std::{borrow::Cow, mem, sync::Mutex};
 
 use console::style;
 use failure::{format_err, ResultExt};
+use futures::channel::mpsc::{self, UnboundedReceiver};
 use indexmap::{indexmap, IndexMap, IndexSet};
 use itertools::Either::{self, Left, Right};
+use petgraph::graph::NodeIndex;
+use scoped_threadpool::Pool;
 use semver::Version;
 use semver_constraints::{Constraint, Interval, Range, Relation};
 use slog::{debug, info, o, trace, Logger};
 
-pub use self::cache::{Cache, Source};
+pub use self::cache::{Cache, Refresh, Source};
 use crate::{
-    package::{PackageId, Summary},
+    package::{workspace::Workspace, PackageId, Summary},
     remote::{
         resolution::{DirectRes, IndexRes, Resolution},
         Indices, ResolvedEntry,
@@ -26,7 +29,9 @@ use crate::{
     resolve::incompat::{Incompatibility, IncompatibilityCause},
     util::{
         error::{Error, Result},
+        git,
         graph::Graph,
+        profile,
         shell::{Shell, Verbosity},
     },
 };
@@ -53,8 +58,16 @@ pub struct Retriever<'cache> {
     pub ixmap: &'cache IndexMap<String, IndexRes>,
     pub shell: Shell,
     offline_cache: Option<IndexSet<String>>,
+    /// The version of the compiler packages are going to be built with, if we know it.
+    pub compiler: Option<Version>,
+    /// The workspace the root package is in, whose packages depend on each other directly.
+    pub workspace: Option<Workspace>,
     sources: IndexMap<PackageId, Source>,
     pub res_mapping: IndexMap<PackageId, PackageId>,
+    /// The checksums the packages in the lockfile were locked with.
+    pub locked_checksums: IndexMap<Summary, String>,
+    /// Where the packages in the lockfile were retrieved from.
+    pub locked_sources: IndexMap<Summary, DirectRes>,
 }
 
 impl<'cache> Retriever<'cache> {
@@ -68,6 +81,7 @@ impl<'cache> Retriever<'cache> {
         ixmap: &'cache IndexMap<String, IndexRes>,
         shell: Shell,
         offline: bool,
+        refresh: Refresh,
     ) -> Self {
         let logger = plog.new(o!("phase" => "retrieve", "root" => root.to_string()));
 
@@ -83,7 +97,13 @@ impl<'cache> Retriever<'cache> {
         };
 
         let (indices, indices_set, reses) = match reses {
-            Left(v) => (cache.get_indices(&v, false, offline), false, v),
+            // If the indices were just refreshed, there's nothing newer we could find by doing
+            // it again.
+            Left(v) => (
+                cache.get_indices(&v, refresh, offline),
+                offline || refresh == Refresh::Always,
+                v,
+            ),
             Right(e) => (e, true, vec![]),
         };
 
@@ -99,61 +119,199 @@ impl<'cache> Retriever<'cache> {
             ixmap,
             shell,
             offline_cache,
+            compiler: None,
+            workspace: None,
             sources: indexmap!(),
             res_mapping: indexmap!(),
+            locked_checksums: indexmap!(),
+            locked_sources: indexmap!(),
         }
     }
 
     /// Loads all of the packages selected in a Solve into the Cache, returning a new graph of all
     /// the Sources.
     ///
-    /// This downloads all the packages into the cache. If we wanted to parallelize downloads
-    /// later, this is where we'd deal with all the Tokio stuff.
-    pub fn retrieve_packages(&mut self, solve: &Graph<Summary>) -> Result<Graph<Source>> {
-        // let mut prg = 0;
-        // Until pb.println gets added, we can't use progress bars
-        // let pb = ProgressBar::new(solve.inner.raw_nodes().len() as u64);
-        // pb.set_style(ProgressStyle::default_bar().template("  [-->] {bar} {pos}/{len}"));
-
-        info!(self.logger, "beginning bulk package retrieval");
+    /// This downloads all the packages into the cache. Since downloading and extracting packages
+    /// is mostly waiting around, packages which haven't been checked out yet are retrieved on a
+    /// pool of `threads` threads.
+    pub fn retrieve_packages(
+        &mut self,
+        solve: &Graph<Summary>,
+        threads: u32,
+    ) -> Result<Graph<Source>> {
+        Ok(self.pending(solve)?.retrieve(solve, threads, |_| ())?.0)
+    }
 
-        let sources = solve.map(|_, sum| {
-            let loc = match sum.resolution() {
-                Resolution::Direct(direct) => direct.clone(),
-                Resolution::Index(_) => self.select(sum).unwrap().into_owned().location,
-            };
+    /// Works out which of the packages selected in a Solve still have to be retrieved, and where
+    /// from. The packages can then be retrieved without holding on to the Retriever (and its
+    /// locks on the indices).
+    pub fn pending(&mut self, solve: &Graph<Summary>) -> Result<Pending<'cache>> {
+        let mut sources = IndexMap::new();
+        let mut todo = vec![];
+        let mut downloads = 0;
 
+        for ix in solve.inner.node_indices() {
+            let sum = &solve[ix];
             if let Some(s) = self.remove(sum.id()) {
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(s)
+                sources.insert(ix, s);
             } else {
-                let source = self
-                    .cache
-                    .checkout_source(sum.id(), &loc, false, self.offline_cache.is_some(), || {
-                        self.shell.println(
-                            style("Retrieving").cyan(),
-                            sum.to_string(),
-                            Verbosity::Normal,
-                        );
-                    })
-                    .context(format_err!("unable to retrieve package {}", sum))?;
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(source.1)
+                let loc = self.location(sum)?;
+                if !self.cache.has_source(&loc) {
+                    downloads += 1;
+                }
+                todo.push((ix, loc));
             }
-        })?;
+        }
 
-        // pb.finish_and_clear();
-        self.shell.println(
-            style("Cached").dim(),
-            format!("packages in {}", self.cache.layout.src.display()),
-            Verbosity::Verbose,
-        );
+        Ok(Pending {
+            cache: self.cache,
+            logger: self.logger.clone(),
+            shell: self.shell,
+            offline: self.offline_cache.is_some(),
+            sources,
+            todo,
+            downloads,
+        })
+    }
 
-        info!(self.logger, "retrieve successful"; "cache" => self.cache.layout.src.display());
+    /// Where a package in a solve gets retrieved from.
+    pub fn location(&mut self, sum: &Summary) -> Result<DirectRes> {
+        Ok(match sum.resolution() {
+            Resolution::Direct(direct) => direct.clone(),
+            // We go back to wherever the package came from when it was locked, even if the index
+            // points somewhere else now.
+            Resolution::Index(_) => match self.locked_sources.get(sum) {
+                Some(loc) if self.offline_cache.is_none() => loc.clone(),
+                _ => self.select(sum)?.into_owned().location,
+            },
+        })
+    }
+
+    /// Whether a package in a solve can be retrieved without downloading anything.
+    pub fn is_cached(&mut self, sum: &Summary) -> Result<bool> {
+        if self.sources.contains_key(sum.id()) {
+            return Ok(true);
+        }
+
+        let loc = self.location(sum)?;
+        Ok(self.cache.has_source(&loc))
+    }
+
+    /// Works out the checksum of every package in a solve, making sure that the packages which
+    /// were already locked still have the checksums they were locked with.
+    ///
+    /// A checksum which changed means that a registry handed us a different tarball for the same
+    /// version, or that a git dependency is now checked out at a different commit, so we refuse
+    /// to go on. Directories are the
+    /// exception, since they're expected to change as they're worked on.
+    pub fn checksums(&mut self, solve: &Graph<Summary>) -> Result<IndexMap<Summary, String>> {
+        let mut checksums = IndexMap::new();
+
+        for ix in solve.inner.node_indices() {
+            let sum = &solve[ix];
+            if sum == &self.root {
+                continue;
+            }
+            let locked = self.locked_checksums.get(sum).cloned();
+            let checksum = match self.checksum(sum) {
+                Some(checksum) => checksum,
+                // We can't always tell, like when we're offline, in which case we trust what we
+                // checked the last time around.
+                None => match &locked {
+                    Some(locked) => locked.clone(),
+                    None => continue,
+                },
+            };
+
+            if let Some(locked) = locked {
+                let unchecked = match sum.resolution() {
+                    Resolution::Direct(DirectRes::Dir { .. }) => true,
+                    // Older versions of elba locked git dependencies with whatever they asked
+                    // for, which isn't necessarily a commit.
+                    Resolution::Direct(DirectRes::Git { .. }) => {
+                        let commit = locked.trim_start_matches("git=");
+                        commit.len() != 40 || !commit.chars().all(|c| c.is_ascii_hexdigit())
+                    }
+                    _ => false,
+                };
+                if locked != checksum && !unchecked {
+                    return Err(format_err!(
+                        "the checksum of {} doesn't match the one in elba.lock\n  \
+                         locked: {}\n  \
+                         found:  {}\n\
+                         its source might have been tampered with; if the change is expected, \
+                         run `elba update` to lock it again",
+                        sum,
+                        locked,
+                        checksum
+                    ))?;
+                }
+            }
+
+            checksums.insert(sum.clone(), checksum);
+        }
+
+        Ok(checksums)
+    }
+
+    /// Works out where the contents of every package in a solve come from: the tarball of a
+    /// package from an index, or the commit of a git repository. Packages which were already
+    /// locked keep coming from wherever they came from before.
+    pub fn sources(&mut self, solve: &Graph<Summary>) -> IndexMap<Summary, DirectRes> {
+        let mut sources = IndexMap::new();
+
+        for ix in solve.inner.node_indices() {
+            let sum = &solve[ix];
+            let locked = self.locked_sources.get(sum).cloned();
+            let source = match sum.resolution() {
+                // When we're offline, the index just points at our own cache.
+                Resolution::Index(_) if self.offline_cache.is_some() => locked,
+                Resolution::Index(_) => {
+                    locked.or_else(|| self.select(sum).ok().map(|entry| entry.location.clone()))
+                }
+                Resolution::Direct(git @ DirectRes::Git { .. }) => Some(git.clone()),
+                Resolution::Direct(_) => None,
+            };
+            if let Some(source) = source {
+                sources.insert(sum.clone(), source);
+            }
+        }
+
+        sources
+    }
 
-        Ok(sources)
+    /// The checksum of a package's contents: the checksum of its tarball, the commit its git
+    /// repository is checked out at, or the hash of the files in its directory.
+    ///
+    /// A git dependency is checked against the commit we actually ended up with rather than the
+    /// one it asked for, since a tag or branch can be moved to point somewhere else.
+    fn checksum(&mut self, sum: &Summary) -> Option<String> {
+        let loc = match sum.resolution() {
+            Resolution::Index(_) if self.offline_cache.is_some() => return None,
+            Resolution::Index(_) => self.select(sum).ok()?.location.clone(),
+            Resolution::Direct(direct) => direct.clone(),
+        };
+
+        match loc {
+            DirectRes::Tar {
+                cksum: Some(cksum), ..
+            } => Some(cksum.to_string()),
+            DirectRes::Git { .. } => self
+                .checked_out(sum.id())
+                .and_then(|src| git::head_commit(src.path()).ok())
+                .map(|commit| format!("git={}", commit)),
+            _ => self
+                .checked_out(sum.id())
+                .map(|src| format!("tree={}", src.hash())),
+        }
+    }
+
+    /// The source a package was checked out to, if it has been.
+    fn checked_out(&self, pkg: &PackageId) -> Option<&Source> {
+        self.res_mapping
+            .get(pkg)
+            .and_then(|id| self.sources.get(id))
+            .or_else(|| self.sources.get(pkg))
     }
 
     /// Chooses the best version of a package given a constraint.
@@ -211,12 +369,18 @@ impl<'cache> Retriever<'cache> {
                 .clone());
         }
 
-        self.get_indices();
-
+        // Yanked versions and versions our compiler is too old for are only fair game if they're
+        // already in the lockfile, which we dealt with above.
+        let compiler = self.compiler.clone();
         let (mut pre, mut not_pre): (Vec<Version>, Vec<Version>) = self
             .entries(pkg)?
             .into_owned()
             .into_iter()
+            .filter(|(_, e)| !e.yanked)
+            .filter(|(_, e)| match (&e.min_compiler, &compiler) {
+                (Some(min), Some(cur)) => min <= cur,
+                _ => true,
+            })
             .map(|v| v.0)
             .filter(|v| con.satisfies(v))
             .partition(|v| v.is_prerelease());
@@ -277,6 +441,10 @@ impl<'cache> Retriever<'cache> {
                 .direct_checkout(pkg.id(), None, false)?
                 .meta()
                 .deps(&ixmap, parent_pkg, false)?;
+            let deps = match &self.workspace {
+                Some(ws) => ws.redirect(deps),
+                None => deps.into_iter().collect(),
+            };
 
             let mut res = vec![];
             for dep in deps {
@@ -514,12 +682,20 @@ impl<'cache> Retriever<'cache> {
         }
     }
 
+    /// Refreshes the indices, in case what we're looking for was added after they were last
+    /// retrieved.
     fn get_indices(&mut self) {
         if !self.indices_set {
             debug!(self.logger, "updating indices eagerly");
-            self.indices = self
-                .cache
-                .get_indices(&self.reses, true, self.offline_cache.is_some());
+            // We might have locked some sources by now, so we keep our locks on the indices
+            // rather than locking them again.
+            let old = mem::replace(&mut self.indices, Indices::default());
+            self.indices = self.cache.reload_indices(
+                old,
+                &self.reses,
+                Refresh::Always,
+                self.offline_cache.is_some(),
+            );
             self.indices_set = true;
             self.shell.println(
                 style("Cached").dim(),
@@ -529,3 +705,92 @@ impl<'cache> Retriever<'cache> {
         }
     }
 }
+
+/// The packages of a Solve which are waiting to be retrieved, along with the ones which already
+/// have been.
+pub struct Pending<'cache> {
+    cache: &'cache Cache,
+    logger: Logger,
+    shell: Shell,
+    offline: bool,
+    sources: IndexMap<NodeIndex, Source>,
+    todo: Vec<(NodeIndex, DirectRes)>,
+    downloads: usize,
+}
+
+impl<'cache> Pending<'cache> {
+    /// How many of the packages aren't in the cache at all, and have to be downloaded.
+    pub fn downloads(&self) -> usize {
+        self.downloads
+    }
+
+    /// Retrieves the packages into the cache on a pool of `threads` threads, returning a graph of
+    /// all the Sources along with whatever `ready` returned.
+    ///
+    /// While that's going on, `ready` is run on this thread with a channel down which each Source
+    /// is sent as soon as it's been retrieved, starting with the ones which already had been. The
+    /// channel closes once every package has been retrieved or has failed to be.
+    pub fn retrieve<T, F>(
+        mut self,
+        solve: &Graph<Summary>,
+        threads: u32,
+        ready: F,
+    ) -> Result<(Graph<Source>, T)>
+    where
+        F: FnOnce(UnboundedReceiver<(NodeIndex, Source)>) -> T,
+    {
+        let span = profile::span(&self.logger, "retrieve");
+        info!(self.logger, "beginning bulk package retrieval");
+
+        let (tx, rx) = mpsc::unbounded();
+        for (ix, source) in &self.sources {
+            let _ = tx.unbounded_send((*ix, source.clone()));
+        }
+
+        let retrieved = Mutex::new(vec![]);
+        let (cache, shell, offline) = (self.cache, self.shell, self.offline);
+        let res = Pool::new(threads.max(1)).scoped(|scope| {
+            for (ix, loc) in &self.todo {
+                let (retrieved, span, tx) = (&retrieved, &span, tx.clone());
+                scope.execute(move || {
+                    let sum = &solve[*ix];
+                    let _span = span.child(format!("retrieve {}", sum));
+                    let source = cache
+                        .checkout_source(sum.id(), loc, false, offline, || {
+                            shell.println(
+                                style("Retrieving").cyan(),
+                                sum.to_string(),
+                                Verbosity::Normal,
+                            );
+                        })
+                        .context(format_err!("unable to retrieve package {}", sum))
+                        .map_err(failure::Error::from);
+                    if let Ok((_, source)) = &source {
+                        // Nobody listening any more is no reason to stop retrieving.
+                        let _ = tx.unbounded_send((*ix, source.clone()));
+                    }
+                    retrieved.lock().unwrap().push((*ix, source));
+                });
+            }
+            drop(tx);
+
+            ready(rx)
+        });
+
+        for (ix, source) in retrieved.into_inner().unwrap() {
+            self.sources.insert(ix, source?.1);
+        }
+
+        let sources = solve.map(|ix, _| Ok(self.sources.swap_remove(&ix).unwrap()))?;
+
+        self.shell.println(
+            style("Cached").dim(),
+            format!("packages in {}", self.cache.layout.src.display()),
+            Verbosity::Verbose,
+        );
+
+        info!(self.logger, "retrieve successful"; "cache" => self.cache.layout.src.display());
+
+        Ok((sources, res))
+    }
+}
@@ -6,16 +6,27 @@
 //!
 //! Environment variables (.env files?) should also be able to modify the configuration.
 
-use super::shell::{Shell, Verbosity};
+use super::{
+    keys::PublicKey,
+    shell::{ascii_by_default, plain_by_default, MessageFormat, Shell, Verbosity},
+};
 use crate::{
-    remote::resolution::{DirectRes, IndexRes},
+    cli::build::{find_manifest_root, target_dir},
+    remote::{
+        mirror::{Mirror, Mirrors},
+        resolution::{DirectRes, IndexRes},
+    },
     retrieve::cache::Layout,
 };
 use config;
 use directories::{BaseDirs, ProjectDirs};
 use indexmap::{indexmap, IndexMap};
 use serde::{Deserialize, Serialize};
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use url::Url;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,50 +44,125 @@ pub struct Config {
     // The purpose of the mapping is to allow for index aliases.
     #[serde(default = "default_indices")]
     pub indices: IndexMap<String, IndexRes>,
+    /// How long, in seconds, a retrieved index is used for before it gets refreshed.
+    #[serde(default = "default_index_ttl")]
+    pub index_ttl: u64,
+    /// Whether to cache the parsed entries of indices, so that warm runs don't parse them again.
+    #[serde(default = "default_index_cache")]
+    pub index_cache: bool,
+    /// How big, in KiB, a packaged tarball can get before elba warns about it.
+    #[serde(default = "default_package_warn_size")]
+    pub package_warn_size: u64,
     #[serde(default)]
     pub backend: Vec<Backend>,
+    /// Places to retrieve indices and packages from in place of their upstream sources.
+    #[serde(default)]
+    pub mirror: Vec<Mirror>,
+    /// How many threads to build with, unless `--threads` says otherwise.
+    #[serde(default = "default_jobs")]
+    pub jobs: u32,
+    /// Whether to always run in offline mode, as if `--offline` were passed.
+    #[serde(default)]
+    pub offline: bool,
+    /// How long, in seconds, to wait on a directory another elba process has locked before giving
+    /// up on it. Without one, elba waits for as long as it takes.
+    #[serde(default)]
+    pub lock_timeout: Option<u64>,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default)]
+    pub tools: Tools,
+    /// Where `elba self update` gets new versions of elba from.
+    #[serde(default)]
+    pub releases: Releases,
+    /// Where `elba audit` gets its advisories from.
+    #[serde(default = "default_advisory_db")]
+    pub advisory_db: DirectRes,
+    /// What the dependencies of a project are allowed to be, checked every time they're resolved.
+    /// Only the global configuration files can set it.
+    #[serde(default)]
+    pub policy: Policy,
 }
 
 fn default_compiler() -> String {
     "idris".to_string()
 }
 
+fn default_index_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_index_cache() -> bool {
+    true
+}
+
+fn default_package_warn_size() -> u64 {
+    10 * 1024
+}
+
+fn default_jobs() -> u32 {
+    2
+}
+
 impl Config {
     pub fn new() -> Result<Config, config::ConfigError> {
-        let mut c = config::Config::new();
+        Config::in_dir(env::current_dir().ok().as_ref().map(|x| x.as_path()))
+    }
 
-        let cwd = env::current_dir();
+    /// Loads the configuration which applies in a directory: that of the directory and its
+    /// ancestors, on top of the global configuration.
+    pub fn in_dir(cwd: Option<&Path>) -> Result<Config, config::ConfigError> {
+        let mut c = config::Config::new();
 
-        c.merge(
-            config::File::from(BaseDirs::new().unwrap().home_dir().join(".elba/config"))
-                .format(config::FileFormat::Toml)
-                .required(false),
+        merge_dir(&mut c, &BaseDirs::new().unwrap().home_dir().join(".elba"))?;
+        merge_dir(
+            &mut c,
+            ProjectDirs::from("", "", "elba").unwrap().config_dir(),
         )?;
 
-        c.merge(
-            config::File::from(
-                ProjectDirs::from("", "", "elba")
-                    .unwrap()
-                    .config_dir()
-                    .join("config"),
-            )
-            .format(config::FileFormat::Toml)
-            .required(false),
-        )?;
+        // The policy holds every project to the same rules, so it only comes from the global
+        // configuration: neither a project nor its environment gets to relax it.
+        let policy = match c.get::<Policy>("policy") {
+            Ok(policy) => policy,
+            Err(config::ConfigError::NotFound(_)) => Policy::default(),
+            Err(e) => return Err(e),
+        };
 
-        if let Ok(cwd) = cwd {
+        if let Some(cwd) = cwd {
             for parent in cwd.ancestors().collect::<Vec<_>>().iter().rev() {
-                c.merge(
-                    config::File::from(parent.join(".elba/config"))
-                        .format(config::FileFormat::Toml)
-                        .required(false),
-                )?;
+                merge_dir(&mut c, &parent.join(".elba"))?;
             }
         }
 
-        c.merge(config::Environment::with_prefix("elba"))?;
+        // Nested keys are separated with a double underscore, like `ELBA_NETWORK__TIMEOUT`.
+        c.merge(config::Environment::with_prefix("elba").separator("__"))?;
+
+        let mut res: Config = c.try_into()?;
+        res.policy = policy;
+        res.directories.apply_overrides(cwd);
+        // Indices are known by their upstream resolutions, even if they're given as mirrors.
+        let mirrors = res.mirrors();
+        for index in res.indices.values_mut() {
+            *index = mirrors.canonicalize_index(index);
+        }
+
+        Ok(res)
+    }
+
+    /// The global configuration file which `elba config set` writes to by default.
+    pub fn global_path() -> PathBuf {
+        ProjectDirs::from("", "", "elba")
+            .unwrap()
+            .config_dir()
+            .join("config.toml")
+    }
+
+    /// The configuration file of the project a directory is in, or of the directory itself if it
+    /// isn't in one.
+    pub fn project_path(cwd: &Path) -> PathBuf {
+        let root = find_manifest_root(cwd).unwrap_or(cwd);
 
-        c.try_into()
+        root.join(".elba").join("config.toml")
     }
 
     pub fn verbosity(&mut self, v: Verbosity) -> &mut Config {
@@ -89,6 +175,26 @@ impl Config {
         self
     }
 
+    pub fn message_format(&mut self, f: MessageFormat) -> &mut Config {
+        self.term.message_format = f;
+        self
+    }
+
+    pub fn ascii(&mut self, a: bool) -> &mut Config {
+        self.term.ascii = Some(a);
+        self
+    }
+
+    pub fn log_level(&mut self, l: u8) -> &mut Config {
+        self.term.log_level = l;
+        self
+    }
+
+    pub fn log_file(&mut self, f: PathBuf) -> &mut Config {
+        self.term.log_file = Some(f);
+        self
+    }
+
     pub fn default_backend(&self) -> Backend {
         self.backend
             .iter()
@@ -101,23 +207,51 @@ impl Config {
         self.backend.iter().find(|x| x.name == name).cloned()
     }
 
+    pub fn mirrors(&self) -> Mirrors {
+        Mirrors::new(self.mirror.clone())
+    }
+
     pub fn shell(&self) -> Shell {
         Shell {
             verbosity: self.term.verbosity,
+            format: self.term.message_format,
+            plain: self.term.plain.unwrap_or_else(plain_by_default),
+            ascii: self.term.ascii.unwrap_or_else(ascii_by_default),
         }
     }
 
+    /// The file in which the tokens for logging into registries are kept.
+    pub fn credentials_path(&self) -> PathBuf {
+        self.directories.data.join("logins.toml")
+    }
+
     pub fn layout(&self) -> Layout {
         Layout {
             bin: self.directories.bin.to_path_buf(),
             build: self.directories.cache.join("build"),
             indices: self.directories.cache.join("indices"),
+            keys: self.directories.data.join("keys.toml"),
             src: self.directories.cache.join("src"),
+            store: self.directories.cache.join("store"),
             tmp: self.directories.cache.join("tmp"),
         }
     }
 }
 
+/// Merges the configuration file in a directory, which can be called either `config` or
+/// `config.toml`. If both are there, `config.toml` wins.
+fn merge_dir(c: &mut config::Config, dir: &Path) -> Result<(), config::ConfigError> {
+    for name in &["config", "config.toml"] {
+        c.merge(
+            config::File::from(dir.join(name))
+                .format(config::FileFormat::Toml)
+                .required(false),
+        )?;
+    }
+
+    Ok(())
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
@@ -127,7 +261,19 @@ impl Default for Config {
             alias: default_aliases(),
             directories: Directories::default(),
             indices: IndexMap::default(),
+            index_ttl: default_index_ttl(),
+            index_cache: default_index_cache(),
+            package_warn_size: default_package_warn_size(),
             backend: Vec::default(),
+            mirror: Vec::default(),
+            jobs: default_jobs(),
+            offline: false,
+            lock_timeout: None,
+            network: Network::default(),
+            tools: Tools::default(),
+            releases: Releases::default(),
+            advisory_db: default_advisory_db(),
+            policy: Policy::default(),
         }
     }
 }
@@ -147,6 +293,12 @@ fn default_indices() -> IndexMap<String, IndexRes> {
     indexmap!("official".to_string() => DirectRes::Git { repo, tag }.into())
 }
 
+fn default_advisory_db() -> DirectRes {
+    let repo = Url::parse("https://github.com/elba/advisory-db").unwrap();
+    let tag = "master".to_string();
+    DirectRes::Git { repo, tag }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Profile {
     pub name: String,
@@ -159,6 +311,24 @@ pub struct Term {
     pub color: bool,
     #[serde(default)]
     pub verbosity: Verbosity,
+    /// Whether to print status lines for people or JSON events for other programs.
+    #[serde(default)]
+    pub message_format: MessageFormat,
+    /// Whether to print output for log files rather than for terminals (see `Shell::plain`). If
+    /// this isn't set, output is plain when stdout isn't a terminal or when running in CI.
+    #[serde(default)]
+    pub plain: Option<bool>,
+    /// Whether to print nothing but ASCII, without any styling (see `Shell::ascii`). If this isn't
+    /// set, output is ASCII when the `TERM` environment variable is `dumb`.
+    #[serde(default)]
+    pub ascii: Option<bool>,
+    /// How much of the library's logging to print to stderr: nothing at 0, and the info, debug and
+    /// trace levels at 1, 2 and 3. `-v`, `-vv` and `-vvv` raise it to those.
+    #[serde(default)]
+    pub log_level: u8,
+    /// A file to write every log record to as line-delimited JSON, whatever `log_level` is.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
 }
 
 fn default_color() -> bool {
@@ -170,6 +340,11 @@ impl Default for Term {
         Term {
             color: true,
             verbosity: Verbosity::Normal,
+            message_format: MessageFormat::Human,
+            plain: None,
+            ascii: None,
+            log_level: 0,
+            log_file: None,
         }
     }
 }
@@ -182,6 +357,40 @@ pub struct Directories {
     pub data: PathBuf,
     #[serde(default = "default_cache_dir")]
     pub cache: PathBuf,
+    /// Whether everything should be cached under `target/cache` in the current project instead,
+    /// so that nothing outside the project gets touched.
+    #[serde(default)]
+    pub project_cache: bool,
+}
+
+impl Directories {
+    /// Applies the environment variables which relocate the cache, and then the per-project cache
+    /// mode if it's been turned on.
+    ///
+    /// An explicit `ELBA_CACHE_DIR` takes precedence over the per-project cache.
+    fn apply_overrides(&mut self, cwd: Option<&Path>) {
+        if let Some(dir) = env::var_os("ELBA_CACHE_DIR").filter(|x| !x.is_empty()) {
+            self.cache = dir.into();
+            return;
+        }
+
+        if let Ok(val) = env::var("ELBA_PROJECT_CACHE") {
+            self.project_cache = !(val.is_empty() || val == "0" || val == "false");
+        }
+
+        if self.project_cache {
+            // The cache goes in the target directory, which a workspace's members share.
+            let target = cwd
+                .and_then(find_manifest_root)
+                .and_then(|root| target_dir(root).ok());
+            if let Some(target) = target {
+                let cache = target.join("cache");
+                self.bin = cache.join("bin");
+                self.data = cache.join("data");
+                self.cache = cache;
+            }
+        }
+    }
 }
 
 fn default_bin_dir() -> PathBuf {
@@ -208,6 +417,95 @@ impl Default for Directories {
             bin: default_bin_dir(),
             data: default_data_dir(),
             cache: default_cache_dir(),
+            project_cache: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Network {
+    /// How long, in seconds, to wait on a download before giving up on it.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network {
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// The external tools `elba fmt` and `elba lint` run. Each is a shell command, which gets the
+/// source files of the package appended to it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Tools {
+    /// The formatter, which rewrites files in place.
+    #[serde(default)]
+    pub fmt: Option<String>,
+    /// The formatter in a mode which only checks files, failing if any of them would change.
+    #[serde(default)]
+    pub fmt_check: Option<String>,
+    /// The linter.
+    #[serde(default)]
+    pub lint: Option<String>,
+}
+
+/// Rules for the dependencies of projects, which organizations can set centrally. They're checked
+/// against the lockfile every time dependencies are resolved, before it's written.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Policy {
+    /// Packages which can't be depended on, as specs like `user/pkg` or `user/pkg|1.0.0`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// The only places git repositories can be depended on from, as prefixes of their URLs. An
+    /// empty list forbids depending on git repositories at all; without one, any is fine.
+    #[serde(default)]
+    pub allow_git: Option<Vec<String>>,
+    /// A shell command which gets the lockfile as JSON on its standard input, and rejects it by
+    /// exiting unsuccessfully.
+    #[serde(default)]
+    pub hook: Option<String>,
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.allow_git.is_none() && self.hook.is_none()
+    }
+}
+
+/// Where releases of elba are published, for `elba self update`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Releases {
+    /// The GitHub API endpoint listing the releases.
+    #[serde(default = "default_releases_url")]
+    pub url: String,
+    /// The key releases are signed with. Releases which aren't signed with it are refused, and
+    /// without one, nothing can be updated.
+    #[serde(default = "default_release_key")]
+    pub key: Option<PublicKey>,
+}
+
+fn default_releases_url() -> String {
+    "https://api.github.com/repos/elba/elba/releases".to_string()
+}
+
+/// The key elba's own releases are signed with. Release builds have it built in, from the
+/// `ELBA_RELEASE_KEY` their build is given; builds from source have to be told it.
+fn default_release_key() -> Option<PublicKey> {
+    option_env!("ELBA_RELEASE_KEY").and_then(|key| PublicKey::from_str(key).ok())
+}
+
+impl Default for Releases {
+    fn default() -> Self {
+        Releases {
+            url: default_releases_url(),
+            key: default_release_key(),
         }
     }
 }
@@ -236,3 +534,31 @@ impl Default for Backend {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn config_policy_is_global() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::create_dir_all(tmp.path().join(".elba")).unwrap();
+        fs::write(
+            tmp.path().join(".elba/config.toml"),
+            "jobs = 7\n\n[policy]\ndeny = []\nallow_git = [\"https://\"]\nhook = \"true\"\n",
+        )
+        .unwrap();
+        env::set_var("ELBA_POLICY__HOOK", "true");
+
+        let global = Config::in_dir(None).unwrap().policy;
+        let project = Config::in_dir(Some(tmp.path())).unwrap();
+        env::remove_var("ELBA_POLICY__HOOK");
+
+        // The rest of the project's configuration still applies; its policy doesn't.
+        assert_eq!(project.jobs, 7);
+        assert_eq!(project.policy.deny, global.deny);
+        assert_eq!(project.policy.allow_git, global.allow_git);
+        assert_eq!(project.policy.hook, global.hook);
+    }
+}
@@ -0,0 +1,107 @@
+//! Storage for the tokens used to authenticate with registries.
+//!
+//! Tokens are kept in their own file rather than in the configuration, so that configuration can
+//! be shared (or checked into a repository) without leaking them. The file is only readable by
+//! the user who owns it.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use failure::{format_err, ResultExt};
+use indexmap::IndexMap;
+
+use crate::{remote::resolution::IndexRes, util::error::Result};
+
+/// The tokens for each index the user has logged into.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    path: PathBuf,
+    tokens: IndexMap<String, String>,
+}
+
+impl Credentials {
+    /// Loads the credentials file at a path. A file which doesn't exist yet has no tokens in it.
+    pub fn load(path: &Path) -> Result<Self> {
+        let tokens = if path.exists() {
+            let contents = fs::read_to_string(path).with_context(|e| {
+                format_err!("couldn't read credentials file {}: {}", path.display(), e)
+            })?;
+            toml::from_str(&contents).with_context(|e| {
+                format_err!("invalid credentials file {}: {}", path.display(), e)
+            })?
+        } else {
+            IndexMap::new()
+        };
+
+        Ok(Credentials {
+            path: path.to_path_buf(),
+            tokens,
+        })
+    }
+
+    pub fn get(&self, index: &IndexRes) -> Option<&str> {
+        self.tokens.get(&index.to_string()).map(|x| x.as_str())
+    }
+
+    pub fn set(&mut self, index: &IndexRes, token: String) {
+        self.tokens.insert(index.to_string(), token);
+    }
+
+    pub fn remove(&mut self, index: &IndexRes) -> Option<String> {
+        self.tokens.remove(&index.to_string())
+    }
+
+    /// Writes the credentials back to disk, making sure that nobody else can read them.
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = toml::to_string(&self.tokens)?;
+        let mut f = open_private(&self.path).with_context(|e| {
+            format_err!(
+                "couldn't open credentials file {}: {}",
+                self.path.display(),
+                e
+            )
+        })?;
+        f.write_all(contents.as_bytes()).with_context(|e| {
+            format_err!(
+                "couldn't write credentials file {}: {}",
+                self.path.display(),
+                e
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn open_private(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to newly created files, so we fix up existing ones too.
+    f.set_permissions(fs::Permissions::from_mode(0o600))?;
+
+    Ok(f)
+}
+
+#[cfg(not(unix))]
+fn open_private(path: &Path) -> std::io::Result<fs::File> {
+    // On Windows, files in the user's data directory aren't readable by other users anyway.
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
@@ -1,7 +1,20 @@
 //! Because nothing ever works correctly...
+//!
+//! The errors which users run into the most have codes, so that they can look up what went wrong
+//! and what to do about it with `elba explain`. The codes are grouped by what elba was doing when
+//! the error happened:
+//!
+//! - `E00xx`: reading the manifest of a project
+//! - `E01xx`: parsing the places packages come from
+//! - `E02xx`: looking packages up in indices
+//! - `E03xx`: resolving dependencies
+//! - `E04xx`: retrieving packages
+//! - `E05xx`: building and testing packages
 
 pub use failure::Fail;
 
+use failure::Context;
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
 // TODO: More principled error handling.
@@ -9,6 +22,12 @@ pub type Result<T> = std::result::Result<T, failure::Error>;
 // implement Fail for each type of error: Index failures, Parsing failures, etc.
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
 pub enum Error {
+    #[fail(display = "no manifest file (elba.toml) exists in any parent directory")]
+    ManifestNotFound,
+    #[fail(display = "invalid manifest file")]
+    InvalidManifest,
+    #[fail(display = "elba.lock is out of date with the manifest")]
+    LockfileOutdated,
     #[fail(display = "invalid source resolution specifier")]
     InvalidSourceUrl,
     #[fail(display = "package doesn't exist in index")]
@@ -17,7 +36,473 @@ pub enum Error {
     NoConflictRes,
     #[fail(display = "could not download package")]
     CannotDownload,
+    #[fail(display = "couldn't look up the host of a download")]
+    DnsFailure,
+    #[fail(display = "couldn't make a secure connection for a download")]
+    TlsFailure,
+    #[fail(display = "a download doesn't exist on the server")]
+    DownloadNotFound,
+    #[fail(display = "a download was refused for lack of permission")]
+    DownloadUnauthorized,
+    #[fail(display = "a download timed out")]
+    DownloadTimedOut,
+    #[fail(display = "one or more packages couldn't be built")]
+    BuildFailed,
+    #[fail(display = "one or more tests failed")]
+    TestsFailed,
     #[doc(hidden)]
     #[fail(display = "if you see this error, everything is wrong")]
     __Nonexhaustive,
 }
+
+impl Error {
+    /// Every error which has a code, in the order of their codes.
+    pub const ALL: &'static [Error] = &[
+        Error::ManifestNotFound,
+        Error::InvalidManifest,
+        Error::LockfileOutdated,
+        Error::InvalidSourceUrl,
+        Error::PackageNotFound,
+        Error::NoConflictRes,
+        Error::CannotDownload,
+        Error::DnsFailure,
+        Error::TlsFailure,
+        Error::DownloadNotFound,
+        Error::DownloadUnauthorized,
+        Error::DownloadTimedOut,
+        Error::BuildFailed,
+        Error::TestsFailed,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Error::ManifestNotFound => "E0001",
+            Error::InvalidManifest => "E0002",
+            Error::LockfileOutdated => "E0003",
+            Error::InvalidSourceUrl => "E0101",
+            Error::PackageNotFound => "E0201",
+            Error::NoConflictRes => "E0301",
+            Error::CannotDownload => "E0401",
+            Error::DnsFailure => "E0402",
+            Error::TlsFailure => "E0403",
+            Error::DownloadNotFound => "E0404",
+            Error::DownloadUnauthorized => "E0405",
+            Error::DownloadTimedOut => "E0406",
+            Error::BuildFailed => "E0501",
+            Error::TestsFailed => "E0502",
+            Error::__Nonexhaustive => "E0000",
+        }
+    }
+
+    /// The error with a code, which can be given with or without the `E` in front of it.
+    pub fn from_code(code: &str) -> Option<Error> {
+        let code = code.trim().to_uppercase();
+        let code = if code.starts_with('E') {
+            code
+        } else {
+            format!("E{}", code)
+        };
+
+        Error::ALL.iter().cloned().find(|e| e.code() == code)
+    }
+
+    /// What to try next after running into the error.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Error::ManifestNotFound => {
+                Some("run `elba init` to make one, or point `--manifest-path` at an existing one")
+            }
+            Error::InvalidManifest => Some("run `elba verify` to check the whole manifest"),
+            Error::LockfileOutdated => Some("run `elba lock` to update it"),
+            Error::InvalidSourceUrl => {
+                Some("sources look like `git+<url>`, `dir+<path>`, `tar+<url>` or `index+<source>`")
+            }
+            Error::PackageNotFound => {
+                Some("check the spelling of the package, or run `elba update` to refresh indices")
+            }
+            Error::NoConflictRes => {
+                Some("loosen the version constraints the conflict above is about")
+            }
+            Error::CannotDownload => {
+                Some("check your connection, or run with `--offline` to use what's cached")
+            }
+            Error::DnsFailure => Some(
+                "check the host name and your connection, or run with `--offline` to use what's \
+                 cached",
+            ),
+            Error::TlsFailure => Some(
+                "check your system clock and certificates, or configure the proxy (HTTPS_PROXY) \
+                 if your network goes through one",
+            ),
+            Error::DownloadNotFound => {
+                Some("check the url of the source; it might have been moved or deleted")
+            }
+            Error::DownloadUnauthorized => Some(
+                "check your token or credentials (`elba login`, or a git credential helper for \
+                 git repositories)",
+            ),
+            Error::DownloadTimedOut => Some(
+                "check your connection and proxy, raise `timeout` in [network], or run with \
+                 `--offline`",
+            ),
+            Error::BuildFailed => Some("the output of the compiler above says what went wrong"),
+            Error::TestsFailed => {
+                Some("the output of the failing tests above says what went wrong")
+            }
+            Error::__Nonexhaustive => None,
+        }
+    }
+
+    /// A longer explanation of the error, which `elba explain` prints.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Error::ManifestNotFound => {
+                "\
+elba looks for the manifest of a project (a file called elba.toml) in the
+current directory and then in each of its parents, and none of them had one.
+
+Either run the command from inside the project, point --manifest-path at the
+manifest of the project, or make a new manifest with `elba init`."
+            }
+            Error::InvalidManifest => {
+                "\
+The manifest of a project (elba.toml) couldn't be read: it isn't valid TOML,
+a field has the wrong type, or a field elba doesn't know about is in it. The
+causes of the error say which.
+
+`elba verify` checks the whole manifest at once, reporting every problem it
+finds instead of only the first."
+            }
+            Error::LockfileOutdated => {
+                "\
+elba was asked to use the lockfile exactly as it is (with `elba lock --check`
+or `--locked`), but resolving the dependencies of the manifest again gave
+something different: a dependency was added, removed or had its constraint
+changed since the lockfile was written.
+
+Run `elba lock` to update elba.lock, and commit the change."
+            }
+            Error::InvalidSourceUrl => {
+                "\
+A place to get a package from couldn't be parsed. Sources have a kind in
+front of them:
+
+    git+https://github.com/doesnt/exist     a git repository
+    dir+../awesome                          a directory
+    tar+https://example.com/pkg.tar.gz      a tarball
+    index+git+https://github.com/elba/index an index, which is itself a source
+
+Check the source in the manifest, the configuration or the command line."
+            }
+            Error::PackageNotFound => {
+                "\
+A package (or the version of it which was asked for) wasn't in any of the
+indices elba looked in.
+
+Check that the name of the package is spelled right, including its group, and
+that the index it's in is in the [indices] of the configuration. If it was
+only published recently, the copy of the index elba has might be out of date;
+`elba update` refreshes it."
+            }
+            Error::NoConflictRes => {
+                "\
+No set of versions satisfies the dependencies of the project. Usually, two
+packages depend on versions of a third which don't overlap. elba prints the
+chain of reasoning which led to the conflict above the error.
+
+Loosen the constraint of one of the packages involved in the conflict, or
+update the package which has the stricter constraint to a version with a
+looser one."
+            }
+            Error::CannotDownload => {
+                "\
+A package couldn't be retrieved from where it lives: a download failed, a git
+repository couldn't be cloned, or the git ref it's pinned to doesn't exist.
+
+Check your network connection and that the source is right. If the package
+was retrieved before, `--offline` builds with the copy in the cache; mirrors
+in the configuration can stand in for sources which are down."
+            }
+            Error::DnsFailure => {
+                "\
+The name of the host a package or index lives on couldn't be looked up, so
+elba couldn't connect to it at all. The causes of the error say which url it
+was.
+
+Usually this means that there's no network connection, or that the host name
+in the source is misspelled. If the package was retrieved before, `--offline`
+builds with the copy in the cache."
+            }
+            Error::TlsFailure => {
+                "\
+elba connected to the host a package or index lives on, but couldn't make a
+secure connection with it: its certificate wasn't trusted, had expired, or
+didn't match the host name.
+
+Check that the system clock is right and that the system's certificates are up
+to date. Networks which intercept secure connections need their proxy set in
+the HTTPS_PROXY environment variable (or http.proxy in the git configuration,
+for git repositories)."
+            }
+            Error::DownloadNotFound => {
+                "\
+The server a package or index lives on answered, but said that what elba asked
+for doesn't exist (404). The causes of the error say which url it was.
+
+Check the source in the manifest, the configuration or the index. The package
+or repository might have been moved, renamed or deleted; if it's private, some
+servers say it doesn't exist rather than that you can't see it."
+            }
+            Error::DownloadUnauthorized => {
+                "\
+The server a package or index lives on refused to hand it over without
+credentials, or didn't accept the ones elba had (401 or 403).
+
+For registries, log in again with `elba login`. For git repositories, check
+that a git credential helper or ssh-agent has credentials for the host."
+            }
+            Error::DownloadTimedOut => {
+                "\
+A download took longer than elba is willing to wait on it.
+
+Check your connection, and your proxy if you go through one. For slow
+connections, raise `timeout` in the [network] section of the configuration.
+If the package was retrieved before, `--offline` builds with the copy in the
+cache."
+            }
+            Error::BuildFailed => {
+                "\
+The Idris compiler failed to build a package. Its output, printed above the
+error, says which module failed and why; the causes of the error say which
+package and target it was.
+
+If the package which failed is a dependency rather than the project itself,
+it might not work with the version of Idris in use."
+            }
+            Error::TestsFailed => {
+                "\
+At least one of the test binaries of the project exited unsuccessfully. elba
+prints the output of every test, along with whether it passed, above the
+error."
+            }
+            Error::__Nonexhaustive => "",
+        }
+    }
+}
+
+/// The code of an error: the first one anywhere in its chain of causes, whether it's the error
+/// itself or context which was added to another error.
+pub fn error_code(e: &failure::Error) -> Option<Error> {
+    e.iter_chain().find_map(|f| {
+        f.downcast_ref::<Error>()
+            .cloned()
+            .or_else(|| f.downcast_ref::<Context<Error>>().map(|c| *c.get_context()))
+    })
+}
+
+/// Adds a code to a failed download of `url`, saying what kind of failure it was, along with the
+/// url itself. Errors which already have a code are left alone.
+pub fn download_error(url: &str, e: failure::Error) -> failure::Error {
+    if error_code(&e).is_some() {
+        return e;
+    }
+
+    let kind = e
+        .iter_chain()
+        .find_map(|f| {
+            f.downcast_ref::<reqwest::Error>()
+                .map(classify_http)
+                .or_else(|| f.downcast_ref::<git2::Error>().map(classify_git))
+                .or_else(|| {
+                    f.downcast_ref::<std::io::Error>()
+                        .filter(|e| e.kind() == std::io::ErrorKind::TimedOut)
+                        .map(|_| Error::DownloadTimedOut)
+                })
+        })
+        .unwrap_or(Error::CannotDownload);
+
+    e.context(format!("couldn't download {}", url))
+        .context(kind)
+        .into()
+}
+
+/// The kind of failure an HTTP request ran into.
+pub fn classify_http(e: &reqwest::Error) -> Error {
+    if e.is_timeout() {
+        return Error::DownloadTimedOut;
+    }
+
+    if let Some(status) = e.status() {
+        return classify_status(status.as_u16());
+    }
+
+    // reqwest doesn't say what went wrong with a connection, but the errors underneath it do.
+    // Its own message has the url in it, which could say anything, so it's skipped.
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        let kind = err
+            .downcast_ref::<std::io::Error>()
+            .filter(|e| e.kind() == std::io::ErrorKind::TimedOut)
+            .map(|_| Error::DownloadTimedOut)
+            .or_else(|| classify_message(&err.to_string()));
+        if let Some(kind) = kind {
+            return kind;
+        }
+        source = err.source();
+    }
+
+    Error::CannotDownload
+}
+
+/// The kind of failure a git operation ran into.
+pub fn classify_git(e: &git2::Error) -> Error {
+    match (e.code(), e.class()) {
+        (git2::ErrorCode::Auth, _) | (_, git2::ErrorClass::Ssh) => Error::DownloadUnauthorized,
+        (git2::ErrorCode::Certificate, _) | (_, git2::ErrorClass::Ssl) => Error::TlsFailure,
+        // libgit2 files what goes wrong with HTTP under its network errors too; errors made up on
+        // our end don't have a class.
+        (_, git2::ErrorClass::Net) | (_, git2::ErrorClass::None) => {
+            classify_message(e.message()).unwrap_or(Error::CannotDownload)
+        }
+        _ => Error::CannotDownload,
+    }
+}
+
+/// The kind of failure an HTTP status code means.
+fn classify_status(status: u16) -> Error {
+    match status {
+        401 | 403 => Error::DownloadUnauthorized,
+        404 | 410 => Error::DownloadNotFound,
+        408 | 504 => Error::DownloadTimedOut,
+        _ => Error::CannotDownload,
+    }
+}
+
+/// The kind of failure a message from libgit2, hyper or the OS describes. Only the phrases they
+/// use are matched, where they use them, since the rest of a message can have a url in it, and a
+/// url can say anything.
+fn classify_message(msg: &str) -> Option<Error> {
+    let msg = msg.trim().to_lowercase();
+    let starts = |phrases: &[&str]| phrases.iter().any(|p| msg.starts_with(p));
+
+    // libgit2 says `unexpected http status code: 404` or `request failed with status code: 404`.
+    let status = msg
+        .rfind("status code: ")
+        .filter(|&ix| msg[..ix].ends_with("http ") || msg[..ix].ends_with("with "))
+        .and_then(|ix| msg[ix + "status code: ".len()..].parse::<u16>().ok());
+
+    if let Some(status) = status {
+        Some(classify_status(status))
+    } else if starts(&[
+        "dns error",
+        "failed to lookup address information",
+        "failed to resolve address for ",
+    ]) {
+        Some(Error::DnsFailure)
+    } else if starts(&[
+        "the ssl certificate is invalid",
+        "certificate verify failed",
+    ]) || msg.contains(":certificate verify failed")
+        || msg.contains("ssl routines:")
+    {
+        Some(Error::TlsFailure)
+    } else if msg.ends_with(": operation timed out")
+        || msg.ends_with(": connection timed out")
+        || starts(&["operation timed out", "connection timed out"])
+    {
+        Some(Error::DownloadTimedOut)
+    } else if starts(&[
+        "remote authentication required",
+        "too many redirects or authentication replays",
+    ]) {
+        Some(Error::DownloadUnauthorized)
+    } else {
+        None
+    }
+}
+
+/// Context for an error saying which package it happened in.
+#[derive(Clone, Eq, PartialEq, Debug, Fail)]
+#[fail(display = "in package {}", _0)]
+pub struct InPackage(pub String);
+
+/// The package an error happened in, if it says.
+pub fn error_package(e: &failure::Error) -> Option<&str> {
+    e.iter_chain().find_map(|f| {
+        f.downcast_ref::<InPackage>()
+            .or_else(|| {
+                f.downcast_ref::<Context<InPackage>>()
+                    .map(|c| c.get_context())
+            })
+            .map(|p| p.0.as_str())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::{format_err, ResultExt};
+
+    #[test]
+    fn error_codes() {
+        for e in Error::ALL {
+            assert_eq!(Error::from_code(e.code()), Some(*e));
+            assert!(!e.explanation().is_empty());
+        }
+        assert_eq!(Error::from_code("0301"), Some(Error::NoConflictRes));
+        assert_eq!(Error::from_code("e0301"), Some(Error::NoConflictRes));
+        assert_eq!(Error::from_code("E9999"), None);
+
+        let res: Result<()> = Err(format_err!("the compiler said no"));
+        let e: failure::Error = res
+            .context(InPackage("grp/pkg 1.0.0".to_string()))
+            .context(Error::BuildFailed)
+            .unwrap_err()
+            .into();
+        assert_eq!(error_code(&e), Some(Error::BuildFailed));
+        assert_eq!(error_package(&e), Some("grp/pkg 1.0.0"));
+        assert_eq!(error_code(&format_err!("no code")), None);
+    }
+
+    #[test]
+    fn download_errors() {
+        let git = |msg: &str| {
+            let e = git2::Error::from_str(msg);
+            error_code(&download_error("https://example.com", e.into()))
+        };
+        for (msg, kind) in &[
+            (
+                "failed to resolve address for example.com: Name or service not known",
+                Error::DnsFailure,
+            ),
+            ("the SSL certificate is invalid", Error::TlsFailure),
+            ("unexpected http status code: 404", Error::DownloadNotFound),
+            (
+                "remote authentication required",
+                Error::DownloadUnauthorized,
+            ),
+            ("connection timed out", Error::DownloadTimedOut),
+        ] {
+            assert_eq!(git(*msg), Some(*kind), "{}", msg);
+        }
+
+        // Words which only mean something in the right place don't count anywhere else.
+        for msg in &[
+            "failed to fetch https://ssl.example.com/tls/401/403/404/not-found/timeout.git",
+            "repository 'https://example.com/status code: 404/' has no master branch",
+            "reference 'refs/heads/dns error' not found",
+        ] {
+            assert_eq!(git(*msg), Some(Error::CannotDownload), "{}", msg);
+        }
+
+        let e = download_error("https://example.com", format_err!("who knows"));
+        assert_eq!(error_code(&e), Some(Error::CannotDownload));
+        assert!(e
+            .iter_chain()
+            .any(|x| x.to_string() == "couldn't download https://example.com"));
+
+        // An error which already has a code keeps it.
+        let e = download_error("https://example.com", Error::PackageNotFound.into());
+        assert_eq!(error_code(&e), Some(Error::PackageNotFound));
+    }
+}
@@ -0,0 +1,204 @@
+//! Everything in `std::fs`, along with ways of writing files and directories which never leave
+//! anything half-written behind.
+//!
+//! Whatever's being written is put together under a temporary name next to where it's going, and
+//! only renamed into place once it's done. If elba is killed (or the power goes out) partway
+//! through, there's either the old contents or nothing at all, never something in between which
+//! would later be taken for the real thing.
+
+pub use std::fs::*;
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use failure::{bail, format_err, ResultExt};
+
+use crate::util::{error::Result, lock::LOCK_FILE};
+
+/// The name of the file which marks a directory whose contents are still being moved in.
+pub const INCOMPLETE: &str = ".incomplete";
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A name next to `path` which nothing else (not even another thread) is going to be writing to.
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(
+        ".{}.tmp-{}-{}",
+        name,
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+/// Whether a file name is one which was made up for something on its way into place.
+pub fn is_tmp_name(name: &str) -> bool {
+    name.starts_with('.') && name.contains(".tmp-")
+}
+
+/// Writes a file, replacing whatever was there before all at once.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let tmp = tmp_path(path);
+
+    let res = File::create(&tmp)
+        .and_then(|mut f| {
+            f.write_all(contents.as_ref())?;
+            f.sync_all()
+        })
+        .and_then(|_| rename(&tmp, path));
+    if let Err(e) = res {
+        let _ = remove_file(&tmp);
+        bail!("couldn't write {}: {}", path.display(), e)
+    }
+
+    Ok(())
+}
+
+/// Creates a directory, having `fill` put everything in it before it shows up at `path`.
+///
+/// If something else puts a directory at `path` first, that one is kept, and what `fill` came up
+/// with is thrown away; this is meant for directories whose contents are determined by their
+/// name, like the ones in the store or the build cache.
+pub fn create_dir_atomic(path: &Path, fill: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = tmp_path(path);
+    create_dir_all(&tmp)?;
+
+    if let Err(e) = fill(&tmp) {
+        let _ = remove_dir_all::remove_dir_all(&tmp);
+        return Err(e);
+    }
+
+    if let Err(e) = rename(&tmp, path) {
+        let _ = remove_dir_all::remove_dir_all(&tmp);
+        if !path.exists() {
+            bail!("couldn't move {} into place: {}", path.display(), e)
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the contents of a directory with what `fill` puts in a temporary directory next to
+/// it, leaving the directory itself (and its lockfile) where it is.
+///
+/// Nothing in the directory is touched unless `fill` succeeds. Moving the new contents in can't
+/// happen all at once, so the directory is marked as incomplete while that's going on; callers
+/// should check `is_complete` before trusting what's in it.
+pub fn fill_dir_atomic(dir: &Path, fill: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = tmp_path(dir);
+    create_dir_all(&tmp)?;
+
+    if let Err(e) = fill(&tmp) {
+        let _ = remove_dir_all::remove_dir_all(&tmp);
+        return Err(e);
+    }
+
+    let res = (|| -> Result<()> {
+        create_dir_all(dir)?;
+        File::create(dir.join(INCOMPLETE))?.sync_all()?;
+
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == LOCK_FILE || name == INCOMPLETE {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                remove_dir_all::remove_dir_all(entry.path())?;
+            } else {
+                remove_file(entry.path())?;
+            }
+        }
+
+        for entry in read_dir(&tmp)? {
+            let entry = entry?;
+            rename(entry.path(), dir.join(entry.file_name()))?;
+        }
+
+        remove_file(dir.join(INCOMPLETE))?;
+        Ok(())
+    })();
+    let _ = remove_dir_all::remove_dir_all(&tmp);
+
+    res.with_context(|e| {
+        format_err!(
+            "couldn't move the contents of {} into place: {}",
+            dir.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Whether a directory filled in by `fill_dir_atomic` has everything in it.
+pub fn is_complete(dir: &Path) -> bool {
+    !dir.join(INCOMPLETE).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn write_atomic_replaces() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let dir = tmp.path();
+        let path = dir.join("file");
+
+        write_atomic(&path, "one").unwrap();
+        write_atomic(&path, "two").unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "two");
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn fill_dir_atomic_failure_keeps_contents() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let dir = tmp.path();
+        let target = dir.join("target");
+        create_dir_all(&target).unwrap();
+        write(target.join("old"), "old").unwrap();
+
+        let res = fill_dir_atomic(&target, |tmp| {
+            write(tmp.join("new"), "new")?;
+            bail!("interrupted")
+        });
+
+        assert!(res.is_err());
+        assert!(target.join("old").exists());
+        assert!(!target.join("new").exists());
+        assert!(is_complete(&target));
+        // The temporary directory is cleaned up after itself.
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+
+        fill_dir_atomic(&target, |tmp| Ok(write(tmp.join("new"), "new")?)).unwrap();
+
+        assert!(!target.join("old").exists());
+        assert_eq!(read_to_string(target.join("new")).unwrap(), "new");
+        assert!(is_complete(&target));
+    }
+
+    #[test]
+    fn create_dir_atomic_keeps_existing() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let dir = tmp.path();
+        let target = dir.join("target");
+
+        create_dir_atomic(&target, |tmp| Ok(write(tmp.join("a"), "first")?)).unwrap();
+        create_dir_atomic(&target, |tmp| Ok(write(tmp.join("a"), "second")?)).unwrap();
+
+        assert_eq!(read_to_string(target.join("a")).unwrap(), "first");
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+    }
+}
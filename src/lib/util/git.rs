@@ -39,9 +39,12 @@ pub fn init(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn clone(url: &Url, into: &Path) -> Result<git2::Repository> {
+/// A callback which is told how much of a download has been received so far out of the total.
+pub type Progress<'a> = Option<&'a dyn Fn(u64, u64)>;
+
+pub fn clone(url: &Url, into: &Path, progress: Progress) -> Result<git2::Repository> {
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, &url, &mut |opts| {
+    with_fetch_options(&git_config, &url, progress, &mut |opts| {
         let repo = git2::build::RepoBuilder::new()
             .fetch_options(opts)
             .clone(url.as_str(), into)?;
@@ -100,7 +103,7 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     // Fetch data from origin and reset to the head commit
     let refspec = "refs/heads/*:refs/heads/*";
     let url = Url::parse(url)?;
-    fetch(&mut repo, &url, refspec).with_context(|_| {
+    fetch(&mut repo, &url, refspec, None).with_context(|_| {
         format_err!(
             "failed to fetch submodule `{}` from {}",
             child.name().unwrap_or(""),
@@ -113,7 +116,12 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     update_submodules(&repo)
 }
 
-pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()> {
+pub fn fetch(
+    repo: &mut git2::Repository,
+    url: &Url,
+    refspec: &str,
+    progress: Progress,
+) -> Result<()> {
     // The `fetch` operation here may fail spuriously due to a corrupt
     // repository. It could also fail, however, for a whole slew of other
     // reasons (aka network related reasons). We want Cargo to automatically
@@ -126,7 +134,7 @@ pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()
     // blown away the repository, then we want to return the error as-is.
     let mut repo_reinitialized = false;
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, url, &mut |mut opts| {
+    with_fetch_options(&git_config, url, progress, &mut |mut opts| {
         loop {
             let res = repo
                 .remote_anonymous(url.as_str())?
@@ -157,6 +165,38 @@ pub fn reset(repo: &git2::Repository, obj: &git2::Object) -> Result<()> {
     Ok(())
 }
 
+/// Checks whether any of the tracked files in a repository's working tree have been changed
+/// since its HEAD was checked out.
+pub fn is_dirty(repo: &git2::Repository) -> Result<bool> {
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut opts))?;
+
+    Ok(statuses.iter().any(|x| x.status() != git2::Status::CURRENT))
+}
+
+/// Reads a file as it was at a revision of the repository it's in.
+pub fn read_at(path: &Path, rev: &str) -> Result<String> {
+    let repo = git2::Repository::discover(path)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| format_err!("{} is in a bare repository", path.display()))?;
+    let path = fs::canonicalize(path)?;
+    let rel = path.strip_prefix(fs::canonicalize(workdir)?)?;
+
+    let tree = repo.revparse_single(rev)?.peel_to_tree()?;
+    let blob = tree.get_path(rel)?.to_object(&repo)?.peel_to_blob()?;
+
+    Ok(String::from_utf8(blob.content().to_vec())?)
+}
+
+/// The commit which is checked out in the repository a directory belongs to.
+pub fn head_commit(path: &Path) -> Result<String> {
+    let repo = git2::Repository::discover(path)?;
+    let head = repo.head()?.peel_to_commit()?;
+    Ok(head.id().to_string())
+}
+
 fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
     // Here we want to drop the current repository object pointed to by `repo`,
     // so we initialize temporary repository in a sub-folder, blow away the
@@ -369,11 +409,21 @@ where
 pub fn with_fetch_options<T>(
     git_config: &git2::Config,
     url: &Url,
+    progress: Progress,
     cb: &mut dyn FnMut(git2::FetchOptions) -> Result<T>,
 ) -> Result<T> {
     with_authentication(url.as_str(), git_config, |f| {
         let mut rcb = git2::RemoteCallbacks::new();
         rcb.credentials(f);
+        if let Some(progress) = progress {
+            rcb.transfer_progress(move |stats| {
+                progress(
+                    stats.received_objects() as u64,
+                    stats.total_objects() as u64,
+                );
+                true
+            });
+        }
 
         // Create a local anonymous remote in the repository to fetch the
         // url
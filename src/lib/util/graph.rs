@@ -6,8 +6,9 @@ use petgraph::{
     Direction,
 };
 use std::{
-    collections::HashMap,
-    ops::{Index, IndexMut},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    ops::{Add, Index, IndexMut},
 };
 
 /// A wrapper for `petgraph::Graph`.
@@ -75,6 +76,118 @@ impl<T: Eq> Graph<T> {
             .map(move |node_id| (node_id, &self.inner[node_id]))
     }
 
+    /// Traverse every node in the graph, each one coming after all of the nodes it depends on.
+    ///
+    /// Nodes which don't depend on each other come in the order they were added to the graph, so
+    /// the same graph always comes out in the same order. Nodes which are part of a cycle (which
+    /// a solve never has) are left out, and so is every node which depends on one of them, even
+    /// indirectly, since there's no point at which all of its dependencies have come out.
+    pub fn topological<'a>(&'a self) -> impl Iterator<Item = (NodeIndex, &T)> + 'a {
+        let mut deps = self
+            .inner
+            .node_indices()
+            .map(|ix| {
+                self.inner
+                    .neighbors_directed(ix, Direction::Outgoing)
+                    .count()
+            })
+            .collect::<Vec<_>>();
+        let mut ready = self
+            .inner
+            .node_indices()
+            .filter(|ix| deps[ix.index()] == 0)
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+
+        let mut order = Vec::with_capacity(deps.len());
+        while let Some(Reverse(ix)) = ready.pop() {
+            order.push(ix);
+            for parent in self.inner.neighbors_directed(ix, Direction::Incoming) {
+                deps[parent.index()] -= 1;
+                if deps[parent.index()] == 0 {
+                    ready.push(Reverse(parent));
+                }
+            }
+        }
+
+        order
+            .into_iter()
+            .map(move |node_id| (node_id, &self.inner[node_id]))
+    }
+
+    /// The heaviest chain of dependencies below `root`, going by what each node `weight`s: with
+    /// the time each package took to build as its weight, this is the chain of builds which no
+    /// amount of parallelism could have sped up.
+    ///
+    /// Returns the nodes of the chain from `root` down, along with their total weight. Where two
+    /// chains weigh the same, the one through the node which was added to the graph first wins.
+    pub fn critical_path<W, F>(&self, root: NodeIndex, mut weight: F) -> (Vec<NodeIndex>, W)
+    where
+        W: Copy + Ord + Default + Add<Output = W>,
+        F: FnMut(NodeIndex, &T) -> W,
+    {
+        let reachable = self
+            .sub_tree(root)
+            .map(|(ix, _)| ix)
+            .collect::<HashSet<_>>();
+        // The weight of the heaviest chain starting at each node, and the next node in it.
+        let mut heaviest: HashMap<NodeIndex, (W, Option<NodeIndex>)> = HashMap::new();
+
+        for (ix, node) in self.topological() {
+            if !reachable.contains(&ix) {
+                continue;
+            }
+
+            let next = self
+                .children(ix)
+                .filter_map(|(child, _)| heaviest.get(&child).map(|(w, _)| (*w, child)))
+                .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+            let own = weight(ix, node);
+            heaviest.insert(
+                ix,
+                match next {
+                    Some((w, child)) => (own + w, Some(child)),
+                    None => (own, None),
+                },
+            );
+        }
+
+        let total = heaviest.get(&root).map(|x| x.0).unwrap_or_default();
+        let mut path = vec![];
+        let mut next = heaviest.get(&root).map(|_| root);
+        while let Some(ix) = next {
+            path.push(ix);
+            next = heaviest[&ix].1;
+        }
+
+        (path, total)
+    }
+
+    /// The part of the graph which is reachable from `root`, as a graph of its own with `root` at
+    /// node index 0. The rest of the nodes keep the order they were in relative to each other.
+    pub fn subgraph(&self, root: NodeIndex) -> Graph<T>
+    where
+        T: Clone,
+    {
+        let mut keep = self.sub_tree(root).map(|(ix, _)| ix).collect::<Vec<_>>();
+        keep[1..].sort();
+
+        let mut tree = petgraph::Graph::new();
+        let mut node_map: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        for ix in keep {
+            node_map.insert(ix, tree.add_node(self.inner[ix].clone()));
+        }
+
+        // Everything a node we kept depends on was kept too.
+        for edge in self.inner.edge_references() {
+            if let Some(&source) = node_map.get(&edge.source()) {
+                tree.add_edge(source, node_map[&edge.target()], ());
+            }
+        }
+
+        Graph::new(tree)
+    }
+
     pub fn map<U, F>(&self, mut f: F) -> Result<Graph<U>>
     where
         U: Eq,
@@ -128,3 +241,84 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The graph `0 -> {1, 2}, 1 -> 3, 2 -> 3, 3 -> 4`, plus a node 5 which only depends on 4
+    /// and which nothing depends on.
+    fn diamond() -> Graph<u32> {
+        let mut inner = petgraph::Graph::new();
+        let n = (0..6).map(|x| inner.add_node(x)).collect::<Vec<_>>();
+        inner.add_edge(n[0], n[1], ());
+        inner.add_edge(n[0], n[2], ());
+        inner.add_edge(n[1], n[3], ());
+        inner.add_edge(n[2], n[3], ());
+        inner.add_edge(n[3], n[4], ());
+        inner.add_edge(n[5], n[4], ());
+        Graph::new(inner)
+    }
+
+    #[test]
+    fn topological_deps_first() {
+        let graph = diamond();
+        let order = graph.topological().map(|(_, x)| *x).collect::<Vec<_>>();
+
+        assert_eq!(order, vec![4, 3, 1, 2, 0, 5]);
+    }
+
+    #[test]
+    fn topological_skips_cycles() {
+        let mut graph = diamond();
+        graph
+            .inner
+            .add_edge(NodeIndex::new(4), NodeIndex::new(3), ());
+        let order = graph.topological().map(|(_, x)| *x).collect::<Vec<_>>();
+
+        assert!(order.is_empty());
+    }
+
+    #[test]
+    fn subgraph_reachable() {
+        let graph = diamond();
+        let sub = graph.subgraph(NodeIndex::new(2));
+
+        assert_eq!(sub.root(), Some(&2));
+        assert_eq!(
+            sub.inner
+                .raw_nodes()
+                .iter()
+                .map(|x| x.weight)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(sub.inner.edge_count(), 2);
+        assert_eq!(
+            sub.children(NodeIndex::new(0))
+                .map(|x| *x.1)
+                .collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn critical_path_heaviest() {
+        let graph = diamond();
+        let (path, total) = graph.critical_path(NodeIndex::new(0), |_, x| *x);
+        let path = path.into_iter().map(|ix| graph[ix]).collect::<Vec<_>>();
+
+        assert_eq!(path, vec![0, 2, 3, 4]);
+        assert_eq!(total, 9);
+    }
+
+    #[test]
+    fn critical_path_ties() {
+        let graph = diamond();
+        let (path, total) = graph.critical_path(NodeIndex::new(0), |_, _| 1);
+        let path = path.into_iter().map(|ix| graph[ix]).collect::<Vec<_>>();
+
+        assert_eq!(path, vec![0, 1, 3, 4]);
+        assert_eq!(total, 4);
+    }
+}
@@ -0,0 +1,163 @@
+//! The keys which index entries are signed with, and storage for the ones the user trusts.
+//!
+//! Secure indices list the public keys they sign their entries with in their config. The first
+//! time elba sees a secure index, it trusts whichever keys the index lists; after that, entries
+//! are only accepted if they're signed by a key the user has already trusted, so a compromised
+//! index (or mirror) can't quietly start signing entries with a key of its own. When an index
+//! rotates its keys, the user has to trust the new ones explicitly. An index with trusted keys
+//! can't stop being secure, either, until the user stops trusting it altogether.
+
+use std::{
+    convert::TryFrom,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ed25519_dalek::Signature;
+use failure::{bail, format_err, ResultExt};
+use indexmap::IndexMap;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{remote::resolution::IndexRes, util::error::Result};
+
+/// An ed25519 public key, written out as hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicKey(ed25519_dalek::PublicKey);
+
+impl PublicKey {
+    /// Checks that `sig`, a hex-encoded signature, is a signature of `msg` made with this key.
+    pub fn verify(&self, msg: &[u8], sig: &str) -> bool {
+        let sig = match hex::decode(sig) {
+            Ok(sig) => sig,
+            Err(_) => return false,
+        };
+        match Signature::try_from(&sig[..]) {
+            Ok(sig) => self.0.verify_strict(msg, &sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim()).context(format_err!("invalid public key {}", s))?;
+        let key = ed25519_dalek::PublicKey::from_bytes(&bytes)
+            .map_err(|_| format_err!("invalid public key {}", s))?;
+
+        Ok(PublicKey(key))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0.as_bytes()))
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// The keys the user trusts to sign the entries of each secure index.
+#[derive(Debug, Clone)]
+pub struct TrustedKeys {
+    path: PathBuf,
+    keys: IndexMap<String, Vec<PublicKey>>,
+}
+
+impl TrustedKeys {
+    /// Loads the trusted keys file at a path. A file which doesn't exist yet trusts nothing.
+    pub fn load(path: &Path) -> Result<Self> {
+        let keys = if path.exists() {
+            let contents = fs::read_to_string(path).with_context(|e| {
+                format_err!("couldn't read trusted keys file {}: {}", path.display(), e)
+            })?;
+            toml::from_str(&contents).with_context(|e| {
+                format_err!("invalid trusted keys file {}: {}", path.display(), e)
+            })?
+        } else {
+            IndexMap::new()
+        };
+
+        Ok(TrustedKeys {
+            path: path.to_path_buf(),
+            keys,
+        })
+    }
+
+    /// The keys trusted for an index, or `None` if we've never seen the index before.
+    pub fn get(&self, index: &IndexRes) -> Option<&[PublicKey]> {
+        self.keys.get(&index.to_string()).map(|x| x.as_slice())
+    }
+
+    /// Trusts a key for an index, returning whether it wasn't trusted already.
+    pub fn trust(&mut self, index: &IndexRes, key: PublicKey) -> bool {
+        let keys = self.keys.entry(index.to_string()).or_insert_with(Vec::new);
+        if keys.contains(&key) {
+            false
+        } else {
+            keys.push(key);
+            true
+        }
+    }
+
+    /// Stops trusting a key for an index.
+    ///
+    /// The last key of an index can't be removed this way, since an index with no trusted keys
+    /// would trust whatever keys it's seen with next.
+    pub fn untrust(&mut self, index: &IndexRes, key: &PublicKey) -> Result<()> {
+        let keys = match self.keys.get_mut(&index.to_string()) {
+            Some(keys) if keys.contains(key) => keys,
+            _ => bail!("key {} isn't trusted for {}", key, index),
+        };
+        if keys.len() == 1 {
+            bail!(
+                "key {} is the only key trusted for {}; trust its replacement first",
+                key,
+                index
+            )
+        }
+        keys.retain(|x| x != key);
+
+        Ok(())
+    }
+
+    /// Stops trusting any keys for an index, returning whether there were any. This is what
+    /// lets an index stop being secure; if it's secure again later, the keys it lists then are
+    /// trusted as if it were new.
+    pub fn forget(&mut self, index: &IndexRes) -> bool {
+        self.keys.swap_remove(&index.to_string()).is_some()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = toml::to_string(&self.keys)?;
+        fs::write(&self.path, contents).with_context(|e| {
+            format_err!(
+                "couldn't write trusted keys file {}: {}",
+                self.path.display(),
+                e
+            )
+        })?;
+
+        Ok(())
+    }
+}
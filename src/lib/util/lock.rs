@@ -2,24 +2,166 @@
 //!
 //! As it is currently designed, `elba` doesn't need to lock individual files. It does, however,
 //! need to lock directories to prevent other processes from using them.
+//!
+//! The process holding a lock records its pid and the time at which it took the lock in the
+//! lockfile, which lets other processes tell the user who they're waiting on. The lock itself
+//! goes away along with the process holding it, so a lockfile left behind by a process which
+//! crashed is just taken over by the next one to come along.
+//!
+//! #### Lock order
+//! Every lock has a `LockKind`, and locks are always taken in the order of their kinds:
+//!
+//! 1. indices,
+//! 2. package sources (`src` and `store` in the cache, and directory dependencies),
+//! 3. the `target` directory of a project,
+//! 4. builds in the cache (`build` and `tmp`).
+//!
+//! The `target` directory comes before the build cache because a build writes into it from start
+//! to finish, checking out and storing cached builds of dependencies as it goes.
+//!
+//! A thread which holds a lock can wait on another lock of the same kind or a later one, but never
+//! on one of an earlier kind; if two processes did that to each other, they'd both wait forever.
+//! Such a lock is only taken if nobody else has it, and is an error otherwise. Locks on
+//! directories inside one the thread has already locked don't count, since anyone else would have
+//! to get through the outer lock first. Locks of the same kind can still end up waiting on each
+//! other, which is what the lock timeout (see `DirLock::acquire_with`) is for.
+//!
+//! These checks only know about the locks the thread taking a lock holds. Locks are taken on
+//! worker threads and handed between threads all the time, so a lock held by another thread of
+//! the same process is waited on just like one held by another process.
 
+use super::shell::{Shell, Verbosity};
+use console::{style, StyledObject};
 use failure::{bail, format_err, Error, ResultExt};
 use fs2::FileExt;
 use std::{
-    fs,
+    cell::RefCell,
+    fmt, fs,
+    io::{self, prelude::*, SeekFrom},
     path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{Arc, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-/// A lock on a directory. This just generates a sibling file to the directory which indicates that
+/// The name of the lockfile which is placed in every locked directory.
+pub const LOCK_FILE: &str = ".dirlock";
+
+/// How often we check on a lock we're waiting on when there's a timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// The locks this thread has taken. A lock can be dropped on any thread, so each one is
+    /// tracked by a weak reference which dies along with it. Locks which were handed to this
+    /// thread by another one aren't in here.
+    static HELD: RefCell<Vec<(Weak<()>, PathBuf, LockKind)>> = RefCell::new(vec![]);
+}
+
+/// What a locked directory holds, which decides the order it has to be locked in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LockKind {
+    /// An index, whether it's cached or a local directory.
+    Index,
+    /// The source of a package: a folder in the cache's `src` or `store`, or a directory
+    /// dependency.
+    Source,
+    /// The `target` directory of a project.
+    Target,
+    /// A stored build in the cache, or a temporary directory a package is being built in.
+    Build,
+}
+
+impl fmt::Display for LockKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockKind::Index => write!(f, "index"),
+            LockKind::Source => write!(f, "source"),
+            LockKind::Target => write!(f, "target"),
+            LockKind::Build => write!(f, "build"),
+        }
+    }
+}
+
+/// Finds the lock this thread holds which a lock on `path` has to come before, if there is one.
+fn out_of_order(path: &Path, kind: LockKind) -> Result<Option<(PathBuf, LockKind)>, Error> {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        held.retain(|(token, _, _)| token.upgrade().is_some());
+
+        if held.iter().any(|(_, p, _)| p == path) {
+            bail!(
+                "{} is already locked by this thread, so waiting on it would never end",
+                path.display()
+            )
+        }
+
+        if held.iter().any(|(_, p, _)| path.starts_with(p)) {
+            return Ok(None);
+        }
+
+        Ok(held
+            .iter()
+            .filter(|(_, _, k)| *k > kind)
+            .map(|(_, p, k)| (p.clone(), *k))
+            .next())
+    })
+}
+
+/// A lock on a directory. This just generates a file in the directory which indicates that
 /// the directory is locked.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct DirLock {
     path: PathBuf,
     lock_path: PathBuf,
+    kind: LockKind,
+    file: fs::File,
+    /// Keeps this lock's entry in `HELD` alive.
+    _token: Arc<()>,
 }
 
 impl DirLock {
-    pub fn acquire(path: &Path) -> Result<Self, Error> {
+    /// Takes a lock, waiting for as long as it takes if somebody else has it.
+    pub fn acquire(path: &Path, kind: LockKind) -> Result<Self, Error> {
+        DirLock::acquire_with(path, kind, None, None)
+    }
+
+    /// Like `acquire`, but if the lock has to be waited on or taken back from a process which
+    /// left it behind, this is said through `shell` rather than straight to stderr. If somebody
+    /// else holds on to the lock for longer than `timeout`, it's given up on.
+    pub fn acquire_with(
+        path: &Path,
+        kind: LockKind,
+        shell: Option<Shell>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        DirLock::acquire_inner(path, kind, shell, timeout, true)
+            .map(|x| x.expect("a blocking lock was given up on"))
+    }
+
+    /// Takes the lock only if nobody else has it (or the process which had it is gone), rather
+    /// than waiting on it.
+    pub fn try_acquire(
+        path: &Path,
+        kind: LockKind,
+        shell: Option<Shell>,
+    ) -> Result<Option<Self>, Error> {
+        if out_of_order(path, kind).is_err() {
+            return Ok(None);
+        }
+        DirLock::acquire_inner(path, kind, shell, None, false)
+    }
+
+    fn acquire_inner(
+        path: &Path,
+        kind: LockKind,
+        shell: Option<Shell>,
+        timeout: Option<Duration>,
+        block: bool,
+    ) -> Result<Option<Self>, Error> {
+        let conflict = out_of_order(path, kind)?;
+
         fs::create_dir_all(&path).with_context(|e| {
             format_err!(
                 "couldn't create dir {} while locking: {}",
@@ -28,55 +170,295 @@ impl DirLock {
             )
         })?;
 
-        let lock_path = path.join(".dirlock");
+        let lock_path = path.join(LOCK_FILE);
+        let mut notified = false;
 
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&lock_path)
-            .with_context(|e| {
-                format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
-            })?;
+        loop {
+            let mut f = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&lock_path)
+                .with_context(|e| {
+                    format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
+                })?;
 
-        if f.metadata()
-            .with_context(|e| format_err!("couldn't get lockfile metadata: {}", e))?
-            .len()
-            != 0
-        {
-            bail!(
-                "lockfile name conflict with existing file {}",
-                lock_path.display()
-            )
-        }
+            // Whoever holds the lock might still be writing their info, so we can't hold what we
+            // find here against them; it's only used to say who we're waiting on.
+            let holder = LockInfo::peek(&mut f);
 
-        f.lock_exclusive().with_context(|e| {
-            format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
-        })?;
+            if let Err(e) = f.try_lock_exclusive() {
+                if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                    bail!("couldn't lock lockfile {}: {}", lock_path.display(), e)
+                }
 
-        Ok(DirLock {
-            path: path.to_path_buf(),
-            lock_path,
-        })
+                // A contended lock always has a live holder, since the lock goes away along with
+                // the process holding it. Even if we can't see that process (it could be in
+                // another pid namespace, or on another machine), it's not ours to take.
+                if !block {
+                    return Ok(None);
+                }
+
+                let by = holder_name(holder);
+
+                if let Some((held, held_kind)) = &conflict {
+                    bail!(
+                        "couldn't lock {} ({}) without risking a deadlock: {} has it, and this \
+                         thread holds {} ({}), which has to be locked after it",
+                        path.display(),
+                        kind,
+                        by,
+                        held.display(),
+                        held_kind
+                    )
+                }
+
+                if !notified {
+                    report(
+                        shell,
+                        style("Blocking").cyan(),
+                        format!("waiting for {} to release {}", by, path.display()),
+                    );
+                    notified = true;
+                }
+
+                wait(&f, &lock_path, timeout).with_context(|e| {
+                    format_err!("couldn't lock {} (held by {}): {}", path.display(), by, e)
+                })?;
+            }
+
+            // The process we were waiting on removes the lockfile when it's done with it. If that
+            // happened, we're holding a lock on a file nobody else can see anymore, so we start
+            // over with a fresh lockfile.
+            if !same_file(&f, &lock_path) {
+                continue;
+            }
+
+            // A process which lets go of its lock removes the lockfile first, so if there's still
+            // info in it now that we hold the lock, it was left by a process which died holding
+            // it.
+            f.seek(SeekFrom::Start(0))?;
+            if let Some(info) = LockInfo::read(&mut f, &lock_path)? {
+                report(
+                    shell,
+                    style("Reclaiming").yellow(),
+                    format!(
+                        "stale lock on {} left by process {}",
+                        path.display(),
+                        info.pid
+                    ),
+                );
+            }
+
+            LockInfo::current().write(&mut f).with_context(|e| {
+                format_err!("couldn't write lockfile {}: {}", lock_path.display(), e)
+            })?;
+
+            let token = Arc::new(());
+            HELD.with(|x| {
+                x.borrow_mut()
+                    .push((Arc::downgrade(&token), path.to_path_buf(), kind))
+            });
+
+            return Ok(Some(DirLock {
+                path: path.to_path_buf(),
+                lock_path,
+                kind,
+                file: f,
+                _token: token,
+            }));
+        }
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+}
+
+/// Waits for the lock on a lockfile, for as long as `timeout` allows.
+fn wait(f: &fs::File, lock_path: &Path, timeout: Option<Duration>) -> Result<(), Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            f.lock_exclusive()
+                .with_context(|e| format_err!("couldn't lock {}: {}", lock_path.display(), e))?;
+            return Ok(());
+        }
+    };
+
+    let start = Instant::now();
+    loop {
+        match f.try_lock_exclusive() {
+            Ok(()) => return Ok(()),
+            Err(e) if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() => {
+                bail!("couldn't lock {}: {}", lock_path.display(), e)
+            }
+            Err(_) if start.elapsed() >= timeout => bail!(
+                "gave up waiting after {} seconds (see lock_timeout in the configuration)",
+                timeout.as_secs_f32()
+            ),
+            Err(_) => thread::sleep(POLL_INTERVAL.min(timeout)),
+        }
+    }
+}
+
+/// Who holds a lock, going by what they wrote in its lockfile. Other threads of this process take
+/// locks just like other processes do.
+fn holder_name(holder: Option<LockInfo>) -> String {
+    match holder {
+        Some(info) if info.pid == process::id() => "another thread of this process".to_string(),
+        Some(info) => format!("another elba process (pid {})", info.pid),
+        None => "another elba process or thread".to_string(),
+    }
 }
 
+/// Tells the user what's going on with a lock, through a shell if there is one.
+fn report(shell: Option<Shell>, status: StyledObject<&str>, message: String) {
+    match shell {
+        Some(shell) => shell.println(status, message, Verbosity::Normal),
+        None => eprintln!("{:>12} {}", status, message),
+    }
+}
+
+impl PartialEq for DirLock {
+    fn eq(&self, other: &DirLock) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for DirLock {}
+
 impl Drop for DirLock {
     fn drop(&mut self) {
-        let f = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .open(&self.lock_path);
+        // We remove the lockfile before unlocking it so that anyone waiting on us notices that
+        // they've been handed a dead lockfile.
+        let _ = fs::remove_file(&self.lock_path);
+        let _ = self.file.unlock();
+    }
+}
 
-        if let Ok(f) = f {
-            let _ = f.unlock();
+/// The information that a process holding a lock records in the lockfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockInfo {
+    pub pid: u32,
+    /// Seconds since the Unix epoch at which the lock was taken.
+    pub timestamp: u64,
+}
+
+impl LockInfo {
+    pub fn current() -> Self {
+        LockInfo {
+            pid: process::id(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0),
         }
+    }
 
-        let _ = fs::remove_file(&self.lock_path);
+    /// Reads the info out of a lockfile someone else might be holding, if it makes sense.
+    fn peek(f: &mut fs::File) -> Option<Self> {
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).ok()?;
+        LockInfo::from_str(&contents).ok()
+    }
+
+    /// Reads the info out of a lockfile we hold. An empty lockfile has no info, but one which
+    /// has something else in it isn't a lockfile at all.
+    fn read(f: &mut fs::File, lock_path: &Path) -> Result<Option<Self>, Error> {
+        let mut contents = String::new();
+        if f.read_to_string(&mut contents).is_err() || contents.is_empty() {
+            return Ok(None);
+        }
+
+        match LockInfo::from_str(&contents) {
+            Ok(info) => Ok(Some(info)),
+            Err(_) => bail!(
+                "lockfile name conflict with existing file {}",
+                lock_path.display()
+            ),
+        }
     }
+
+    fn write(&self, f: &mut fs::File) -> io::Result<()> {
+        f.set_len(0)?;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(self.to_string().as_bytes())?;
+        f.flush()
+    }
+}
+
+impl FromStr for LockInfo {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut pid = None;
+        let mut timestamp = None;
+
+        for line in s.lines().filter(|x| !x.trim().is_empty()) {
+            let mut split = line.splitn(2, '=');
+            let key = split.next().unwrap().trim();
+            let val = split
+                .next()
+                .ok_or_else(|| format_err!("invalid lockfile line: {}", line))?
+                .trim();
+            match key {
+                "pid" => pid = Some(val.parse()?),
+                "timestamp" => timestamp = Some(val.parse()?),
+                _ => bail!("unknown lockfile key: {}", key),
+            }
+        }
+
+        Ok(LockInfo {
+            pid: pid.ok_or_else(|| format_err!("lockfile is missing pid"))?,
+            timestamp: timestamp.ok_or_else(|| format_err!("lockfile is missing timestamp"))?,
+        })
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "pid = {}", self.pid)?;
+        writeln!(f, "timestamp = {}", self.timestamp)
+    }
+}
+
+/// Whether a process might still be running. Where we can't tell, we assume that it is.
+#[cfg(unix)]
+pub fn process_alive(pid: u32) -> bool {
+    // kill treats zero and negative pids specially, and no real process has them.
+    if pid == 0 || pid > libc::pid_t::max_value() as u32 {
+        return false;
+    }
+
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM means that the process exists, but belongs to someone else.
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn process_alive(_pid: u32) -> bool {
+    // We have no cheap way of checking, so we err on the side of waiting.
+    true
+}
+
+#[cfg(unix)]
+fn same_file(f: &fs::File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (f.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_f: &fs::File, path: &Path) -> bool {
+    path.exists()
 }
 
 #[cfg(test)]
@@ -84,20 +466,20 @@ mod test {
     extern crate tempdir;
 
     use super::*;
-    use std::fs;
+    use std::{fs, process, str::FromStr, sync::mpsc};
 
     #[test]
     fn dirlock_simple() {
         // As long as nothing panics, we're ok.
         let tmp = tempdir::TempDir::new("elba").unwrap();
-        DirLock::acquire(tmp.path()).unwrap();
+        DirLock::acquire(tmp.path(), LockKind::Source).unwrap();
     }
 
     #[test]
     fn dirlock_wrong_order() {
         // As long as nothing panics, we're ok.
         let tmp = tempdir::TempDir::new("elba").unwrap();
-        let lock = DirLock::acquire(tmp.path()).unwrap();
+        let lock = DirLock::acquire(tmp.path(), LockKind::Source).unwrap();
 
         // Purposely drop in the wrong order
         drop(tmp);
@@ -109,8 +491,185 @@ mod test {
         let tmp = tempdir::TempDir::new("elba").unwrap();
         fs::write(tmp.path().join(".dirlock"), b"hello world").unwrap();
 
-        let lock = DirLock::acquire(tmp.path());
+        let lock = DirLock::acquire(tmp.path(), LockKind::Source);
 
         assert!(lock.is_err());
     }
+
+    #[test]
+    fn dirlock_records_info() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let _lock = DirLock::acquire(tmp.path(), LockKind::Source).unwrap();
+
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        let info = LockInfo::from_str(&contents).unwrap();
+
+        assert_eq!(info.pid, process::id());
+    }
+
+    #[test]
+    fn dirlock_leftover_info() {
+        // A lockfile left behind by a process which crashed shouldn't stop us.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let info = LockInfo {
+            pid: u32::max_value(),
+            timestamp: 0,
+        };
+        fs::write(tmp.path().join(".dirlock"), info.to_string()).unwrap();
+
+        let _lock = DirLock::acquire(tmp.path(), LockKind::Source).unwrap();
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(LockInfo::from_str(&contents).unwrap().pid, process::id());
+    }
+
+    #[test]
+    fn dirlock_unwritten_holder() {
+        // The holder might not have finished writing its info yet, which isn't our problem.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let release = hold_elsewhere(tmp.path());
+        fs::write(tmp.path().join(".dirlock"), b"pid = ").unwrap();
+
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Source, None)
+            .unwrap()
+            .is_none());
+
+        drop(release);
+    }
+
+    #[test]
+    fn dirlock_invisible_holder() {
+        // A holder whose pid we can't see (say, because it's in another container) still holds
+        // its lock, and mustn't have it taken away.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let release = hold_elsewhere(tmp.path());
+        let info = LockInfo {
+            pid: u32::max_value(),
+            timestamp: 0,
+        };
+        fs::write(tmp.path().join(".dirlock"), info.to_string()).unwrap();
+
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Source, None)
+            .unwrap()
+            .is_none());
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(LockInfo::from_str(&contents).unwrap(), info);
+
+        drop(release);
+    }
+
+    /// Holds a lock on `path` on another thread until the returned sender is dropped.
+    fn hold_elsewhere(path: &Path) -> mpsc::Sender<()> {
+        let path = path.to_path_buf();
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let _lock = DirLock::acquire(&path, LockKind::Source).unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+        release_tx
+    }
+
+    #[test]
+    fn dirlock_twice() {
+        // Locking the same directory twice on one thread would block forever, so it's an error.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let _lock = DirLock::acquire(tmp.path(), LockKind::Source).unwrap();
+
+        assert!(DirLock::acquire(tmp.path(), LockKind::Source).is_err());
+    }
+
+    #[test]
+    fn dirlock_out_of_order() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let index = tmp.path().join("index");
+        let _build = DirLock::acquire(&tmp.path().join("build"), LockKind::Build).unwrap();
+
+        // Nobody else has the index, so there's nothing to deadlock on.
+        drop(DirLock::acquire(&index, LockKind::Index).unwrap());
+
+        // Somebody else does, so waiting on it could deadlock.
+        let release = hold_elsewhere(&index);
+        let start = Instant::now();
+        assert!(DirLock::acquire(&index, LockKind::Index).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(release);
+    }
+
+    #[test]
+    fn dirlock_nested_out_of_order() {
+        // A directory inside one we already hold can be waited on regardless of its kind.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let target = tmp.path().join("target");
+        let lib = target.join("lib");
+
+        let release = hold_elsewhere(&lib);
+        let _target = DirLock::acquire(&target, LockKind::Target).unwrap();
+        let _build = DirLock::acquire(&tmp.path().join("build"), LockKind::Build).unwrap();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(release);
+        });
+        assert!(DirLock::acquire(&lib, LockKind::Target).is_ok());
+    }
+
+    #[test]
+    fn dirlock_released_elsewhere() {
+        // A lock dropped on another thread no longer counts as held by the one which took it.
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let build = DirLock::acquire(&tmp.path().join("build"), LockKind::Build).unwrap();
+        thread::spawn(move || drop(build)).join().unwrap();
+
+        let release = hold_elsewhere(&tmp.path().join("index"));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(release);
+        });
+        assert!(DirLock::acquire(&tmp.path().join("index"), LockKind::Index).is_ok());
+    }
+
+    #[test]
+    fn dirlock_timeout() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let release = hold_elsewhere(tmp.path());
+
+        let res = DirLock::acquire_with(
+            tmp.path(),
+            LockKind::Source,
+            None,
+            Some(Duration::from_millis(300)),
+        );
+        // The lock is held by another thread of this process, not another process.
+        let err = res.unwrap_err().to_string();
+        assert!(
+            err.contains("held by another thread of this process"),
+            "{}",
+            err
+        );
+        drop(release);
+    }
+
+    #[test]
+    fn dirlock_try() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let release = hold_elsewhere(tmp.path());
+
+        let start = Instant::now();
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Source, None)
+            .unwrap()
+            .is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(release);
+        thread::sleep(Duration::from_millis(200));
+        let lock = DirLock::try_acquire(tmp.path(), LockKind::Source, None).unwrap();
+        assert!(lock.is_some());
+
+        // Nor is a lock this thread already holds waited on.
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Source, None)
+            .unwrap()
+            .is_none());
+    }
 }
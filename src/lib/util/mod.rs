@@ -1,18 +1,22 @@
 //! Utility functions.
 
 pub mod config;
+pub mod credentials;
 pub mod error;
+pub mod fs;
 pub mod git;
 pub mod graph;
+pub mod keys;
 pub mod lock;
 pub mod parser;
+pub mod profile;
 pub mod read2;
 pub mod shell;
 
 pub use crate::util::read2::read2;
 
 use std::{
-    fs,
+    ffi::OsStr,
     path::{Component, Path, PathBuf},
     process::Output,
     str::FromStr,
@@ -23,19 +27,34 @@ use itertools::Itertools;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use walkdir::{DirEntry, WalkDir};
 
-use crate::util::error::Result;
+use crate::util::{error::Result, lock::LOCK_FILE};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SubPath(pub PathBuf);
 
 impl SubPath {
+    /// Whether a path stays inside whatever directory it's relative to.
+    ///
+    /// The path has to be checked the same way on every platform, since manifests are shared
+    /// between them: on Unix, `..\foo` and `C:foo` would otherwise be a single harmless file
+    /// name, but on Windows they escape the directory.
     pub fn is_subpath(p: &Path) -> bool {
-        p.is_relative() && p.components().all(|x| x != Component::ParentDir)
+        let p = p.to_string_lossy().replace('\\', "/");
+        let p = Path::new(&p);
+
+        p.is_relative()
+            && p.components().all(|x| match x {
+                Component::Normal(x) => !x.to_string_lossy().contains(':'),
+                Component::CurDir => true,
+                _ => false,
+            })
     }
 
+    /// Makes a `SubPath`, treating backslashes as separators so that a path written on Windows
+    /// means the same thing everywhere.
     pub fn from_path(p: &Path) -> Result<Self> {
         if SubPath::is_subpath(&p) {
-            Ok(SubPath(p.to_path_buf()))
+            Ok(SubPath(p.to_string_lossy().replace('\\', "/").into()))
         } else {
             bail!("p {} isn't a strict subdirectory", p.display())
         }
@@ -67,7 +86,58 @@ impl<'de> Deserialize<'de> for SubPath {
     }
 }
 
+/// The path of the file an Idris module lives in, relative to the source directory it's in:
+/// `Data.Map` is in `Data/Map` (without an extension).
+pub fn mod_path(module: &str) -> PathBuf {
+    module.trim_matches('.').split('.').collect()
+}
+
+/// Turns an absolute path into one which isn't limited to 260 characters on Windows (a
+/// `\\?\` path), so that deeply nested packages can still be copied, unpacked and cleared.
+///
+/// Windows doesn't interpret `\\?\` paths at all, so the path is normalized first. Elsewhere,
+/// or if the path isn't absolute, it's returned as-is.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::{ffi::OsString, path::Prefix};
+
+    let path = normalize_path(path);
+    let mut components = path.components();
+    let mut res = match components.next() {
+        Some(Component::Prefix(p)) => match p.kind() {
+            Prefix::Disk(d) => OsString::from(format!("\\\\?\\{}:", d as char)),
+            Prefix::UNC(server, share) => {
+                let mut res = OsString::from("\\\\?\\UNC\\");
+                res.push(server);
+                res.push("\\");
+                res.push(share);
+                res
+            }
+            _ => return path,
+        },
+        _ => return path,
+    };
+
+    for c in components {
+        match c {
+            Component::RootDir => {}
+            c => {
+                res.push("\\");
+                res.push(c.as_os_str());
+            }
+        }
+    }
+
+    PathBuf::from(res)
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
 pub fn copy_dir_iter(walker: impl Iterator<Item = DirEntry>, from: &Path, to: &Path) -> Result<()> {
+    let to = long_path(to);
     for entry in walker {
         let to_p = to.join(entry.path().strip_prefix(from).unwrap());
         // Make sure that the file exists before we try copying
@@ -90,7 +160,12 @@ pub fn copy_dir(from: &Path, to: &Path, gitless: bool) -> Result<()> {
     let walker = WalkDir::new(from)
         .follow_links(true)
         .into_iter()
-        .filter_entry(|x| x.path() != to && (!gitless || x.file_name() != ".git"))
+        .filter_entry(|x| {
+            x.path() != to
+                && x.file_name() != LOCK_FILE
+                && !is_junk(x.file_name())
+                && (!gitless || x.file_name() != ".git")
+        })
         .filter_map(|x| {
             x.ok()
                 .and_then(|x| if valid_file(&x) { Some(x) } else { None })
@@ -99,14 +174,116 @@ pub fn copy_dir(from: &Path, to: &Path, gitless: bool) -> Result<()> {
     copy_dir_iter(walker, from, to)
 }
 
+/// Empties a directory, creating it if it doesn't exist.
+///
+/// The directory's lockfile is left alone, since whoever is clearing the directory is most likely
+/// the one holding the lock on it.
 pub fn clear_dir(dir: &Path) -> Result<()> {
+    let dir = &long_path(dir);
     if dir.exists() {
-        remove_dir_all::remove_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_str() == Some(LOCK_FILE) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                remove_dir_all::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
     }
     fs::create_dir_all(dir)?;
     Ok(())
 }
 
+/// Lexically resolves all of the `.` and `..` components of a path, without touching the file
+/// system.
+///
+/// Unlike `fs::canonicalize`, this works on paths which don't exist (yet) and doesn't resolve
+/// symlinks, so a path dependency is always named by the path the user wrote down.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                // We can't go above the root, and we have to keep leading `..`s of relative paths.
+                match res.components().next_back() {
+                    Some(Component::Normal(_)) => {
+                        res.pop();
+                    }
+                    Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                    _ => res.push(".."),
+                }
+            }
+            c => res.push(c.as_os_str()),
+        }
+    }
+
+    res
+}
+
+/// Finds a relative path which leads from `base` to `path`. Both paths should be absolute and
+/// normalized. Returns `None` if there's no such path (i.e. they're on different drives).
+pub fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut path_c = path.components().peekable();
+    let mut base_c = base.components().peekable();
+
+    // Paths on different drives don't share a prefix.
+    if path_c.peek() != base_c.peek() {
+        return None;
+    }
+
+    while path_c.peek().is_some() && path_c.peek() == base_c.peek() {
+        path_c.next();
+        base_c.next();
+    }
+
+    let mut res: PathBuf = base_c.map(|_| Component::ParentDir).collect();
+    res.extend(path_c);
+
+    if res.as_os_str().is_empty() {
+        res.push(".");
+    }
+
+    Some(res)
+}
+
+/// The date `days` days after the Unix epoch, as a year, month and day of the (proleptic)
+/// Gregorian calendar. This is Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Whether a file is junk which should never be considered part of a package: the swap and backup
+/// files of text editors, and the metadata files operating systems like to leave lying around.
+///
+/// We skip these whenever we copy, unpack or hash sources, so that having a file open in an
+/// editor doesn't change a package's hash and cause everything depending on it to be rebuilt.
+pub fn is_junk(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+
+    name == ".DS_Store"
+        || name == "Thumbs.db"
+        || name.ends_with(".swp")
+        || name.ends_with(".swo")
+        || name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+}
+
 pub fn valid_file(entry: &DirEntry) -> bool {
     entry.file_type().is_file()
 }
@@ -126,21 +303,17 @@ pub fn fmt_multiple(c: &shell::OutputGroup) -> String {
     c.0.iter().map(|x| fmt_output(&x)).join("\n")
 }
 
+/// Formats everything a process printed. If it printed to both stdout and stderr, each of them
+/// gets a header; nothing is left out, since the output of a failed compile is usually the only
+/// clue as to why it failed.
 pub fn fmt_output(c: &Output) -> String {
-    let mut res = String::new();
-    if !c.stderr.is_empty() {
-        if !c.stdout.is_empty() {
-            res.push_str("--- stdout\n");
-        }
-        res.push_str(format!("{}\n", String::from_utf8_lossy(&c.stderr)).as_ref());
-        res.push_str(format!("--- stderr\n{}\n", String::from_utf8_lossy(&c.stderr)).as_ref());
-    }
-    if !c.stdout.is_empty() {
-        if !c.stderr.is_empty() {
-            res.push_str("--- stdout\n");
-        }
-        res.push_str(format!("{}\n", String::from_utf8_lossy(&c.stdout)).as_ref());
-    }
+    let stdout = String::from_utf8_lossy(&c.stdout);
+    let stderr = String::from_utf8_lossy(&c.stderr);
+    let mut res = if !stdout.is_empty() && !stderr.is_empty() {
+        format!("--- stdout\n{}\n--- stderr\n{}\n", stdout, stderr)
+    } else {
+        format!("{}{}\n", stdout, stderr)
+    };
     // Remove the ending newline if it exists
     res.pop();
     res
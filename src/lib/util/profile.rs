@@ -0,0 +1,400 @@
+//! Timing spans, for finding out where elba itself spends its time.
+//!
+//! Every span is logged at the debug level, along with how long it took, once it ends. When
+//! profiling has been turned on with `enable`, spans are also recorded, and can be written out at
+//! the end as a Chrome trace (which chrome://tracing and Perfetto can open) or as folded stacks
+//! (which flamegraph tools take).
+//!
+//! Spans made with `span` belong to the thread they're made on: whatever span the thread is
+//! already in becomes their parent, and they become the parent of the spans made on that thread
+//! until they end. Work which runs alongside other work (like packages being built at the same
+//! time) gets its spans from `Span::child` instead, which doesn't touch the thread's spans at all.
+
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    path::Path,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use failure::{bail, format_err, ResultExt};
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use serde_json::json;
+use slog::{debug, Logger};
+
+use crate::util::error::Result;
+
+/// The thread ids which lanes show up with in a Chrome trace, after those of real threads.
+const LANE_TID: usize = 1000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(1);
+
+lazy_static! {
+    static ref PROFILE: Mutex<Profile> = Mutex::new(Profile::default());
+}
+
+thread_local! {
+    static THREAD: usize = NEXT_THREAD.fetch_add(1, Ordering::SeqCst);
+    /// The spans this thread is in, innermost last.
+    static STACK: RefCell<Vec<usize>> = RefCell::new(vec![]);
+}
+
+/// Everything recorded since profiling was turned on.
+struct Profile {
+    start: Instant,
+    records: Vec<Record>,
+    /// Which lanes are being used by a span at the moment.
+    lanes: Vec<bool>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            start: Instant::now(),
+            records: vec![],
+            lanes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    id: usize,
+    parent: Option<usize>,
+    name: String,
+    start: Instant,
+    duration: Duration,
+    place: Place,
+}
+
+/// Where a span shows up in a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Place {
+    /// On the thread it was made on.
+    Thread(usize),
+    /// In a lane of its own, shared only with spans which don't overlap with it.
+    Lane(usize),
+}
+
+impl Place {
+    fn tid(self) -> usize {
+        match self {
+            Place::Thread(t) => t,
+            Place::Lane(l) => LANE_TID + l,
+        }
+    }
+}
+
+/// Starts recording spans.
+pub fn enable() {
+    let mut profile = PROFILE.lock().unwrap();
+    *profile = Profile::default();
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Starts a span on this thread, inside whatever span the thread is already in.
+pub fn span(logger: &Logger, name: impl Into<String>) -> Span {
+    Span::enter(Some(logger.clone()), name.into())
+}
+
+/// A part of what elba does which is timed. The span ends when it's dropped.
+#[derive(Debug)]
+pub struct Span {
+    id: usize,
+    parent: Option<usize>,
+    name: String,
+    logger: Option<Logger>,
+    start: Instant,
+    place: Place,
+    entered: bool,
+}
+
+impl Span {
+    /// Starts a span which isn't logged anywhere, just recorded if profiling is on. This is meant
+    /// for what's outside of the library, which doesn't have a logger to log to.
+    pub fn root(name: impl Into<String>) -> Span {
+        Span::enter(None, name.into())
+    }
+
+    fn enter(logger: Option<Logger>, name: String) -> Span {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let parent = STACK.with(|x| {
+            let mut stack = x.borrow_mut();
+            let parent = stack.last().cloned();
+            stack.push(id);
+            parent
+        });
+
+        Span {
+            id,
+            parent,
+            name,
+            logger,
+            start: Instant::now(),
+            place: Place::Thread(THREAD.with(|x| *x)),
+            entered: true,
+        }
+    }
+
+    /// Starts a span inside this one, for work which might run alongside other work. It doesn't
+    /// become the parent of anything else on the thread it's made on.
+    pub fn child(&self, name: impl Into<String>) -> Span {
+        let lane = if enabled() {
+            let mut profile = PROFILE.lock().unwrap();
+            match profile.lanes.iter().position(|x| !x) {
+                Some(lane) => {
+                    profile.lanes[lane] = true;
+                    lane
+                }
+                None => {
+                    profile.lanes.push(true);
+                    profile.lanes.len() - 1
+                }
+            }
+        } else {
+            0
+        };
+
+        Span {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            parent: Some(self.id),
+            name: name.into(),
+            logger: self.logger.clone(),
+            start: Instant::now(),
+            place: Place::Lane(lane),
+            entered: false,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+
+        if let Some(logger) = &self.logger {
+            debug!(
+                logger, "span finished";
+                "span" => &self.name,
+                "ms" => duration.as_millis() as u64
+            );
+        }
+
+        if self.entered {
+            let _ = STACK.try_with(|x| {
+                let mut stack = x.borrow_mut();
+                if let Some(ix) = stack.iter().rposition(|&x| x == self.id) {
+                    stack.remove(ix);
+                }
+            });
+        }
+
+        if enabled() {
+            let mut profile = PROFILE.lock().unwrap();
+            if let Place::Lane(lane) = self.place {
+                if let Some(x) = profile.lanes.get_mut(lane) {
+                    *x = false;
+                }
+            }
+            profile.records.push(Record {
+                id: self.id,
+                parent: self.parent,
+                name: self.name.clone(),
+                start: self.start,
+                duration,
+                place: self.place,
+            });
+        }
+    }
+}
+
+/// The formats a profile can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The Trace Event Format, as JSON.
+    Chrome,
+    /// One line for each stack of spans, with the time (in microseconds) spent in the innermost
+    /// span and not in any of the spans inside of it.
+    Folded,
+}
+
+impl FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "chrome" => Ok(Format::Chrome),
+            "folded" => Ok(Format::Folded),
+            _ => bail!("unknown profile format {} (expected chrome or folded)", s),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Chrome => write!(f, "chrome"),
+            Format::Folded => write!(f, "folded"),
+        }
+    }
+}
+
+/// Writes out the spans which have ended since profiling was turned on.
+pub fn write(path: &Path, format: Format) -> Result<()> {
+    let profile = PROFILE.lock().unwrap();
+    let contents = match format {
+        Format::Chrome => chrome(&profile),
+        Format::Folded => folded(&profile.records),
+    };
+
+    fs::write(path, contents)
+        .with_context(|e| format_err!("couldn't write profile to {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros() as u64
+}
+
+fn chrome(profile: &Profile) -> String {
+    let pid = process::id();
+    let mut events = vec![];
+
+    let mut places = profile.records.iter().map(|x| x.place).collect::<Vec<_>>();
+    places.sort_by_key(|x| x.tid());
+    places.dedup();
+    for place in places {
+        let name = match place {
+            Place::Thread(t) => format!("thread {}", t),
+            Place::Lane(l) => format!("lane {}", l + 1),
+        };
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": pid,
+            "tid": place.tid(),
+            "args": { "name": name },
+        }));
+    }
+
+    for record in &profile.records {
+        events.push(json!({
+            "name": record.name,
+            "cat": "elba",
+            "ph": "X",
+            "ts": micros(record.start.saturating_duration_since(profile.start)),
+            "dur": micros(record.duration),
+            "pid": pid,
+            "tid": record.place.tid(),
+        }));
+    }
+
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" }).to_string()
+}
+
+fn folded(records: &[Record]) -> String {
+    let by_id = records
+        .iter()
+        .map(|x| (x.id, x))
+        .collect::<IndexMap<_, _>>();
+
+    let mut children = IndexMap::new();
+    for record in records {
+        if let Some(parent) = record.parent {
+            *children.entry(parent).or_insert(Duration::from_secs(0)) += record.duration;
+        }
+    }
+
+    let mut stacks: IndexMap<String, u64> = IndexMap::new();
+    for record in records {
+        let mut names = vec![record.name.replace(';', ",")];
+        let mut parent = record.parent.and_then(|x| by_id.get(&x));
+        while let Some(p) = parent {
+            names.push(p.name.replace(';', ","));
+            parent = p.parent.and_then(|x| by_id.get(&x));
+        }
+        names.reverse();
+
+        // Work which ran alongside other work can add up to more than the span it's in.
+        let own = record.duration.checked_sub(
+            children
+                .get(&record.id)
+                .cloned()
+                .unwrap_or_else(|| Duration::from_secs(0)),
+        );
+        *stacks.entry(names.join(";")).or_insert(0) += own.map(micros).unwrap_or(0);
+    }
+
+    stacks
+        .into_iter()
+        .filter(|(_, t)| *t > 0)
+        .map(|(s, t)| format!("{} {}\n", s, t))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, parent: Option<usize>, name: &str, ms: u64) -> Record {
+        Record {
+            id,
+            parent,
+            name: name.to_string(),
+            start: Instant::now(),
+            duration: Duration::from_millis(ms),
+            place: Place::Thread(1),
+        }
+    }
+
+    #[test]
+    fn folded_self_time() {
+        let records = vec![
+            record(2, Some(1), "resolve", 30),
+            record(4, Some(3), "build a", 20),
+            record(5, Some(3), "build b", 10),
+            record(3, Some(1), "build", 50),
+            record(1, None, "elba build", 100),
+        ];
+
+        assert_eq!(
+            folded(&records),
+            "elba build;resolve 30000\n\
+             elba build;build;build a 20000\n\
+             elba build;build;build b 10000\n\
+             elba build;build 20000\n\
+             elba build 20000\n"
+        );
+    }
+
+    #[test]
+    fn spans_nest_on_threads() {
+        let outer = Span::root("outer");
+        let inner = Span::root("inner");
+        let lane = inner.child("lane");
+        let sibling = Span::root("sibling");
+
+        assert_eq!(outer.parent, None);
+        assert_eq!(inner.parent, Some(outer.id));
+        assert_eq!(lane.parent, Some(inner.id));
+        // A child isn't the parent of what comes after it on the same thread.
+        assert_eq!(sibling.parent, Some(inner.id));
+
+        drop(sibling);
+        drop(lane);
+        drop(inner);
+        assert_eq!(Span::root("after").parent, Some(outer.id));
+    }
+}
@@ -2,16 +2,19 @@
 //! building command-line invocations.
 
 use crate::util::{error::Result, fmt_output, read2};
+use console::{strip_ansi_codes, Term};
 use failure::{bail, ResultExt};
-use serde::{
-    de::{Deserialize, Deserializer, Error},
-    ser::{Serialize, Serializer},
-};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     borrow::Cow,
+    env,
     fmt::Display,
+    io::{self, Write},
     process::{Command, ExitStatus, Output, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 /// The requested verbosity of output
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -60,23 +63,261 @@ impl Default for Verbosity {
     }
 }
 
+/// How output is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// Status lines meant to be read by people.
+    Human,
+    /// One JSON object per line, meant to be read by other programs. Every object is an `Event`.
+    Json,
+}
+
+impl Default for MessageFormat {
+    fn default() -> Self {
+        MessageFormat::Human
+    }
+}
+
+/// Something that happened, as it's printed with `--message-format json`. Every event is an object
+/// with a `reason` field saying which kind of event it is.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A step of whatever elba is doing, like retrieving or compiling a package.
+    Status {
+        status: Cow<'a, str>,
+        message: Cow<'a, str>,
+    },
+    /// How far along a long-running step is.
+    Progress {
+        status: Cow<'a, str>,
+        message: Cow<'a, str>,
+        done: u64,
+        total: u64,
+    },
+    Warning {
+        message: Cow<'a, str>,
+    },
+    Error {
+        message: Cow<'a, str>,
+        /// The code of the error, which `elba explain` says more about.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<&'a str>,
+    },
+    /// Output which isn't a status line, like the output of the compiler.
+    Output {
+        message: Cow<'a, str>,
+    },
+    /// The result of running a test binary.
+    Test {
+        name: &'a str,
+        success: bool,
+        stdout: Cow<'a, str>,
+        stderr: Cow<'a, str>,
+    },
+    /// The end of the command. This is always the last event.
+    Finished {
+        success: bool,
+        message: Cow<'a, str>,
+        /// How long the command took, in seconds.
+        elapsed: f64,
+    },
+}
+
+impl<'a> Event<'a> {
+    /// The event for a status line. `[warn]` and `[error]` lines are warnings and errors.
+    fn from_status(status: &'a str, message: &'a str) -> Self {
+        let message = strip_ansi_codes(message);
+        match strip_ansi_codes(status).trim() {
+            "[warn]" => Event::Warning { message },
+            "[error]" => Event::Error {
+                message,
+                code: None,
+            },
+            status => Event::Status {
+                status: Cow::Owned(status.to_string()),
+                message,
+            },
+        }
+    }
+
+    pub fn print(&self) {
+        // Serializing an event can't fail: it's nothing but strings, numbers and bools.
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
+
+/// Whether output should be plain by default: when stdout isn't a terminal, or when running in CI
+/// (where the `CI` environment variable is set).
+pub fn plain_by_default() -> bool {
+    let ci = env::var("CI")
+        .map(|x| !x.is_empty() && x != "false" && x != "0")
+        .unwrap_or(false);
+
+    ci || !console::user_attended()
+}
+
+/// Whether output should be ASCII by default: when the terminal says it's a dumb one.
+pub fn ascii_by_default() -> bool {
+    env::var("TERM").map(|x| x == "dumb").unwrap_or(false)
+}
+
+/// How many columns a string takes up in a terminal. Wide characters (like those of CJK scripts)
+/// take up two, and escape codes for styling take up none.
+pub fn width(s: &str) -> usize {
+    UnicodeWidthStr::width(strip_ansi_codes(s).as_ref())
+}
+
+/// Pads a string with spaces on the right until it takes up `w` columns.
+pub fn pad(s: &str, w: usize) -> String {
+    format!("{}{}", s, " ".repeat(w.saturating_sub(width(s))))
+}
+
+/// Cuts a string short so that it takes up at most `w` columns, ending it with `tail` if anything
+/// was cut. A string which gets cut loses its styling.
+pub fn truncate<'a>(s: &'a str, w: usize, tail: &str) -> Cow<'a, str> {
+    if width(s) <= w {
+        return Cow::Borrowed(s);
+    }
+
+    let budget = w.saturating_sub(width(tail));
+    let mut res = String::new();
+    let mut used = 0;
+    for c in strip_ansi_codes(s).chars() {
+        let cw = c.width().unwrap_or(0);
+        if used + cw > budget {
+            break;
+        }
+        used += cw;
+        res.push(c);
+    }
+    res.push_str(tail);
+
+    Cow::Owned(res)
+}
+
+/// Replaces everything a dumb terminal can't show: styling is removed, and characters which
+/// aren't ASCII are escaped.
+pub fn to_ascii(s: &str) -> Cow<str> {
+    let stripped = strip_ansi_codes(s);
+    if stripped.is_ascii() {
+        return stripped;
+    }
+
+    Cow::Owned(
+        stripped
+            .chars()
+            .map(|c| {
+                if c.is_ascii() {
+                    c.to_string()
+                } else {
+                    c.escape_unicode().to_string()
+                }
+            })
+            .collect(),
+    )
+}
+
+/// The time of day (in UTC) which plain output puts in front of every line.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+        % 86400;
+
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// The last tenth of the way through something which plain output printed the progress of.
+static LAST_PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Where status lines, warnings and progress go. The command line makes one out of its
+/// configuration and hands it to everything which has something to say (the `Cache`, the
+/// `Retriever`, the resolver and the build queue all hold a copy), so that all of it comes out
+/// in the same format and respects the same verbosity, rather than being printed directly.
 #[derive(Debug, Clone, Copy)]
 pub struct Shell {
     pub verbosity: Verbosity,
+    pub format: MessageFormat,
+    /// Whether to print output which reads well in a log file rather than in a terminal: every
+    /// line gets a timestamp, and progress is printed on lines of its own every so often instead
+    /// of on one line which keeps getting overwritten.
+    pub plain: bool,
+    /// Whether to print nothing but ASCII, without any styling, for terminals which can't show
+    /// anything else.
+    pub ascii: bool,
 }
 
 impl Default for Shell {
     fn default() -> Self {
         Shell {
             verbosity: Verbosity::Normal,
+            format: MessageFormat::Human,
+            plain: false,
+            ascii: false,
         }
     }
 }
 
 impl Shell {
+    pub fn is_json(self) -> bool {
+        self.format == MessageFormat::Json
+    }
+
+    /// Whether output is plain, printed for log files rather than for terminals.
+    pub fn is_plain(self) -> bool {
+        self.plain && !self.is_json()
+    }
+
+    /// Prints an event if output is JSON.
+    pub fn event(self, event: &Event, min_verbosity: Verbosity) {
+        if self.is_json() && self.verbosity >= min_verbosity {
+            event.print();
+        }
+    }
+
+    /// Whether output is ASCII, for dumb terminals.
+    pub fn is_ascii(self) -> bool {
+        self.ascii && !self.is_json()
+    }
+
+    /// What to end a string with when it's been cut short.
+    pub fn ellipsis(self) -> &'static str {
+        if self.is_ascii() {
+            "..."
+        } else {
+            "…"
+        }
+    }
+
+    /// A status, right-aligned the way status lines have them.
+    fn status(self, status: impl Display) -> String {
+        // Styling doesn't count towards the alignment, so this happens before it's removed.
+        self.render(format!("{:>12}", status))
+    }
+
+    /// Output for people, as it should be printed on this terminal.
+    fn render(self, s: impl Display) -> String {
+        let s = s.to_string();
+        if self.ascii {
+            to_ascii(&s).into_owned()
+        } else {
+            s
+        }
+    }
+
     pub fn println(self, status: impl Display, message: impl Display, min_verbosity: Verbosity) {
         if self.verbosity >= min_verbosity {
-            println!("{:>12} {}", status, message);
+            if self.is_json() {
+                Event::from_status(&status.to_string(), &message.to_string()).print();
+            } else if self.plain {
+                let (status, message) = (self.status(status), self.render(message));
+                println!("[{}] {} {}", timestamp(), status, message);
+            } else {
+                println!("{} {}", self.status(status), self.render(message));
+            }
         }
     }
 
@@ -88,7 +329,15 @@ impl Shell {
     ) {
         if self.verbosity >= min_verbosity {
             let message = format!("{}", message);
-            println!("{} {}", status, message);
+            if self.is_json() {
+                Event::from_status(&status.to_string(), &message).print();
+            } else if self.plain {
+                let (status, message) = (self.render(status), self.render(message));
+                println!("[{}] {} {}", timestamp(), status, message);
+            } else {
+                let (status, message) = (self.render(status), self.render(message));
+                println!("{} {}", status, message);
+            }
         }
     }
 
@@ -96,7 +345,14 @@ impl Shell {
         if self.verbosity >= min_verbosity {
             let message = format!("{}", message);
             if !message.trim().is_empty() {
-                println!("{}", message);
+                if self.is_json() {
+                    Event::Output {
+                        message: strip_ansi_codes(&message),
+                    }
+                    .print();
+                } else {
+                    println!("{}", self.render(message));
+                }
             }
         }
     }
@@ -105,13 +361,82 @@ impl Shell {
         if self.verbosity >= min_verbosity {
             let message = format!("{}", message);
             if !message.trim().is_empty() {
-                print!("{}", message);
+                if self.is_json() {
+                    Event::Output {
+                        message: strip_ansi_codes(&message),
+                    }
+                    .print();
+                } else {
+                    print!("{}", self.render(message));
+                }
             }
         }
     }
 
+    /// Prints a status line with how far along something is, overwriting the status line printed
+    /// before it. Whoever prints the last one is responsible for ending the line with
+    /// `end_progress`.
+    ///
+    /// Plain output can't overwrite lines, so it prints a line of its own every tenth of the way
+    /// instead.
+    pub fn print_progress(
+        self,
+        status: impl Display,
+        message: impl Display,
+        done: u64,
+        total: u64,
+        min_verbosity: Verbosity,
+    ) {
+        if self.verbosity >= min_verbosity && total > 0 {
+            if self.is_json() {
+                Event::Progress {
+                    status: Cow::Owned(strip_ansi_codes(&status.to_string()).trim().to_string()),
+                    message: Cow::Owned(strip_ansi_codes(&message.to_string()).into_owned()),
+                    done,
+                    total,
+                }
+                .print();
+            } else if self.plain {
+                let tenth = done * 10 / total;
+                let last = LAST_PROGRESS.swap(tenth, Ordering::Relaxed);
+                if tenth != last {
+                    println!(
+                        "[{}] {} {} ({}%)",
+                        timestamp(),
+                        self.status(status),
+                        self.render(message),
+                        done * 100 / total
+                    );
+                }
+            } else {
+                // The line has to fit on the terminal to be overwritten by the next one, and has
+                // to cover up all of the one before it.
+                let cols = (Term::stdout().size().1 as usize).saturating_sub(1);
+                let percent = format!(" ({}%)", done * 100 / total);
+                let room = cols.saturating_sub(12 + 1 + width(&percent));
+                let message = self.render(message);
+                let line = format!(
+                    "{} {}{}",
+                    self.status(status),
+                    truncate(&message, room, self.ellipsis()),
+                    percent
+                );
+                print!("\r{}", pad(&line, cols));
+                let _ = io::stdout().flush();
+            }
+        }
+    }
+
+    /// Ends the line of progress printed by `print_progress`.
+    pub fn end_progress(self, min_verbosity: Verbosity) {
+        LAST_PROGRESS.store(0, Ordering::Relaxed);
+        if !self.is_plain() {
+            self.println_empty(min_verbosity);
+        }
+    }
+
     pub fn println_empty(self, min_verbosity: Verbosity) {
-        if self.verbosity >= min_verbosity {
+        if self.verbosity >= min_verbosity && !self.is_json() {
             println!("");
         }
     }
@@ -265,3 +590,33 @@ impl CommandExt for Command {
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widths() {
+        assert_eq!(width("elba"), 4);
+        assert_eq!(width("中文包"), 6);
+        assert_eq!(width("\u{1b}[36mBuilding\u{1b}[0m"), 8);
+
+        assert_eq!(pad("中文", 6), "中文  ");
+        assert_eq!(pad("too long", 3), "too long");
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("short", 10, "..."), "short");
+        assert_eq!(truncate("a long summary", 9, "..."), "a long...");
+        // A wide character which doesn't fit whole is left out rather than split.
+        assert_eq!(truncate("中文包名字", 6, "…"), "中文…");
+    }
+
+    #[test]
+    fn ascii() {
+        let styled = "\u{1b}[36mBuilding\u{1b}[0m";
+        assert_eq!(to_ascii(styled), "Building");
+        assert_eq!(to_ascii("grp/包 1.0"), "grp/\\u{5305} 1.0");
+    }
+}
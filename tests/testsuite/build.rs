@@ -1,3 +1,268 @@
 // At the moment, this set of tests is one big TODO.
 // If we test the build process, we're gonna need the Idris compiler, but this is untenable for
 // Travis...
+
+use super::util::{shell, LOGGER};
+use elba::{
+    cli::{
+        build::{self, BuildCtx},
+        config, index,
+        new::{self, NewCtx},
+    },
+    package::{ipkg::Ipkg, manifest::Manifest, workspace::Workspace, Name, PackageId},
+    remote::{
+        mirror::Mirrors,
+        resolution::{DirectRes, IndexRes},
+    },
+    retrieve::{cache::Layout, Refresh},
+    util::{
+        config::{Config, Policy},
+        copy_dir,
+        error::Error,
+    },
+};
+use indexmap::IndexMap;
+use semver_constraints::Constraint;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tempdir::TempDir;
+
+fn data(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(path)
+}
+
+/// A context for working on projects without any indices, with its global cache under `root`.
+fn build_ctx(root: &Path) -> BuildCtx {
+    BuildCtx {
+        compiler: "idris".to_string(),
+        indices: IndexMap::new(),
+        global_cache: Layout {
+            bin: root.join("bin"),
+            build: root.join("build"),
+            indices: root.join("indices"),
+            keys: root.join("keys.toml"),
+            src: root.join("src"),
+            store: root.join("store"),
+            tmp: root.join("tmp"),
+        },
+        mirrors: Mirrors::default(),
+        logger: LOGGER.clone(),
+        threads: 1,
+        shell: shell(),
+        offline: false,
+        refresh: Refresh::Never,
+        opts: vec![],
+        timeout: Duration::from_secs(10),
+        index_cache: true,
+        policy: Policy::default(),
+        lock_timeout: None,
+    }
+}
+
+#[test]
+fn workspace_find_member() {
+    let ws = Workspace::find(&data("workspace/pkgs/b")).unwrap().unwrap();
+    let names = ws.members.keys().map(|n| n.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(ws.root, data("workspace"));
+    assert_eq!(ws.root_package, None);
+    assert_eq!(names, vec!["ws/a", "ws/b"]);
+}
+
+#[test]
+fn workspace_find_subdir() {
+    let member = Workspace::find(&data("workspace/pkgs/b/src"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(member.root, data("workspace"));
+
+    // A directory in the workspace which isn't in any member is in the workspace root's project.
+    let root = Workspace::find(&data("workspace/pkgs")).unwrap().unwrap();
+    assert_eq!(root.root, data("workspace"));
+}
+
+#[test]
+fn workspace_find_outside() {
+    assert!(Workspace::find(&data("pkgs/one")).unwrap().is_none());
+}
+
+#[test]
+fn workspace_overlap() {
+    assert!(Workspace::load(&data("workspace-overlap")).is_err());
+}
+
+#[test]
+fn workspace_redirect() {
+    let ws = Workspace::load(&data("workspace")).unwrap().unwrap();
+    let index = IndexRes {
+        res: DirectRes::from_str("dir+data/index/").unwrap(),
+    };
+    let a = PackageId::new(Name::from_str("ws/a").unwrap(), index.into());
+    let deps = ws.redirect(vec![
+        (a.clone(), Constraint::from_str("0.1").unwrap()),
+        (a.clone(), Constraint::from_str("2.0").unwrap()),
+    ]);
+
+    assert_eq!(deps[0].0, ws.member_id(a.name()).unwrap());
+    assert_eq!(deps[1].0, a);
+}
+
+#[test]
+fn workspace_new_member() {
+    let dir = TempDir::new("elba-new").unwrap();
+    let root = dir.path().join("ws");
+    new::new_workspace(&root, false).unwrap();
+
+    let name = Name::from_str("ws/c").unwrap();
+    let ctx = NewCtx {
+        path: root.join("pkgs/c"),
+        name: name.clone(),
+        author: None,
+        description: None,
+        license: None,
+        lib: true,
+        bin: false,
+        git: false,
+    };
+    new::new(ctx).unwrap();
+
+    let ws = Workspace::load(&root).unwrap().unwrap();
+    assert_eq!(ws.members[&name].path, root.join("pkgs/c"));
+}
+
+#[test]
+fn ipkg_import() {
+    let ipkg = Ipkg::from_str(
+        r#"
+package maths
+
+sourcedir = src
+modules = Maths, Maths.Ops
+pkgs = contrib, lightyear, bytes
+opts = "--warnreach -p contrib"
+"#,
+    )
+    .unwrap();
+
+    let toml = ipkg
+        .to_manifest(|pkg| {
+            Ok(match pkg {
+                "lightyear" => vec![r#""ziman/lightyear" = "0.1.0""#.to_owned()],
+                _ => vec![],
+            })
+        })
+        .unwrap();
+    let manifest = Manifest::from_str(&toml).unwrap();
+    let lib = manifest.targets.lib.unwrap();
+
+    assert_eq!(
+        manifest.package.name,
+        Name::from_str("maths/maths").unwrap()
+    );
+    assert_eq!(
+        manifest.dependencies.keys().collect::<Vec<_>>(),
+        vec![&Name::from_str("ziman/lightyear").unwrap()]
+    );
+    assert!(toml.contains("# TODO: `bytes` wasn't found in any index"));
+    assert_eq!(lib.mods.unwrap(), vec!["Maths", "Maths.Ops"]);
+    assert_eq!(
+        lib.idris_opts,
+        vec!["-p", "contrib", "--warnreach", "-p", "contrib"]
+    );
+}
+
+#[test]
+fn config_set() {
+    let dir = TempDir::new("elba-config").unwrap();
+    let path = dir.path().join(".elba/config.toml");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "# my settings\ncompiler = \"idris\"\n").unwrap();
+
+    config::set(&path, "compiler", "idris2").unwrap();
+    config::set(&path, "network.timeout", "30").unwrap();
+    assert!(config::set(&path, "jobs", "many").is_err());
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with("# my settings\n"));
+    let c: Config = toml::from_str(&contents).unwrap();
+    assert_eq!(c.compiler, "idris2");
+    assert_eq!(c.network.timeout, 30);
+    assert_eq!(c.jobs, 2);
+}
+
+#[test]
+fn package_reproducible() {
+    let dir = TempDir::new("elba-package").unwrap();
+    let root = dir.path().join("pkg");
+    new::new(NewCtx {
+        path: root.clone(),
+        name: Name::from_str("grp/pkg").unwrap(),
+        author: None,
+        description: None,
+        license: None,
+        lib: true,
+        bin: false,
+        git: false,
+    })
+    .unwrap();
+
+    let (tarball, _) = index::package(&root).unwrap();
+    let first = std::fs::read(&tarball).unwrap();
+
+    // Touching a file changes its modification time, but not what gets packaged.
+    let lib = root.join("src/Pkg.idr");
+    let contents = std::fs::read(&lib).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::write(&lib, contents).unwrap();
+
+    let (tarball, _) = index::package(&root).unwrap();
+    assert_eq!(std::fs::read(&tarball).unwrap(), first);
+}
+
+#[test]
+fn lock_check() {
+    let dir = TempDir::new("elba-lock").unwrap();
+    let ctx = build_ctx(&dir.path().join("cache"));
+    copy_dir(&data("pkgs/one"), &dir.path().join("one"), false).unwrap();
+    let root = dir.path().join("pkg");
+    new::new(NewCtx {
+        path: root.clone(),
+        name: Name::from_str("grp/pkg").unwrap(),
+        author: None,
+        description: None,
+        license: None,
+        lib: true,
+        bin: false,
+        git: false,
+    })
+    .unwrap();
+
+    let manifest = root.join("elba.toml");
+    let without = std::fs::read_to_string(&manifest).unwrap();
+    let with = without.replace(
+        "[dependencies]\n",
+        "[dependencies]\n\"one/one\" = { path = \"../one\" }\n",
+    );
+    std::fs::write(&manifest, &with).unwrap();
+
+    // There's nothing to check until there's a lockfile.
+    assert!(build::lock(&ctx, &root, true).is_err());
+    build::lock(&ctx, &root, false).unwrap();
+    let lock = std::fs::read_to_string(root.join("elba.lock")).unwrap();
+    assert!(lock.contains("one/one"));
+    build::lock(&ctx, &root, true).unwrap();
+
+    // Checking a lockfile which is out of date fails without touching it.
+    std::fs::write(&manifest, &without).unwrap();
+    let err = build::lock(&ctx, &root, true).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&Error::LockfileOutdated));
+    assert_eq!(
+        std::fs::read_to_string(root.join("elba.lock")).unwrap(),
+        lock
+    );
+}
@@ -0,0 +1,522 @@
+// The global cache: what goes into it, and cleaning up after whatever went wrong with it.
+
+use super::util::{shell, LOGGER};
+use elba::{
+    cli::cache::{clean_tmp, verify, Verification},
+    package::{Name, PackageId},
+    remote::resolution::{DirectRes, IndexRes, Validators},
+    retrieve::cache::{
+        BuildHash, Cache, Layout, Refresh, Source, CONTENT_HASH_VERSION, FILE_HASHES, SESSIONS,
+    },
+    util::{
+        copy_dir,
+        fs::{is_complete, INCOMPLETE},
+        keys::TrustedKeys,
+        lock::{DirLock, LockKind},
+    },
+};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
+use tempdir::TempDir;
+use url::Url;
+
+fn cache(root: &Path) -> Cache {
+    let layout = Layout {
+        bin: root.join("bin"),
+        build: root.join("build"),
+        indices: root.join("indices"),
+        keys: root.join("keys.toml"),
+        src: root.join("src"),
+        store: root.join("store"),
+        tmp: root.join("tmp"),
+    };
+
+    Cache::from_disk(&LOGGER, layout, shell()).unwrap()
+}
+
+/// Packs `tests/data/pkgs/one` into a tarball at `path`, along with some `extra` files.
+fn tarball(path: &Path, extra: &[(&str, &str)]) -> DirectRes {
+    let pkg = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/pkgs/one");
+    let enc = GzEncoder::new(fs::File::create(path).unwrap(), Compression::default());
+    let mut tar = tar::Builder::new(enc);
+    for file in &["elba.toml", "src/Main.idr"] {
+        tar.append_path_with_name(pkg.join(file), file).unwrap();
+    }
+    for (name, contents) in extra {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+
+    DirectRes::Tar {
+        url: Url::from_file_path(path).unwrap(),
+        cksum: None,
+    }
+}
+
+/// Checks out a package, returning where its contents ended up.
+fn checkout(cache: &Cache, loc: &DirectRes) -> PathBuf {
+    let pkg = PackageId::new(Name::from_str("one/one").unwrap(), loc.clone().into());
+    let (_, source) = cache
+        .checkout_source(&pkg, loc, false, false, || ())
+        .unwrap();
+    source.path().to_owned()
+}
+
+/// The directory a resolution is checked out into, which links to its contents in the store.
+fn link(root: &Path, loc: &DirectRes) -> PathBuf {
+    root.join("src").join(Cache::get_source_dir(loc, true))
+}
+
+fn entries(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Serves `body` over HTTP for the next `n` requests, with an ETag. A request which already has
+/// the ETag is told that nothing has changed. Hands back the headers of every request it got.
+fn serve(body: Vec<u8>, n: usize) -> (Url, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/one.tar.gz", listener.local_addr().unwrap());
+
+    let handle = thread::spawn(move || {
+        let mut requests = vec![];
+        for stream in listener.incoming().take(n) {
+            let mut stream = stream.unwrap();
+            let mut req = vec![];
+            let mut buf = [0; 1024];
+            while !req.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => req.extend_from_slice(&buf[..len]),
+                }
+            }
+            let req = String::from_utf8_lossy(&req).to_lowercase();
+
+            if req.contains("if-none-match: \"v1\"") {
+                stream
+                    .write_all(
+                        b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                    )
+                    .unwrap();
+            } else {
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+            requests.push(req);
+        }
+        requests
+    });
+
+    (Url::parse(&url).unwrap(), handle)
+}
+
+/// A temporary build directory with something in it, like one a crashed build left behind.
+fn leftover(tmp: &Path, name: &str) {
+    fs::create_dir_all(tmp.join(name).join("lib")).unwrap();
+    fs::write(tmp.join(name).join("lib/Main.ibc"), "half-built").unwrap();
+}
+
+#[test]
+fn session_removed_when_done() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let tmp = root.path().join("tmp");
+    let cache = cache(root.path());
+
+    let layout = cache.checkout_tmp(&BuildHash("done".to_string())).unwrap();
+    fs::write(layout.lib.join("Main.ibc"), "built").unwrap();
+    drop(layout);
+    assert!(tmp.join("done").exists());
+    assert_eq!(fs::read_dir(tmp.join(SESSIONS)).unwrap().count(), 1);
+
+    drop(cache);
+    assert!(!tmp.join("done").exists());
+    assert_eq!(fs::read_dir(tmp.join(SESSIONS)).unwrap().count(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn crashed_session_reaped() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let tmp = root.path().join("tmp");
+    leftover(&tmp, "crashed");
+    leftover(&tmp, "unrelated");
+
+    // A process which has already exited stands in for one which crashed.
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let pid = child.id();
+    child.wait().unwrap();
+
+    fs::create_dir_all(tmp.join(SESSIONS)).unwrap();
+    fs::write(tmp.join(SESSIONS).join(format!("{}-0", pid)), "crashed\n").unwrap();
+
+    let _cache = cache(root.path());
+    assert!(!tmp.join("crashed").exists());
+    assert!(tmp.join("unrelated").exists());
+    assert_eq!(fs::read_dir(tmp.join(SESSIONS)).unwrap().count(), 0);
+}
+
+#[test]
+fn clean_tmp_skips_in_use() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let tmp = root.path().join("tmp");
+    let cache = cache(root.path());
+
+    let _layout = cache
+        .checkout_tmp(&BuildHash("in-use".to_string()))
+        .unwrap();
+    leftover(&tmp, "orphan");
+    fs::create_dir_all(tmp.join("scripts/project")).unwrap();
+
+    let res = clean_tmp(&cache).unwrap();
+    assert_eq!((res.removed, res.skipped), (1, 1));
+    assert!(tmp.join("in-use").exists());
+    assert!(!tmp.join("orphan").exists());
+    assert!(tmp.join("scripts/project").exists());
+}
+
+#[test]
+fn source_file_hashes_cached() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let pkg = root.path().join("one");
+    copy_dir(Path::new("tests/data/pkgs/one"), &pkg, false).unwrap();
+    fs::create_dir_all(pkg.join("target")).unwrap();
+
+    let id = PackageId::new(
+        Name::from_str("one/one").unwrap(),
+        DirectRes::Dir { path: pkg.clone() }.into(),
+    );
+    let source = || {
+        let lock = DirLock::acquire(&pkg, LockKind::Source).unwrap();
+        let loc = DirectRes::Dir { path: pkg.clone() };
+        Source::from_folder(&id, lock, loc)
+            .unwrap()
+            .hash()
+            .to_owned()
+    };
+
+    let first = source();
+    assert!(pkg.join("target").join(FILE_HASHES).exists());
+    assert_eq!(source(), first);
+
+    // A file which changed is hashed again, whatever the cache says.
+    fs::write(pkg.join("src/Main.idr"), "module Main\n\nmain : IO ()\n").unwrap();
+    assert_ne!(source(), first);
+}
+
+/// Sets the modification time of a file, in seconds since the epoch.
+#[cfg(unix)]
+fn set_modified(path: &Path, secs: i64) {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let time = libc::timespec {
+        tv_sec: secs as libc::time_t,
+        tv_nsec: 0,
+    };
+    let res = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), [time, time].as_ptr(), 0) };
+    assert_eq!(res, 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn source_file_hashes_racy() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let pkg = root.path().join("one");
+    copy_dir(Path::new("tests/data/pkgs/one"), &pkg, false).unwrap();
+    fs::create_dir_all(pkg.join("target")).unwrap();
+
+    let id = PackageId::new(
+        Name::from_str("one/one").unwrap(),
+        DirectRes::Dir { path: pkg.clone() }.into(),
+    );
+    let source = || {
+        let lock = DirLock::acquire(&pkg, LockKind::Source).unwrap();
+        let loc = DirectRes::Dir { path: pkg.clone() };
+        Source::from_folder(&id, lock, loc)
+            .unwrap()
+            .hash()
+            .to_owned()
+    };
+
+    // A file modified no earlier than it was hashed could have been changed again without its
+    // size or modification time changing, like when both happen within a tick of the clock.
+    let main = pkg.join("src/Main.idr");
+    let modified = 4_000_000_000;
+    set_modified(&main, modified);
+    let first = source();
+
+    let contents = fs::read_to_string(&main).unwrap().replace("Main", "Niam");
+    fs::write(&main, contents).unwrap();
+    set_modified(&main, modified);
+    assert_ne!(source(), first);
+}
+
+#[test]
+fn identical_sources_share_store() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    let a = tarball(&root.path().join("a.tar.gz"), &[]);
+    let b = tarball(&root.path().join("b.tar.gz"), &[]);
+
+    let stored = checkout(&cache, &a);
+    assert_eq!(checkout(&cache, &b), stored);
+    assert_eq!(
+        entries(&root.path().join("store").join(CONTENT_HASH_VERSION)).len(),
+        1
+    );
+
+    // What's left where each of them was retrieved is just a link to the store.
+    for loc in &[a, b] {
+        let link = link(root.path(), loc);
+        assert_eq!(cache.stored_path(&link), Some(Some(stored.clone())));
+        assert!(!link.join("elba.toml").exists());
+    }
+    assert!(stored.join("elba.toml").exists());
+}
+
+#[test]
+fn verify_corrupt_source() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    let loc = tarball(&root.path().join("one.tar.gz"), &[]);
+    let stored = checkout(&cache, &loc);
+
+    let clean = Verification {
+        checked: 1,
+        bad: 0,
+        evicted: 0,
+    };
+    assert_eq!(verify(&cache, false).unwrap(), clean);
+
+    fs::write(stored.join("src/Main.idr"), "module Main\n").unwrap();
+    let res = verify(&cache, false).unwrap();
+    assert_eq!((res.checked, res.bad, res.evicted), (1, 1, 0));
+    assert!(stored.exists());
+
+    // Evicting the contents leaves the link to them dangling, which is evicted too.
+    let res = verify(&cache, true).unwrap();
+    assert_eq!((res.checked, res.bad, res.evicted), (2, 2, 2));
+    assert!(!stored.exists());
+    assert!(!link(root.path(), &loc).exists());
+
+    // The next checkout retrieves it all over again.
+    assert_eq!(checkout(&cache, &loc), stored);
+    assert_eq!(verify(&cache, false).unwrap(), clean);
+}
+
+#[test]
+fn verify_corrupt_build() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+
+    let hash = BuildHash("corrupt".to_string());
+    let layout = cache.checkout_tmp(&hash).unwrap();
+    fs::write(layout.lib.join("Main.ibc"), "built").unwrap();
+    drop(cache.store_build(&layout.lib, &hash).unwrap());
+    drop(layout);
+
+    // Builds from older versions of elba don't have a checksum to check.
+    fs::create_dir_all(root.path().join("build/old")).unwrap();
+
+    let res = verify(&cache, false).unwrap();
+    assert_eq!((res.checked, res.bad), (1, 0));
+
+    fs::write(root.path().join("build/corrupt/Main.ibc"), "tampered").unwrap();
+    let res = verify(&cache, true).unwrap();
+    assert_eq!((res.checked, res.bad, res.evicted), (1, 1, 1));
+    assert!(!root.path().join("build/corrupt").exists());
+    assert!(root.path().join("build/old").exists());
+}
+
+#[test]
+fn verify_removes_leftovers() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+
+    // Copies into the store or the build cache which were interrupted.
+    let store = root
+        .path()
+        .join("store")
+        .join(CONTENT_HASH_VERSION)
+        .join(".abc.tmp-1-0");
+    let build = root.path().join("build/.abc.tmp-1-0");
+    for dir in &[&store, &build] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("Main.ibc"), "half-copied").unwrap();
+    }
+
+    assert_eq!(verify(&cache, false).unwrap(), Verification::default());
+    assert!(store.exists() && build.exists());
+
+    assert_eq!(verify(&cache, true).unwrap(), Verification::default());
+    assert!(!store.exists() && !build.exists());
+}
+
+#[test]
+fn incomplete_source_retrieved_again() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    let loc = tarball(&root.path().join("one.tar.gz"), &[]);
+    let stored = checkout(&cache, &loc);
+
+    // A retrieval which was interrupted while moving the package into place leaves this behind.
+    let link = link(root.path(), &loc);
+    fs::write(link.join(INCOMPLETE), "").unwrap();
+    assert!(!is_complete(&link));
+
+    assert_eq!(checkout(&cache, &loc), stored);
+    assert!(is_complete(&link));
+    assert_eq!(cache.stored_path(&link), Some(Some(stored)));
+}
+
+#[test]
+fn nested_meta_files_kept() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    // Only the files right at the top of a cache entry are elba's.
+    let loc = tarball(
+        &root.path().join("one.tar.gz"),
+        &[("src/.checksum", "mine"), ("src/.store", "also mine")],
+    );
+
+    let stored = checkout(&cache, &loc);
+    assert_eq!(
+        fs::read_to_string(stored.join("src/.checksum")).unwrap(),
+        "mine"
+    );
+    assert_eq!(
+        fs::read_to_string(stored.join("src/.store")).unwrap(),
+        "also mine"
+    );
+
+    // They're part of what the contents are checked against, too.
+    assert_eq!(verify(&cache, false).unwrap().bad, 0);
+    fs::write(stored.join("src/.checksum"), "changed").unwrap();
+    assert_eq!(verify(&cache, false).unwrap().bad, 1);
+}
+
+#[test]
+fn tarball_revalidated() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    let file = root.path().join("one.tar.gz");
+    tarball(&file, &[]);
+    let (url, server) = serve(fs::read(&file).unwrap(), 2);
+
+    let loc = DirectRes::Tar { url, cksum: None };
+    let pkg = PackageId::new(Name::from_str("one/one").unwrap(), loc.clone().into());
+    let stored = checkout(&cache, &loc);
+    let validators = Validators::read(&link(root.path(), &loc)).unwrap();
+    assert_eq!(validators.etag, Some("\"v1\"".to_string()));
+
+    // Updating eagerly asks the server whether the tarball changed, rather than downloading it
+    // all over again.
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, true, false, || ())
+        .unwrap();
+    assert_eq!(source.path(), stored.as_path());
+
+    let requests = server.join().unwrap();
+    assert!(!requests[0].contains("if-none-match"));
+    assert!(requests[1].contains("if-none-match: \"v1\""));
+}
+
+#[test]
+fn corrupt_index_retrieved_again() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+
+    let file = root.path().join("index.tar.gz");
+    let enc = GzEncoder::new(fs::File::create(&file).unwrap(), Compression::default());
+    let mut tar = tar::Builder::new(enc);
+    tar.append_dir_all(
+        ".",
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/index"),
+    )
+    .unwrap();
+    tar.into_inner().unwrap().finish().unwrap();
+    let res = DirectRes::Tar {
+        url: Url::from_file_path(&file).unwrap(),
+        cksum: None,
+    };
+
+    let load = || {
+        cache
+            .get_indices(&[res.clone()], Refresh::Never, false)
+            .indices
+            .len()
+    };
+    assert_eq!(load(), 1);
+
+    // However the cached copy got broken, it's thrown away and retrieved again.
+    let dir = root
+        .path()
+        .join("indices")
+        .join(Cache::get_source_dir(&res, false));
+    fs::write(dir.join("index.toml"), "garbage").unwrap();
+    assert_eq!(load(), 1);
+    assert_ne!(
+        fs::read_to_string(dir.join("index.toml")).unwrap(),
+        "garbage"
+    );
+
+    // Which is what clearing it by hand does too.
+    cache.clear_index(&res).unwrap();
+    assert!(!dir.join("index.toml").exists());
+    assert_eq!(load(), 1);
+}
+
+#[test]
+fn trusted_index_stays_secure() {
+    let root = TempDir::new("elba-cache").unwrap();
+    let cache = cache(root.path());
+    let path = root.path().join("index");
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/index");
+    copy_dir(&data, &path, false).unwrap();
+
+    let config = |secure: bool| {
+        format!(
+            "[index]\nsecure = {}\nkeys = [\"{}\"]\ndependencies = {{}}\n",
+            secure, "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"
+        )
+    };
+    let res = DirectRes::Dir { path: path.clone() };
+    let load = || {
+        cache
+            .get_indices(&[res.clone()], Refresh::Never, false)
+            .indices
+            .len()
+    };
+
+    fs::write(path.join("index.toml"), config(true)).unwrap();
+    assert_eq!(load(), 1);
+
+    // Otherwise, whoever controls the index could get unsigned entries accepted just by turning
+    // signing off.
+    fs::write(path.join("index.toml"), config(false)).unwrap();
+    assert_eq!(load(), 0);
+
+    let mut trusted = TrustedKeys::load(&root.path().join("keys.toml")).unwrap();
+    assert!(trusted.forget(&IndexRes { res: res.clone() }));
+    trusted.save().unwrap();
+    assert_eq!(load(), 1);
+}
@@ -0,0 +1,263 @@
+// Several elba operations at once against one cache, the way separate elba processes would run
+// them. Each thread gets its own Cache, just like a process would.
+
+use super::util::{shell, LOGGER};
+use elba::{
+    package::{Name, PackageId, Summary},
+    remote::resolution::DirectRes,
+    retrieve::{
+        cache::{BuildHash, Cache, Layout, OutputLayout},
+        Refresh, Retriever,
+    },
+    util::{
+        copy_dir,
+        graph::Graph,
+        lock::{DirLock, LockKind},
+    },
+};
+use failure::Error;
+use flate2::{write::GzEncoder, Compression};
+use futures::{executor::block_on, StreamExt};
+use indexmap::IndexMap;
+use itertools::Either::Left;
+use semver::Version;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Barrier},
+    thread,
+    time::Duration,
+};
+use tempdir::TempDir;
+use url::Url;
+
+const THREADS: usize = 8;
+const ROUNDS: usize = 4;
+/// Long enough for anything to finish, so that a deadlock fails a test instead of hanging it.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn data(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(path)
+}
+
+struct Env {
+    dir: TempDir,
+    index: DirectRes,
+    tarball: DirectRes,
+}
+
+impl Env {
+    fn new() -> Self {
+        let dir = TempDir::new("elba-concurrency").unwrap();
+        copy_dir(&data("index"), &dir.path().join("index"), false).unwrap();
+
+        let tarball = dir.path().join("one.tar.gz");
+        let enc = GzEncoder::new(fs::File::create(&tarball).unwrap(), Compression::default());
+        let mut tar = tar::Builder::new(enc);
+        for file in &["elba.toml", "src/Main.idr"] {
+            tar.append_path_with_name(data("pkgs/one").join(file), file)
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        Env {
+            index: DirectRes::Dir {
+                path: dir.path().join("index"),
+            },
+            tarball: DirectRes::Tar {
+                url: Url::from_file_path(&tarball).unwrap(),
+                cksum: None,
+            },
+            dir,
+        }
+    }
+
+    fn cache_dir(&self) -> PathBuf {
+        self.dir.path().join("cache")
+    }
+
+    fn cache(&self) -> Cache {
+        let root = self.cache_dir();
+        let layout = Layout {
+            bin: root.join("bin"),
+            build: root.join("build"),
+            indices: root.join("indices"),
+            keys: root.join("keys.toml"),
+            src: root.join("src"),
+            store: root.join("store"),
+            tmp: root.join("tmp"),
+        };
+
+        Cache::from_disk(&LOGGER, layout, shell())
+            .unwrap()
+            .with_lock_timeout(Some(LOCK_TIMEOUT))
+    }
+}
+
+/// Loads the indices and checks out a package, like resolving and retrieving dependencies does.
+fn retrieve(cache: &Cache, env: &Env) -> Result<(), Error> {
+    let indices = cache.get_indices(&[env.index.clone()], Refresh::Never, true);
+    assert_eq!(indices.indices.len(), 1);
+
+    let pkg = PackageId::new(Name::from_str("one/one")?, env.tarball.clone().into());
+    let (_, source) = cache.checkout_source(&pkg, &env.tarball, false, true, || ())?;
+
+    // Reloading the indices while holding a source is what a resolver does when it can't find a
+    // package, and it mustn't go against the lock order.
+    let indices = cache.reload_indices(indices, &[env.index.clone()], Refresh::Never, true);
+    assert_eq!(indices.indices.len(), 1);
+
+    drop(indices);
+    drop(source);
+    Ok(())
+}
+
+/// Builds a package into a project's target directory, reusing the cached build if there is one.
+fn build(cache: &Cache, env: &Env, hash: &BuildHash) -> Result<(), Error> {
+    let target = env.dir.path().join("project").join("target");
+    let target = DirLock::acquire_with(&target, LockKind::Target, None, Some(LOCK_TIMEOUT))?;
+    let target = OutputLayout::new(target)?;
+
+    let binary = match cache.checkout_build(hash)? {
+        Some(binary) => binary,
+        None => {
+            let layout = cache.checkout_tmp(hash)?;
+            fs::write(layout.lib.join("Main.ibc"), "built")?;
+            cache.store_build(&layout.lib, hash)?
+        }
+    };
+
+    let lib = target.lock_lib()?;
+    copy_dir(binary.target.path(), lib.path(), false)?;
+    Ok(())
+}
+
+fn entries(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+#[test]
+fn concurrent_operations() {
+    let env = Arc::new(Env::new());
+    let hash = BuildHash("concurrency".to_string());
+
+    let handles = (0..THREADS)
+        .map(|i| {
+            let env = env.clone();
+            let hash = hash.clone();
+            thread::spawn(move || -> Result<(), Error> {
+                let cache = env.cache();
+                for round in 0..ROUNDS {
+                    // Half of the threads start with each operation, so that they overlap.
+                    if (i + round) % 2 == 0 {
+                        retrieve(&cache, &env)?;
+                        build(&cache, &env, &hash)?;
+                    } else {
+                        build(&cache, &env, &hash)?;
+                        retrieve(&cache, &env)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
+
+    // Everything ended up in the cache exactly once, with nothing half-finished left behind.
+    let cache = env.cache_dir();
+    assert_eq!(entries(&cache.join("store")).len(), 1);
+    assert_eq!(entries(&cache.join("build")), vec!["concurrency"]);
+    assert!(cache.join("build/concurrency/Main.ibc").exists());
+    assert!(env.dir.path().join("project/target/lib/Main.ibc").exists());
+}
+
+#[test]
+fn crossed_locks() {
+    // Two threads which each hold a lock the other one wants would wait on each other forever.
+    // The one going against the lock order gives up instead.
+    let tmp = TempDir::new("elba-concurrency").unwrap();
+    let index = tmp.path().join("index");
+    let build = tmp.path().join("build");
+    let barrier = Arc::new(Barrier::new(2));
+
+    let other = {
+        let (index, build, barrier) = (index.clone(), build.clone(), barrier.clone());
+        thread::spawn(move || {
+            let _index = DirLock::acquire(&index, LockKind::Index).unwrap();
+            barrier.wait();
+            DirLock::acquire(&build, LockKind::Build).map(|_| ())
+        })
+    };
+
+    let held = DirLock::acquire(&build, LockKind::Build).unwrap();
+    barrier.wait();
+    assert!(DirLock::acquire(&index, LockKind::Index).is_err());
+    drop(held);
+
+    other.join().unwrap().unwrap();
+}
+
+#[test]
+fn retrieval_streams_sources() {
+    let env = Env::new();
+    let cache = env.cache();
+    let ixmap = IndexMap::new();
+
+    let path = env.dir.path().join("root");
+    copy_dir(&data("pkgs/one"), &path, false).unwrap();
+    let name = Name::from_str("one/one").unwrap();
+    let version = Version::parse("0.1.0").unwrap();
+    let root = Summary::new(
+        PackageId::new(name.clone(), DirectRes::Dir { path }.into()),
+        version.clone(),
+    );
+    let dep = Summary::new(PackageId::new(name, env.tarball.clone().into()), version);
+
+    let mut graph = petgraph::Graph::new();
+    let r = graph.add_node(root.clone());
+    let d = graph.add_node(dep);
+    graph.add_edge(r, d, ());
+    let solve = Graph::new(graph);
+
+    let retriever = || {
+        Retriever::new(
+            &LOGGER,
+            &cache,
+            root.clone(),
+            vec![],
+            Left(vec![]),
+            Graph::default(),
+            &ixmap,
+            shell(),
+            false,
+            Refresh::Never,
+        )
+    };
+
+    // The root is already there, so only its dependency has to be downloaded.
+    let pending = retriever().pending(&solve).unwrap();
+    assert_eq!(pending.downloads(), 1);
+
+    // Each source is handed over as it's retrieved, and the channel closes once they all have
+    // been, so that whatever's waiting on them knows when to stop.
+    let (sources, mut ready) = pending
+        .retrieve(&solve, 2, |rx| {
+            block_on(rx.map(|(ix, _)| ix).collect::<Vec<_>>())
+        })
+        .unwrap();
+    ready.sort();
+    assert_eq!(ready, vec![r, d]);
+    assert!(sources[d].path().starts_with(env.cache_dir().join("store")));
+    drop(sources);
+
+    assert_eq!(retriever().pending(&solve).unwrap().downloads(), 0);
+}
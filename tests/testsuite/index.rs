@@ -1,6 +1,12 @@
-use super::util::index;
-use elba::package::Name;
-use std::str::FromStr;
+use super::util::{index, INDEX_DIR};
+use elba::{
+    package::Name,
+    remote::{canonical_json, resolution::DirectRes, Indices, ENTRY_VERSION},
+    util::keys::PublicKey,
+};
+use semver::Version;
+use std::{fs, path::Path, str::FromStr};
+use tempdir::TempDir;
 
 #[test]
 fn index_success() {
@@ -9,3 +15,181 @@ fn index_success() {
     let vs = i.entries(&Name::from_str("no_conflict/root").unwrap());
     vs.unwrap();
 }
+
+#[test]
+fn index_entry_versions() {
+    let i = index();
+
+    let vs = i
+        .entries(&Name::from_str("entry_schema/mixed").unwrap())
+        .unwrap();
+    // The version 3 entry is left out.
+    assert_eq!(vs.len(), 2);
+
+    let v1 = &vs[&Version::parse("1.0.0").unwrap()];
+    assert_eq!(v1.v, ENTRY_VERSION);
+    assert!(v1.cksum.is_none());
+    assert!(v1.features.is_empty());
+
+    let v2 = &vs[&Version::parse("2.0.0").unwrap()];
+    assert_eq!(v2.min_compiler, Some(Version::parse("1.3.0").unwrap()));
+    assert_eq!(v2.features["default"], vec!["fast".to_string()]);
+    match &v2.location {
+        DirectRes::Tar { cksum, .. } => assert_eq!(cksum, &v2.cksum),
+        _ => panic!("expected a tarball location"),
+    }
+}
+
+#[test]
+fn index_local_package() {
+    let i = index();
+
+    let vs = i
+        .entries(&Name::from_str("entry_schema/local").unwrap())
+        .unwrap();
+    let v = &vs[&Version::parse("1.0.0").unwrap()];
+
+    match &v.location {
+        DirectRes::Tar { url, cksum } => {
+            assert_eq!(
+                url.to_file_path().unwrap(),
+                INDEX_DIR
+                    .path()
+                    .join("packages/entry_schema_local-1.0.0.tar.gz")
+            );
+            assert_eq!(cksum, &v.cksum);
+        }
+        _ => panic!("expected a tarball location"),
+    }
+    assert_eq!(v.metadata["docs"]["theme"], "dark");
+}
+
+#[test]
+fn index_signed_entries() {
+    let mut i = index();
+    i.config.index.secure = true;
+    i.trusted = vec![PublicKey::from_str(
+        "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c",
+    )
+    .unwrap()];
+
+    let vs = i.entries(&Name::from_str("entry_schema/signed").unwrap());
+    assert_eq!(vs.unwrap().len(), 1);
+
+    // Its location was changed after it was signed.
+    let vs = i.entries(&Name::from_str("entry_schema/tampered").unwrap());
+    assert!(vs.is_err());
+
+    let vs = i.entries(&Name::from_str("entry_schema/mixed").unwrap());
+    assert!(vs.is_err());
+
+    i.trusted = vec![PublicKey::from_str(
+        "8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394",
+    )
+    .unwrap()];
+    let vs = i.entries(&Name::from_str("entry_schema/signed").unwrap());
+    assert!(vs.is_err());
+
+    // An index with trusted keys can't turn signing off.
+    i.config.index.secure = false;
+    let vs = i.entries(&Name::from_str("entry_schema/signed").unwrap());
+    assert!(vs.is_err());
+    let vs = i.entries(&Name::from_str("entry_schema/mixed").unwrap());
+    assert!(vs.is_err());
+}
+
+#[test]
+fn index_canonical_json() {
+    let value: serde_json::Value =
+        serde_json::from_str(r#"{"b": 1, "a": {"d": "x\ny\u001fé", "c": [true, null, -2]}}"#)
+            .unwrap();
+    assert_eq!(
+        canonical_json(&value).unwrap(),
+        r#"{"a":{"c":[true,null,-2],"d":"x\ny\u001fé"},"b":1}"#
+    );
+
+    let value: serde_json::Value = serde_json::from_str(r#"{"a": 1.5}"#).unwrap();
+    assert!(canonical_json(&value).is_err());
+
+    // The form the signed test entry was signed in, outside of elba.
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/index/entry-schema/signed");
+    let mut entry: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    let sig = entry.as_object_mut().unwrap().remove("sig").unwrap();
+    let msg = canonical_json(&entry).unwrap();
+    assert_eq!(
+        msg,
+        r#"{"cksum":"sha256=3b7c4f1e0d2a9b8c6f5e4d3c2b1a09f8e7d6c5b4a3928170f6e5d4c3b2a19080","dependencies":[],"location":"tar+https://example.com/signed-1.0.0.tar.gz","name":"entry_schema/signed","v":2,"version":"1.0.0","yanked":false}"#
+    );
+    let key =
+        PublicKey::from_str("8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c")
+            .unwrap();
+    assert!(key.verify(msg.as_bytes(), sig.as_str().unwrap()));
+}
+
+#[test]
+fn index_search_ranking() {
+    let ixs = Indices::new(vec![index()]);
+
+    let hits = ixs.search("entry_schema/mixed", None, None).unwrap();
+    assert_eq!(hits[0].name.as_str(), "entry_schema/mixed");
+
+    // Its newest version has been yanked.
+    let hits = ixs.search("yanked", None, None).unwrap();
+    assert_eq!(hits[0].name.as_str(), "entry_schema/yanked");
+    assert_eq!(hits[0].version, Version::parse("1.0.0").unwrap());
+}
+
+#[test]
+fn index_search_category() {
+    let ixs = Indices::new(vec![index()]);
+
+    let hits = ixs.search("", None, Some("parsing")).unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].name.as_str(), "entry_schema/local");
+    assert_eq!(hits[0].keywords, vec!["local".to_string()]);
+
+    let hits = ixs.search("local", None, Some("networking")).unwrap();
+    assert!(hits.is_empty());
+}
+
+#[test]
+fn index_group() {
+    let ixs = Indices::new(vec![index()]);
+
+    let hits = ixs.group("Avoid_Conflict", None).unwrap();
+    let names = hits.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            "avoid_conflict/bar",
+            "avoid_conflict/foo",
+            "avoid_conflict/root"
+        ]
+    );
+
+    // Each package is listed with its latest version which hasn't been yanked.
+    let hits = ixs.group("entry-schema", None).unwrap();
+    let yanked = hits.iter().find(|x| x.name.name() == "yanked").unwrap();
+    assert_eq!(yanked.version.to_string(), "1.0.0");
+}
+
+#[test]
+fn index_entry_cache() {
+    let dir = TempDir::new("elba").unwrap();
+    let name = Name::from_str("entry_schema/local").unwrap();
+    let parsed = index().entries(&name).unwrap();
+
+    let i = index().with_entry_cache(dir.path().to_path_buf());
+    assert_eq!(i.entries(&name).unwrap(), parsed);
+    let cached = dir.path().join(name.as_normalized());
+    assert!(cached.exists());
+
+    // Reading them back from the cache gives the same entries, metadata and all.
+    assert_eq!(i.entries(&name).unwrap(), parsed);
+
+    // A cache which can't be read is parsed again from scratch and replaced.
+    fs::write(&cached, b"garbage").unwrap();
+    assert_eq!(i.entries(&name).unwrap(), parsed);
+    assert_ne!(fs::read(&cached).unwrap(), b"garbage".to_vec());
+}
@@ -1,4 +1,7 @@
 mod build;
+mod cache;
+mod concurrency;
 mod index;
+mod paths;
 mod resolver;
 mod util;
@@ -0,0 +1,196 @@
+use elba::{
+    package::manifest::Manifest,
+    util::{mod_path, SubPath},
+};
+use std::{fs, path::PathBuf, str::FromStr};
+use tempdir::TempDir;
+
+fn manifest(main: &str) -> Manifest {
+    Manifest::from_str(&format!(
+        r#"
+[package]
+name = "paths/test"
+version = "0.1.0"
+authors = []
+
+[[targets.bin]]
+name = "test"
+main = '{}'
+"#,
+        main
+    ))
+    .unwrap()
+}
+
+fn project() -> TempDir {
+    let tmp = TempDir::new("elba-paths").unwrap();
+    fs::create_dir_all(tmp.path().join("src/Data")).unwrap();
+    fs::write(tmp.path().join("src/Main.idr"), "").unwrap();
+    fs::write(tmp.path().join("src/Data/Map.idr"), "").unwrap();
+    tmp
+}
+
+#[test]
+fn subpath_backslashes() {
+    assert_eq!(
+        SubPath::from_str("src\\Data\\Map.idr").unwrap().0,
+        PathBuf::from("src/Data/Map.idr")
+    );
+    assert_eq!(
+        SubPath::from_str(".\\src").unwrap(),
+        SubPath::from_str("./src").unwrap()
+    );
+}
+
+#[test]
+fn subpath_escapes() {
+    for p in &[
+        "..",
+        "../x",
+        "..\\x",
+        "src\\..\\..\\x",
+        "/x",
+        "\\x",
+        "C:x",
+        "C:\\x",
+        "\\\\server\\share",
+    ] {
+        assert!(SubPath::from_str(p).is_err(), "{} is a subpath", p);
+    }
+}
+
+#[test]
+fn mod_paths() {
+    assert_eq!(mod_path("Main"), PathBuf::from("Main"));
+    assert_eq!(mod_path("Data.Map"), PathBuf::from("Data").join("Map"));
+    assert_eq!(mod_path(".Data.Map."), PathBuf::from("Data").join("Map"));
+}
+
+#[test]
+fn resolve_bin_backslashes() {
+    let tmp = project();
+    let bin = &manifest("src\\Main.idr").targets.bin[0];
+
+    assert_eq!(
+        bin.resolve_bin(tmp.path()),
+        Some((tmp.path().join("src"), PathBuf::from("Main.idr")))
+    );
+}
+
+#[test]
+fn resolve_bin_module() {
+    let tmp = project();
+    let bin = &manifest("Data.Map.main").targets.bin[0];
+
+    assert_eq!(
+        bin.resolve_bin(tmp.path()),
+        Some((
+            tmp.path().join("src"),
+            PathBuf::from("Data").join("Map").with_extension("main")
+        ))
+    );
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use elba::{
+        retrieve::cache::OutputLayout,
+        util::{
+            clear_dir, copy_dir,
+            lock::{DirLock, LockKind},
+            long_path,
+        },
+    };
+    use std::path::Path;
+
+    /// A directory nested deeply enough that its path is longer than `MAX_PATH`.
+    fn deep(root: &Path) -> PathBuf {
+        let mut path = long_path(root);
+        for _ in 0..12 {
+            path.push("a-directory-with-a-long-name");
+        }
+        assert!(path.as_os_str().len() > 260);
+        path
+    }
+
+    #[test]
+    fn long_path_disk() {
+        assert_eq!(
+            long_path(Path::new("C:\\Users\\elba\\..\\pkg\\.\\src")),
+            PathBuf::from("\\\\?\\C:\\Users\\pkg\\src")
+        );
+        assert_eq!(
+            long_path(Path::new("C:/Users/elba/pkg")),
+            PathBuf::from("\\\\?\\C:\\Users\\elba\\pkg")
+        );
+    }
+
+    #[test]
+    fn long_path_unc() {
+        assert_eq!(
+            long_path(Path::new("\\\\server\\share\\pkg")),
+            PathBuf::from("\\\\?\\UNC\\server\\share\\pkg")
+        );
+    }
+
+    #[test]
+    fn long_path_unchanged() {
+        for p in &[
+            "src\\Main.idr",
+            "\\\\?\\C:\\pkg",
+            "\\\\?\\UNC\\server\\share\\pkg",
+        ] {
+            assert_eq!(long_path(Path::new(p)), PathBuf::from(p));
+        }
+    }
+
+    #[test]
+    fn resolve_bin_verbatim() {
+        let tmp = project();
+        let parent = long_path(tmp.path());
+
+        let bin = &manifest("src/Main.idr").targets.bin[0];
+        assert_eq!(
+            bin.resolve_bin(&parent),
+            Some((parent.join("src"), PathBuf::from("Main.idr")))
+        );
+
+        let bin = &manifest("Data.Map.main").targets.bin[0];
+        assert_eq!(
+            bin.resolve_bin(&parent),
+            Some((parent.join("src"), PathBuf::from("Data\\Map.main")))
+        );
+    }
+
+    #[test]
+    fn copy_dir_deep() {
+        let from = project();
+        let to = TempDir::new("elba-paths").unwrap();
+        let target = deep(to.path());
+
+        copy_dir(from.path(), &target, false).unwrap();
+        assert!(target.join("src\\Data\\Map.idr").exists());
+
+        clear_dir(&target).unwrap();
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn output_layout_deep() {
+        let tmp = TempDir::new("elba-paths").unwrap();
+        let root = deep(tmp.path());
+        fs::create_dir_all(&root).unwrap();
+
+        let layout = OutputLayout::new(DirLock::acquire(&root, LockKind::Target).unwrap()).unwrap();
+        for dir in &[
+            &layout.artifacts,
+            &layout.bin,
+            &layout.docs,
+            &layout.lib,
+            &layout.build,
+        ] {
+            assert!(dir.is_dir());
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use super::util::{shell, CACHE, INDEX_DIR, IXMAP};
 use elba::{
-    package::{Name, PackageId, Summary},
+    package::{lockfile::LockfileToml, Name, PackageId, Summary},
     remote::{
         resolution::{DirectRes, IndexRes, Resolution},
         Indices,
     },
     resolve::Resolver,
-    retrieve::Retriever,
+    retrieve::{Refresh, Retriever},
     util::graph::Graph,
 };
 use itertools::Either::Right;
@@ -29,7 +29,7 @@ fn indices() -> Indices {
         path: INDEX_DIR.path().to_owned(),
     };
 
-    CACHE.get_indices(&[index], false, false)
+    CACHE.get_indices(&[index], Refresh::Never, false)
 }
 
 fn retriever(root: Summary) -> Retriever<'static> {
@@ -54,6 +54,7 @@ fn retriever(root: Summary) -> Retriever<'static> {
         &IXMAP,
         shell(),
         false,
+        Refresh::Never,
     )
 }
 
@@ -104,3 +105,29 @@ fn resolve_conflict_complex_report() {
     let msg = resolver.solve();
     assert!(msg.is_err())
 }
+
+#[test]
+fn resolve_skip_yanked() {
+    let mut retriever = retriever(sum!("entry_schema/root", "1.0.0"));
+    let resolver = resolver(&mut retriever);
+    let solve = resolver.solve().unwrap();
+    assert!(solve
+        .find_by(|sum| sum.name().as_str() == "entry_schema/yanked")
+        .map(|sum| sum.version() == &Version::parse("1.0.0").unwrap())
+        .unwrap_or(false))
+}
+
+#[test]
+fn resolve_lockfile_stable() {
+    let lockfile = || {
+        let mut retriever = retriever(sum!("conflict_res_partial/root", "1.0.0"));
+        let solve = resolver(&mut retriever).solve().unwrap();
+        let lf: LockfileToml = solve.into();
+        toml::to_string_pretty(&lf).unwrap()
+    };
+
+    let first = lockfile();
+    for _ in 0..3 {
+        assert_eq!(lockfile(), first);
+    }
+}
@@ -4,7 +4,11 @@ use elba::{
         Index,
     },
     retrieve::cache::{Cache, Layout},
-    util::{copy_dir, lock::DirLock, shell::Shell},
+    util::{
+        copy_dir,
+        lock::{DirLock, LockKind},
+        shell::Shell,
+    },
 };
 use indexmap::{indexmap, IndexMap};
 use lazy_static::lazy_static;
@@ -29,7 +33,7 @@ fn new_logger() -> Logger {
 
 pub fn index() -> Index {
     let url = DirectRes::from_str("dir+data/index").unwrap();
-    let path = DirLock::acquire(&INDEX_DIR.path()).unwrap();
+    let path = DirLock::acquire(&INDEX_DIR.path(), LockKind::Index).unwrap();
     Index::from_disk(url, path).unwrap()
 }
 
@@ -42,7 +46,9 @@ pub fn cache() -> Cache {
         bin: CACHE_DIR.path().join("bin"),
         build: CACHE_DIR.path().join("build"),
         indices: CACHE_DIR.path().join("indices"),
+        keys: CACHE_DIR.path().join("keys.toml"),
         src: CACHE_DIR.path().join("src"),
+        store: CACHE_DIR.path().join("store"),
         tmp: CACHE_DIR.path().join("tmp"),
     };
 